@@ -0,0 +1,155 @@
+#!/usr/bin/python
+"""Generates the article fixtures in fixtures/benchmark used by src/benchmark.rs.
+
+Each fixture is an (old, new, other) triple: an article, the same article after some organic
+edits, and the same article after some vandalism.
+
+The article is a redacted snapshot of a real article if one is given on the command line, and
+generated otherwise. A snapshot is the wikitext of an article, saved from its "?action=raw" URL
+(e.g. https://en.wikipedia.org/w/index.php?title=Fox&action=raw) to a file named for the fixture
+it's for (e.g. "medium.wiki"). Redacting it replaces every word of it with a placeholder word, so
+that only its structure is kept: its length, sections, paragraphs, links, templates and references.
+A generated article has the same kind of structure, made up. The fixtures checked in are
+generated.
+
+The output is deterministic. Run from the repository root:
+
+    $ scripts/generate_benchmark_fixtures.py [SNAPSHOT ...]
+"""
+
+import os
+import random
+import re
+import sys
+
+# The words that prose is made of, in generated articles, organic edits, and redacted snapshots.
+VOCAB = ("lorem ipsum dolor sit amet consectetur adipiscing elit sed do eiusmod tempor incididunt "
+         "ut labore et dolore magna aliqua enim ad minim veniam quis nostrud exercitation ullamco "
+         "laboris nisi aliquip ex ea commodo consequat duis aute irure in reprehenderit voluptate "
+         "velit esse cillum fugiat nulla pariatur excepteur sint occaecat cupidatat non proident "
+         "sunt culpa qui officia deserunt mollit anim id est laborum").split()
+
+# The words that vandalism is made of.
+VANDAL = "REDACTED VANDALISM lol was here is the best ever !!! hi mom".split()
+
+# Each fixture's name, the seed of its random numbers, the number of sections and of paragraphs
+# per section of its generated article, and the number of organic edits and of vandal edits made
+# to it.
+FIXTURES = [
+    ("small", 1, 2, 2, 5, 2),
+    ("medium", 2, 8, 3, 25, 4),
+    ("large", 3, 16, 5, 60, 6),
+]
+
+# The directory the fixtures are written to.
+FIXTURE_DIR = "fixtures/benchmark"
+
+
+def capitalized_word(r):
+    """Returns a random word, capitalized, as at the start of a sentence or title."""
+    return r.choice(VOCAB).capitalize()
+
+
+def sentence(r):
+    """Returns a random sentence, which may contain a link, and may be followed by a reference."""
+    words = [r.choice(VOCAB) for _ in range(r.randint(6, 18))]
+    if r.random() < 0.3:
+        index = r.randrange(len(words))
+        link_target = capitalized_word(r)
+        words[index] = "[[%s %s]]" % (link_target, r.choice(VOCAB))
+    text = " ".join(words).capitalize() + "."
+    if r.random() < 0.25:
+        url_id = r.randrange(10000)
+        title = capitalized_word(r)
+        text += ("<ref>{{cite web |url=http://example.com/%d |title=%s "
+                 "|accessdate=1 January 2015}}</ref>" % (url_id, title))
+    return text
+
+
+def paragraph(r):
+    """Returns a random paragraph of 3 to 7 sentences."""
+    sentence_count = r.randint(3, 7)
+    return " ".join(sentence(r) for _ in range(sentence_count))
+
+
+def article(r, sections, paragraphs):
+    """Returns a random article: a lead with a bolded title, then `sections` sections of
+    `paragraphs` paragraphs each."""
+    title = "'''%s %s'''" % (capitalized_word(r), r.choice(VOCAB))
+    lead = " ".join(sentence(r) for _ in range(4))
+    parts = ["%s %s" % (title, lead)]
+    for _ in range(sections):
+        heading = "==%s %s==" % (capitalized_word(r), r.choice(VOCAB))
+        parts.append("\n\n%s\n" % heading)
+        parts.append("\n\n".join(paragraph(r) for _ in range(paragraphs)))
+    return "".join(parts) + "\n"
+
+
+# Matches the markup that redaction keeps, i.e. HTML tags, template names, and template parameter
+# names, as group 1, or a word to redact, as group 2.
+REDACTION_REGEX = re.compile(r"(<[^>]*>|\{\{[^|}]*|[^\W\d_]+(?=\s*=[^=]))|([^\W\d_]+)", re.UNICODE)
+
+
+def redact(r, wikitext):
+    """Returns `wikitext` with each word replaced by a random word, capitalized like it, keeping
+    everything else (markup, punctuation, numbers and whitespace) as it is."""
+    def replace(match):
+        if match.group(1):
+            return match.group(1)
+        word = r.choice(VOCAB)
+        return word.capitalize() if match.group(2)[0].isupper() else word
+    return REDACTION_REGEX.sub(replace, wikitext)
+
+
+def edit(r, text, edit_count, vocab):
+    """Returns `text` after `edit_count` random edits at word boundaries, each of which inserts 1
+    to 6 words from `vocab`, deletes 1 to 4 words, or replaces a word with one from `vocab`."""
+    words = text.split(" ")
+    for _ in range(edit_count):
+        index = r.randrange(len(words))
+        kind = r.random()
+        if kind < 0.4:
+            inserted_count = r.randint(1, 6)
+            words.insert(index, " ".join(r.choice(vocab) for _ in range(inserted_count)))
+        elif kind < 0.7:
+            deleted_count = r.randint(1, 4)
+            del words[index:index + deleted_count]
+        else:
+            words[index] = r.choice(vocab)
+    return " ".join(words)
+
+
+def read_snapshots(paths):
+    """Returns the wikitext of each snapshot in `paths`, keyed by the name of its fixture."""
+    snapshots = {}
+    for path in paths:
+        name = os.path.splitext(os.path.basename(path))[0]
+        if name not in [fixture[0] for fixture in FIXTURES]:
+            sys.exit("Unknown fixture \"%s\" for snapshot %s" % (name, path))
+        with open(path) as snapshot_file:
+            snapshots[name] = snapshot_file.read()
+    return snapshots
+
+
+def write_fixture(name, texts):
+    """Writes the (suffix, text) pairs `texts` of the fixture `name`."""
+    for suffix, text in texts:
+        with open(os.path.join(FIXTURE_DIR, "%s.%s.txt" % (name, suffix)), "w") as fixture_file:
+            fixture_file.write(text)
+
+
+def main():
+    snapshots = read_snapshots(sys.argv[1:])
+    for name, seed, sections, paragraphs, organic_edits, vandal_edits in FIXTURES:
+        r = random.Random(seed)
+        if name in snapshots:
+            old = redact(r, snapshots[name])
+        else:
+            old = article(r, sections, paragraphs)
+        new = edit(r, old, organic_edits, VOCAB)
+        other = edit(r, old, vandal_edits, VANDAL)
+        write_fixture(name, [("old", old), ("new", new), ("other", other)])
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,88 @@
+//! A timing harness for the merge and LCS engines. It runs `Merger::try_merge()` and
+//! `get_longest_common_subsequence()` over the (old, new, other) article fixtures in
+//! fixtures/benchmark, and prints timings and timeout rates. It doesn't need network access.
+//!
+//! The benchmarks are ignored by default, because they're slow. To run them:
+//!
+//!     $ cargo test benchmark -- --ignored --nocapture
+//!
+//! The fixtures are generated by scripts/generate_benchmark_fixtures.py.
+
+use merge::Merger;
+use merge::Words;
+use longest_common_subsequence::get_longest_common_subsequence;
+
+/// The number of times each fixture is run. Reported times are averages over all runs.
+const ITERATIONS: u64 = 5;
+
+/// The diff time limit to benchmark with (the same as the --diff_time_limit_ms default).
+const TIME_LIMIT_MS: u64 = 500;
+
+struct Fixture {
+    name: &'static str,
+    old: &'static str,
+    new: &'static str,
+    other: &'static str,
+}
+
+fn fixtures() -> Vec<Fixture> {
+    vec![
+        Fixture {
+            name: "small",
+            old: include_str!("../fixtures/benchmark/small.old.txt"),
+            new: include_str!("../fixtures/benchmark/small.new.txt"),
+            other: include_str!("../fixtures/benchmark/small.other.txt"),
+        },
+        Fixture {
+            name: "medium",
+            old: include_str!("../fixtures/benchmark/medium.old.txt"),
+            new: include_str!("../fixtures/benchmark/medium.new.txt"),
+            other: include_str!("../fixtures/benchmark/medium.other.txt"),
+        },
+        Fixture {
+            name: "large",
+            old: include_str!("../fixtures/benchmark/large.old.txt"),
+            new: include_str!("../fixtures/benchmark/large.new.txt"),
+            other: include_str!("../fixtures/benchmark/large.other.txt"),
+        },
+    ]
+}
+
+/// Runs `f` ITERATIONS times, and prints the average time in milliseconds and the number of runs
+/// for which `f` returned true (i.e. timed out).
+fn report<F>(benchmark_name: &str, fixture: &Fixture, mut f: F) where F: FnMut() -> bool {
+    let mut total_ns = 0;
+    let mut timeouts = 0;
+    for _ in 0..ITERATIONS {
+        let start_time_ns = ::time::precise_time_ns();
+        if f() {
+            timeouts += 1;
+        }
+        total_ns += ::time::precise_time_ns() - start_time_ns;
+    }
+    println!("{} {} ({} bytes): {} ms average, {}/{} timed out", benchmark_name, fixture.name,
+             fixture.old.len(), total_ns / ITERATIONS / 1_000_000, timeouts, ITERATIONS);
+}
+
+#[test]
+#[ignore]
+fn benchmark_longest_common_subsequence() {
+    for fixture in fixtures() {
+        report("get_longest_common_subsequence", &fixture, || {
+            get_longest_common_subsequence(
+                Words::new(fixture.old), Words::new(fixture.other), TIME_LIMIT_MS, 0, 0).is_none()
+        });
+    }
+}
+
+#[test]
+#[ignore]
+fn benchmark_try_merge() {
+    // The size limit is effectively disabled, so that every fixture is actually merged.
+    let merger = Merger::new(usize::max_value(), TIME_LIMIT_MS);
+    for fixture in fixtures() {
+        report("try_merge", &fixture, || {
+            merger.try_merge(fixture.old, fixture.new, fixture.other, "1").1
+        });
+    }
+}
@@ -0,0 +1,330 @@
+//! The caches that API responses are kept in: Redis, which is shared between processes and survives
+//! restarts, this process's memory, which is faster, or both, tiered.
+
+use std::str::FromStr;
+
+use redis;
+use redis::Commands;
+use redis::ConnectionInfo;
+use time;
+
+use connection_pool::{ConnectionPool, PooledConnection};
+use page_cache::PageCache;
+
+/// A store of string values, keyed by strings, which values may expire from.
+pub trait Cache: Send + Sync {
+    /// Returns the value cached under `key`, or None if there isn't one (or the cache can't be
+    /// reached).
+    fn get(&self, key: &str) -> Option<String>;
+
+    /// Caches `value` under `key`, expiring after `ttl_seconds`, or never if it's None.
+    fn set(&self, key: &str, value: &str, ttl_seconds: Option<u64>);
+
+    /// Returns an error if the cache can't be reached. Caches in this process's memory always can.
+    fn ping(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A kind of cache that API responses can be kept in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheBackend {
+    /// A `MemoryCache`.
+    Memory,
+    /// A `RedisCache`, if a Redis server is configured.
+    Redis,
+}
+
+impl FromStr for CacheBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CacheBackend, String> {
+        match s {
+            "memory" => Ok(CacheBackend::Memory),
+            "redis" => Ok(CacheBackend::Redis),
+            _ => Err(format!("Unknown cache backend \"{}\"", s)),
+        }
+    }
+}
+
+/// Parses a comma-separated list of cache backends, in the order they're checked, e.g.
+/// "memory,redis".
+pub fn parse_cache_backends(backends: &str) -> Result<Vec<CacheBackend>, String> {
+    backends.split(',')
+        .map(|backend| backend.trim())
+        .filter(|backend| !backend.is_empty())
+        .map(|backend| backend.parse())
+        .collect()
+}
+
+/// A connection to Redis, which can be handed between threads. Moving a value to another thread is
+/// only unsafe if it shares unsynchronized state with values left behind (e.g. through an Rc), and
+/// redis::Connection owns its socket outright, with nothing else referring to it. (It isn't Sync,
+/// since queries borrow its socket through a RefCell, but the pool never shares a connection: it's
+/// only used by the thread that has checked it out.)
+struct RedisConnection(redis::Connection);
+
+unsafe impl Send for RedisConnection {}
+
+/// A cache on a Redis server.
+pub struct RedisCache {
+    pool: ConnectionPool<RedisConnection>,
+}
+
+impl RedisCache {
+    /// Constructs a cache on the Redis server at `connection_info`, with at most `max_connections`
+    /// connections to it open at once (0 for no limit).
+    pub fn new(connection_info: ConnectionInfo, max_connections: usize) -> RedisCache {
+        RedisCache {
+            pool: ConnectionPool::new(max_connections, Box::new(move|| {
+                // The redis-rs docs "heavily encourage" the use of URLs instead of the
+                // ConnectionInfo struct, but redis::IntoConnectionInfo is only implemented for
+                // &str, so I can't construct a URL and pass it in without using
+                // String::as_str(), which is marked unstable.
+                let redis_client = try_display!(redis::Client::open(connection_info.clone()),
+                                                "Failed to create Redis client");
+                Ok(RedisConnection(try_display!(redis_client.get_connection(),
+                                                "Failed to connect to Redis")))
+            })),
+        }
+    }
+
+    /// Checks a connection to Redis out of the pool. Returns None, after logging why, if Redis
+    /// can't be connected to.
+    fn get_connection(&self) -> Option<PooledConnection<RedisConnection>> {
+        match self.pool.get() {
+            Ok(connection) => Some(connection),
+            Err(msg) => {
+                warn!("{}", msg);
+                None
+            },
+        }
+    }
+}
+
+/// Returns the result of a command run on `connection`. If the command failed, the connection
+/// (which may have been left mid-response, or disconnected) is discarded, and the error is
+/// described with `description`.
+fn check_redis_result<T>(connection: PooledConnection<RedisConnection>,
+                         result: redis::RedisResult<T>, description: &str) -> Result<T, String> {
+    result.map_err(|err| {
+        connection.discard();
+        format!("{}: {}", description, err)
+    })
+}
+
+impl Cache for RedisCache {
+    fn get(&self, key: &str) -> Option<String> {
+        let connection = match self.get_connection() {
+            Some(connection) => connection,
+            None => return None,
+        };
+        // A missing key is Ok(None), so only real failures are logged.
+        let result: redis::RedisResult<Option<String>> = connection.0.get(key);
+        match check_redis_result(connection, result,
+                                 &format!("Failed to get \"{}\" from Redis", key)) {
+            Ok(value) => value,
+            Err(msg) => {
+                warn!("{}", msg);
+                None
+            },
+        }
+    }
+
+    fn set(&self, key: &str, value: &str, ttl_seconds: Option<u64>) {
+        if let Some(connection) = self.get_connection() {
+            let result: redis::RedisResult<String> = match ttl_seconds {
+                Some(ttl_seconds) => connection.0.set_ex(key, value, ttl_seconds as usize),
+                None => connection.0.set(key, value),
+            };
+            if let Err(msg) = check_redis_result(connection, result,
+                                                 &format!("Failed to set \"{}\" in Redis", key)) {
+                warn!("{}", msg);
+            }
+        }
+    }
+
+    fn ping(&self) -> Result<(), String> {
+        let connection = try!(self.pool.get());
+        let result: redis::RedisResult<String> = redis::cmd("PING").query(&connection.0);
+        try!(check_redis_result(connection, result, "Failed to ping Redis"));
+        Ok(())
+    }
+}
+
+/// A cache in this process's memory. When full, the oldest value is evicted.
+pub struct MemoryCache {
+    /// The cached values, with the times (from `time::precise_time_ns()`) they expire, if they do.
+    entries: PageCache<(String, Option<u64>)>,
+}
+
+impl MemoryCache {
+    /// Constructs a cache that holds at most `capacity` values.
+    pub fn new(capacity: usize) -> MemoryCache {
+        MemoryCache { entries: PageCache::new(capacity) }
+    }
+}
+
+impl Cache for MemoryCache {
+    fn get(&self, key: &str) -> Option<String> {
+        match self.entries.get(key) {
+            Some((value, None)) => Some(value),
+            Some((value, Some(expiry_ns))) if time::precise_time_ns() < expiry_ns => Some(value),
+            _ => None,
+        }
+    }
+
+    fn set(&self, key: &str, value: &str, ttl_seconds: Option<u64>) {
+        let expiry_ns =
+            ttl_seconds.map(|ttl_seconds| time::precise_time_ns() + ttl_seconds * 1_000_000_000);
+        self.entries.insert(key, (value.to_string(), expiry_ns));
+    }
+}
+
+/// Two caches, one in front of the other: values are looked up in `l1` first, and only in `l2` if
+/// they aren't there, in which case they're copied into `l1`. Values are cached in both.
+pub struct TieredCache {
+    l1: Box<Cache>,
+    l2: Box<Cache>,
+    /// How long values copied from `l2` into `l1` live there, or None for them to never expire.
+    /// Their TTLs in `l2` aren't known, so this should be no longer than the shortest of them.
+    promoted_ttl_seconds: Option<u64>,
+}
+
+impl TieredCache {
+    pub fn new(l1: Box<Cache>, l2: Box<Cache>, promoted_ttl_seconds: Option<u64>) -> TieredCache {
+        TieredCache {
+            l1: l1,
+            l2: l2,
+            promoted_ttl_seconds: promoted_ttl_seconds,
+        }
+    }
+}
+
+impl Cache for TieredCache {
+    fn get(&self, key: &str) -> Option<String> {
+        if let Some(value) = self.l1.get(key) {
+            return Some(value);
+        }
+        let value = self.l2.get(key);
+        if let Some(ref value) = value {
+            self.l1.set(key, value, self.promoted_ttl_seconds);
+        }
+        value
+    }
+
+    fn set(&self, key: &str, value: &str, ttl_seconds: Option<u64>) {
+        self.l1.set(key, value, ttl_seconds);
+        self.l2.set(key, value, ttl_seconds);
+    }
+
+    fn ping(&self) -> Result<(), String> {
+        try!(self.l1.ping());
+        self.l2.ping()
+    }
+}
+
+/// Combines `caches` into one, checked in order (see `TieredCache`), or returns None if there are
+/// no caches.
+pub fn tier_caches(mut caches: Vec<Box<Cache>>, promoted_ttl_seconds: Option<u64>)
+                   -> Option<Box<Cache>> {
+    let last_cache = match caches.pop() {
+        Some(last_cache) => last_cache,
+        None => return None,
+    };
+    Some(caches.into_iter().rev().fold(last_cache, |l2, l1| {
+        Box::new(TieredCache::new(l1, l2, promoted_ttl_seconds)) as Box<Cache>
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, CacheBackend, MemoryCache, RedisCache, TieredCache, parse_cache_backends,
+                tier_caches};
+    use redis;
+    use std::sync::{Arc, Mutex};
+    use test_server::unused_port;
+
+    /// A cache that records the keys looked up in it.
+    struct RecordingCache {
+        cache: MemoryCache,
+        lookups: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Cache for RecordingCache {
+        fn get(&self, key: &str) -> Option<String> {
+            self.lookups.lock().unwrap().push(key.to_string());
+            self.cache.get(key)
+        }
+
+        fn set(&self, key: &str, value: &str, ttl_seconds: Option<u64>) {
+            self.cache.set(key, value, ttl_seconds);
+        }
+    }
+
+    #[test]
+    fn test_l2_hits_populate_l1() {
+        let l1_lookups = Arc::new(Mutex::new(vec![]));
+        let l2_lookups = Arc::new(Mutex::new(vec![]));
+        let l2 = RecordingCache { cache: MemoryCache::new(10), lookups: l2_lookups.clone() };
+        l2.set("key", "value", None);
+        let cache = TieredCache::new(
+            Box::new(RecordingCache { cache: MemoryCache::new(10), lookups: l1_lookups.clone() }),
+            Box::new(l2), None);
+
+        assert_eq!(Some("value".to_string()), cache.get("key"));
+        assert_eq!(vec!["key"], *l1_lookups.lock().unwrap());
+        assert_eq!(vec!["key"], *l2_lookups.lock().unwrap());
+
+        // The value was copied into L1, so L2 isn't checked again.
+        assert_eq!(Some("value".to_string()), cache.get("key"));
+        assert_eq!(vec!["key", "key"], *l1_lookups.lock().unwrap());
+        assert_eq!(vec!["key"], *l2_lookups.lock().unwrap());
+
+        assert_eq!(None, cache.get("missing"));
+        assert_eq!(vec!["key", "missing"], *l2_lookups.lock().unwrap());
+    }
+
+    #[test]
+    fn test_tiered_cache_ping() {
+        let cache = TieredCache::new(Box::new(MemoryCache::new(10)),
+                                     Box::new(MemoryCache::new(10)), None);
+        assert_eq!(Ok(()), cache.ping());
+
+        let port = unused_port();
+        let redis_cache = RedisCache::new(redis::ConnectionInfo {
+            addr: Box::new(redis::ConnectionAddr::Tcp("127.0.0.1".to_string(), port)),
+            db: 0,
+            passwd: None,
+        }, 1);
+        let cache = TieredCache::new(Box::new(MemoryCache::new(10)), Box::new(redis_cache), None);
+        assert!(cache.ping().is_err());
+    }
+
+    #[test]
+    fn test_memory_cache_expiry() {
+        let cache = MemoryCache::new(10);
+        cache.set("forever", "value", None);
+        cache.set("expired", "value", Some(0));
+        assert_eq!(Some("value".to_string()), cache.get("forever"));
+        assert_eq!(None, cache.get("expired"));
+    }
+
+    #[test]
+    fn test_tier_caches() {
+        assert!(tier_caches(vec![], None).is_none());
+        let caches: Vec<Box<Cache>> = vec![Box::new(MemoryCache::new(10)),
+                                           Box::new(MemoryCache::new(10))];
+        let cache = tier_caches(caches, None).unwrap();
+        cache.set("key", "value", None);
+        assert_eq!(Some("value".to_string()), cache.get("key"));
+    }
+
+    #[test]
+    fn test_parse_cache_backends() {
+        assert_eq!(Ok(vec![CacheBackend::Memory, CacheBackend::Redis]),
+                   parse_cache_backends("memory, redis"));
+        assert_eq!(Ok(vec![]), parse_cache_backends(""));
+        assert!(parse_cache_backends("memory,disk").is_err());
+    }
+}
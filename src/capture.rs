@@ -0,0 +1,89 @@
+//! Captures everything that goes into merging a page (the sections of its latest revision, and the
+//! clean and vandalized content of each revision merged into them) as a JSON bundle, so that a bad
+//! merge seen in production can be replayed offline with --replay, even after the page has changed.
+
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+
+use rustc_serialize::json;
+use time;
+
+use wiki::Revision;
+
+/// One revision's change to one section, as it was sent to be merged.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct CapturedRevision {
+    pub section_title: String,
+    pub clean_content: String,
+    pub vandalized_content: String,
+    pub revid: u64,
+}
+
+impl CapturedRevision {
+    pub fn new(section_title: &str, clean_content: &str, vandalized_content: &str,
+               revision: &Revision) -> CapturedRevision {
+        CapturedRevision {
+            section_title: section_title.to_string(),
+            clean_content: clean_content.to_string(),
+            vandalized_content: vandalized_content.to_string(),
+            revid: revision.revid,
+        }
+    }
+}
+
+/// The inputs to, and output of, merging one page.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Capture {
+    pub title: String,
+    /// The (section title, content) pairs of the page's latest revision.
+    pub latest_sections: Vec<(String, String)>,
+    /// The revisions merged into the page, in the order each section merged them.
+    pub revisions: Vec<CapturedRevision>,
+    /// The merged wikitext that was served.
+    pub merged_wikitext: String,
+}
+
+impl Capture {
+    /// Writes the capture to a new file in the directory `dir`, and returns the file's path.
+    pub fn write_to_dir(&self, dir: &str) -> Result<String, String> {
+        let path = format!("{}/{}-{}.json", dir, self.title.replace("/", "%2F"),
+                           time::precise_time_ns());
+        let encoded = try_display!(json::encode(self), "Failed to encode capture");
+        let mut file = try_display!(File::create(&path), "Failed to create capture {}", path);
+        try_display!(file.write_all(encoded.as_bytes()), "Failed to write capture {}", path);
+        Ok(path)
+    }
+
+    /// Reads a capture written by `write_to_dir()`.
+    pub fn read(path: &str) -> Result<Capture, String> {
+        let mut file = try_display!(File::open(path), "Failed to open capture {}", path);
+        let mut contents = String::new();
+        try_display!(file.read_to_string(&mut contents), "Failed to read capture {}", path);
+        Ok(try_display!(json::decode(&contents), "Failed to parse capture {}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capture, CapturedRevision};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_capture_round_trip() {
+        let capture = Capture {
+            title: "AC/DC".to_string(),
+            latest_sections: vec![("".to_string(), "Text".to_string())],
+            revisions: vec![CapturedRevision {
+                section_title: "".to_string(),
+                clean_content: "Text".to_string(),
+                vandalized_content: "Text vandalism".to_string(),
+                revid: 123,
+            }],
+            merged_wikitext: "Text vandalism".to_string(),
+        };
+        let dir = TempDir::new("captures").unwrap();
+        let path = capture.write_to_dir(dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(Ok(capture), Capture::read(&path));
+    }
+}
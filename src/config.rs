@@ -0,0 +1,240 @@
+//! A TOML configuration file, given with --config, holding the values of the flags that a
+//! deployment usually sets, e.g.:
+//!
+//! ```toml
+//! port = 8080
+//! wiki = "fr.wikipedia.org"
+//! redis_hostname = "localhost"
+//! diff_time_limit_ms = 1000
+//! ```
+//!
+//! Each key is the name of a flag, without the leading "--". Flags given on the command line
+//! override the file's values.
+
+use std::fs::File;
+use std::io::Read;
+
+use rustc_serialize::Decodable;
+use toml;
+
+/// The settings in a configuration file. Each field is the value of the flag of the same name, or
+/// None if the file doesn't set it. Every flag has one, except --config itself, --log_format
+/// (since logging starts before the file is read), and --replay (which runs a one-off command
+/// instead of serving).
+#[derive(Clone, Debug, Default, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Config {
+    pub port: Option<u16>,
+    pub wiki: Option<String>,
+    pub wiki_profiles_dir: Option<String>,
+    pub wiki_scheme: Option<String>,
+    pub user_agent: Option<String>,
+    pub redis_hostname: Option<String>,
+    pub redis_port: Option<u16>,
+    pub max_redis_connections: Option<usize>,
+    pub cache_ttl_seconds: Option<u64>,
+    pub cache_rules: Option<String>,
+    pub cache_backends: Option<String>,
+    pub memory_cache_capacity: Option<usize>,
+    pub diff_size_limit: Option<usize>,
+    pub diff_time_limit_ms: Option<u64>,
+    pub diff_time_limit_ms_per_kib: Option<u64>,
+    pub min_diff_time_limit_ms: Option<u64>,
+    pub lcs_max_tasks: Option<u64>,
+    pub lcs_max_queue_size: Option<usize>,
+    pub min_merge_similarity: Option<f64>,
+    pub partial_lcs_on_timeout: Option<bool>,
+    /// Parsed like the flag, e.g. "keep_vandalism".
+    pub conflict_policy: Option<String>,
+    pub prefer_recent_restorations: Option<bool>,
+    /// Parsed like the flag, e.g. "word".
+    pub merge_granularity: Option<String>,
+    pub unicode_word_boundaries: Option<bool>,
+    pub atomic_literal_blocks: Option<bool>,
+    pub max_consecutive_diff_timeouts: Option<u64>,
+    pub max_revisions_scanned: Option<u64>,
+    pub canonical_titles_file: Option<String>,
+    pub canonical_title_ttl_seconds: Option<u64>,
+    pub revision_fetch_parallelism: Option<u64>,
+    pub maxlag: Option<u64>,
+    pub max_api_requests_per_second: Option<u64>,
+    /// Parsed like the flag: 1 or 2.
+    pub api_formatversion: Option<u64>,
+    pub http2: Option<bool>,
+    pub http2_ca_file: Option<String>,
+    pub max_redirect_depth: Option<u64>,
+    pub api_max_retries: Option<u64>,
+    pub maxlag_retries: Option<u64>,
+    pub escalate_granularity: Option<bool>,
+    pub exclude_infoboxes: Option<bool>,
+    pub skip_reference_lists: Option<bool>,
+    pub section_selector: Option<String>,
+    pub min_section_quality: Option<f64>,
+    pub ignore_maintenance_templates: Option<bool>,
+    pub maintenance_templates: Option<String>,
+    pub min_corroborating_reverts: Option<u64>,
+    pub target_revert_count: Option<u64>,
+    pub vandalism_keywords: Option<String>,
+    /// Parsed like the flag, e.g. "either".
+    pub revert_detection: Option<String>,
+    pub follow_moves: Option<bool>,
+    pub include_talk_page_reverts: Option<bool>,
+    pub no_capital_links: Option<bool>,
+    /// Parsed like the flag, e.g. "redirect".
+    pub fragment_policy: Option<String>,
+    pub empty_title_page: Option<String>,
+    /// Parsed like the flag, e.g. "span".
+    pub marker_output: Option<String>,
+    pub severity_shading: Option<bool>,
+    pub vandalism_style: Option<String>,
+    pub diff_tooltips: Option<bool>,
+    pub show_merge_timestamp: Option<bool>,
+    pub inject_toggle: Option<bool>,
+    pub show_restoration_footer: Option<bool>,
+    pub max_spans_per_page: Option<usize>,
+    pub removal_placeholder: Option<String>,
+    /// Parsed like the flag, e.g. "mobile".
+    pub mobile_requests: Option<String>,
+    pub emit_stats_header: Option<bool>,
+    pub validate_render: Option<bool>,
+    pub worker_threads: Option<usize>,
+    pub share_skeleton_fetches: Option<bool>,
+    pub page_cache: Option<bool>,
+    pub page_cache_size: Option<usize>,
+    pub page_cache_ttl_seconds: Option<u64>,
+    pub section_cache_size: Option<usize>,
+    pub max_inflight_bytes: Option<usize>,
+    pub max_concurrent_merges: Option<usize>,
+    pub live_poll_interval_ms: Option<u64>,
+    pub live_timeout_ms: Option<u64>,
+    pub max_live_requests: Option<usize>,
+    pub max_response_bytes: Option<usize>,
+    pub event_log: Option<String>,
+    pub event_webhook: Option<String>,
+    pub capture_dir: Option<String>,
+    pub capture_titles: Option<String>,
+    pub snapshot_dir: Option<String>,
+    pub max_snapshots: Option<usize>,
+    pub about_page: Option<String>,
+    pub healthz_checks_upstream: Option<bool>,
+}
+
+impl Config {
+    /// Reads the configuration file at `path`, warning about any keys in it that aren't settings.
+    pub fn read(path: &str) -> Result<Config, String> {
+        let mut file = try_display!(File::open(path), "Failed to open config file {}", path);
+        let mut contents = String::new();
+        try_display!(file.read_to_string(&mut contents), "Failed to read config file {}", path);
+        let (config, unknown_keys) =
+            try_display!(parse_config(&contents), "Failed to parse config file {}", path);
+        for key in unknown_keys {
+            warn!("Ignoring unknown setting \"{}\" in config file {}", key, path);
+        }
+        Ok(config)
+    }
+}
+
+/// Parses the contents of a configuration file. Returns the configuration, and the keys in it that
+/// aren't settings. A value of the wrong type for its setting is an error.
+fn parse_config(contents: &str) -> Result<(Config, Vec<String>), String> {
+    let mut parser = toml::Parser::new(contents);
+    let table = match parser.parse() {
+        Some(table) => table,
+        None => {
+            return Err(parser.errors.iter().map(|error| format!("{}", error))
+                       .collect::<Vec<_>>().join("; "));
+        },
+    };
+    let mut decoder = toml::Decoder::new(toml::Value::Table(table));
+    let config = try_display!(Config::decode(&mut decoder), "Invalid setting");
+    // The decoder leaves behind the keys it didn't decode.
+    let mut unknown_keys = match decoder.toml {
+        Some(toml::Value::Table(table)) => table.keys().cloned().collect::<Vec<_>>(),
+        _ => vec![],
+    };
+    unknown_keys.sort();
+    Ok((config, unknown_keys))
+}
+
+/// Returns the path given to --config in the command-line arguments `args`, if there is one. It's
+/// needed before the rest of the arguments are parsed, so that they can override the file.
+pub fn find_config_path(args: &[String]) -> Option<String> {
+    find_flag_value(args, "--config")
+}
+
+/// Returns the value given to the flag `flag` (e.g. "--config") in the command-line arguments
+/// `args`, if there is one, for the few flags that are needed before the arguments are parsed.
+pub fn find_flag_value(args: &[String], flag: &str) -> Option<String> {
+    let prefix = format!("{}=", flag);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next().cloned();
+        } else if arg.starts_with(&prefix) {
+            return Some(arg[prefix.len()..].to_string());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, find_config_path, parse_config};
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_read_config() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, r#"
+# Mirror of the French Wikipedia.
+port = 8080
+wiki = "fr.wikipedia.org"
+redis_hostname = "localhost"
+redis_port = 6380
+diff_time_limit_ms = 1000
+page_cache = true
+page_cache_size = 500
+follow_moves = true
+revert_detection = "either"
+"#).unwrap();
+        assert_eq!(Ok(Config {
+                       port: Some(8080),
+                       wiki: Some("fr.wikipedia.org".to_string()),
+                       redis_hostname: Some("localhost".to_string()),
+                       redis_port: Some(6380),
+                       diff_time_limit_ms: Some(1000),
+                       page_cache: Some(true),
+                       page_cache_size: Some(500),
+                       follow_moves: Some(true),
+                       revert_detection: Some("either".to_string()),
+                       ..Config::default()
+                   }),
+                   Config::read(file.path().to_str().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_config_unknown_keys() {
+        assert_eq!(Ok((Config { port: Some(8080), ..Config::default() },
+                       vec!["colour".to_string(), "prot".to_string()])),
+                   parse_config("prot = 80\nport = 8080\ncolour = \"red\"\n"));
+    }
+
+    #[test]
+    fn test_parse_config_bad_values() {
+        assert!(parse_config("port = \"eighty\"").is_err());
+        assert!(parse_config("port = 8080\nport = 8081").is_err());
+        assert!(parse_config("wiki = ").is_err());
+    }
+
+    #[test]
+    fn test_find_config_path() {
+        fn args(args: &[&str]) -> Vec<String> {
+            args.iter().map(|arg| arg.to_string()).collect()
+        }
+        assert_eq!(Some("wmw.toml".to_string()),
+                   find_config_path(&args(&["wmw", "--port", "80", "--config", "wmw.toml"])));
+        assert_eq!(Some("wmw.toml".to_string()),
+                   find_config_path(&args(&["wmw", "--config=wmw.toml"])));
+        assert_eq!(None, find_config_path(&args(&["wmw", "--port", "80"])));
+    }
+}
@@ -0,0 +1,139 @@
+//! A pool of reusable connections (to Redis), so that every cache lookup doesn't open and tear down
+//! a connection of its own.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+use limiter::ConcurrencyLimiter;
+use limiter::Permit;
+
+pub struct ConnectionPool<C> {
+    /// The connections that are open, but not checked out.
+    idle: Mutex<Vec<C>>,
+    /// Limits the number of connections checked out at once, and so the number open.
+    limiter: ConcurrencyLimiter,
+    /// Opens a new connection.
+    connect: Box<Fn() -> Result<C, String> + Send + Sync>,
+}
+
+/// A connection checked out of a ConnectionPool. It's returned to the pool when dropped, unless
+/// it's been discarded.
+pub struct PooledConnection<'a, C: 'a> {
+    pool: &'a ConnectionPool<C>,
+    /// Always Some, except while being dropped.
+    connection: Option<C>,
+    _permit: Permit,
+}
+
+impl<C> ConnectionPool<C> {
+    /// Creates a pool of at most `max_connections` connections (0 for no limit), which are opened
+    /// with `connect` as they're needed.
+    pub fn new(max_connections: usize, connect: Box<Fn() -> Result<C, String> + Send + Sync>)
+               -> ConnectionPool<C> {
+        ConnectionPool {
+            idle: Mutex::new(Vec::new()),
+            limiter: ConcurrencyLimiter::new(max_connections),
+            connect: connect,
+        }
+    }
+
+    /// Checks out an idle connection, or opens a new one if there aren't any. Blocks while the
+    /// maximum number of connections are checked out.
+    pub fn get(&self) -> Result<PooledConnection<C>, String> {
+        let permit = self.limiter.acquire();
+        let idle_connection = self.idle.lock().unwrap().pop();
+        let connection = match idle_connection {
+            Some(connection) => connection,
+            None => try!((self.connect)()),
+        };
+        Ok(PooledConnection {
+            pool: self,
+            connection: Some(connection),
+            _permit: permit,
+        })
+    }
+}
+
+impl<'a, C> PooledConnection<'a, C> {
+    /// Closes the connection instead of returning it to the pool, e.g. because it failed, and may
+    /// be left mid-response or disconnected.
+    pub fn discard(mut self) {
+        self.connection.take();
+    }
+}
+
+impl<'a, C> Deref for PooledConnection<'a, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl<'a, C> DerefMut for PooledConnection<'a, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.connection.as_mut().unwrap()
+    }
+}
+
+impl<'a, C> Drop for PooledConnection<'a, C> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.idle.lock().unwrap().push(connection);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectionPool;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    /// Returns a pool of at most `max_connections` connections, and the count of connections it has
+    /// opened.
+    fn counting_pool(max_connections: usize) -> (Arc<ConnectionPool<usize>>, Arc<AtomicUsize>) {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let opened_clone = opened.clone();
+        let pool = ConnectionPool::new(max_connections, Box::new(move|| {
+            Ok(opened_clone.fetch_add(1, Ordering::SeqCst))
+        }));
+        (Arc::new(pool), opened)
+    }
+
+    #[test]
+    fn test_connections_are_reused() {
+        let (pool, opened) = counting_pool(2);
+        for _ in 0..10 {
+            assert_eq!(0, *pool.get().unwrap());
+        }
+        assert_eq!(1, opened.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_discarded_connections_are_not_reused() {
+        let (pool, opened) = counting_pool(2);
+        pool.get().unwrap().discard();
+        assert_eq!(1, *pool.get().unwrap());
+        assert_eq!(2, opened.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_connections_do_not_exceed_maximum() {
+        let (pool, opened) = counting_pool(2);
+        let threads = (0..8).map(|_| {
+            let pool = pool.clone();
+            thread::spawn(move|| {
+                for _ in 0..100 {
+                    let _connection = pool.get().unwrap();
+                    thread::yield_now();
+                }
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(opened.load(Ordering::SeqCst) <= 2);
+    }
+}
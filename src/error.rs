@@ -0,0 +1,34 @@
+//! The ways serving a page can fail, which decide the status of the error response.
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AppError {
+    /// The page doesn't exist on the wiki.
+    PageNotFound,
+    /// The page isn't cached, and in-flight merges are using too much memory to merge it, or too
+    /// many requests are already waiting for the same kind of work.
+    Overloaded,
+    /// The wiki's API failed, or returned something that couldn't be parsed.
+    Upstream(String),
+    /// Something went wrong in the server itself.
+    Internal(String),
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AppError::PageNotFound => write!(formatter, "Page not found"),
+            AppError::Overloaded => write!(formatter, "Too busy"),
+            AppError::Upstream(ref msg) => write!(formatter, "Upstream error: {}", msg),
+            AppError::Internal(ref msg) => write!(formatter, "Internal error: {}", msg),
+        }
+    }
+}
+
+/// Lets `try!` turn an AppError into the String errors used everywhere else.
+impl From<AppError> for String {
+    fn from(error: AppError) -> String {
+        error.to_string()
+    }
+}
@@ -0,0 +1,169 @@
+//! Records each restoration of vandalism to one or more `EventSink`s (a JSONL file, an HTTP
+//! webhook), so the mirror can be used as a data source for vandalism research.
+//!
+//! Events are handed off to a dedicated thread through an `EventLogger`, so a slow disk or webhook
+//! never slows down serving a page.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use hyper::Client;
+use hyper::header::Connection;
+use hyper::header::ContentType;
+use hyper::mime::Mime;
+use hyper::mime::SubLevel;
+use hyper::mime::TopLevel;
+use rustc_serialize::json;
+
+/// A single restoration of vandalism: merging revision `revid` of `title` into the current page kept
+/// `restored_bytes` bytes of content that `user` had reverted.
+#[derive(Clone, Debug, PartialEq, RustcEncodable)]
+pub struct RestorationEvent {
+    pub title: String,
+    pub revid: u64,
+    /// The user who reverted the vandalism.
+    pub user: String,
+    pub restored_bytes: u64,
+    /// When the restoration happened, in RFC 3339 format.
+    pub timestamp: String,
+}
+
+impl RestorationEvent {
+    /// Formats the event as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        json::encode(self).unwrap()
+    }
+}
+
+/// A destination for RestorationEvents.
+pub trait EventSink: Send {
+    fn record(&mut self, event: &RestorationEvent) -> Result<(), String>;
+}
+
+/// Appends each event to a file, one JSON object per line.
+pub struct FileEventSink {
+    file: File,
+}
+
+impl FileEventSink {
+    pub fn new(path: &str) -> Result<FileEventSink, String> {
+        Ok(FileEventSink {
+            file: try_display!(OpenOptions::new().append(true).create(true).open(path),
+                               "Failed to open event log {}", path),
+        })
+    }
+}
+
+impl EventSink for FileEventSink {
+    fn record(&mut self, event: &RestorationEvent) -> Result<(), String> {
+        Ok(try_display!(writeln!(self.file, "{}", event.to_json()),
+                        "Failed to write to event log"))
+    }
+}
+
+/// POSTs each event, as a JSON object, to a URL.
+pub struct WebhookEventSink {
+    url: String,
+    client: Client,
+}
+
+impl WebhookEventSink {
+    pub fn new(url: String, client: Client) -> WebhookEventSink {
+        WebhookEventSink {
+            url: url,
+            client: client,
+        }
+    }
+}
+
+impl EventSink for WebhookEventSink {
+    fn record(&mut self, event: &RestorationEvent) -> Result<(), String> {
+        let body = event.to_json();
+        try_display!(
+            self.client.post(&self.url).body(&body[..])
+                .header(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])))
+                .header(Connection::close()).send(),
+            "Error calling event webhook {}", self.url);
+        Ok(())
+    }
+}
+
+/// Hands RestorationEvents off to a background thread, which records each one to every sink.
+#[derive(Clone)]
+pub struct EventLogger {
+    /// None if there are no sinks, in which case events are dropped.
+    sender: Option<Sender<RestorationEvent>>,
+}
+
+impl EventLogger {
+    pub fn new(mut sinks: Vec<Box<EventSink>>) -> EventLogger {
+        if sinks.is_empty() {
+            return EventLogger { sender: None };
+        }
+        let (sender, receiver) = channel::<RestorationEvent>();
+        thread::Builder::new().name("event-logger".to_string()).spawn(move|| {
+            for event in receiver.iter() {
+                for sink in sinks.iter_mut() {
+                    if let Err(msg) = sink.record(&event) {
+                        warn!("Failed to record restoration event: {}", msg);
+                    }
+                }
+            }
+        }).unwrap();
+        EventLogger { sender: Some(sender) }
+    }
+
+    /// Queues `event` to be recorded. Never blocks.
+    pub fn log(&self, event: RestorationEvent) {
+        if let Some(ref sender) = self.sender {
+            if let Err(err) = sender.send(event) {
+                warn!("Failed to queue restoration event: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventLogger, EventSink, RestorationEvent};
+    use std::sync::mpsc::{channel, Sender};
+
+    struct TestEventSink {
+        sender: Sender<RestorationEvent>,
+    }
+
+    impl EventSink for TestEventSink {
+        fn record(&mut self, event: &RestorationEvent) -> Result<(), String> {
+            self.sender.send(event.clone()).unwrap();
+            Ok(())
+        }
+    }
+
+    fn test_event() -> RestorationEvent {
+        RestorationEvent {
+            title: "Test_page".to_string(),
+            revid: 123,
+            user: "Test user".to_string(),
+            restored_bytes: 45,
+            timestamp: "2015-08-01T00:00:00Z".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_event_logger_records_to_sink() {
+        let (sender, receiver) = channel();
+        let event_logger = EventLogger::new(vec![Box::new(TestEventSink { sender: sender })]);
+        event_logger.log(test_event());
+        assert_eq!(test_event(), receiver.recv().unwrap());
+    }
+
+    #[test]
+    fn test_event_to_json() {
+        assert_eq!(
+            "{\"title\":\"Test_page\",\"revid\":123,\"user\":\"Test user\",\"restored_bytes\":45,\"timestamp\":\"2015-08-01T00:00:00Z\"}",
+            test_event().to_json());
+    }
+}
@@ -0,0 +1,214 @@
+//! An HTTP/2 client for requests to the wiki. Where the HTTP/1.1 client opens (and closes) a
+//! connection for every request, this keeps one connection to the wiki open, and multiplexes every
+//! request onto it, which makes the many small API calls for each page much cheaper.
+
+use std::str;
+use std::sync::Mutex;
+
+use solicit::client::Client;
+use solicit::http::Header;
+use solicit::http::StaticHeader;
+use solicit::http::client::CleartextConnector;
+use solicit::http::client::tls::TlsConnector;
+use time;
+
+/// How long after failing to open a connection to the wiki (e.g. because it doesn't support HTTP/2)
+/// to wait before trying again, in seconds. Until then, requests fail without trying.
+const RECONNECT_INTERVAL_SECONDS: i64 = 600;
+
+/// A response received over HTTP/2.
+pub struct Http2Response {
+    pub status: u16,
+    /// The response's headers, with lowercase names, as HTTP/2 requires.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Http2Response {
+    /// Returns the value of the header `name`, if it's present.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers.iter().find(|header| header.0 == name).map(|header| &header.1[..])
+    }
+}
+
+pub struct Http2Client {
+    scheme: String,
+    hostname: String,
+    port: u16,
+    /// The file of CA certificates that the wiki's certificate is verified against, over https.
+    ca_file: String,
+    /// The connection to the wiki, or None if it hasn't been opened yet, or has failed.
+    client: Mutex<Option<Client>>,
+    /// When opening a connection to the wiki last failed, in seconds since the epoch, or None if
+    /// the last attempt succeeded.
+    connect_failed_at: Mutex<Option<i64>>,
+}
+
+impl Http2Client {
+    /// Constructs a client for the wiki at `hostname`, reached over `scheme` ("http" or "https").
+    /// The connection isn't opened until the first request.
+    pub fn new(scheme: &str, hostname: &str, port: u16, ca_file: &str) -> Http2Client {
+        Http2Client {
+            scheme: scheme.to_string(),
+            hostname: hostname.to_string(),
+            port: port,
+            ca_file: ca_file.to_string(),
+            client: Mutex::new(None),
+            connect_failed_at: Mutex::new(None),
+        }
+    }
+
+    /// Returns whether requests are worth sending with this client, i.e. unless opening a
+    /// connection to the wiki failed within the last RECONNECT_INTERVAL_SECONDS. Requests that
+    /// would only fail should go straight to HTTP/1.1.
+    pub fn is_available(&self) -> bool {
+        match *self.connect_failed_at.lock().unwrap() {
+            Some(failed_at) => time::get_time().sec - failed_at >= RECONNECT_INTERVAL_SECONDS,
+            None => true,
+        }
+    }
+
+    /// Opens a connection to the wiki. Over https, HTTP/2 is negotiated with ALPN, so this fails if
+    /// the wiki doesn't support it.
+    fn connect(&self) -> Result<Client, String> {
+        let client = if self.scheme == "https" {
+            Client::with_connector(TlsConnector::new(&self.hostname[..], &self.ca_file))
+        } else {
+            Client::with_connector(CleartextConnector::with_port(&self.hostname[..], self.port))
+        };
+        client.ok_or(format!("Failed to open HTTP/2 connection to {}", self.hostname))
+    }
+
+    /// Returns the open connection to the wiki, opening it first if there isn't one (and the last
+    /// attempt didn't fail too recently; see `is_available()`).
+    fn get_client(&self) -> Result<Client, String> {
+        let mut client = self.client.lock().unwrap();
+        if client.is_none() {
+            if !self.is_available() {
+                return Err(format!("HTTP/2 is unavailable from {}", self.hostname));
+            }
+            let connected = self.connect();
+            *self.connect_failed_at.lock().unwrap() = match connected {
+                Ok(_) => None,
+                Err(_) => Some(time::get_time().sec),
+            };
+            *client = Some(try!(connected));
+        }
+        Ok(client.as_ref().unwrap().clone())
+    }
+
+    /// Sends a GET request for `path` (which includes any query string) with the extra headers
+    /// `headers`, and waits for the response.
+    pub fn get(&self, path: &str, headers: &[(&str, &str)]) -> Result<Http2Response, String> {
+        self.send(path, headers, None)
+    }
+
+    /// Sends a POST request to `path` with the body `body` and the extra headers `headers`, and
+    /// waits for the response.
+    pub fn post(&self, path: &str, headers: &[(&str, &str)], body: &str)
+                -> Result<Http2Response, String> {
+        self.send(path, headers, Some(body.as_bytes().to_vec()))
+    }
+
+    /// Does the work of `get()` and `post()`: sends a GET request if `body` is None, and a POST
+    /// request otherwise. Fails if the connection can't be opened, or breaks before the response
+    /// arrives, in which case it's reopened for the next request.
+    fn send(&self, path: &str, headers: &[(&str, &str)], body: Option<Vec<u8>>)
+            -> Result<Http2Response, String> {
+        let client = try!(self.get_client());
+        let headers = headers.iter()
+            .map(|&(name, value)| Header::new(name.to_lowercase().into_bytes(),
+                                              value.as_bytes().to_vec()))
+            .collect::<Vec<StaticHeader>>();
+        let receiver = match body {
+            Some(body) => client.post(path.as_bytes(), &headers, body),
+            None => client.get(path.as_bytes(), &headers),
+        };
+        let response = match receiver.and_then(|receiver| receiver.recv().ok()) {
+            Some(response) => response,
+            None => {
+                *self.client.lock().unwrap() = None;
+                return Err(format!("HTTP/2 connection to {} was closed", self.hostname));
+            },
+        };
+        let status = try_display!(response.status_code(),
+                                  "Invalid status in HTTP/2 response from {}", self.hostname);
+        Ok(Http2Response {
+            status: status,
+            headers: response.headers.iter()
+                .filter_map(|header| match (str::from_utf8(header.name()),
+                                            str::from_utf8(header.value())) {
+                    (Ok(name), Ok(value)) => Some((name.to_lowercase(), value.to_string())),
+                    _ => None,
+                })
+                .collect(),
+            body: response.body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Http2Client, Http2Response};
+    use std::sync::mpsc::channel;
+    use test_server::{serve, serve_http2, unused_port};
+
+    #[test]
+    fn test_header() {
+        let response = Http2Response {
+            status: 200,
+            headers: vec![("content-encoding".to_string(), "gzip".to_string())],
+            body: vec![],
+        };
+        assert_eq!(Some("gzip"), response.header("Content-Encoding"));
+        assert_eq!(None, response.header("Retry-After"));
+    }
+
+    #[test]
+    fn test_unreachable_server_fails() {
+        // Nothing listens on the port, so the connection can't be opened.
+        let client = Http2Client::new("http", "127.0.0.1", unused_port(), "");
+        assert!(client.get("/w/api.php", &[]).is_err());
+    }
+
+    #[test]
+    fn test_failed_connections_are_not_retried() {
+        let (sender, receiver) = channel();
+        // Not an HTTP/2 server: it closes every connection straight away.
+        let port = serve(None, move|_| { let _ = sender.send(()); });
+        let client = Http2Client::new("http", "127.0.0.1", port, "");
+        assert!(client.is_available());
+        assert!(client.get("/w/api.php", &[]).is_err());
+        assert!(!client.is_available());
+        assert!(client.get("/w/api.php", &[]).is_err());
+        assert_eq!(Ok(()), receiver.recv());
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_requests_over_http2() {
+        let client = Http2Client::new("http", "127.0.0.1", serve_http2(), "");
+        let response = client.get("/w/api.php?action=query", &[("User-Agent", "test-agent/1.0")])
+            .unwrap();
+        assert_eq!(200, response.status);
+        assert_eq!(Some("/w/api.php?action=query"), response.header("X-Path"));
+        assert!(response.body.is_empty());
+        // Both requests go over the same connection.
+        let response = client.post("/w/api.php", &[], "a=b").unwrap();
+        assert_eq!(200, response.status);
+        assert_eq!(b"a=b".to_vec(), response.body);
+        assert!(client.is_available());
+    }
+
+    /// Checks that requests succeed against a real HTTP/2 server over TLS. Needs network access,
+    /// so it only runs with --ignored.
+    #[test]
+    #[ignore]
+    fn test_requests_over_http2_with_tls() {
+        let client = Http2Client::new("https", "en.wikipedia.org", 443,
+                                      "/etc/ssl/certs/ca-certificates.crt");
+        let response = client.get("/w/api.php?action=query&format=json", &[]).unwrap();
+        assert_eq!(200, response.status);
+    }
+}
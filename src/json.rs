@@ -24,6 +24,8 @@
 
 extern crate rustc_serialize;
 
+use std::fmt;
+
 use rustc_serialize::json::Json;
 use rustc_serialize::json::Json::{Array, Object};
 
@@ -37,26 +39,77 @@ pub enum JsonPathElement {
     Key(&'static str),
     /// Represents the only key in an object.
     Only,
+    /// Represents a specific index in an array.
+    Index(usize),
 }
 
+use json::JsonPathElement::Index;
 use json::JsonPathElement::Key;
 use json::JsonPathElement::Only;
 
+/// The ways following a JSON path can fail. Each `path` is the path to the value where it failed,
+/// as formatted by `pretty_print()`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonError {
+    /// The object at `path` has no key `key`.
+    KeyNotFound { key: String, path: String },
+    /// The value at `path` isn't of the type `expected` (e.g. "an object"), which `asked_for`
+    /// needed (e.g. "key foo in", or "string").
+    WrongType { asked_for: String, expected: &'static str, path: String },
+    /// The only key was asked for in the object (or array, if `array` is set) at `path`, which has
+    /// `count` values instead.
+    NotSingleton { path: String, count: usize, array: bool },
+    /// Index `index` was asked for in the array at `path`, which has only `len` elements.
+    IndexOutOfBounds { index: usize, path: String, len: usize },
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &JsonError::KeyNotFound { ref key, ref path } =>
+                write!(formatter, "Key {} not found in {}", key, path),
+            &JsonError::WrongType { ref asked_for, expected, ref path } =>
+                write!(formatter, "Asked for {} {}, but value is not {}", asked_for, path,
+                       expected),
+            &JsonError::NotSingleton { ref path, count, array: false } =>
+                write!(formatter, "Asked for only key in {}, but object has {} values", path,
+                       count),
+            &JsonError::NotSingleton { ref path, count, array: true } =>
+                write!(formatter, "Asked for only key in {}, but array has {} elements", path,
+                       count),
+            &JsonError::IndexOutOfBounds { index, ref path, len } =>
+                write!(formatter, "Asked for index {} in {}, but array has {} elements", index,
+                       path, len),
+        }
+    }
+}
+
+/// Lets `try!` turn a JsonError into the String errors used everywhere else.
+impl From<JsonError> for String {
+    fn from(error: JsonError) -> String {
+        error.to_string()
+    }
+}
+
 fn pretty_print(path_elements: &[JsonPathElement]) -> String {
-    let mut display_elements = Vec::with_capacity(path_elements.len() + 1);
-    display_elements.push("(root)".to_string());
-    display_elements.extend(path_elements.into_iter().map(
-        |path_element| {
-            match path_element {
-                &Key(ref key) => key.to_string(),
-                &Only => "(only)".to_string(),
-            }
-        }));
-    display_elements.into_iter().collect::<Vec<_>>().join(".")
+    let mut display_path = "(root)".to_string();
+    for path_element in path_elements {
+        match path_element {
+            &Key(ref key) => display_path.push_str(&format!(".{}", key)),
+            &Only => display_path.push_str(".(only)"),
+            &Index(index) => display_path.push_str(&format!("[{}]", index)),
+        }
+    }
+    display_path
+}
+
+/// Returns a WrongType error for the value at `path`.
+fn wrong_type(asked_for: String, expected: &'static str, path: &[JsonPathElement]) -> JsonError {
+    JsonError::WrongType { asked_for: asked_for, expected: expected, path: pretty_print(path) }
 }
 
 fn get_json_value<'a>(json: &'a Json, path: &[JsonPathElement], index: usize) ->
-    Result<&'a Json, String> {
+    Result<&'a Json, JsonError> {
     if index == path.len() {
         return Ok(json);
     }
@@ -66,11 +119,13 @@ fn get_json_value<'a>(json: &'a Json, path: &[JsonPathElement], index: usize) ->
                 &Object(ref obj) => {
                     match obj.get(key) {
                         Some(value) => get_json_value(value, path, index + 1),
-                        None => Err(format!("Key {} not found in {}", key, pretty_print(&path[0 .. index]))),
+                        None => Err(JsonError::KeyNotFound {
+                            key: key.to_string(),
+                            path: pretty_print(&path[0 .. index]),
+                        }),
                     }
                 }
-                _ => Err(format!("Asked for key {} in {}, but value is not an object",
-                         key, pretty_print(&path[0 .. index]))),
+                _ => Err(wrong_type(format!("key {} in", key), "an object", &path[0 .. index])),
             }
         },
         JsonPathElement::Only => {
@@ -79,56 +134,92 @@ fn get_json_value<'a>(json: &'a Json, path: &[JsonPathElement], index: usize) ->
                     if obj.len() == 1 {
                         get_json_value(obj.values().next().unwrap(), path, index + 1)
                     } else {
-                        Err(format!("Asked for only key in {}, but object has {} values",
-                                    pretty_print(&path[0 .. index]), obj.len()))
+                        Err(JsonError::NotSingleton {
+                            path: pretty_print(&path[0 .. index]),
+                            count: obj.len(),
+                            array: false,
+                        })
                     },
                 &Array(ref vec) =>
                     if vec.len() == 1 {
                         get_json_value(vec.first().unwrap(), path, index + 1)
                     } else {
-                        Err(format!("Asked for only key in {}, but array has {} elements",
-                                    pretty_print(&path[0 .. index]), vec.len()))
+                        Err(JsonError::NotSingleton {
+                            path: pretty_print(&path[0 .. index]),
+                            count: vec.len(),
+                            array: true,
+                        })
+                    },
+                _ => Err(wrong_type("only key in".to_string(), "an object or array",
+                                    &path[0 .. index])),
+            }
+        },
+        JsonPathElement::Index(array_index) => {
+            match json {
+                &Array(ref vec) =>
+                    match vec.get(array_index) {
+                        Some(value) => get_json_value(value, path, index + 1),
+                        None => Err(JsonError::IndexOutOfBounds {
+                            index: array_index,
+                            path: pretty_print(&path[0 .. index]),
+                            len: vec.len(),
+                        }),
                     },
-                _ => Err(format!("Asked for only key in {}, but value is not an object or array",
-                                 pretty_print(&path[0 .. index]))),
+                _ => Err(wrong_type(format!("index {} in", array_index), "an array",
+                                    &path[0 .. index])),
             }
         },
     }
 }
 
+/// Returns the value found at `path` inside `json`, or None if the last element of `path` is a key
+/// that's absent from the object it's looked up in. Anything else that stops `path` from being
+/// followed (a missing key before the last one, or a value of the wrong type) is still an error.
+pub fn get_optional_json_value<'a>(json: &'a Json, path: &[JsonPathElement])
+                                   -> Result<Option<&'a Json>, JsonError> {
+    if let Some(&Key(key)) = path.last() {
+        let parent_path = &path[..path.len() - 1];
+        if let &Object(ref obj) = try!(get_json_value(json, parent_path, 0)) {
+            return Ok(obj.get(key));
+        }
+    }
+    get_json_value(json, path, 0).map(Some)
+}
+
 /// Returns the array found at `path` inside `json`.
-pub fn get_json_array<'a>(json: &'a Json, path: &[JsonPathElement]) -> Result<&'a Vec<Json>, String> {
-    match get_json_value(json, path, 0) {
-        Ok(&Json::Array(ref value)) => Ok(value),
-        Ok(..) => Err(format!("Asked for array {}, but value is not an array",
-                              pretty_print(&path[..]))),
-        Err(message) => Err(message),
+pub fn get_json_array<'a>(json: &'a Json, path: &[JsonPathElement])
+                          -> Result<&'a Vec<Json>, JsonError> {
+    match try!(get_json_value(json, path, 0)) {
+        &Json::Array(ref value) => Ok(value),
+        _ => Err(wrong_type("array".to_string(), "an array", path)),
     }
 }
 
+/// Returns the boolean found at `path` inside `json`.
+pub fn get_json_bool(json: &Json, path: &[JsonPathElement]) -> Result<bool, JsonError> {
+    try!(get_json_value(json, path, 0)).as_boolean()
+        .ok_or(wrong_type("boolean".to_string(), "a boolean", path))
+}
+
 /// Returns the number found at `path` inside `json`.
-pub fn get_json_number(json: &Json, path: &[JsonPathElement]) -> Result<u64, String> {
-    match get_json_value(json, path, 0) {
-        Ok(ref value) =>
-            value.as_u64().ok_or(format!(
-                "Asked for number {}, but value is not a number", pretty_print(&path[..]))),
-        Err(message) => Err(message),
-    }
+pub fn get_json_number(json: &Json, path: &[JsonPathElement]) -> Result<u64, JsonError> {
+    try!(get_json_value(json, path, 0)).as_u64()
+        .ok_or(wrong_type("number".to_string(), "a number", path))
 }
 
 /// Returns the string found at `path` inside `json`.
-pub fn get_json_string<'a>(json: &'a Json, path: &[JsonPathElement]) -> Result<&'a str, String> {
-    match get_json_value(json, path, 0) {
-        Ok(&Json::String(ref value)) => Ok(value),
-        Ok(..) => Err(format!("Asked for string {}, but value is not a string",
-                              pretty_print(&path[..]))),
-        Err(message) => Err(message),
+pub fn get_json_string<'a>(json: &'a Json, path: &[JsonPathElement])
+                           -> Result<&'a str, JsonError> {
+    match try!(get_json_value(json, path, 0)) {
+        &Json::String(ref value) => Ok(value),
+        _ => Err(wrong_type("string".to_string(), "a string", path)),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{get_json_array, get_json_string, get_json_number};
+    use super::{JsonError, get_json_array, get_json_bool, get_json_string, get_json_number,
+                get_optional_json_value};
     use super::JsonPathElement::*;
     use rustc_serialize::json::Json;
 
@@ -171,20 +262,56 @@ mod tests {
                 &[Key("key2"), Only, Only]))
     }
 
-    fn assert_error_message<T>(result: &Result<T, String>, expected_message: &str) {
+    fn assert_error<T>(result: &Result<T, JsonError>, expected_error: JsonError) {
         match result {
-            &Ok(..) => panic!(format!("Expected error message: \"{}\"", expected_message)),
-            &Err(ref message) if message == expected_message => return,
-            &Err(ref message) => panic!(format!("Wrong error message: \"{}\"", message)),
+            &Ok(..) => panic!(format!("Expected error: {:?}", expected_error)),
+            &Err(ref error) if *error == expected_error => return,
+            &Err(ref error) => panic!(format!("Wrong error: {:?}", error)),
         }
     }
 
+    fn key_not_found(key: &str, path: &str) -> JsonError {
+        JsonError::KeyNotFound { key: key.to_string(), path: path.to_string() }
+    }
+
+    fn wrong_type(asked_for: &str, expected: &'static str, path: &str) -> JsonError {
+        JsonError::WrongType {
+            asked_for: asked_for.to_string(),
+            expected: expected,
+            path: path.to_string(),
+        }
+    }
+
+    fn not_singleton(path: &str, count: usize, array: bool) -> JsonError {
+        JsonError::NotSingleton { path: path.to_string(), count: count, array: array }
+    }
+
+    #[test]
+    fn test_json_error_messages() {
+        assert_eq!("Key wrong_key not found in (root)",
+                   key_not_found("wrong_key", "(root)").to_string());
+        assert_eq!("Asked for key key2 in (root).key1, but value is not an object",
+                   wrong_type("key key2 in", "an object", "(root).key1").to_string());
+        assert_eq!("Asked for string (root).key1, but value is not a string",
+                   wrong_type("string", "a string", "(root).key1").to_string());
+        assert_eq!("Asked for only key in (root).key, but object has 2 values",
+                   not_singleton("(root).key", 2, false).to_string());
+        assert_eq!("Asked for only key in (root).key, but array has 0 elements",
+                   not_singleton("(root).key", 0, true).to_string());
+        assert_eq!("Asked for index 3 in (root).foo, but array has 2 elements",
+                   JsonError::IndexOutOfBounds { index: 3, path: "(root).foo".to_string(), len: 2 }
+                       .to_string());
+        assert_eq!("Key wrong_key not found in (root)".to_string(),
+                   String::from(key_not_found("wrong_key", "(root)")));
+    }
+
+
     #[test]
     fn test_get_json_value_key_not_found() {
-        assert_error_message(
+        assert_error(
             &get_json_string(
                 &Json::from_str("{\"key\": \"val\"}").unwrap(), &[Key("wrong_key")]),
-            "Key wrong_key not found in (root)");
+            key_not_found("wrong_key", "(root)"));
     }
 
     #[test]
@@ -194,44 +321,44 @@ mod tests {
                       "{\"key1\": \"val1\"}",
                       "{\"key1\": [1, 2, 3]}",
                       "{\"key1\": null}"] {
-            assert_error_message(
+            assert_error(
                 &get_json_string(&Json::from_str(json).unwrap(), &[Key("key1"), Key("key2")]),
-                "Asked for key key2 in (root).key1, but value is not an object");
+                wrong_type("key key2 in", "an object", "(root).key1"));
         }
     }
 
     #[test]
     fn test_get_json_value_only_object_empty() {
-        assert_error_message(
+        assert_error(
             &get_json_string(
                 &Json::from_str("{\"key\": {}}").unwrap(), &[Key("key"), Only]),
-            "Asked for only key in (root).key, but object has 0 values");
+            not_singleton("(root).key", 0, false));
     }
 
     #[test]
     fn test_get_json_value_only_object_multiple_values() {
-        assert_error_message(
+        assert_error(
             &get_json_string(
                 &Json::from_str("{\"key\": {\"key1\": \"val1\", \"key2\": \"val2\"}}").unwrap(),
                 &[Key("key"), Only]),
-            "Asked for only key in (root).key, but object has 2 values");
+            not_singleton("(root).key", 2, false));
     }
 
     #[test]
     fn test_get_json_value_only_array_empty() {
-        assert_error_message(
+        assert_error(
             &get_json_string(
                 &Json::from_str("{\"key\": []}").unwrap(), &[Key("key"), Only]),
-            "Asked for only key in (root).key, but array has 0 elements");
+            not_singleton("(root).key", 0, true));
     }
 
     #[test]
     fn test_get_json_value_only_array_multiple_values() {
-        assert_error_message(
+        assert_error(
             &get_json_string(
                 &Json::from_str("{\"key\": [\"val1\", \"val2\"]}").unwrap(),
                 &[Key("key"), Only]),
-            "Asked for only key in (root).key, but array has 2 elements");
+            not_singleton("(root).key", 2, true));
     }
 
     #[test]
@@ -240,12 +367,72 @@ mod tests {
                       "{\"key1\": false}",
                       "{\"key1\": \"val1\"}",
                       "{\"key1\": null}"] {
-            assert_error_message(
+            assert_error(
                 &get_json_string(&Json::from_str(json).unwrap(), &[Key("key1"), Only]),
-                "Asked for only key in (root).key1, but value is not an object or array");
+                wrong_type("only key in", "an object or array", "(root).key1"));
         }
     }
 
+    #[test]
+    fn test_get_json_value_index() {
+        let json = Json::from_str("{\"key\": [{\"revid\": 1}, {\"revid\": 2}]}").unwrap();
+        assert_eq!(Ok(1), get_json_number(&json, &[Key("key"), Index(0), Key("revid")]));
+        assert_eq!(Ok(2), get_json_number(&json, &[Key("key"), Index(1), Key("revid")]));
+    }
+
+    #[test]
+    fn test_get_json_value_index_out_of_bounds() {
+        assert_error(
+            &get_json_string(
+                &Json::from_str("{\"foo\": [\"val1\", \"val2\"]}").unwrap(),
+                &[Key("foo"), Index(3)]),
+            JsonError::IndexOutOfBounds { index: 3, path: "(root).foo".to_string(), len: 2 });
+    }
+
+    #[test]
+    fn test_get_json_value_index_not_array() {
+        for json in &["{\"key1\": 4}",
+                      "{\"key1\": false}",
+                      "{\"key1\": \"val1\"}",
+                      "{\"key1\": {\"0\": \"val1\"}}",
+                      "{\"key1\": null}"] {
+            assert_error(
+                &get_json_string(&Json::from_str(json).unwrap(), &[Key("key1"), Index(0)]),
+                wrong_type("index 0 in", "an array", "(root).key1"));
+        }
+    }
+
+    #[test]
+    fn test_get_json_value_error_after_index() {
+        assert_error(
+            &get_json_string(&Json::from_str("[{\"key\": \"val\"}]").unwrap(),
+                             &[Index(0), Key("wrong_key")]),
+            key_not_found("wrong_key", "(root)[0]"));
+    }
+
+    #[test]
+    fn test_get_optional_json_value_present() {
+        let json = Json::from_str("{\"key1\": {\"key2\": \"val\"}}").unwrap();
+        assert_eq!(Ok(Some(&Json::String("val".to_string()))),
+                   get_optional_json_value(&json, &[Key("key1"), Key("key2")]));
+    }
+
+    #[test]
+    fn test_get_optional_json_value_absent() {
+        let json = Json::from_str("{\"key1\": {\"key2\": \"val\"}}").unwrap();
+        assert_eq!(Ok(None), get_optional_json_value(&json, &[Key("key1"), Key("key3")]));
+    }
+
+    #[test]
+    fn test_get_optional_json_value_errors_before_last_key() {
+        let json = Json::from_str("{\"key1\": {\"key2\": \"val\"}}").unwrap();
+        assert_error(&get_optional_json_value(&json, &[Key("key3"), Key("key2")]),
+                             key_not_found("key3", "(root)"));
+        assert_error(
+            &get_optional_json_value(&json, &[Key("key1"), Key("key2"), Key("key3")]),
+            wrong_type("key key3 in", "an object", "(root).key1.key2"));
+    }
+
     #[test]
     fn test_get_json_array_wrong_type() {
         for json in &["{\"key1\": 4}",
@@ -253,9 +440,29 @@ mod tests {
                       "{\"key1\": false}",
                       "{\"key1\": {\"key2\": \"val1\"}}",
                       "{\"key1\": null}"] {
-            assert_error_message(
+            assert_error(
                 &get_json_array(&Json::from_str(json).unwrap(), &[Key("key1")]),
-                "Asked for array (root).key1, but value is not an array");
+                wrong_type("array", "an array", "(root).key1"));
+        }
+    }
+
+    #[test]
+    fn test_get_json_bool() {
+        let json = Json::from_str("{\"missing\": true, \"redirect\": false}").unwrap();
+        assert_eq!(Ok(true), get_json_bool(&json, &[Key("missing")]));
+        assert_eq!(Ok(false), get_json_bool(&json, &[Key("redirect")]));
+    }
+
+    #[test]
+    fn test_get_json_bool_wrong_type() {
+        for json in &["{\"key1\": 4}",
+                      "{\"key1\": \"val1\"}",
+                      "{\"key1\": [false]}",
+                      "{\"key1\": {\"key2\": true}}",
+                      "{\"key1\": null}"] {
+            assert_error(
+                &get_json_bool(&Json::from_str(json).unwrap(), &[Key("key1")]),
+                wrong_type("boolean", "a boolean", "(root).key1"));
         }
     }
 
@@ -266,9 +473,9 @@ mod tests {
                       "{\"key1\": [\"val1\"]}",
                       "{\"key1\": {\"key2\": \"val1\"}}",
                       "{\"key1\": null}"] {
-            assert_error_message(
+            assert_error(
                 &get_json_number(&Json::from_str(json).unwrap(), &[Key("key1")]),
-                "Asked for number (root).key1, but value is not a number");
+                wrong_type("number", "a number", "(root).key1"));
         }
     }
 
@@ -279,9 +486,9 @@ mod tests {
                       "{\"key1\": [\"val1\"]}",
                       "{\"key1\": {\"key2\": \"val1\"}}",
                       "{\"key1\": null}"] {
-            assert_error_message(
+            assert_error(
                 &get_json_string(&Json::from_str(json).unwrap(), &[Key("key1")]),
-                "Asked for string (root).key1, but value is not a string");
+                wrong_type("string", "a string", "(root).key1"));
         }
     }
 }
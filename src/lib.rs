@@ -0,0 +1,39 @@
+//! The merge engine behind Wikipedia Minus Wikipedians: a 3-way merge (see the `merge` module),
+//! built on a longest-common-subsequence algorithm (see the `longest_common_subsequence` module).
+//!
+//! ```
+//! use wikipedia_minus_wikipedians::{Merger, START_MARKER};
+//!
+//! let merger = Merger::new(1000, 500);
+//! let (merged, timed_out) = merger.try_merge(
+//!     "The fox jumped over the dog.",        // old
+//!     "The quick fox jumped over the dog.",  // new
+//!     "The fox jumped over the lazy dog.",   // other
+//!     "1");                                   // marker for regions merged in from other
+//! assert!(!timed_out);
+//! assert!(merged.contains("quick"));
+//! assert!(merged.contains("lazy"));
+//! assert!(merged.contains(START_MARKER));
+//! ```
+
+#[macro_use]
+extern crate log;
+extern crate num;
+#[cfg(test)]
+extern crate regex;
+extern crate time;
+
+#[cfg(test)]
+mod benchmark;
+pub mod longest_common_subsequence;
+pub mod markers;
+pub mod merge;
+pub mod similarity;
+
+pub use longest_common_subsequence::{get_longest_common_subsequence,
+                                     get_longest_common_subsequence_or_partial, CommonRegion,
+                                     CommonSubsequence, Timeout};
+pub use markers::{START_MARKER, END_MARKER, CURRENT_MARKER, REMOVAL_MARKER,
+                  TITLE_COUNT_SEPARATOR, remove_current_text_markers, remove_markers};
+pub use merge::{Change, ConflictPolicy, Granularity, MergeResult, MergeSegment, MergeStats,
+                MergedArticle, MergedSection, Merger, TimeLimitScaling};
@@ -0,0 +1,211 @@
+//! Limits how many expensive operations (like merging an uncached article) can run at once, so that
+//! a burst of them can't slow everything else down, how often operations (like calls to the
+//! wiki's API) can start, and how much memory they can use between them.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use time;
+
+#[derive(Clone)]
+pub struct ConcurrencyLimiter {
+    /// The most operations that can run at once, or 0 for no limit.
+    max_running: usize,
+    /// The number of operations running, and a Condvar signalled whenever one finishes.
+    state: Arc<(Mutex<usize>, Condvar)>,
+}
+
+/// Permission to run one operation. The operation is counted as running until the Permit is
+/// dropped.
+pub struct Permit {
+    state: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max_running: usize) -> ConcurrencyLimiter {
+        ConcurrencyLimiter {
+            max_running: max_running,
+            state: Arc::new((Mutex::new(0), Condvar::new())),
+        }
+    }
+
+    /// Blocks until fewer than the maximum number of operations are running, then returns a
+    /// Permit.
+    pub fn acquire(&self) -> Permit {
+        let &(ref running, ref finished) = &*self.state;
+        let mut running = running.lock().unwrap();
+        while self.max_running > 0 && *running >= self.max_running {
+            running = finished.wait(running).unwrap();
+        }
+        *running += 1;
+        Permit { state: self.state.clone() }
+    }
+
+    /// Returns a Permit if fewer than the maximum number of operations are running, and None
+    /// otherwise. Never blocks.
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let mut running = self.state.0.lock().unwrap();
+        if self.max_running > 0 && *running >= self.max_running {
+            return None;
+        }
+        *running += 1;
+        Some(Permit { state: self.state.clone() })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let &(ref running, ref finished) = &*self.state;
+        *running.lock().unwrap() -= 1;
+        finished.notify_one();
+    }
+}
+
+/// A token bucket, shared between clones, that limits operations to a number per second. Up to a
+/// second's worth of operations can start at once after a quiet period.
+#[derive(Clone)]
+pub struct RateLimiter {
+    /// The most operations that can start per second, or 0 for no limit.
+    per_second: u64,
+    /// The number of tokens in the bucket, and the time (from time::precise_time_ns()) it was last
+    /// refilled.
+    bucket: Arc<Mutex<(f64, u64)>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u64) -> RateLimiter {
+        RateLimiter {
+            per_second: per_second,
+            bucket: Arc::new(Mutex::new((per_second as f64, time::precise_time_ns()))),
+        }
+    }
+
+    /// Blocks until an operation can start without exceeding the rate limit.
+    pub fn acquire(&self) {
+        if self.per_second == 0 {
+            return;
+        }
+        loop {
+            let wait_ns = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now_ns = time::precise_time_ns();
+                let refilled = (now_ns - bucket.1) as f64 * self.per_second as f64 / 1e9;
+                bucket.0 = (bucket.0 + refilled).min(self.per_second as f64);
+                bucket.1 = now_ns;
+                if bucket.0 >= 1.0 {
+                    bucket.0 -= 1.0;
+                    return;
+                }
+                ((1.0 - bucket.0) * 1e9 / self.per_second as f64) as u64
+            };
+            thread::sleep(Duration::new(wait_ns / 1_000_000_000, (wait_ns % 1_000_000_000) as u32));
+        }
+    }
+}
+
+/// Approximately tracks the number of bytes of content buffered by in-flight operations, so that new
+/// operations can be turned away before a burst of them runs the server out of memory.
+#[derive(Clone)]
+pub struct MemoryAccountant {
+    /// The number of bytes at which new operations are turned away, or 0 for no limit.
+    max_bytes: usize,
+    bytes: Arc<AtomicUsize>,
+}
+
+/// An amount of memory counted by a MemoryAccountant until the MemoryReservation is dropped.
+pub struct MemoryReservation {
+    size: usize,
+    bytes: Arc<AtomicUsize>,
+}
+
+impl MemoryAccountant {
+    pub fn new(max_bytes: usize) -> MemoryAccountant {
+        MemoryAccountant {
+            max_bytes: max_bytes,
+            bytes: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Counts `size` bytes as in use until the returned MemoryReservation is dropped. Never blocks
+    /// or fails, even over the limit, since the memory is already allocated.
+    pub fn reserve(&self, size: usize) -> MemoryReservation {
+        self.bytes.fetch_add(size, Ordering::SeqCst);
+        MemoryReservation { size: size, bytes: self.bytes.clone() }
+    }
+
+    /// Returns true if new operations should be turned away.
+    pub fn is_over_limit(&self) -> bool {
+        self.max_bytes > 0 && self.bytes.load(Ordering::SeqCst) >= self.max_bytes
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.bytes.fetch_sub(self.size, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConcurrencyLimiter, MemoryAccountant, RateLimiter};
+    use time;
+
+    #[test]
+    fn test_try_acquire_when_saturated() {
+        let limiter = ConcurrencyLimiter::new(2);
+        let permit1 = limiter.try_acquire();
+        let permit2 = limiter.try_acquire();
+        assert!(permit1.is_some());
+        assert!(permit2.is_some());
+        assert!(limiter.try_acquire().is_none());
+        drop(permit1);
+        assert!(limiter.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = ConcurrencyLimiter::new(0);
+        let _permits = (0..100).map(|_| limiter.acquire()).collect::<Vec<_>>();
+        assert!(limiter.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_rate_limiter_spreads_out_operations() {
+        let limiter = RateLimiter::new(10);
+        let start_ns = time::precise_time_ns();
+        for _ in 0..20 {
+            limiter.acquire();
+        }
+        // The first 10 start at once, and the other 10 at 10 per second.
+        let elapsed_ms = (time::precise_time_ns() - start_ns) / 1_000_000;
+        assert!(elapsed_ms >= 900, "20 operations took only {} ms", elapsed_ms);
+    }
+
+    #[test]
+    fn test_rate_limiter_unlimited() {
+        let limiter = RateLimiter::new(0);
+        let start_ns = time::precise_time_ns();
+        for _ in 0..1000 {
+            limiter.acquire();
+        }
+        assert!((time::precise_time_ns() - start_ns) / 1_000_000 < 100);
+    }
+
+    #[test]
+    fn test_memory_accountant() {
+        let accountant = MemoryAccountant::new(1000);
+        let small = accountant.reserve(600);
+        assert!(!accountant.is_over_limit());
+        let large = accountant.clone().reserve(600);
+        assert!(accountant.is_over_limit());
+        drop(small);
+        assert!(!accountant.is_over_limit());
+        drop(large);
+
+        let unlimited = MemoryAccountant::new(0);
+        let _reservation = unlimited.reserve(1000000);
+        assert!(!unlimited.is_over_limit());
+    }
+}
@@ -0,0 +1,189 @@
+//! Live updates to merged pages, by long-polling: a client asks for the changes to a page's restored
+//! content since the revision it last saw, and the request is held open until the page gets a new
+//! revision (or a timeout passes). Each watched page is polled for new revisions by one thread,
+//! however many requests are waiting on it.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use rustc_serialize::json;
+use time;
+
+use review::SuggestedEdit;
+
+/// A request waiting for a page to get a revision other than `since_revid`.
+struct Subscriber {
+    since_revid: u64,
+    /// When to give up on the request, as a time::precise_time_ns() value.
+    deadline_ns: u64,
+    sender: Sender<u64>,
+}
+
+pub struct RevisionWatcher {
+    /// Returns the ID of the latest revision of a page.
+    get_latest_revid: Arc<Fn(&str) -> Result<u64, String> + Send + Sync>,
+    poll_interval_ms: u64,
+    /// The requests waiting on each watched page, by title. A page is watched exactly as long as
+    /// it has an entry here.
+    subscribers: Arc<Mutex<HashMap<String, Vec<Subscriber>>>>,
+}
+
+impl RevisionWatcher {
+    /// Creates a watcher that polls each watched page every `poll_interval_ms` milliseconds, with
+    /// `get_latest_revid`.
+    pub fn new(poll_interval_ms: u64,
+               get_latest_revid: Box<Fn(&str) -> Result<u64, String> + Send + Sync>)
+               -> RevisionWatcher {
+        RevisionWatcher {
+            get_latest_revid: Arc::new(get_latest_revid),
+            poll_interval_ms: poll_interval_ms,
+            subscribers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns a Receiver that receives the ID of the latest revision of the page `title` as soon
+    /// as it's something other than `since_revid`. If that doesn't happen within `timeout_ms`, the
+    /// Receiver is disconnected without receiving anything.
+    pub fn subscribe(&self, title: &str, since_revid: u64, timeout_ms: u64) -> Receiver<u64> {
+        let (sender, receiver) = channel();
+        let subscriber = Subscriber {
+            since_revid: since_revid,
+            deadline_ns: time::precise_time_ns() + timeout_ms * 1_000_000,
+            sender: sender,
+        };
+        match self.subscribers.lock().unwrap().entry(title.to_string()) {
+            Entry::Occupied(mut entry) => entry.get_mut().push(subscriber),
+            Entry::Vacant(entry) => {
+                entry.insert(vec![subscriber]);
+                self.spawn_poller(title.to_string());
+            },
+        }
+        receiver
+    }
+
+    /// Starts a thread that polls the page `title` until no requests are waiting on it.
+    fn spawn_poller(&self, title: String) {
+        let get_latest_revid = self.get_latest_revid.clone();
+        let subscribers = self.subscribers.clone();
+        let poll_interval_ms = self.poll_interval_ms;
+        thread::spawn(move|| {
+            loop {
+                let latest_revid = match (*get_latest_revid)(&title) {
+                    Ok(latest_revid) => Some(latest_revid),
+                    Err(msg) => {
+                        warn!("Failed to poll \"{}\" for new revisions: {}", title, msg);
+                        None
+                    },
+                };
+                {
+                    let mut subscribers = subscribers.lock().unwrap();
+                    let now_ns = time::precise_time_ns();
+                    // Dropping a Subscriber disconnects its Receiver, so the request stops waiting.
+                    let still_waiting = subscribers.remove(&title).unwrap_or(vec![]).into_iter()
+                        .filter(|subscriber| match latest_revid {
+                            Some(latest_revid) if latest_revid != subscriber.since_revid => {
+                                let _ = subscriber.sender.send(latest_revid);
+                                false
+                            },
+                            _ => subscriber.deadline_ns > now_ns,
+                        })
+                        .collect::<Vec<_>>();
+                    if still_waiting.is_empty() {
+                        return;
+                    }
+                    subscribers.insert(title.clone(), still_waiting);
+                }
+                thread::sleep(Duration::from_millis(poll_interval_ms));
+            }
+        });
+    }
+}
+
+/// The changes to a page's restored content from one merge of it to the next.
+#[derive(Debug, PartialEq, RustcEncodable)]
+pub struct LiveUpdate {
+    /// The revision the new merge is of.
+    pub revid: u64,
+    /// Content restored in the new merge but not the previous one.
+    pub added: Vec<SuggestedEdit>,
+    /// Content restored in the previous merge but not the new one.
+    pub removed: Vec<SuggestedEdit>,
+}
+
+impl LiveUpdate {
+    pub fn to_json(&self) -> String {
+        json::encode(self).unwrap()
+    }
+}
+
+/// Returns the changes from `previous`, the content restored in the previous merge of a page, to
+/// `current`, the content restored in its merge as of revision `revid`.
+pub fn diff_restored_content(revid: u64, previous: &[SuggestedEdit], current: &[SuggestedEdit])
+                             -> LiveUpdate {
+    LiveUpdate {
+        revid: revid,
+        added: current.iter().filter(|edit| !previous.contains(edit)).cloned().collect(),
+        removed: previous.iter().filter(|edit| !current.contains(edit)).cloned().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LiveUpdate, RevisionWatcher, diff_restored_content};
+    use review::SuggestedEdit;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::TryRecvError;
+    use std::thread;
+    use std::time::Duration;
+
+    fn suggested_edit(revid: u64, wikitext: &str) -> SuggestedEdit {
+        SuggestedEdit {
+            section_title: "".to_string(),
+            revid: revid,
+            user: None,
+            wikitext: wikitext.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_new_revision_is_sent_to_subscribers() {
+        let latest_revid = Arc::new(AtomicUsize::new(100));
+        let latest_revid_clone = latest_revid.clone();
+        let watcher = RevisionWatcher::new(10, Box::new(move |_: &str| {
+            Ok(latest_revid_clone.load(Ordering::SeqCst) as u64)
+        }));
+        let first_receiver = watcher.subscribe("Foo", 100, 10_000);
+        let second_receiver = watcher.subscribe("Foo", 100, 10_000);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(Err(TryRecvError::Empty), first_receiver.try_recv());
+
+        latest_revid.store(101, Ordering::SeqCst);
+        assert_eq!(Ok(101), first_receiver.recv());
+        assert_eq!(Ok(101), second_receiver.recv());
+        // A request that's already behind is answered on the next poll.
+        assert_eq!(Ok(101), watcher.subscribe("Foo", 100, 10_000).recv());
+    }
+
+    #[test]
+    fn test_subscribers_time_out() {
+        let watcher = RevisionWatcher::new(10, Box::new(|_: &str| Ok(100)));
+        assert!(watcher.subscribe("Foo", 100, 30).recv().is_err());
+    }
+
+    #[test]
+    fn test_diff_restored_content() {
+        let previous = [suggested_edit(12, "lol"), suggested_edit(34, "Bad word")];
+        let current = [suggested_edit(34, "Bad word"), suggested_edit(56, "word")];
+        assert_eq!(LiveUpdate {
+                       revid: 78,
+                       added: vec![suggested_edit(56, "word")],
+                       removed: vec![suggested_edit(12, "lol")],
+                   },
+                   diff_restored_content(78, &previous, &current));
+    }
+}
@@ -0,0 +1,218 @@
+//! Log output. By default, log4rs writes lines of text, as log.toml configures. With
+//! --log_format=json, records are instead written to stdout as JSON objects, one per line, for log
+//! aggregation systems to ingest. Each has the time, level and message; the ID and title of the
+//! request being served on the thread that logged it (see `RequestContext`), if any; and, for
+//! records logged by `Timer`s, the phase timed and how long it took.
+
+use std::cell::RefCell;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+use log;
+use log::{LogLevel, LogLevelFilter, LogMetadata, LogRecord};
+use log4rs;
+use rustc_serialize::json;
+use time;
+
+/// How log records are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// As lines of text, as configured in log.toml.
+    Text,
+    /// As JSON objects on stdout, one per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format \"{}\"", s)),
+        }
+    }
+}
+
+/// The ID to give the next request that enters a `RequestContext`.
+static NEXT_REQUEST_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+thread_local! {
+    /// The ID and title of the request being served on this thread, if any.
+    static REQUEST_CONTEXT: RefCell<Option<(usize, String)>> = RefCell::new(None);
+
+    /// The phase and duration (in milliseconds) of the timing being logged on this thread, if any.
+    static TIMING: RefCell<Option<(String, u64)>> = RefCell::new(None);
+}
+
+/// Marks the records logged on this thread, while it's in scope, as being for one request, so
+/// that they can be told apart from those of requests served concurrently. Records logged on the
+/// threads a request spawns aren't marked.
+pub struct RequestContext {
+    _private: (),
+}
+
+impl RequestContext {
+    /// Gives the request for the page `title` a new ID, and marks the records logged on this thread
+    /// as being for it until the returned context is dropped.
+    pub fn enter(title: &str) -> RequestContext {
+        let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::SeqCst);
+        REQUEST_CONTEXT.with(|context| {
+            *context.borrow_mut() = Some((request_id, title.to_string()));
+        });
+        RequestContext { _private: () }
+    }
+}
+
+impl Drop for RequestContext {
+    fn drop(&mut self) {
+        REQUEST_CONTEXT.with(|context| *context.borrow_mut() = None);
+    }
+}
+
+/// Logs that `name`, an instance of `phase`, took `duration_ms` milliseconds: as the message
+/// "<name>: <duration_ms> ms", and, in JSON records, with the phase and duration as fields of their
+/// own.
+pub fn log_timing(phase: &str, name: &str, duration_ms: u64) {
+    TIMING.with(|timing| *timing.borrow_mut() = Some((phase.to_string(), duration_ms)));
+    info!("{}: {} ms", name, duration_ms);
+    TIMING.with(|timing| *timing.borrow_mut() = None);
+}
+
+/// Sets up logging in `format`. Should be called once, at startup.
+pub fn init(format: LogFormat) {
+    match format {
+        LogFormat::Text => log4rs::init_file("log.toml", Default::default()).unwrap(),
+        LogFormat::Json => {
+            init_json_logger(LogLevelFilter::Info, Box::new(io::stdout())).unwrap()
+        },
+    }
+}
+
+/// Sets up logging of records at `level` and above as JSON objects, written to `output`.
+fn init_json_logger(level: LogLevelFilter, output: Box<Write + Send>)
+                    -> Result<(), log::SetLoggerError> {
+    log::set_logger(|max_level| {
+        max_level.set(level);
+        Box::new(JsonLogger { level: level, output: Mutex::new(output) })
+    })
+}
+
+/// A logger that writes each record as a JSON object, on a line of its own.
+struct JsonLogger {
+    level: LogLevelFilter,
+    output: Mutex<Box<Write + Send>>,
+}
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_json_record(record.level(), format!("{}", record.args()));
+        // There's nowhere to report a failure to write a log record.
+        let _ = writeln!(self.output.lock().unwrap(), "{}", line);
+    }
+}
+
+/// The fields of a JSON log record. Those that don't apply to a record are null.
+#[derive(RustcEncodable)]
+struct JsonRecord {
+    timestamp: String,
+    level: String,
+    request_id: Option<usize>,
+    title: Option<String>,
+    phase: Option<String>,
+    duration_ms: Option<u64>,
+    message: String,
+}
+
+/// Returns the JSON record of `message`, logged at `level` on this thread.
+fn format_json_record(level: LogLevel, message: String) -> String {
+    let (request_id, title) = match REQUEST_CONTEXT.with(|context| context.borrow().clone()) {
+        Some((request_id, title)) => (Some(request_id), Some(title)),
+        None => (None, None),
+    };
+    let (phase, duration_ms) = match TIMING.with(|timing| timing.borrow().clone()) {
+        Some((phase, duration_ms)) => (Some(phase), Some(duration_ms)),
+        None => (None, None),
+    };
+    json::encode(&JsonRecord {
+        timestamp: format!("{}", time::now_utc().rfc3339()),
+        level: format!("{}", level),
+        request_id: request_id,
+        title: title,
+        phase: phase,
+        duration_ms: duration_ms,
+        message: message,
+    }).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogFormat, RequestContext, init_json_logger};
+    use log::LogLevelFilter;
+    use rustc_serialize::json::Json;
+    use std::io;
+    use std::io::Write;
+    use std::str;
+    use std::sync::{Arc, Mutex};
+    use timer::Timer;
+
+    /// A log output that can still be read after it's handed to the logger.
+    #[derive(Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_timer_emits_json_record() {
+        // The logger is global, so this is the only test that can set it up.
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        init_json_logger(LogLevelFilter::Info, Box::new(buffer.clone())).unwrap();
+        {
+            let _context = RequestContext::enter("Foo");
+            drop(Timer::new("test_timer_emits_json_record", "Test timer".to_string()));
+        }
+
+        let output = buffer.0.lock().unwrap();
+        // Other tests may log concurrently, so look for this test's record among theirs.
+        let record = str::from_utf8(&output).unwrap().lines()
+            .map(|line| Json::from_str(line).unwrap())
+            .find(|record| {
+                record.find("phase").and_then(|phase| phase.as_string()) ==
+                    Some("test_timer_emits_json_record")
+            })
+            .unwrap();
+        assert_eq!(Some("INFO"), record.find("level").and_then(|level| level.as_string()));
+        assert_eq!(Some("Foo"), record.find("title").and_then(|title| title.as_string()));
+        assert!(record.find("request_id").and_then(|id| id.as_u64()).is_some());
+        assert!(record.find("duration_ms").and_then(|ms| ms.as_u64()).is_some());
+        assert!(record.find("timestamp").and_then(|ts| ts.as_string()).is_some());
+        assert!(record.find("message").and_then(|message| message.as_string()).unwrap()
+                .starts_with("Test timer: "));
+    }
+
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!(Ok(LogFormat::Text), "text".parse());
+        assert_eq!(Ok(LogFormat::Json), "json".parse());
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
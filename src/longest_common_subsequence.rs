@@ -34,11 +34,13 @@
 extern crate num;
 extern crate time;
 
+use std::cmp;
 use std::cmp::Ord;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
 use std::collections::binary_heap::BinaryHeap;
+use std::mem;
 use std::ops::Index;
 use std::ops::IndexMut;
 
@@ -188,11 +190,42 @@ impl<T, I> Ord for Task<T, I> where I: Iterator<Item=T> + Clone {
     }
 }
 
-/// Returns None if the calculation takes more than `time_limit_ms` milliseconds.
-pub fn get_longest_common_subsequence<T, I>(iter1: I, iter2: I, time_limit_ms: u64) -> Option<CommonSubsequence>
+/// The error from a longest-common-subsequence calculation that ran out of time (or tasks).
+#[derive(PartialEq, Clone, Debug)]
+pub struct Timeout {
+    /// The common subsequence of the most promising task on the work queue when the calculation
+    /// stopped. It's a common subsequence of the two iterators, but it only reaches part of the way
+    /// into them, and may not be the longest.
+    pub partial: CommonSubsequence,
+}
+
+/// Returns None if the calculation takes more than `time_limit_ms` milliseconds, or executes more
+/// than `max_tasks` tasks (0 for no limit). Unlike the time limit, the task limit bounds the work
+/// done the same way no matter how loaded the CPU is.
+///
+/// If the work queue grows past `max_queue_size` tasks (0 for no limit), its less promising half is
+/// dropped. Each task holds a copy of its common subsequence, so on dissimilar inputs the queue can
+/// otherwise run out of memory before the time limit is reached. The tradeoff is that a dropped
+/// task may have been the start of the longest common subsequence, in which case a shorter one is
+/// returned.
+pub fn get_longest_common_subsequence<T, I>(iter1: I, iter2: I, time_limit_ms: u64,
+                                            max_tasks: u64, max_queue_size: usize)
+                                            -> Option<CommonSubsequence>
+    where I: Iterator<Item=T> + Clone,
+          T: Eq {
+    get_longest_common_subsequence_or_partial(iter1, iter2, time_limit_ms, max_tasks,
+                                              max_queue_size).ok()
+}
+
+/// Identical to `get_longest_common_subsequence()`, but if the calculation runs out of time or
+/// tasks, returns the best common subsequence found so far in a Timeout.
+pub fn get_longest_common_subsequence_or_partial<T, I>(iter1: I, iter2: I, time_limit_ms: u64,
+                                                       max_tasks: u64, max_queue_size: usize)
+                                                       -> Result<CommonSubsequence, Timeout>
     where I: Iterator<Item=T> + Clone,
           T: Eq {
     let timeout_ns = time::precise_time_ns() + time_limit_ms * 1_000_000;
+    let mut tasks_executed = 0;
 
     let mut work_queue: BinaryHeap<Task<T, I>> = BinaryHeap::new();
     let first_task =
@@ -211,11 +244,35 @@ pub fn get_longest_common_subsequence<T, I>(iter1: I, iter2: I, time_limit_ms: u
     let mut longest_known_common_subsequences: HashMap<(usize, usize), usize> = HashMap::new();
 
     loop {
-        if time::precise_time_ns() > timeout_ns {
-            return None;
+        if time::precise_time_ns() > timeout_ns ||
+            (max_tasks > 0 && tasks_executed >= max_tasks) {
+            // The task at the top of the queue has the lowest implied edit distance so far.
+            return Err(Timeout { partial: work_queue.pop().unwrap().common_subsequence });
+        }
+
+        if max_queue_size > 0 && work_queue.len() > max_queue_size {
+            // Keep the most promising half of the queue, rather than just enough to fit, so that
+            // the queue only needs to be rebuilt once every max_queue_size / 2 tasks.
+            let mut dropped_tasks =
+                mem::replace(&mut work_queue, BinaryHeap::new()).into_sorted_vec();
+            let kept_tasks_count = cmp::max(max_queue_size / 2, 1);
+            let dropped_tasks_count = dropped_tasks.len() - kept_tasks_count;
+            let kept_tasks = dropped_tasks.split_off(dropped_tasks_count);
+            // Forget the dropped tasks' common subsequences, so that other tasks can still reach
+            // their offsets. Otherwise the work queue could run dry before reaching the end of both
+            // iterators.
+            for task in &dropped_tasks {
+                let offsets = (task.iter1_offset, task.iter2_offset);
+                if longest_known_common_subsequences.get(&offsets) ==
+                    Some(&task.common_subsequence.size) {
+                    longest_known_common_subsequences.remove(&offsets);
+                }
+            }
+            work_queue = kept_tasks.into_iter().collect();
         }
 
         let mut task = work_queue.pop().unwrap();
+        tasks_executed += 1;
 
         // 1. Move forward in both iterators for as long as they match.
         let mut matching_items = 0;
@@ -263,7 +320,7 @@ pub fn get_longest_common_subsequence<T, I>(iter1: I, iter2: I, time_limit_ms: u
         }
 
         if iter1_finished && iter2_finished {
-            return Some(new_common_subsequence);
+            return Ok(new_common_subsequence);
         }
 
         // 3a. Enqueue another task in the work queue that starts one item farther into iter1 and at
@@ -329,14 +386,22 @@ pub fn get_longest_common_subsequence<T, I>(iter1: I, iter2: I, time_limit_ms: u
 
 #[cfg(test)]
 mod tests {
-    use super::{get_longest_common_subsequence, CommonSubsequence, CommonRegion};
+    use super::{get_longest_common_subsequence, get_longest_common_subsequence_or_partial,
+                CommonSubsequence, CommonRegion, Timeout};
+    use std::iter;
+
+    /// Returns the longest common subsequence of the characters of `s1` and `s2`, with a time limit
+    /// that's never reached.
+    fn lcs(s1: &str, s2: &str) -> Option<CommonSubsequence> {
+        get_longest_common_subsequence(s1.chars(), s2.chars(), 1_000_000, 0, 0)
+    }
 
     #[test]
     fn test_lcs_identical_strings() {
         let test_string = "test identical strings";
         let expected = CommonSubsequence::new(vec![CommonRegion::new(0, 0, 22)]);
         assert_eq!(Some(expected),
-                   get_longest_common_subsequence(test_string.chars(), test_string.chars()));
+                   lcs(test_string, test_string));
     }
 
     #[test]
@@ -346,7 +411,7 @@ mod tests {
         let expected =
             CommonSubsequence::new(vec![CommonRegion::new(0, 0, 5), CommonRegion::new(5, 20, 6)]);
         assert_eq!(Some(expected),
-                   get_longest_common_subsequence(test_string.chars(), test_string2.chars()));
+                   lcs(test_string, test_string2));
     }
 
     #[test]
@@ -357,7 +422,7 @@ mod tests {
             CommonSubsequence::new(vec![CommonRegion::new(0, 0, 2), CommonRegion::new(3, 2, 2),
                                         CommonRegion::new(5, 5, 1)]);
         assert_eq!(Some(expected),
-                   get_longest_common_subsequence(test_string.chars(), test_string2.chars()));
+                   lcs(test_string, test_string2));
     }
 
     #[test]
@@ -365,7 +430,7 @@ mod tests {
         let test_string = "abcdefg";
         let test_string2 = "12345678";
         assert_eq!(Some(CommonSubsequence::new(vec![])),
-                   get_longest_common_subsequence(test_string.chars(), test_string2.chars()));
+                   lcs(test_string, test_string2));
     }
 
     #[test]
@@ -377,7 +442,66 @@ mod tests {
                 vec![CommonRegion::new(0, 0, 7), CommonRegion::new(7, 11, 6),
                      CommonRegion::new(14, 17, 1)]);
         assert_eq!(Some(expected),
-                   get_longest_common_subsequence(test_string.chars(), test_string2.chars()));
+                   lcs(test_string, test_string2));
+    }
+
+    #[test]
+    fn test_lcs_task_limit() {
+        let test_string = (0..500).map(|i| format!("{} ", i)).collect::<String>();
+        let test_string2 = (0..500).rev().map(|i| format!("{} ", i)).collect::<String>();
+        // The time limit is long enough that only the task limit can stop the calculation.
+        assert_eq!(None, get_longest_common_subsequence(test_string.split(' '),
+                                                        test_string2.split(' '), 1_000_000, 100,
+                                                        0));
+        assert!(get_longest_common_subsequence(test_string.split(' '), test_string.split(' '),
+                                               1_000_000, 1, 0).is_some());
+    }
+
+    #[test]
+    fn test_lcs_partial_on_timeout() {
+        let test_string = "prefix a b c d e f g h";
+        let test_string2 = "prefix h g f e d c b a";
+        // The first task matches "prefix ", and then the calculation runs out of tasks.
+        let partial = CommonSubsequence::new(vec![CommonRegion::new(0, 0, 7)]);
+        assert_eq!(Err(Timeout { partial: partial }),
+                   get_longest_common_subsequence_or_partial(
+                       test_string.chars(), test_string2.chars(), 1_000_000, 1, 0));
+        assert_eq!(None, get_longest_common_subsequence(
+            test_string.chars(), test_string2.chars(), 1_000_000, 1, 0));
+
+        // With no time at all, no task runs, so the partial subsequence is empty.
+        assert_eq!(Err(Timeout { partial: CommonSubsequence::new(vec![]) }),
+                   get_longest_common_subsequence_or_partial(
+                       test_string.chars(), test_string2.chars(), 0, 0, 0));
+    }
+
+    #[test]
+    fn test_lcs_max_queue_size() {
+        let test_string = iter::repeat('a').take(1000).collect::<String>();
+        let test_string2 = iter::repeat('b').take(1000).collect::<String>();
+        // With nothing in common, every offset is equally promising, so an unbounded calculation
+        // would queue up, and execute, a task for each of the million pairs of offsets. With a
+        // bounded queue, only the tasks nearest the diagonal survive, and the calculation finishes
+        // well within the task limit.
+        assert_eq!(Some(CommonSubsequence::new(vec![])),
+                   get_longest_common_subsequence(test_string.chars(), test_string2.chars(),
+                                                  1_000_000, 200_000, 100));
+
+        // A queue that's too small can drop the task that would have found the longest common
+        // subsequence, leaving a shorter one.
+        let expected =
+            CommonSubsequence::new(vec![CommonRegion::new(0, 0, 5), CommonRegion::new(5, 20, 6)]);
+        assert_eq!(Some(expected),
+                   get_longest_common_subsequence("test string".chars(),
+                                                  "test diff in middle string".chars(),
+                                                  1_000_000, 0, 100));
+        let expected =
+            CommonSubsequence::new(vec![CommonRegion::new(0, 0, 5), CommonRegion::new(8, 10, 2),
+                                        CommonRegion::new(10, 25, 1)]);
+        assert_eq!(Some(expected),
+                   get_longest_common_subsequence("test string".chars(),
+                                                  "test diff in middle string".chars(),
+                                                  1_000_000, 0, 4));
     }
 
     //use hyper::Client;
@@ -2,33 +2,51 @@
 #![plugin(regex_macros)]
 
 extern crate argparse;
+extern crate flate2;
 extern crate html5ever;
 extern crate html5ever_dom_sink;
 extern crate hyper;
 extern crate iron;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate log;
 extern crate log4rs;
+extern crate rand;
 extern crate redis;
 extern crate regex;
 extern crate rustc_serialize;
+extern crate solicit;
+#[cfg(test)]
+extern crate tempdir;
 extern crate tempfile;
+extern crate time;
+extern crate toml;
 extern crate url;
+extern crate wikipedia_minus_wikipedians;
 
 use argparse::ArgumentParser;
 use argparse::Store;
+use argparse::StoreConst;
+use argparse::StoreOption;
+use argparse::StoreTrue;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::hash_map::Entry;
+use std::env;
+use std::fs::File;
 use std::fs::OpenOptions;
+use std::hash::{Hash, Hasher, SipHasher};
+use std::io;
 use std::io::Read;
 use std::io::Write;
 use std::iter::FromIterator;
+use std::process;
 use std::process::Command;
 use std::process::Stdio;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
 
 use hyper::Client;
 use hyper::header::Connection;
@@ -38,28 +56,80 @@ use iron::Request;
 use iron::Response;
 use iron::headers::ContentType;
 use iron::middleware::Handler;
+use iron::mime::Attr;
 use iron::mime::Mime;
 use iron::mime::SubLevel;
 use iron::mime::TopLevel;
+use iron::mime::Value;
+use regex::Regex;
 use tempfile::NamedTempFile;
 
-use merge::Merger;
+use capture::Capture;
+use capture::CapturedRevision;
+use config::Config;
+use error::AppError;
+use events::EventLogger;
+use events::EventSink;
+use events::FileEventSink;
+use events::RestorationEvent;
+use events::WebhookEventSink;
+use limiter::ConcurrencyLimiter;
+use limiter::MemoryAccountant;
+use limiter::MemoryReservation;
+use live::LiveUpdate;
+use live::RevisionWatcher;
+use logging::LogFormat;
+use metrics::{Counter, metrics};
+use page::MarkerOutput;
 use page::Page;
+use page::RenderOptions;
+use page::SkeletonFetches;
+use page::Skin;
+use page_cache::PageCache;
+use review::SuggestedEdit;
+use revert::RevertDetection;
+use revert::find_reverts;
+use snapshot::SnapshotStore;
 use timer::Timer;
+use wiki::FragmentPolicy;
 use wiki::Revision;
 use wiki::Wiki;
+use wiki::WikiOptions;
+use wikipedia_minus_wikipedians::Change;
+use wikipedia_minus_wikipedians::ConflictPolicy;
+use wikipedia_minus_wikipedians::END_MARKER;
+use wikipedia_minus_wikipedians::Granularity;
+use wikipedia_minus_wikipedians::MergeStats;
+use wikipedia_minus_wikipedians::MergedArticle;
+use wikipedia_minus_wikipedians::MergedSection;
+use wikipedia_minus_wikipedians::Merger;
+use wikipedia_minus_wikipedians::REMOVAL_MARKER;
+use wikipedia_minus_wikipedians::START_MARKER;
+use wikipedia_minus_wikipedians::TITLE_COUNT_SEPARATOR;
+use wikipedia_minus_wikipedians::TimeLimitScaling;
+use wikipedia_minus_wikipedians::remove_current_text_markers;
+use wikipedia_minus_wikipedians::remove_markers;
+use worker_pool::WorkerPool;
+
+
+/// The templates left out of merging when --exclude_infoboxes is set. Vandalism in these tends to be
+/// wrong data (a changed date or flag) rather than anything interesting, and renders awkwardly.
+const EXCLUDED_TEMPLATES: [&'static str; 2] = ["Infobox", "Navbox"];
 
-/// To mark areas of the merged text that were merged in from vandalized edits, the code uses
-/// placeholder characters at the start and end of each merged region.
-///
-/// These two characters are taken from a Unicode Private Use Area, so they should never appear in
-/// actual Wikipedia text.
-const START_MARKER: &'static str = "\u{E000}";
-const END_MARKER: &'static str = "\u{E001}";
+/// The maintenance templates whose changes aren't restored when --ignore_maintenance_templates is
+/// set, unless --maintenance_templates lists others. Reverts often add or remove these along with
+/// the vandalism, and restoring that would tag good content, or untag bad content, misleadingly.
+const MAINTENANCE_TEMPLATES: [&'static str; 14] =
+    ["Citation needed", "Cn", "Fact", "Dubious", "Clarify", "Who", "When", "Vague",
+     "Failed verification", "Better source needed", "Unreliable source?", "According to whom",
+     "Weasel inline", "Peacock inline"];
 
-/// See the documentation for `deduplicate_section_titles` for a description of how this constant is
-/// used.
-const TITLE_COUNT_SEPARATOR: &'static str = "\u{E002}";
+/// The page served at /about, explaining how the mirror works, unless --about_page is set.
+const DEFAULT_ABOUT_PAGE: &'static str = include_str!("about.html");
+
+/// The most pages whose restored content is remembered for live update requests. Past that, the
+/// oldest is forgotten, and its next update sends everything restored in it as new.
+const LIVE_RESTORED_CONTENT_SIZE: usize = 1000;
 
 /// Helper macro for unwrapping Result values whose E types implement std::fmt::Display. For Ok(),
 /// evaluates to the contained value. For Err(), returns early with an Err containing the formatted
@@ -85,213 +155,991 @@ macro_rules! try_return {
     })
 }
 
+mod cache;
+mod capture;
+mod config;
+mod connection_pool;
+mod error;
+mod events;
+mod http2;
 mod json;
-mod longest_common_subsequence;
-mod merge;
+mod limiter;
+mod live;
+mod logging;
+mod metrics;
 mod page;
+mod page_cache;
+mod profile;
+mod review;
+mod revert;
+mod snapshot;
+#[cfg(test)]
+mod test_server;
 mod timer;
 mod wiki;
-
-// TODO: consider doing s/en.wikipedia.org/this app's url/ on the HTML before serving it. This
-// currently works fine, but might not over HTTPS.
+mod worker_pool;
 
 struct WikipediaMinusWikipediansHandler {
     wiki: Wiki,
     client: Client,
     merger: Merger,
     max_consecutive_diff_timeouts: u64,
+    /// Whether to retry merges that time out at word granularity at coarser granularities.
+    escalate_granularity: bool,
+    /// Whether to leave vandalism inside EXCLUDED_TEMPLATES unrestored.
+    exclude_infoboxes: bool,
+    /// Whether to leave reference list sections (see `is_reference_list()`) unmerged.
+    skip_reference_lists: bool,
+    /// Matches the titles of the sections to merge (see `select_sections()`), or None to merge
+    /// them all.
+    section_selector: Option<Regex>,
+    /// The quality score (see `MergeStats::quality()`) below which merged sections are logged and
+    /// counted as low quality. 0 to not check.
+    min_section_quality: f64,
+    /// The names of the maintenance templates whose additions and removals aren't restored, or None
+    /// to restore them like any other change.
+    maintenance_templates: Option<Arc<Vec<String>>>,
+    /// The number of reverts, by different users, that must have removed a piece of content for it
+    /// to be restored.
+    min_corroborating_reverts: u64,
+    /// Whether to also merge in reverted vandalism from the article's talk page, in sections whose
+    /// titles match the article's.
+    include_talk_page_reverts: bool,
+    /// The number of antivandalism revisions of a page to scan for before stopping, fetching its
+    /// revisions in growing batches. 0 to always fetch them all at once.
+    target_revert_count: u64,
+    /// The words (in lowercase) that mark a revision as reverting vandalism when its edit summary
+    /// contains any of them. See `is_antivandalism_revision()`.
+    vandalism_keywords: Vec<String>,
+    /// How revisions that reverted vandalism are detected.
+    revert_detection: RevertDetection,
+    /// Whether to also merge in reverted vandalism from the draft an article was promoted from.
+    follow_moves: bool,
+    /// Whether the wiki uppercases the first letter of page titles.
+    capital_links: bool,
+    /// What to do with requests for titles with fragments in them.
+    fragment_policy: FragmentPolicy,
+    /// The page that requests without a title (e.g. "/wiki/") are redirected to, or None to reject
+    /// them.
+    empty_title_page: Option<String>,
+    /// Whether to add an X-WMW-Merge-Stats header to /wiki/ responses.
+    emit_stats_header: bool,
+    /// Whether to check merged pages for MediaWiki error messages, and revert the sections whose
+    /// restored content causes them.
+    validate_render: bool,
+    event_logger: EventLogger,
+    render_options: RenderOptions,
+    /// The fetches of page skeletons in progress, which concurrent requests for a page can share.
+    skeleton_fetches: SkeletonFetches,
+    /// The workers that sections are merged on, shared with `skeleton_fetches`.
+    workers: WorkerPool,
+    /// Finished pages, keyed by `get_page_cache_key()`, with the statistics from merging them, the
+    /// IDs of the revisions their restored content came from, and the time (from
+    /// `time::precise_time_ns()`) they expire. Pages are keyed by the revision they were merged
+    /// into, so an edit to an article makes it merge again.
+    page_cache: PageCache<((String, MergeStats, Vec<u64>), u64)>,
+    /// How long pages stay in `page_cache`, in seconds, so that new reverts of an article that
+    /// hasn't been edited since are eventually merged in.
+    page_cache_ttl_seconds: u64,
+    /// Merged sections, keyed by `get_section_cache_key()`, with the fingerprints (see
+    /// `get_section_fingerprint()`) of what they were merged from. Sections that haven't changed
+    /// since they were cached aren't merged again when the rest of the page is. See
+    /// `cache_merged_section()`.
+    section_cache: PageCache<(u64, String, MergeStats)>,
+    /// Limits the number of uncached pages being merged at once.
+    merge_limiter: ConcurrencyLimiter,
+    /// The largest merged page to serve, in bytes. Bigger pages are served unmodified instead. 0
+    /// for no limit.
+    max_response_bytes: usize,
+    /// The wiki at its mobile hostname, which mobile requests (see `is_mobile_request`) fetch the
+    /// page skeleton from. None if mobile requests are served the desktop page.
+    mobile_wiki: Option<Wiki>,
+    /// The directory that merges of `capture_titles` are captured to (see the capture module), or
+    /// None to not capture merges.
+    capture_dir: Option<String>,
+    /// The (normalized) titles of the pages whose merges are captured.
+    capture_titles: HashSet<String>,
+    /// Where each page merged is saved, to be served (marked as stale) when the wiki can't be
+    /// reached. None to not save pages.
+    snapshots: Option<SnapshotStore>,
+    /// The HTML page served at /about.
+    about_page: String,
+    /// Whether /healthz reports the server unhealthy when the wiki's API or Redis can't be reached,
+    /// rather than only checking that it's up.
+    healthz_checks_upstream: bool,
+    /// Counts the bytes of content buffered by in-flight merges. New merges are turned away while
+    /// it's over its limit.
+    inflight_bytes: MemoryAccountant,
+    /// Watches the pages that live update requests are waiting on for new revisions.
+    revision_watcher: RevisionWatcher,
+    /// How long to hold a live update request open waiting for a new revision, in milliseconds.
+    live_timeout_ms: u64,
+    /// The latest revision of each page merged for a live update request, and the content restored
+    /// in it, so that the next update only has to send what changed. Holds the
+    /// LIVE_RESTORED_CONTENT_SIZE most recently added pages.
+    live_restored_content: PageCache<(u64, Vec<SuggestedEdit>)>,
+    /// Limits the number of live update requests held open at once, since each holds a server
+    /// thread. Requests past the limit are turned away with a 503.
+    live_request_limiter: ConcurrencyLimiter,
 }
 
 impl WikipediaMinusWikipediansHandler {
-    fn new(wiki: Wiki, client: Client, merger: Merger, max_consecutive_diff_timeouts: u64) ->
-        WikipediaMinusWikipediansHandler {
+    /// Returns a handler that serves pages from `wiki`, merged by `merger` on `workers`, with every
+    /// other setting as its flag defaults to. `client` sends passthrough requests to the wiki, and
+    /// `event_logger` logs the restorations in pages served.
+    fn new(wiki: Wiki, client: Client, merger: Merger, event_logger: EventLogger,
+           workers: WorkerPool) -> WikipediaMinusWikipediansHandler {
         WikipediaMinusWikipediansHandler {
+            revision_watcher: watch_revisions(&wiki, 10_000),
             wiki: wiki,
             client: client,
             merger: merger,
-            max_consecutive_diff_timeouts: max_consecutive_diff_timeouts,
+            max_consecutive_diff_timeouts: 3,
+            escalate_granularity: false,
+            exclude_infoboxes: false,
+            skip_reference_lists: false,
+            section_selector: None,
+            min_section_quality: 0.0,
+            maintenance_templates: None,
+            min_corroborating_reverts: 1,
+            include_talk_page_reverts: false,
+            target_revert_count: 0,
+            vandalism_keywords: parse_vandalism_keywords(""),
+            revert_detection: RevertDetection::Comment,
+            follow_moves: false,
+            capital_links: true,
+            fragment_policy: FragmentPolicy::Redirect,
+            empty_title_page: Some("Main_Page".to_string()),
+            emit_stats_header: false,
+            validate_render: false,
+            event_logger: event_logger,
+            render_options: RenderOptions::default(),
+            skeleton_fetches: SkeletonFetches::new(false, workers.clone()),
+            workers: workers,
+            page_cache: PageCache::new(0),
+            page_cache_ttl_seconds: 300,
+            section_cache: PageCache::new(0),
+            merge_limiter: ConcurrencyLimiter::new(0),
+            max_response_bytes: 0,
+            mobile_wiki: None,
+            capture_dir: None,
+            capture_titles: HashSet::new(),
+            snapshots: None,
+            about_page: DEFAULT_ABOUT_PAGE.to_string(),
+            healthz_checks_upstream: false,
+            inflight_bytes: MemoryAccountant::new(0),
+            live_timeout_ms: 60_000,
+            live_restored_content: PageCache::new(LIVE_RESTORED_CONTENT_SIZE),
+            live_request_limiter: ConcurrencyLimiter::new(64),
         }
     }
 
     /// Returns a vector of Revisions representing all reversions of vandalism for the page `title`.
     fn get_antivandalism_revisions(&self, title: &str) -> Result<Vec<Revision>, String> {
-        let revisions = try!(self.wiki.get_revisions(title, 500));
-        Ok(revisions.into_iter().filter(|revision| revision.comment.contains("vandal")).collect())
+        let keywords = &self.vandalism_keywords;
+        let is_antivandalism_comment =
+            |revision: &Revision| is_antivandalism_revision(revision, keywords);
+        let revert_detection = self.revert_detection;
+        let revisions = if self.target_revert_count > 0 {
+            let target_revert_count = self.target_revert_count;
+            try!(self.wiki.get_revisions_until(title, |revisions| {
+                find_reverts(revisions, revert_detection, &is_antivandalism_comment).len() as u64 >=
+                    target_revert_count
+            }))
+        } else {
+            try!(self.wiki.get_all_revisions(title))
+        };
+        Ok(find_reverts(&revisions, revert_detection, &is_antivandalism_comment).into_iter()
+           .cloned().collect())
     }
 
-    /// Fetches each specified revision of the page `title`, parses it into sections, and sends each
-    /// section's content to the Sender associated with the section's title in
-    /// `revision_content_senders`.
+    /// Fetches each specified (page title, revision) pair (all of which are being merged into the
+    /// page `title`), batching the revisions of each page title into as few API queries as
+    /// possible, parses it into sections, adds each section's content to the merge job associated
+    /// with the section's title in `merge_jobs`, and submits the jobs. If `capture` is set,
+    /// returns everything added, in order.
     fn fetch_revisions_content(
-        &self, title: String, revisions: Vec<Revision>,
-        revision_content_senders: HashMap<String, Sender<Option<(String, String, u64)>>>)
-        -> Result<(), String> {
+        &self, title: String, revisions: Vec<(String, Revision)>,
+        mut merge_jobs: HashMap<String, MergeJob>, capture: bool)
+        -> Result<Vec<CapturedRevision>, String> {
         let _timer =
-            Timer::new(format!("Got content of {} revisions of \"{}\"", revisions.len(), title));
-        // Elements are (clean revision ID, receiver for clean revision content, receiver for
-        // vandalized revision content).
-        let mut receivers: Vec<(u64, Receiver<Result<Vec<(String, String)>, String>>,
-                                Receiver<Result<Vec<(String, String)>, String>>)> =
-            Vec::with_capacity(revisions.len());
-        for revision in &revisions {
-            let mut inner_receivers = Vec::new();
+            Timer::new("fetch_revisions_content",
+                       format!("Got content of {} revisions of \"{}\"", revisions.len(), title));
+        // Maps from each page title to the IDs of its revisions to fetch the content of.
+        let mut revision_ids: HashMap<&str, Vec<u64>> = HashMap::new();
+        for &(ref revision_title, ref revision) in &revisions {
+            let ids = revision_ids.entry(&revision_title[..]).or_insert(Vec::new());
             for revision_id in vec![revision.revid, revision.parentid] {
-                let (sender, receiver) = channel();
-                let wiki = self.wiki.clone();
-                let title = title.to_string().clone();
-                let revision = revision.clone();
-                thread::Builder::new().name(format!("fetch-content-{}-{}", title, revision_id))
-                    .spawn(move|| {
-                        sender.send(
-                            match wiki.get_revision_content(&title, revision_id) {
-                                Ok(content) =>
-                                    Ok(deduplicate_section_titles(wiki::parse_sections(&content))),
-                                _ => Err(format!(
-                                    "Failed to get content of revision {} of \"{}\"", revision_id,
-                                    title)),
-                            }).unwrap();
-                    });
-                inner_receivers.push(receiver);
+                if !ids.contains(&revision_id) {
+                    ids.push(revision_id);
+                }
+            }
+        }
+        // Maps from (page title, revision ID) to the sections of that revision.
+        let mut revision_sections: HashMap<(&str, u64), Vec<(String, String)>> = HashMap::new();
+        let mut content_bytes = 0;
+        for (revision_title, ids) in revision_ids {
+            let contents = try_display!(self.wiki.get_revision_contents(revision_title, &ids),
+                                        "Failed to get content of revisions of \"{}\"",
+                                        revision_title);
+            for (revision_id, content) in contents {
+                content_bytes += content.len();
+                revision_sections.insert(
+                    (revision_title, revision_id),
+                    deduplicate_section_titles(wiki::parse_sections(&remove_markers(&content))));
             }
-            receivers.push(
-                (revision.revid, inner_receivers.remove(0), inner_receivers.remove(0)));
         }
+        let _content_reservation = self.inflight_bytes.reserve(content_bytes);
 
-        for (revision_id, clean_receiver, vandalized_receiver) in receivers {
-            let mut clean_sections: HashMap<String, String> =
-                HashMap::from_iter(
-                    try!(try_display!(clean_receiver.recv(), "Failed to get data from thread")));
-            let mut vandalized_sections: HashMap<String, String> =
-                HashMap::from_iter(try!(
-                    try_display!(vandalized_receiver.recv(), "Failed to get data from thread")));
+        // Maps from section title to the (clean content, vandalized content, revision) tuples for
+        // that section, when they're being held back to check for corroboration.
+        let mut pending_revisions: HashMap<String, Vec<(String, String, Revision)>> =
+            HashMap::new();
+        let mut captured_revisions = Vec::new();
+        for &(ref revision_title, ref revision) in &revisions {
+            let mut clean_sections: HashMap<String, String> = HashMap::from_iter(
+                revision_sections[&(&revision_title[..], revision.revid)].clone());
+            let mut vandalized_sections: HashMap<String, String> = HashMap::from_iter(
+                revision_sections[&(&revision_title[..], revision.parentid)].clone());
 
-            for (title, revision_content_sender) in revision_content_senders.iter() {
+            for (title, merge_job) in merge_jobs.iter_mut() {
                 match (clean_sections.remove(title), vandalized_sections.remove(title)) {
                     (Some(clean_content), Some(vandalized_content)) => {
-                        revision_content_sender.send(
-                            Some((clean_content, vandalized_content, revision_id)));
+                        if self.min_corroborating_reverts > 1 {
+                            // Whether each change is corroborated can't be known until all the
+                            // revisions are in.
+                            pending_revisions.entry(title.clone()).or_insert(Vec::new()).push(
+                                (clean_content, vandalized_content, revision.clone()));
+                        } else {
+                            if capture {
+                                captured_revisions.push(CapturedRevision::new(
+                                    title, &clean_content, &vandalized_content, &revision));
+                            }
+                            merge_job.add_revision(clean_content, vandalized_content,
+                                                   revision.clone());
+                        }
                     },
                     _ => (),
                 }
             }
         }
-        for revision_content_sender in revision_content_senders.values() {
-            revision_content_sender.send(None);
+        for (title, section_revisions) in pending_revisions {
+            for section_revision in keep_corroborated_changes(
+                &self.merger, section_revisions, self.min_corroborating_reverts) {
+                if capture {
+                    let (ref clean_content, ref vandalized_content, ref revision) =
+                        section_revision;
+                    captured_revisions.push(CapturedRevision::new(
+                        &title, clean_content, vandalized_content, revision));
+                }
+                let (clean_content, vandalized_content, revision) = section_revision;
+                merge_jobs.get_mut(&title).unwrap().add_revision(
+                    clean_content, vandalized_content, revision);
+            }
+        }
+        for (_, merge_job) in merge_jobs {
+            merge_job.submit();
         }
 
-        Ok(())
+        Ok(captured_revisions)
     }
 
-    fn get_page_with_vandalism_restored(&self, title: &str) -> Result<String, String> {
-        let page = Page::new(title, self.wiki.clone());
+    /// Returns a multipart/mixed response containing both the current page `title` and
+    /// `restored_page_contents`, the same page with vandalism restored.
+    fn get_multipart_response(&self, title: &str, restored_page_contents: String) -> Response {
+        match self.wiki.get_current_page_content(title) {
+            Ok(clean_page_contents) => {
+                let boundary = format!("wmw-boundary-{}", rand::random::<u64>());
+                let mut response = Response::with(
+                    (iron::status::Ok,
+                     format_multipart(&boundary, &[("clean", &clean_page_contents),
+                                                   ("restored", &restored_page_contents)])));
+                response.headers.set_raw(
+                    "Content-Type",
+                    vec![format!("multipart/mixed; boundary={}", boundary).into_bytes()]);
+                response
+            },
+            Err(msg) => {
+                warn!("Failed to get current page for multipart response: {}", msg);
+                Response::with(
+                    (iron::status::InternalServerError, "<html><body>ERROR</body></html>"))
+            },
+        }
+    }
 
-        // TODO: This almost surely doesn't need to be an Arc.
-        let canonical_title = Arc::new(try!(self.wiki.get_canonical_title(title)));
+    /// Returns the rendered page `title` with vandalism restored, along with the statistics from
+    /// merging its sections, and the IDs of the revisions its restored content came from. Cached
+    /// pages of the article's latest revision are returned immediately. Uncached ones are turned
+    /// away with `AppError::Overloaded` if in-flight merges are using too much memory, and
+    /// otherwise wait their turn with `merge_limiter`, so that merging expensive pages never holds
+    /// up cheap ones.
+    fn get_page(&self, title: &str, skin: Skin)
+                -> Result<(String, MergeStats, Vec<u64>), AppError> {
+        let canonical_title = try!(self.wiki.get_canonical_title(title));
         info!("Canonical page title for \"{}\" is \"{}\"", title, canonical_title);
+        let latest_revid = try!(self.wiki.get_latest_revision(&canonical_title)).revid;
+        let cache_key = get_page_cache_key(&canonical_title, latest_revid, skin);
+        if let Some(page) = self.get_cached_page(&cache_key) {
+            metrics().increment(Counter::PageCacheHits);
+            return Ok(page);
+        }
+        if self.inflight_bytes.is_over_limit() {
+            warn!("Too much content in flight; turning away request for \"{}\"", title);
+            return Err(AppError::Overloaded);
+        }
+        let _permit = self.merge_limiter.acquire();
+        // Another request may have finished this page while this one was waiting.
+        if let Some(page) = self.get_cached_page(&cache_key) {
+            metrics().increment(Counter::PageCacheHits);
+            return Ok(page);
+        }
+        metrics().increment(Counter::PageCacheMisses);
+        let page = try!(self.get_page_with_vandalism_restored(title, &canonical_title,
+                                                               latest_revid, skin));
+        self.cache_page(&cache_key, &page);
+        if let Some(ref snapshots) = self.snapshots {
+            if let Err(msg) = snapshots.save(&get_snapshot_key(title, skin), &page.0) {
+                warn!("Failed to save snapshot of \"{}\": {}", title, msg);
+            }
+        }
+        Ok(page)
+    }
 
-        let latest_revision = try!(self.wiki.get_latest_revision(&canonical_title));
-        let latest_revision_content =
-                try!(self.wiki.get_revision_content(&canonical_title, latest_revision.revid));
-        let latest_revision_sections =
-            deduplicate_section_titles(wiki::parse_sections(&latest_revision_content));
+    /// Returns the page cached in `page_cache` under `key`, unless it has expired.
+    fn get_cached_page(&self, key: &str) -> Option<(String, MergeStats, Vec<u64>)> {
+        self.page_cache.get(key)
+            .and_then(|(cached_page, expiry_ns)| {
+                if time::precise_time_ns() < expiry_ns { Some(cached_page) } else { None }
+            })
+    }
 
-        let (revision_content_senders, merged_content_receivers) =
-            self.spawn_merge_threads(title, latest_revision_sections.clone());
-        let antivandalism_revisions = try!(self.get_antivandalism_revisions(&canonical_title));
+    /// Caches `page` in `page_cache` under `key`, for `page_cache_ttl_seconds`.
+    fn cache_page(&self, key: &str, page: &(String, MergeStats, Vec<u64>)) {
+        let expiry_ns = time::precise_time_ns() + self.page_cache_ttl_seconds * 1_000_000_000;
+        self.page_cache.insert(key, (page.clone(), expiry_ns));
+    }
 
-        let _timer = Timer::new(format!("Fetched and merged {} revisions of \"{}\"",
-                                        (&antivandalism_revisions).len(), title));
-        try!(self.fetch_revisions_content(
-            (*canonical_title).clone(), antivandalism_revisions, revision_content_senders));
-        // TODO: get this working, instead of the for loop below
-        //let merged_article =
-        //    latest_revision_sections.into_iter().map(
-        //        |section_title, _|
-        //        merged_content_receivers.get(&section_title).unwrap().1.recv().unwrap())
-        //    .join("");
-        let mut merged_article = String::new();
-        for (section_title, _) in latest_revision_sections {
-            let merged_section =
-                merged_content_receivers.get(&section_title).unwrap().recv().unwrap();
-            merged_article.push_str(&merged_section);
+    /// Returns the wiki's response to the request for `request_url`, passed through to it.
+    // TODO: should I use an HTTP redirect here instead? Would that work? Would it be desirable?
+    fn get_passthrough_response(&self, request_url: &iron::Url) -> Response {
+        let url = get_passthrough_url(request_url, &self.wiki);
+        match self.client.get(&url).header(Connection::close()).send() {
+            Ok(mut wikipedia_response) => {
+                let mut wikipedia_body: Vec<u8> = Vec::new();
+                match wikipedia_response.read_to_end(&mut wikipedia_body) {
+                    Ok(..) => {
+                        info!("Received {} response from {}", wikipedia_response.status, url);
+                        let mut response = Response::with(wikipedia_body);
+                        response.status = Some(wikipedia_response.status);
+                        response.headers = wikipedia_response.headers.clone();
+                        response
+                    },
+                    Err(error) => {
+                        warn!("Error reading Wikipedia response: {}", error);
+                        let mut response = Response::with(
+                            (iron::status::InternalServerError, "<html><body>ERROR</body></html>"));
+                        response.headers.set(
+                            ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![])));
+                        response
+                    }
+                }
+            },
+            Err(error) => {
+                warn!("Error reading URL {}: {}", url, error);
+                let mut response = Response::with(
+                    (iron::status::InternalServerError, "<html><body>ERROR: {}</body></html>"));
+                response.headers.set(ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![])));
+                response
+            }
+        }
+    }
+
+    /// Returns a response serving the snapshot of the page `title`, in `skin`, marked as stale, if
+    /// getting the page failed with `error` because the wiki can't be reached, and there's a
+    /// snapshot of it. Otherwise returns None.
+    fn get_stale_snapshot_response(&self, title: &str, skin: Skin, error: &AppError)
+                                   -> Option<Response> {
+        let snapshots = match (error, &self.snapshots) {
+            (&AppError::Upstream(_), &Some(ref snapshots)) => snapshots,
+            _ => return None,
+        };
+        // Upstream errors also come from API calls that failed, or returned garbage, while the
+        // wiki was up. Only an outage is worth serving stale pages for.
+        if self.wiki.is_reachable() {
+            return None;
+        }
+        snapshots.load(&get_snapshot_key(title, skin)).map(|snapshot| {
+            info!("Wiki is unreachable; serving snapshot of \"{}\" from {}", title,
+                  snapshot.saved_at);
+            let mut response = Response::with((iron::status::Ok, snapshot.to_stale_page()));
+            response.headers.set_raw("Warning", vec![b"110 - \"Response is Stale\"".to_vec()]);
+            response
+        })
+    }
+
+    /// Returns the rendered page `title` (whose canonical title is `canonical_title`, and whose
+    /// latest revision is `latest_revid`), in `skin`, with vandalism restored, along with the
+    /// statistics from merging its sections, and the IDs of the revisions its restored content came
+    /// from.
+    fn get_page_with_vandalism_restored(&self, title: &str, canonical_title: &str,
+                                        latest_revid: u64, skin: Skin)
+                                        -> Result<(String, MergeStats, Vec<u64>), AppError> {
+        let skeleton_wiki = match (skin, &self.mobile_wiki) {
+            (Skin::Mobile, &Some(ref mobile_wiki)) => mobile_wiki.clone(),
+            _ => self.wiki.clone(),
+        };
+        // The skeleton is fetched alongside the merge.
+        let page = Page::new(title, latest_revid, skeleton_wiki, skin,
+                             self.render_options.clone(), &self.skeleton_fetches);
+
+        let latest_revision_sections = try!(
+            self.get_revision_sections(canonical_title, latest_revid).map_err(AppError::Upstream));
+        if is_blank_article(&latest_revision_sections) {
+            // Merging into an empty article would just render a blank page, so serve the real page
+            // instead.
+            info!("\"{}\" has no content to merge into; serving it unmodified", canonical_title);
+            return Ok((try!(self.wiki.get_current_page_content(canonical_title)
+                                .map_err(AppError::Upstream)),
+                       MergeStats::default(), vec![]));
+        }
+
+        let capture = self.capture_dir.is_some() && self.capture_titles.contains(title);
+        let captured_sections =
+            if capture { latest_revision_sections.clone() } else { Vec::new() };
+        let unmerged_sections =
+            if self.validate_render { latest_revision_sections.clone() } else { Vec::new() };
+        let (mut merged_article, reverting_users, captured_revisions) = try!(
+            self.merge_antivandalism_revisions(title, canonical_title, latest_revision_sections,
+                                               capture)
+                .map_err(AppError::Upstream));
+        let merged_at = time::now_utc();
+        for section in get_low_quality_sections(&merged_article, self.min_section_quality) {
+            warn!("Merges into section \"{}\" of \"{}\" scored {:.2} ({} conflicts)", section.title,
+                  canonical_title, section.stats.quality(), section.stats.conflicts);
+            metrics().increment(Counter::LowQualitySections);
+        }
+
+        let mut merged_wikitext = strip_markers_from_headings(&merged_article.to_wikitext());
+        if let (true, &Some(ref capture_dir)) = (capture, &self.capture_dir) {
+            let captured_merge = Capture {
+                title: title.to_string(),
+                latest_sections: captured_sections,
+                revisions: captured_revisions,
+                merged_wikitext: merged_wikitext.clone(),
+            };
+            match captured_merge.write_to_dir(capture_dir) {
+                Ok(path) => info!("Captured merge of \"{}\" to {}", title, path),
+                Err(msg) => warn!("Failed to capture merge of \"{}\": {}", title, msg),
+            }
+        }
+        let mut article_body = try!(self.wiki.parse_wikitext(canonical_title, &merged_wikitext)
+                                        .map_err(AppError::Upstream));
+        if self.validate_render && count_render_errors(&article_body) > 0 {
+            let reverted = try!(revert_sections_with_render_errors(
+                &mut merged_article.sections, &unmerged_sections,
+                |wikitext| self.wiki.parse_wikitext(canonical_title, wikitext))
+                .map_err(AppError::Upstream));
+            if reverted {
+                merged_wikitext = strip_markers_from_headings(&merged_article.to_wikitext());
+                article_body = try!(self.wiki.parse_wikitext(canonical_title, &merged_wikitext)
+                                        .map_err(AppError::Upstream));
+            }
+        }
+        let mut restored_revids = get_restored_revids(&merged_wikitext);
+
+        let _marker_timer = Timer::new("mangle_html", "Mangled HTML".to_string());
+        let page_contents =
+            try!(page.replace_body_and_remove_merge_markers(article_body, &reverting_users,
+                                                            &merged_at));
+        drop(_marker_timer);
+
+        let page_contents = try!(limit_response_size(page_contents, self.max_response_bytes, || {
+            warn!("Merged page \"{}\" is over {} bytes; serving it unmodified", canonical_title,
+                  self.max_response_bytes);
+            restored_revids.clear();
+            self.wiki.get_current_page_content(canonical_title)
+        }).map_err(AppError::Upstream));
+        Ok((page_contents, merged_article.stats(), restored_revids))
+    }
+
+    /// Returns the response to a health check: 200 if the server is up, unless
+    /// `healthz_checks_upstream` is set and the wiki's API or Redis can't be reached, in which case
+    /// 503. Either way, the body is a small JSON object saying which.
+    fn get_healthz_response(&self) -> Response {
+        let health = if self.healthz_checks_upstream { self.wiki.check_health() } else { Ok(()) };
+        let mut response = match health {
+            Ok(()) => Response::with((iron::status::Ok, "{\"status\":\"ok\"}")),
+            Err(msg) => {
+                warn!("Health check failed: {}", msg);
+                Response::with(
+                    (iron::status::ServiceUnavailable,
+                     format!("{{\"status\":\"unavailable\",\"error\":{}}}",
+                             rustc_serialize::json::Json::String(msg))))
+            },
+        };
+        response.headers.set(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])));
+        response
+    }
+
+    /// Returns the review queue for the page `title`: the content that reverts removed from it,
+    /// and that merges cleanly into its latest revision, listed as suggested edits rather than
+    /// restored. It's never cached, but waits its turn with `merge_limiter` like any other merge,
+    /// and is turned away with `AppError::Overloaded` if in-flight merges are using too much
+    /// memory.
+    fn get_review_page(&self, title: &str) -> Result<String, AppError> {
+        let canonical_title = try!(self.wiki.get_canonical_title(title));
+        if self.inflight_bytes.is_over_limit() {
+            return Err(AppError::Overloaded);
+        }
+        Ok(review::format_review_page(
+            &canonical_title, &try!(self.get_suggested_edits(title, &canonical_title))))
+    }
+
+    /// Returns the response to a request for the review queue (?view=review) of the page `title`.
+    fn get_review_response(&self, title: &str) -> Response {
+        match self.get_review_page(title) {
+            Ok(review_page) => {
+                let mut response = Response::with((iron::status::Ok, review_page));
+                response.headers.set(ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![])));
+                response
+            },
+            Err(error) => {
+                warn!("Failed to get review page of \"{}\": {}", title, error);
+                get_error_response(&error)
+            },
+        }
+    }
+
+    /// Merges the page `title` (whose canonical title is `canonical_title`), and returns the
+    /// content restored in it.
+    fn get_suggested_edits(&self, title: &str, canonical_title: &str)
+                           -> Result<Vec<SuggestedEdit>, AppError> {
+        let latest_revision_sections = try!(self.get_latest_revision_sections(canonical_title));
+        let _permit = self.merge_limiter.acquire();
+        let (merged_article, reverting_users, _) = try!(self.merge_antivandalism_revisions(
+            title, canonical_title, latest_revision_sections, false).map_err(AppError::Upstream));
+        Ok(review::get_suggested_edits(&merged_article, &reverting_users))
+    }
+
+    /// Waits until the page `title` has a revision other than `since_revid`, then merges it, and
+    /// returns the changes to its restored content since the merge of `since_revid`. If
+    /// `since_revid` is None, returns all of the content restored in the latest revision without
+    /// waiting. Returns None if there's no new revision within the timeout, and
+    /// `AppError::Overloaded` if too many live update requests are already open, or in-flight
+    /// merges are using too much memory.
+    fn get_live_update(&self, title: &str, since_revid: Option<u64>)
+                       -> Result<Option<LiveUpdate>, AppError> {
+        let _permit = match self.live_request_limiter.try_acquire() {
+            Some(permit) => permit,
+            None => return Err(AppError::Overloaded),
+        };
+        let canonical_title = try!(self.wiki.get_canonical_title(title));
+        if let Some(since_revid) = since_revid {
+            let new_revision = self.revision_watcher.subscribe(
+                &canonical_title, since_revid, self.live_timeout_ms);
+            if new_revision.recv().is_err() {
+                return Ok(None);
+            }
+        }
+        if self.inflight_bytes.is_over_limit() {
+            return Err(AppError::Overloaded);
+        }
+        let revid = try!(self.wiki.get_latest_revision(&canonical_title)).revid;
+        let suggested_edits = try!(self.get_suggested_edits(title, &canonical_title));
+        // If the merge of `since_revid` isn't the one remembered (e.g. another request has since
+        // moved on, or it's been evicted), everything restored is sent as new.
+        let previous_edits = match self.live_restored_content.get(&canonical_title) {
+            Some((previous_revid, previous_edits)) if Some(previous_revid) == since_revid => {
+                previous_edits
+            },
+            _ => vec![],
+        };
+        let update = live::diff_restored_content(revid, &previous_edits, &suggested_edits);
+        self.live_restored_content.insert(&canonical_title, (revid, suggested_edits));
+        Ok(Some(update))
+    }
+
+    /// Returns the response to a live update request (?view=live) for the page `title`, whose
+    /// query string is `query` (see `get_live_update()`).
+    fn get_live_response(&self, title: &str, query: &Option<String>) -> Response {
+        let since_revid =
+            get_query_parameter(query, "since").and_then(|since_revid| since_revid.parse().ok());
+        let mut response = match self.get_live_update(title, since_revid) {
+            Ok(Some(update)) => Response::with((iron::status::Ok, update.to_json())),
+            // The client is expected to poll again.
+            Ok(None) => Response::with(iron::status::NoContent),
+            Err(error) => {
+                warn!("Failed to get live update of \"{}\": {}", title, error);
+                return get_error_response(&error);
+            },
+        };
+        response.headers.set(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])));
+        response
+    }
+
+    /// Returns the (section title, section content) pairs of the latest revision of the page
+    /// `canonical_title`, with the section titles deduplicated.
+    fn get_latest_revision_sections(&self, canonical_title: &str)
+                                    -> Result<Vec<(String, String)>, AppError> {
+        let latest_revision = try!(self.wiki.get_latest_revision(canonical_title));
+        self.get_revision_sections(canonical_title, latest_revision.revid)
+            .map_err(AppError::Upstream)
+    }
+
+    /// Returns the (section title, section content) pairs of the revision `revid` of the page
+    /// `canonical_title`, with the section titles deduplicated.
+    fn get_revision_sections(&self, canonical_title: &str, revid: u64)
+                             -> Result<Vec<(String, String)>, String> {
+        let revision_content = try!(self.wiki.get_revision_content(canonical_title, revid));
+        Ok(deduplicate_section_titles(wiki::parse_sections(&remove_markers(&revision_content))))
+    }
+
+    /// Merges the vandalism reverted in the history of the page `canonical_title` (and of the
+    /// other pages its reverts are looked for in) into `latest_revision_sections`. Returns the
+    /// merged article; a map from the ID of each antivandalism revision to the user who made it;
+    /// and, if `capture` is true, the revisions merged (see `fetch_revisions_content()`).
+    fn merge_antivandalism_revisions(&self, title: &str, canonical_title: &str,
+                                     latest_revision_sections: Vec<(String, String)>,
+                                     capture: bool)
+                                     -> Result<(MergedArticle, HashMap<u64, String>,
+                                                Vec<CapturedRevision>), String> {
+        let mut antivandalism_revisions = Vec::new();
+        let mut source_titles =
+            get_revision_source_titles(&canonical_title, self.include_talk_page_reverts);
+        if self.follow_moves {
+            match self.wiki.get_draft_origin_title(&canonical_title) {
+                Ok(Some(draft_title)) => {
+                    info!("\"{}\" was moved from \"{}\"", canonical_title, draft_title);
+                    source_titles.push(draft_title);
+                },
+                Ok(None) => (),
+                Err(msg) => warn!("Failed to get moves to \"{}\": {}", canonical_title, msg),
+            }
+        }
+        for (i, source_title) in source_titles.into_iter().enumerate() {
+            let revisions = if i == 0 {
+                try!(self.get_antivandalism_revisions(&source_title))
+            } else {
+                // The other pages are optional (e.g. the talk page might not exist), so
+                // failing to get their revisions isn't fatal.
+                match self.get_antivandalism_revisions(&source_title) {
+                    Ok(revisions) => revisions,
+                    Err(msg) => {
+                        warn!("Failed to get revisions of \"{}\": {}", source_title, msg);
+                        Vec::new()
+                    },
+                }
+            };
+            antivandalism_revisions.extend(
+                revisions.into_iter().map(|revision| (source_title.clone(), revision)));
         }
+
+        let reverting_users = antivandalism_revisions.iter()
+            .map(|&(_, ref revision)| (revision.revid, revision.user.clone()))
+            .collect::<HashMap<_, _>>();
+        let _timer = Timer::new("fetch_and_merge_revisions",
+                                format!("Fetched and merged {} revisions of \"{}\"",
+                                        (&antivandalism_revisions).len(), title));
+        let (selected_sections, unselected_sections) =
+            select_sections(&latest_revision_sections, &self.section_selector);
+        // Captures need every section's revisions, so nothing is reused for them.
+        let (mut cached_sections, changed_sections) = if capture {
+            (HashMap::new(), selected_sections)
+        } else {
+            self.get_cached_sections(canonical_title, &selected_sections, &antivandalism_revisions)
+        };
+        cached_sections.extend(unselected_sections);
+        let fingerprints = changed_sections.iter()
+            .map(|&(ref section_title, ref section_content)| {
+                (section_title.clone(),
+                 get_section_fingerprint(section_content, &antivandalism_revisions))
+            })
+            .collect::<HashMap<_, _>>();
+        let (merge_jobs, merged_content_receivers) =
+            self.start_merge_jobs(title, changed_sections);
+        let captured_revisions = if merge_jobs.is_empty() {
+            info!("Reused all {} merged sections of \"{}\"", cached_sections.len(), title);
+            Vec::new()
+        } else {
+            try!(self.fetch_revisions_content(
+                canonical_title.to_string(), antivandalism_revisions, merge_jobs, capture))
+        };
+        let merged_article = MergedArticle {
+            sections: latest_revision_sections.into_iter().map(|(section_title, _)| {
+                let (content, stats) = match cached_sections.remove(&section_title) {
+                    Some(merged_section) => merged_section,
+                    None => {
+                        let merged_section =
+                            merged_content_receivers.get(&section_title).unwrap().recv().unwrap();
+                        self.cache_merged_section(canonical_title, &section_title,
+                                                  fingerprints[&section_title], &merged_section);
+                        merged_section
+                    },
+                };
+                MergedSection { title: section_title, content: content, stats: stats }
+            }).collect(),
+        };
         drop(_timer);
+        Ok((merged_article, reverting_users, captured_revisions))
+    }
 
-        let article_body = try!(self.wiki.parse_wikitext(&canonical_title, &merged_article));
+    /// Caches `merged_section`, the (merged content, statistics) of the section `section_title` of
+    /// the page `canonical_title`, merged from what `fingerprint` identifies. Merges that timed out
+    /// aren't cached, since they may well finish next time, when the wiki or server is less busy.
+    fn cache_merged_section(&self, canonical_title: &str, section_title: &str, fingerprint: u64,
+                            merged_section: &(String, MergeStats)) {
+        let (ref content, ref stats) = *merged_section;
+        if stats.timeouts > 0 {
+            return;
+        }
+        self.section_cache.insert(&get_section_cache_key(canonical_title, section_title),
+                                  (fingerprint, content.clone(), stats.clone()));
+    }
 
-        let _marker_timer = Timer::new("Mangled HTML".to_string());
-        page.replace_body_and_remove_merge_markers(article_body)
+    /// Splits `sections` (the (section title, content) pairs of the latest revision of the page
+    /// `canonical_title`) into those whose merges into `revisions` are in `section_cache`, returned
+    /// as a map from section title to (merged content, statistics), and those that need merging.
+    fn get_cached_sections(&self, canonical_title: &str, sections: &[(String, String)],
+                           revisions: &[(String, Revision)])
+                           -> (HashMap<String, (String, MergeStats)>, Vec<(String, String)>) {
+        let mut cached_sections = HashMap::new();
+        let mut changed_sections = Vec::new();
+        for &(ref section_title, ref section_content) in sections {
+            let fingerprint = get_section_fingerprint(section_content, revisions);
+            match self.section_cache.get(&get_section_cache_key(canonical_title, section_title)) {
+                Some((cached_fingerprint, content, stats)) if cached_fingerprint == fingerprint => {
+                    cached_sections.insert(section_title.clone(), (content, stats));
+                },
+                _ => changed_sections.push((section_title.clone(), section_content.clone())),
+            }
+        }
+        (cached_sections, changed_sections)
     }
 
-    /// Spawns a single merge thread. The thread starts with `section_content`, accepts (clean
-    /// content, candalized content, revision ID) tuples over an MPSC channel, and merges each into
-    /// the accumulated content to the extent possible. When the thread receives None over its input
-    /// channel, it sends the merged content over another MPSC channel.
+    /// Creates a single merge job, which starts with `section_content`, and merges each
+    /// (clean content, vandalized content, revision) tuple added to it into the accumulated content
+    /// to the extent possible. The job only goes to a worker from `workers` once it's submitted,
+    /// with all its revisions, so that no worker is held waiting for its input. When it's done, it
+    /// sends the merged content, and the statistics from merging it, over an MPSC channel.
     ///
-    /// The return value is the tuple (the sender for the input channel, the receiver for the output
-    /// channel).
-    fn spawn_merge_thread(&self, title: &str, section_title: String, section_content: String) ->
-        (Sender<Option<(String, String, u64)>>, Receiver<String>) {
-            let (in_sender, in_receiver) = channel::<Option<(String, String, u64)>>();
-            let (out_sender, out_receiver) = channel::<String>();
-            // TODO: delete
-            let section_t = section_title.clone();
-            let merger = self.merger.clone();
-            let max_consecutive_diff_timeouts = self.max_consecutive_diff_timeouts;
-            thread::Builder::new().name(format!("merge-{}-{}", title, section_title)).spawn(move|| {
-                let mut merged_content = section_content;
-                // As you go backward in time, pages get different enough that they can't be quickly
-                // diffed against the current version of the page, and trying to do so is a waste of
-                // 500ms per revision. To avoid that, we stop trying to merge after seeing (by
-                // default) 3 timeouts in a row.
-                let mut consecutive_timeouts = 0;
-                let _timer = Timer::new(format!("Merged all revisions of \"{}\"", section_t));
-                loop {
-                    match in_receiver.recv() {
-                        Ok(Some((clean_content, vandalized_content, revision_id))) => {
-                            if consecutive_timeouts < max_consecutive_diff_timeouts {
-                                let (merge_result, timed_out) = merger.try_merge(
-                                    &clean_content, &merged_content, &vandalized_content,
-                                    &revision_id.to_string());
-                                merged_content = merge_result;
-                                if timed_out {
-                                    consecutive_timeouts += 1;
-                                } else {
-                                    consecutive_timeouts = 0;
-                                }
-                            }
-                        },
-                        Ok(None) => {
-                            out_sender.send(merged_content);
-                            drop(_timer);
-                            break;
-                        },
-                        Err(err) => panic!("Failed to receive from in_receiver: {}", err),
-                    }
-                }
-            });
-            (in_sender, out_receiver)
+    /// The return value is the tuple (the job, the receiver for the output channel).
+    fn start_merge_job(&self, title: &str, section_title: String, section_content: String)
+                       -> (MergeJob, Receiver<(String, MergeStats)>) {
+        let (out_sender, out_receiver) = channel::<(String, MergeStats)>();
+        let job = MergeJob {
+            page_title: title.to_string(),
+            section_reservation: self.inflight_bytes.reserve(section_content.len()),
+            section_title: section_title,
+            section_merger: SectionMerger::new(self.merger.clone(), self.section_merge_options(),
+                                               section_content),
+            revisions: Vec::new(),
+            inflight_bytes: self.inflight_bytes.clone(),
+            event_logger: self.event_logger.clone(),
+            workers: self.workers.clone(),
+            out_sender: out_sender,
+        };
+        (job, out_receiver)
+    }
+
+    fn section_merge_options(&self) -> SectionMergeOptions {
+        SectionMergeOptions {
+            max_consecutive_diff_timeouts: self.max_consecutive_diff_timeouts,
+            escalate_granularity: self.escalate_granularity,
+            exclude_infoboxes: self.exclude_infoboxes,
+            skip_reference_lists: self.skip_reference_lists,
+            maintenance_templates: self.maintenance_templates.clone(),
         }
+    }
 
-    /// Given a list of (section title, section content) pairs, spawns one merge thread for each
-    /// section, described in the documentatino on `spawn_merge_thread()`.
+    /// Given a list of (section title, section content) pairs, creates one merge job for each
+    /// section, described in the documentation on `start_merge_job()`.
     ///
-    /// The return value is a 2-tuple of HashMaps. The first maps from the section title to the Sender
-    /// for that section's thread's input channel, and the second maps from the section title to the
-    /// Receiver for that section's thread's output channel.
-    fn spawn_merge_threads<I>(&self, title: &str, sections: I) ->
-        (HashMap<String, Sender<Option<(String, String, u64)>>>, HashMap<String, Receiver<String>>)
+    /// The return value is a 2-tuple of HashMaps. The first maps from the section title to that
+    /// section's job, and the second maps from the section title to the Receiver for that
+    /// section's job's output channel.
+    fn start_merge_jobs<I>(&self, title: &str, sections: I) ->
+        (HashMap<String, MergeJob>, HashMap<String, Receiver<(String, MergeStats)>>)
         where I: IntoIterator<Item=(String, String)> {
-            let mut senders_map = HashMap::new();
+            let mut jobs_map = HashMap::new();
             let mut receivers_map = HashMap::new();
             for (section_title, section_content) in sections.into_iter() {
-                let (in_sender, out_receiver) =
-                    self.spawn_merge_thread(title, section_title.clone(), section_content);
-                senders_map.insert(section_title.clone(), in_sender);
+                let (job, out_receiver) =
+                    self.start_merge_job(title, section_title.clone(), section_content);
+                jobs_map.insert(section_title.clone(), job);
                 receivers_map.insert(section_title, out_receiver);
             }
-            (senders_map, receivers_map)
+            (jobs_map, receivers_map)
+}
+}
+
+/// A merge of revisions into one section of a page, waiting for its revisions (see
+/// `WikipediaMinusWikipediansHandler::start_merge_job()`).
+struct MergeJob {
+    /// The title of the page the section is in.
+    page_title: String,
+    section_title: String,
+    section_merger: SectionMerger,
+    /// The (clean content, vandalized content, revision) tuples to merge, in order, each with the
+    /// reservation of its bytes.
+    revisions: Vec<((String, String, Revision), MemoryReservation)>,
+    section_reservation: MemoryReservation,
+    inflight_bytes: MemoryAccountant,
+    event_logger: EventLogger,
+    workers: WorkerPool,
+    out_sender: Sender<(String, MergeStats)>,
+}
+
+impl MergeJob {
+    /// Adds the revision `revision`, which changed the section from `vandalized_content` back to
+    /// `clean_content`, to the revisions to merge.
+    fn add_revision(&mut self, clean_content: String, vandalized_content: String,
+                    revision: Revision) {
+        let reservation =
+            self.inflight_bytes.reserve(clean_content.len() + vandalized_content.len());
+        self.revisions.push(((clean_content, vandalized_content, revision), reservation));
+    }
+
+    /// Queues the job to run on the next free worker, which merges each of its revisions in turn,
+    /// and then sends the result.
+    fn submit(self) {
+        let workers = self.workers.clone();
+        workers.execute(move|| {
+            let MergeJob { page_title, section_title, mut section_merger, revisions,
+                           section_reservation, event_logger, out_sender, .. } = self;
+            let _timer = Timer::new(
+                "merge_section", format!("Merged all revisions of \"{}\"", section_title));
+            let _section_reservation = section_reservation;
+            for ((clean_content, vandalized_content, revision), _revision_reservation) in
+                revisions {
+                let restored_bytes =
+                    section_merger.merge(clean_content, vandalized_content, revision.revid);
+                if restored_bytes > 0 {
+                    event_logger.log(RestorationEvent {
+                        title: page_title.clone(),
+                        revid: revision.revid,
+                        user: revision.user,
+                        restored_bytes: restored_bytes,
+                        timestamp: format!("{}", time::now_utc().rfc3339()),
+                    });
+                }
+            }
+            // The request may have failed, and stopped waiting for the section.
+            let _ = out_sender.send(section_merger.finish());
+        });
+    }
+}
+
+/// Settings that control how revisions are merged into a section. See the
+/// WikipediaMinusWikipediansHandler fields of the same names.
+#[derive(Clone)]
+struct SectionMergeOptions {
+    max_consecutive_diff_timeouts: u64,
+    escalate_granularity: bool,
+    exclude_infoboxes: bool,
+    skip_reference_lists: bool,
+    maintenance_templates: Option<Arc<Vec<String>>>,
+}
+
+/// Merges revisions, one at a time, into one section of the latest revision of a page.
+struct SectionMerger {
+    merger: Merger,
+    options: SectionMergeOptions,
+    merged_content: String,
+    /// As you go backward in time, pages get different enough that they can't be quickly diffed
+    /// against the current version of the page, and trying to do so is a waste of 500ms per
+    /// revision. To avoid that, we stop trying to merge after seeing (by default) 3 timeouts in a
+    /// row.
+    consecutive_timeouts: u64,
+    stats: MergeStats,
+}
+
+impl SectionMerger {
+    fn new(merger: Merger, options: SectionMergeOptions, section_content: String)
+           -> SectionMerger {
+        SectionMerger {
+            merger: merger,
+            options: options,
+            merged_content: section_content,
+            consecutive_timeouts: 0,
+            stats: MergeStats::default(),
+        }
+    }
+
+    /// Merges the vandalism reverted by revision `revid` (the change from `vandalized_content` back
+    /// to `clean_content`) into the section. Returns the number of bytes of vandalism restored.
+    fn merge(&mut self, clean_content: String, vandalized_content: String, revid: u64) -> u64 {
+        if self.consecutive_timeouts >= self.options.max_consecutive_diff_timeouts {
+            return 0;
+        }
+        // The reference list is generated from the article's citations, and is reordered whenever
+        // they change, so diffs of it are almost all noise.
+        if self.options.skip_reference_lists && is_reference_list(&self.merged_content) {
+            return 0;
+        }
+        let (clean_content, vandalized_content) = if self.options.exclude_infoboxes {
+            remove_excluded_templates(clean_content, vandalized_content)
+        } else {
+            (clean_content, vandalized_content)
+        };
+        // Like excluded templates, maintenance templates are removed from both sides of the diff,
+        // so the current page's tags are left as they are.
+        let (clean_content, vandalized_content) = match self.options.maintenance_templates {
+            Some(ref names) => (wiki::remove_named_templates(&clean_content, names),
+                                wiki::remove_named_templates(&vandalized_content, names)),
+            None => (clean_content, vandalized_content),
+        };
+        let previously_restored_bytes = self.stats.restored_bytes;
+        metrics().increment(Counter::MergesAttempted);
+        let (merge_result, timed_out) = if self.options.escalate_granularity {
+            self.merger.try_merge_escalating(
+                &clean_content, &self.merged_content, &vandalized_content, &revid.to_string(),
+                &mut self.stats)
+        } else {
+            self.merger.try_merge_with_stats(
+                &clean_content, &self.merged_content, &vandalized_content, &revid.to_string(),
+                &mut self.stats)
+        };
+        self.merged_content = merge_result;
+        if timed_out {
+            metrics().increment(Counter::DiffTimeouts);
+            self.consecutive_timeouts += 1;
+        } else {
+            self.consecutive_timeouts = 0;
+            self.stats.sections_merged = 1;
+        }
+        self.stats.restored_bytes - previously_restored_bytes
+    }
+
+    /// Returns the merged section content, and the statistics from merging it.
+    fn finish(self) -> (String, MergeStats) {
+        (self.merged_content, self.stats)
+    }
 }
+
+/// Re-runs the merge recorded in `capture`, of the sections `section_selector` matches (see
+/// `select_sections()`), and returns the merged wikitext.
+fn replay_capture(merger: &Merger, options: &SectionMergeOptions,
+                  section_selector: &Option<Regex>, capture: &Capture) -> String {
+    let (_, mut unselected_sections) = select_sections(&capture.latest_sections, section_selector);
+    let merged_article = MergedArticle {
+        sections: capture.latest_sections.iter().map(|&(ref section_title, ref section_content)| {
+            let (content, stats) = match unselected_sections.remove(section_title) {
+                Some(unselected_section) => unselected_section,
+                None => {
+                    let mut section_merger = SectionMerger::new(
+                        merger.clone(), options.clone(), section_content.clone());
+                    for revision in capture.revisions.iter()
+                        .filter(|revision| revision.section_title == *section_title) {
+                        section_merger.merge(revision.clean_content.clone(),
+                                             revision.vandalized_content.clone(), revision.revid);
+                    }
+                    section_merger.finish()
+                },
+            };
+            MergedSection { title: section_title.clone(), content: content, stats: stats }
+        }).collect(),
+    };
+    strip_markers_from_headings(&merged_article.to_wikitext())
 }
 
 /// A Wikipedia article can have duplicate section titles (for example, as of this writing,
@@ -314,142 +1162,2124 @@ fn deduplicate_section_titles<I>(mut sections: I) -> Vec<(String, String)>
     deduplicated_sections
 }
 
-impl Handler for WikipediaMinusWikipediansHandler {
-    fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        if request.url.path.len() == 2 && request.url.path[0] == "wiki" {
-            let _timer = Timer::new(format!("Served request for /wiki/{}", request.url.path[1]));
-            let mut response =
-                match self.get_page_with_vandalism_restored(&request.url.path[1]) {
-                    Ok(page_contents) => Response::with((iron::status::Ok, page_contents)),
-                    // TODO: create an Error type to pass around, so this can distinguish different
-                    // types of error (if that would be helpful).
-                    // TODO: create a better error page
-                    Err(msg) => {
-                        warn!("Failed to get page with vandalism restored: {}", msg);
-                        Response::with(
-                            (iron::status::InternalServerError, "<html><body>ERROR</body></html>"))
-                    },
-                };
-            response.headers.set(ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![])));
-            Ok(response)
+/// Given the (clean content, vandalized content, revision) tuples for a section, undoes each change
+/// from clean to vandalized content unless at least `min_reverts` different users reverted the same
+/// change (i.e. removed the same content). Revisions whose changes can't be found (because the diff
+/// timed out) are left out.
+fn keep_corroborated_changes(merger: &Merger, revisions: Vec<(String, String, Revision)>,
+                             min_reverts: u64) -> Vec<(String, String, Revision)> {
+    // Identifies a change by its vandalized content or, for changes that only removed content, by
+    // the removed content.
+    fn change_key(clean_content: &str, vandalized_content: &str, change: &Change)
+                  -> (String, String) {
+        let vandalized_text = vandalized_content[change.other_range.clone()].trim();
+        let clean_text = if vandalized_text.is_empty() {
+            clean_content[change.old_range.clone()].trim()
         } else {
-            // TODO: should I use an HTTP redirect here instead? Would that work? Would it be desirable?
-            // TODO: Maybe should be moved to wiki module.
-            let mut url = request.url.clone();
-            url.scheme = "https".to_string();
-            url.host = url::Host::Domain(self.wiki.hostname.clone());
-            url.port = self.wiki.port;
-            let url = url.into_generic_url().serialize();
-            match self.client.get(&url)
-                .header(Connection::close()).send() {
-                    Ok(mut wikipedia_response) => {
-                        let mut wikipedia_body: Vec<u8> = Vec::new();
-                        match wikipedia_response.read_to_end(&mut wikipedia_body) {
-                            Ok(..) => {
-                                info!("Received {} response from {}", wikipedia_response.status,
-                                      url);
-                                let mut response = Response::with(wikipedia_body);
-                                response.status = Some(wikipedia_response.status);
-                                response.headers = wikipedia_response.headers.clone();
-                                Ok(response)
-                            },
-                            Err(error) => {
-                                warn!("Error reading Wikipedia response: {}", error);
-                                let mut response = Response::with(
-                                    (iron::status::InternalServerError,
-                                     "<html><body>ERROR</body></html>"));
-                                response.headers.set(
-                                    ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![])));
-                                Ok(response)
-                            }
-                        }
-                    },
-                    Err(error) => {
-                        warn!("Error reading URL {}: {}", url, error);
-                        let mut response = Response::with(
-                            (iron::status::InternalServerError,
-                             "<html><body>ERROR: {}</body></html>"));
-                        response.headers.set(
-                            ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![])));
-                        Ok(response)
-                    }
-                }
+            ""
+        };
+        (clean_text.to_string(), vandalized_text.to_string())
+    }
+
+    let revisions_changes = revisions.into_iter().filter_map(
+        |(clean_content, vandalized_content, revision)| {
+            match merger.get_changes(&clean_content, &vandalized_content) {
+                Some(changes) => Some((clean_content, vandalized_content, revision, changes)),
+                None => {
+                    info!("Timed out finding the changes in revision {}", revision.revid);
+                    None
+                },
+            }
+        }).collect::<Vec<_>>();
+
+    let mut reverting_users: HashMap<(String, String), HashSet<String>> = HashMap::new();
+    for &(ref clean_content, ref vandalized_content, ref revision, ref changes) in
+        &revisions_changes {
+        for change in changes {
+            reverting_users.entry(change_key(clean_content, vandalized_content, change))
+                .or_insert(HashSet::new()).insert(revision.user.clone());
         }
     }
+
+    revisions_changes.into_iter().map(|(clean_content, vandalized_content, revision, changes)| {
+        let mut filtered_content = String::new();
+        let mut position = 0;
+        for change in changes {
+            let key = change_key(&clean_content, &vandalized_content, &change);
+            filtered_content.push_str(&vandalized_content[position..change.other_range.start]);
+            if reverting_users[&key].len() as u64 >= min_reverts {
+                filtered_content.push_str(&vandalized_content[change.other_range.clone()]);
+            } else {
+                filtered_content.push_str(&clean_content[change.old_range.clone()]);
+            }
+            position = change.other_range.end;
+        }
+        filtered_content.push_str(&vandalized_content[position..]);
+        (clean_content, filtered_content, revision)
+    }).collect()
 }
 
-fn main() {
-    log4rs::init_file("log.toml", Default::default()).unwrap();
+/// Removes EXCLUDED_TEMPLATES from both the clean and the vandalized content of a revision. Since
+/// neither side of the diff then contains those templates, merging leaves the current version of
+/// them untouched, and any vandalism inside them isn't restored.
+fn remove_excluded_templates(clean_content: String, vandalized_content: String)
+                             -> (String, String) {
+    (wiki::remove_templates(&clean_content, &EXCLUDED_TEMPLATES),
+     wiki::remove_templates(&vandalized_content, &EXCLUDED_TEMPLATES))
+}
 
-    let mut port = 3000;
-    let mut wiki = "en.wikipedia.org".to_string();
-    let mut redis_hostname = "".to_string();
-    let mut redis_port = 6379;
-    let mut diff_size_limit = 1000;
-    let mut diff_time_limit_ms = 500;
-    let mut max_consecutive_diff_timeouts = 3;
-    {
-        let mut parser = ArgumentParser::new();
-        parser.set_description("TODO: Usage description");
-        parser.refer(&mut port).add_option(&["-p", "--port"], Store, "The port to serve HTTP on.");
-        parser.refer(&mut wiki).add_option(
-            &["--wiki"], Store, "The hostname or hostname:port of the wiki to mirror.");
-        parser.refer(&mut redis_hostname).add_option(
-            &["--redis_hostname"], Store,
-            "The hostname of the Redis server to use. Leave blank to disable Redis.");
-        parser.refer(&mut redis_port).add_option(
-            &["--redis_port"], Store,
-            "The port of the Redis server to use. Ignored if --redis_hostname is blank.");
-        parser.refer(&mut diff_size_limit).add_option(
-            &["--diff_size_limit"], Store,
-            "The size in bytes at which a diff is considered too big, and is skipped.");
-        parser.refer(&mut diff_time_limit_ms).add_option(
-            &["--diff_time_limit_ms"], Store,
-            "The maximum time (in milliseconds) to attempt to compute a diff before giving up.");
-        parser.refer(&mut max_consecutive_diff_timeouts).add_option(
-            &["--max_consecutive_diff_timeouts"], Store,
-            "The maximum number of consecutive diff-too-large or diff-timeout failures to accept before ceasing to merge a section.");
-        parser.parse_args_or_exit();
+/// Splits `sections` (the (section title, content) pairs of a page) into those whose titles
+/// `selector` matches (or all of them, if it's None), which are merged, and the others, which are
+/// left as they are. The others are returned as a map from section title to (content,
+/// statistics), like merged sections.
+fn select_sections(sections: &[(String, String)], selector: &Option<Regex>)
+                   -> (Vec<(String, String)>, HashMap<String, (String, MergeStats)>) {
+    let mut selected_sections = Vec::new();
+    let mut unselected_sections = HashMap::new();
+    for &(ref section_title, ref section_content) in sections {
+        if selector.as_ref().map_or(true, |selector| selector.is_match(section_title)) {
+            selected_sections.push((section_title.clone(), section_content.clone()));
+        } else {
+            unselected_sections.insert(section_title.clone(),
+                                       (section_content.clone(), MergeStats::default()));
+        }
     }
-    let mut wiki_components = wiki.split(":");
-    let wiki_hostname = wiki_components.next().unwrap();
-    let wiki_port = match wiki_components.next() {
-        Some(port) => port.parse::<u16>().unwrap(),
-        None => 443,
-    };
+    (selected_sections, unselected_sections)
+}
 
-    let redis_connection_info = if redis_hostname == "" {
-        None
+/// Returns whether `section_content` holds an article's reference list, generated by a
+/// <references/> tag or a {{Reflist}} (or {{References}}) template.
+fn is_reference_list(section_content: &str) -> bool {
+    regex!(r"(?i)<references\s*/?>|\{\{\s*(reflist|references)\s*(\||\}\})")
+        .is_match(section_content)
+}
+
+/// Returns the IDs of the revisions whose content was restored in `wikitext` (which still contains
+/// its merge markers), in the order they first appear, without duplicates.
+fn get_restored_revids(wikitext: &str) -> Vec<u64> {
+    let start_marker_regex = Regex::new(&format!(
+        "{}([0-9]+){}?{}", START_MARKER, REMOVAL_MARKER, START_MARKER)).unwrap();
+    let mut revids = Vec::new();
+    for captures in start_marker_regex.captures_iter(wikitext) {
+        let revid = captures.at(1).unwrap().parse().unwrap();
+        if !revids.contains(&revid) {
+            revids.push(revid);
+        }
+    }
+    revids
+}
+
+/// Strings in rendered HTML that mean MediaWiki couldn't render some wikitext: cite errors, Lua
+/// errors, template loops, and the generic error messages of parser functions.
+const RENDER_ERROR_MARKERS: [&'static str; 4] =
+    ["mw-ext-cite-error", "scribunto-error", "Template loop detected", "<strong class=\"error\">"];
+
+/// Returns the number of MediaWiki error messages (see RENDER_ERROR_MARKERS) in the rendered HTML
+/// `html`.
+fn count_render_errors(html: &str) -> usize {
+    RENDER_ERROR_MARKERS.iter().fold(0, |count, marker| count + html.matches(marker).count())
+}
+
+/// Reverts each of `sections` that renders (with `render`) with more MediaWiki errors than the
+/// same section of the latest revision, in `unmerged_sections`, to that section, so that restored
+/// content that breaks the wikitext (say, by unbalancing a template or table) doesn't deface the
+/// page. Returns whether any sections were reverted.
+fn revert_sections_with_render_errors<F>(sections: &mut [MergedSection],
+                                         unmerged_sections: &[(String, String)], render: F)
+                                         -> Result<bool, String>
+    where F: Fn(&str) -> Result<String, String> {
+    let mut reverted = false;
+    for (section, &(ref unmerged_title, ref unmerged_content)) in
+        sections.iter_mut().zip(unmerged_sections) {
+        if section.title != *unmerged_title || section.content == *unmerged_content {
+            continue;
+        }
+        let merged_errors =
+            count_render_errors(&try!(render(&strip_markers_from_headings(&section.content))));
+        if merged_errors > 0 &&
+            merged_errors > count_render_errors(&try!(render(unmerged_content))) {
+            warn!("Restored content in section \"{}\" renders with errors; reverting it",
+                  section.title);
+            section.content = unmerged_content.clone();
+            section.stats = MergeStats::default();
+            reverted = true;
+        }
+    }
+    Ok(reverted)
+}
+
+/// MediaWiki generates each heading's anchor ID from the heading text, so merge markers inside a
+/// heading would change its anchor and break "#Section" links and the table of contents. This
+/// removes merge markers and title count separators (which should never get this far, but must not
+/// be rendered) from heading lines. To keep the remaining markers balanced, a start marker whose end
+/// marker isn't in the heading moves to the start of the next line, and an end marker whose start
+/// marker isn't in the heading moves to the end of the previous line. The current text kept beside
+/// restored vandalism isn't highlighted, so its markers are removed everywhere.
+fn strip_markers_from_headings(wikitext: &str) -> String {
+    let wikitext = remove_current_text_markers(wikitext);
+    let separator_regex = Regex::new(&format!("{}[0-9]+", TITLE_COUNT_SEPARATOR)).unwrap();
+    let marker_regex = Regex::new(&format!(
+        "{}([0-9]+{}?){}|{}([0-9]+{}?){}", START_MARKER, REMOVAL_MARKER, START_MARKER, END_MARKER,
+        REMOVAL_MARKER, END_MARKER)).unwrap();
+    let mut lines: Vec<String> =
+        wikitext.split('\n').map(|line| separator_regex.replace_all(line, "")).collect();
+    for i in 0..lines.len() {
+        let stripped_line = marker_regex.replace_all(&lines[i], "");
+        if !(stripped_line.starts_with("=") && stripped_line.trim_right().ends_with("=")) {
+            continue;
+        }
+        let mut unmatched_starts: Vec<String> = Vec::new();
+        let mut unmatched_ends: Vec<String> = Vec::new();
+        for captures in marker_regex.captures_iter(&lines[i]) {
+            match (captures.at(1), captures.at(2)) {
+                (Some(id), _) => unmatched_starts.push(id.to_owned()),
+                (_, Some(id)) => match unmatched_starts.iter().position(|start_id| start_id == id) {
+                    Some(index) => { unmatched_starts.remove(index); },
+                    None => unmatched_ends.push(id.to_owned()),
+                },
+                _ => unreachable!(),
+            }
+        }
+        lines[i] = stripped_line;
+        if i > 0 {
+            for id in unmatched_ends {
+                lines[i - 1].push_str(&format!("{}{}{}", END_MARKER, id, END_MARKER));
+            }
+        }
+        if i + 1 < lines.len() {
+            let starts = unmatched_starts.into_iter()
+                .map(|id| format!("{}{}{}", START_MARKER, id, START_MARKER))
+                .collect::<Vec<_>>().concat();
+            let next_line = starts + &lines[i + 1];
+            lines[i + 1] = next_line;
+        }
+    }
+    lines.join("\n")
+}
+
+/// The words that mark a revision as reverting vandalism when --vandalism_keywords is blank.
+const DEFAULT_VANDALISM_KEYWORDS: &'static str = "vandal";
+
+/// Returns the (lowercased) words in `keywords`, a comma-separated list, or those in
+/// DEFAULT_VANDALISM_KEYWORDS if it's blank.
+fn parse_vandalism_keywords(keywords: &str) -> Vec<String> {
+    let keywords = if keywords.trim() == "" { DEFAULT_VANDALISM_KEYWORDS } else { keywords };
+    keywords.split(',').map(|keyword| keyword.trim().to_lowercase())
+        .filter(|keyword| !keyword.is_empty()).collect()
+}
+
+/// Returns whether `revision` reverted vandalism, going by its edit summary: whether the summary
+/// contains any of `keywords` (which are in lowercase), ignoring case.
+fn is_antivandalism_revision(revision: &Revision, keywords: &[String]) -> bool {
+    let comment = revision.comment.to_lowercase();
+    keywords.iter().any(|keyword| comment.contains(&keyword[..]))
+}
+
+/// Returns the titles of the pages whose reverted vandalism should be merged into the page `title`:
+/// the page itself and, if `include_talk_page` is set and it has one, its talk page.
+fn get_revision_source_titles(title: &str, include_talk_page: bool) -> Vec<String> {
+    let mut titles = vec![title.to_string()];
+    if include_talk_page {
+        if let Some(talk_page_title) = wiki::get_talk_page_title(title) {
+            titles.push(talk_page_title);
+        }
+    }
+    titles
+}
+
+/// Returns `page_contents` if it's no bigger than `max_response_bytes` (or if `max_response_bytes`
+/// is 0), and otherwise the result of calling `fallback`. This keeps a pathological merge from
+/// producing an enormous page.
+fn limit_response_size<F>(page_contents: String, max_response_bytes: usize, fallback: F)
+                          -> Result<String, String> where F: FnOnce() -> Result<String, String> {
+    if max_response_bytes == 0 || page_contents.len() <= max_response_bytes {
+        Ok(page_contents)
     } else {
-        Some(redis::ConnectionInfo {
-            addr: Box::new(redis::ConnectionAddr::Tcp(redis_hostname, redis_port)),
-            db: 0,
-            passwd: None,
-        })
-    };
+        fallback()
+    }
+}
 
-    let handler =
-        WikipediaMinusWikipediansHandler::new(
-            Wiki::new(wiki_hostname.to_string(), wiki_port, Client::new(), redis_connection_info),
-            Client::new(), Merger::new(diff_size_limit, diff_time_limit_ms),
-            max_consecutive_diff_timeouts);
-    Iron::new(handler).http(("0.0.0.0", port)).unwrap();
+/// Formats `parts`, a list of (name, HTML) pairs, as the body of a multipart/mixed response
+/// delimited by `boundary`.
+fn format_multipart(boundary: &str, parts: &[(&str, &str)]) -> String {
+    let mut body = String::new();
+    for &(name, html) in parts {
+        body.push_str(&format!(
+            "--{}\r\nContent-Type: text/html; charset=utf-8\r\n\
+             Content-Disposition: inline; name=\"{}\"\r\n\r\n{}\r\n",
+            boundary, name, html));
+    }
+    body.push_str(&format!("--{}--\r\n", boundary));
+    body
 }
 
-#[cfg(test)]
-mod tests {
-    use super::{TITLE_COUNT_SEPARATOR, deduplicate_section_titles};
+/// Returns the value of the parameter `name` in the URL query string `query`, if there is one.
+fn get_query_parameter(query: &Option<String>, name: &str) -> Option<String> {
+    query.as_ref().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes()).into_iter()
+            .find(|&(ref parameter_name, _)| parameter_name == name)
+            .map(|(_, value)| value)
+    })
+}
 
-    #[test]
-    fn test_deduplicate_section_titles() {
-        let input = vec![("title1".to_owned(), "content1".to_owned()),
-                         ("title1".to_owned(), "content2".to_owned()),
-                         ("title2".to_owned(), "content3".to_owned()),
-                         ("title1".to_owned(), "content4".to_owned())];
-        let expected = vec![(format!("title1{}1", TITLE_COUNT_SEPARATOR), "content1".to_owned()),
-                            (format!("title1{}2", TITLE_COUNT_SEPARATOR), "content2".to_owned()),
-                            (format!("title2{}1", TITLE_COUNT_SEPARATOR), "content3".to_owned()),
-                            (format!("title1{}3", TITLE_COUNT_SEPARATOR), "content4".to_owned())];
-        assert_eq!(expected, deduplicate_section_titles(input));
+/// The query parameters of /wiki/ requests that ask for something other than the latest version of
+/// the article: an old revision, a diff, or another action (e.g. "?action=raw" for the wikitext).
+const PASSTHROUGH_QUERY_PARAMETERS: [&'static str; 3] = ["action", "diff", "oldid"];
+
+/// Returns whether a /wiki/ request with the query string `query` should be passed through to the
+/// wiki, rather than served the latest merged page, which would silently ignore what it asked
+/// for. "action=view" is what a plain request does anyway, so it's served merged.
+fn is_passthrough_query(query: &Option<String>) -> bool {
+    PASSTHROUGH_QUERY_PARAMETERS.iter().any(|name| {
+        match get_query_parameter(query, name) {
+            Some(ref value) if *name == "action" => value != "view",
+            Some(_) => true,
+            None => false,
+        }
+    })
+}
+
+/// Returns the URL on `wiki` to pass the request for `request_url` through to: the same path and
+/// query, at the wiki's scheme, hostname and port.
+// TODO: Maybe should be moved to wiki module.
+fn get_passthrough_url(request_url: &iron::Url, wiki: &Wiki) -> String {
+    let mut url = request_url.clone();
+    url.scheme = wiki.scheme.clone();
+    url.host = url::Host::Domain(wiki.hostname.clone());
+    url.port = wiki.port;
+    url.into_generic_url().serialize()
+}
+
+/// Returns whether a request to the host `host`, with the query string `query`, is for the mobile
+/// site: either `host` is a mobile hostname (e.g. "en.m.wikipedia.org"), or the request is for an
+/// AMP page (it has an "amp" query parameter).
+fn is_mobile_request(host: &str, query: &Option<String>) -> bool {
+    wiki::get_desktop_hostname(host).is_some() || get_query_parameter(query, "amp").is_some()
+}
+
+/// If the request path `path` is for an article (i.e. it's "/wiki/<title>", possibly with trailing
+/// slashes), returns the requested title. Otherwise, returns None.
+fn get_requested_title(path: &[String]) -> Option<&str> {
+    let mut path = path;
+    while path.last().map_or(false, |segment| segment.is_empty()) {
+        path = &path[..path.len() - 1];
+    }
+    if path.len() == 2 && path[0] == "wiki" {
+        Some(&path[1])
+    } else {
+        None
+    }
+}
+
+/// Returns a response redirecting to the page `title`, scrolled to `fragment`, with the query
+/// string `query`. The redirect is temporary, so that browsers don't hold on to it if
+/// --fragment_policy is changed.
+fn get_fragment_redirect_response(title: &str, fragment: &str, query: &Option<String>)
+                                  -> Response {
+    let query = query.as_ref().map_or("".to_string(), |query| format!("?{}", query));
+    let mut response = Response::with(iron::status::Found);
+    response.headers.set_raw(
+        "Location", vec![format!("/wiki/{}{}#{}", title, query, fragment).into_bytes()]);
+    response
+}
+
+/// Returns a response serving the process's metrics, in the Prometheus text format.
+fn get_metrics_response() -> Response {
+    let mut response = Response::with((iron::status::Ok, metrics().render()));
+    response.headers.set(ContentType(Mime(TopLevel::Text, SubLevel::Plain,
+                                          vec![(Attr::Ext("version".to_string()),
+                                                Value::Ext("0.0.4".to_string()))])));
+    response
+}
+
+/// Returns a response to a request without a page title: a redirect to `empty_title_page`, or if
+/// it's None, a 400.
+fn get_empty_title_response(empty_title_page: &Option<String>) -> Response {
+    let mut response = match *empty_title_page {
+        Some(ref page) => {
+            let mut response = Response::with(iron::status::Found);
+            response.headers.set_raw("Location", vec![format!("/wiki/{}", page).into_bytes()]);
+            response
+        },
+        None => Response::with(
+            (iron::status::BadRequest,
+             "<html><body>No page title given; try /wiki/&lt;title&gt;</body></html>")),
+    };
+    response.headers.set(ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![])));
+    response
+}
+
+/// Returns the response to a request for a page that couldn't be served because of `error`: a
+/// 404 if the page doesn't exist, a 503 if the server is too busy, a 502 if the wiki failed, or a
+/// 500.
+// TODO: create a better error page
+fn get_error_response(error: &AppError) -> Response {
+    let mut response = match *error {
+        AppError::PageNotFound => Response::with(
+            (iron::status::NotFound, "<html><body>No such page</body></html>")),
+        AppError::Overloaded => Response::with(
+            (iron::status::ServiceUnavailable,
+             "<html><body>Too busy; try again later</body></html>")),
+        AppError::Upstream(_) => Response::with(
+            (iron::status::BadGateway, "<html><body>ERROR</body></html>")),
+        AppError::Internal(_) => Response::with(
+            (iron::status::InternalServerError, "<html><body>ERROR</body></html>")),
+    };
+    response.headers.set(ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![])));
+    response
+}
+
+/// Returns the key of the snapshot (see `SnapshotStore`) of the page `title`, in `skin`.
+fn get_snapshot_key(title: &str, skin: Skin) -> String {
+    // "|" can't appear in a title, so mobile pages can't collide with desktop ones.
+    match skin {
+        Skin::Desktop => title.to_string(),
+        Skin::Mobile => format!("{}|mobile", title),
+    }
+}
+
+/// Returns the key of the page `canonical_title`, at its revision `revid`, in `skin` in the page
+/// cache. A new revision of the page gets a new key, so the cache never serves a merge of an
+/// outdated revision.
+fn get_page_cache_key(canonical_title: &str, revid: u64, skin: Skin) -> String {
+    format!("{}|{}", get_snapshot_key(canonical_title, skin), revid)
+}
+
+/// Returns the key of the section `section_title` (as deduplicated by
+/// `deduplicate_section_titles()`) of the page `canonical_title` in the section cache.
+fn get_section_cache_key(canonical_title: &str, section_title: &str) -> String {
+    // "|" can't appear in a title, so keys can't collide.
+    format!("{}|{}", canonical_title, section_title)
+}
+
+/// Returns a hash of everything that goes into merging a section: its content in the latest
+/// revision, and the (page title, revision) pairs merged into it.
+fn get_section_fingerprint(section_content: &str, revisions: &[(String, Revision)]) -> u64 {
+    let mut hasher = SipHasher::new();
+    section_content.hash(&mut hasher);
+    for &(ref revision_title, ref revision) in revisions {
+        (revision_title, revision.revid, revision.parentid).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Returns true if the request path `path` is for the about page ("/about", possibly with trailing
+/// slashes).
+fn is_about_request(path: &[String]) -> bool {
+    is_top_level_request(path, "about")
+}
+
+/// Returns true if the request path `path` is for the health check ("/healthz", possibly with
+/// trailing slashes).
+fn is_healthz_request(path: &[String]) -> bool {
+    is_top_level_request(path, "healthz")
+}
+
+/// Returns true if the request path `path` is "/`name`", possibly with trailing slashes.
+fn is_top_level_request(path: &[String], name: &str) -> bool {
+    let mut path = path;
+    while path.last().map_or(false, |segment| segment.is_empty()) {
+        path = &path[..path.len() - 1];
+    }
+    path.len() == 1 && path[0] == name
+}
+
+/// Returns a response serving the HTML page `about_page`.
+fn get_about_response(about_page: &str) -> Response {
+    let mut response = Response::with((iron::status::Ok, about_page));
+    response.headers.set(ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![])));
+    response
+}
+
+/// Returns a RevisionWatcher that polls `wiki` for the latest revisions of pages every
+/// `poll_interval_ms`.
+fn watch_revisions(wiki: &Wiki, poll_interval_ms: u64) -> RevisionWatcher {
+    let wiki = wiki.clone();
+    RevisionWatcher::new(poll_interval_ms, Box::new(move |title: &str| {
+        wiki.get_latest_revision(title).map(|revision| revision.revid).map_err(String::from)
+    }))
+}
+
+/// Returns the contents of the HTML file `path` to serve at /about, or DEFAULT_ABOUT_PAGE if `path`
+/// is blank.
+fn load_about_page(path: &str) -> Result<String, String> {
+    if path == "" {
+        return Ok(DEFAULT_ABOUT_PAGE.to_string());
+    }
+    let mut file = try_display!(File::open(path), "Failed to open about page {}", path);
+    let mut about_page = String::new();
+    try_display!(file.read_to_string(&mut about_page), "Failed to read about page {}", path);
+    Ok(about_page)
+}
+
+/// Returns true if `sections` has no content at all, or only whitespace.
+fn is_blank_article(sections: &[(String, String)]) -> bool {
+    sections.iter().all(|&(_, ref section_content)| section_content.trim().is_empty())
+}
+
+/// Splits the --wiki argument, which may be of the form "hostname", "hostname:port", or
+/// "scheme://hostname:port", into (scheme, hostname, port). `default_scheme` is used if `wiki` has no
+/// scheme, and the port defaults to the scheme's default port.
+fn parse_wiki_address(wiki: &str, default_scheme: &str) -> Result<(String, String, u16), String> {
+    let (scheme, address) = match wiki.find("://") {
+        Some(index) => (&wiki[..index], &wiki[index + 3..]),
+        None => (default_scheme, wiki),
+    };
+    if scheme != "http" && scheme != "https" {
+        return Err(format!("Unsupported scheme \"{}\" in wiki address \"{}\"; use http or https",
+                           scheme, wiki));
+    }
+    let mut address_components = address.split(":");
+    let hostname = address_components.next().unwrap();
+    if hostname.is_empty() || hostname.contains('/') {
+        return Err(format!("Invalid hostname \"{}\" in wiki address \"{}\"", hostname, wiki));
+    }
+    let port = match address_components.next() {
+        Some(port) => try_display!(port.parse::<u16>(),
+                                   "Invalid port \"{}\" in wiki address \"{}\"", port, wiki),
+        None => if scheme == "http" { 80 } else { 443 },
+    };
+    if address_components.next().is_some() {
+        return Err(format!("Too many colons in wiki address \"{}\"", wiki));
+    }
+    Ok((scheme.to_string(), hostname.to_string(), port))
+}
+
+/// Returns the information to connect to the Redis server at `hostname` and `port`, or None if
+/// `hostname` is blank.
+fn parse_redis_address(hostname: &str, port: u16)
+                       -> Result<Option<redis::ConnectionInfo>, String> {
+    if hostname.is_empty() {
+        return Ok(None);
+    }
+    if hostname.contains(':') || hostname.contains('/') {
+        return Err(format!("Invalid Redis hostname \"{}\"; give the port with --redis_port",
+                           hostname));
+    }
+    if port == 0 {
+        return Err("Invalid Redis port 0".to_string());
+    }
+    Ok(Some(redis::ConnectionInfo {
+        addr: Box::new(redis::ConnectionAddr::Tcp(hostname.to_string(), port)),
+        db: 0,
+        passwd: None,
+    }))
+}
+
+/// Prints `msg` as an error in the command-line arguments, and exits with the same status argparse
+/// exits with for an unparseable argument.
+fn exit_with_usage_error(msg: &str) -> ! {
+    // There's nothing more useful to do if stderr can't be written to.
+    let _ = writeln!(io::stderr(), "Error: {}", msg);
+    process::exit(2);
+}
+
+/// Formats `stats`, along with the total time taken to serve the request, as the compact JSON object
+/// sent in the X-WMW-Merge-Stats response header.
+fn format_merge_stats_header(stats: &MergeStats, total_ms: u64) -> String {
+    format!("{{\"sections_merged\":{},\"conflicts\":{},\"timeouts\":{},\"quality\":{:.3},\
+             \"total_ms\":{}}}",
+            stats.sections_merged, stats.conflicts, stats.timeouts, stats.quality(), total_ms)
+}
+
+/// Returns the sections of `merged_article` that had revisions merged into them, and whose merges
+/// scored below `min_quality` (see `MergeStats::quality()`).
+fn get_low_quality_sections(merged_article: &MergedArticle, min_quality: f64)
+                            -> Vec<&MergedSection> {
+    merged_article.sections.iter()
+        .filter(|section| {
+            section.stats.sections_merged > 0 && section.stats.quality() < min_quality
+        })
+        .collect()
+}
+
+/// Returns the Link header values that point machine clients to the diff of each revision in
+/// `revids` (whose content was restored), one value per revision.
+fn format_provenance_links(revids: &[u64]) -> Vec<Vec<u8>> {
+    revids.iter()
+        .map(|revid| {
+            format!("</w/index.php?diff={}>; rel=\"vandalism-source\"", revid).into_bytes()
+        })
+        .collect()
+}
+
+impl Handler for WikipediaMinusWikipediansHandler {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        if is_healthz_request(&request.url.path) {
+            Ok(self.get_healthz_response())
+        } else if is_top_level_request(&request.url.path, "metrics") {
+            Ok(get_metrics_response())
+        } else if is_about_request(&request.url.path) {
+            Ok(get_about_response(&self.about_page))
+        } else if is_top_level_request(&request.url.path, "wiki") {
+            Ok(get_empty_title_response(&self.empty_title_page))
+        } else if get_requested_title(&request.url.path).is_some() &&
+            is_passthrough_query(&request.url.query) {
+            Ok(self.get_passthrough_response(&request.url))
+        } else if let Some(requested_title) = get_requested_title(&request.url.path) {
+            // Entered before the timer, so that it's still in scope when the timer logs.
+            let _log_context = logging::RequestContext::enter(requested_title);
+            metrics().increment(Counter::RequestsServed);
+            let timer = Timer::new(
+                "serve_request", format!("Served request for /wiki/{}", requested_title));
+            let (requested_title, fragment) = wiki::split_title_fragment(requested_title);
+            let title = wiki::normalize_title(requested_title, self.capital_links);
+            if title.is_empty() {
+                // E.g. "/wiki/%23History", which is all fragment.
+                return Ok(get_empty_title_response(&self.empty_title_page));
+            }
+            if let (Some(fragment), FragmentPolicy::Redirect) = (fragment, self.fragment_policy) {
+                return Ok(get_fragment_redirect_response(&title, fragment, &request.url.query));
+            }
+            let multipart =
+                get_query_parameter(&request.url.query, "format") == Some("multipart".to_string());
+            let mobile = is_mobile_request(&format!("{}", request.url.host), &request.url.query);
+            let skin =
+                if mobile && self.mobile_wiki.is_some() { Skin::Mobile } else { Skin::Desktop };
+            if get_query_parameter(&request.url.query, "view") == Some("review".to_string()) {
+                return Ok(self.get_review_response(&title));
+            }
+            if get_query_parameter(&request.url.query, "view") == Some("live".to_string()) {
+                return Ok(self.get_live_response(&title, &request.url.query));
+            }
+            let mut response =
+                match self.get_page(&title, skin) {
+                    Ok((page_contents, merge_stats, restored_revids)) => {
+                        let mut response = if multipart {
+                            self.get_multipart_response(&title, page_contents)
+                        } else {
+                            Response::with((iron::status::Ok, page_contents))
+                        };
+                        if self.emit_stats_header {
+                            let total_ms = timer.elapsed_ms();
+                            response.headers.set_raw(
+                                "X-WMW-Merge-Stats",
+                                vec![format_merge_stats_header(&merge_stats, total_ms).into_bytes()]);
+                        }
+                        if !restored_revids.is_empty() {
+                            response.headers.set_raw(
+                                "Link", format_provenance_links(&restored_revids));
+                        }
+                        response
+                    },
+                    Err(error) => {
+                        warn!("Failed to get page with vandalism restored: {}", error);
+                        self.get_stale_snapshot_response(&title, skin, &error)
+                            .unwrap_or_else(|| get_error_response(&error))
+                    },
+                };
+            if !response.headers.has::<ContentType>() {
+                response.headers.set(ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![])));
+            }
+            Ok(response)
+        } else {
+            Ok(self.get_passthrough_response(&request.url))
+        }
+    }
+}
+
+fn main() {
+    // Logging is set up before the flags are parsed, so that parsing them can log.
+    let mut log_format = config::find_flag_value(&env::args().collect::<Vec<_>>(), "--log_format")
+        .map_or(Ok(LogFormat::Text), |log_format| log_format.parse())
+        .unwrap_or_else(|msg: String| exit_with_usage_error(&msg));
+    logging::init(log_format);
+
+    let mut port = 3000;
+    let mut wiki = "en.wikipedia.org".to_string();
+    let mut wiki_scheme = "https".to_string();
+    let mut wiki_profiles_dir = "".to_string();
+    let mut user_agent = concat!("wikipedia-minus-wikipedians/0.1 ",
+                                 "(https://github.com/TheJakeSchmidt/wikipedia-minus-wikipedians)")
+        .to_string();
+    let mut redis_hostname = "".to_string();
+    let mut redis_port = 6379;
+    let mut max_redis_connections = WikiOptions::default().max_redis_connections;
+    let mut diff_size_limit = 1000;
+    let mut diff_time_limit_ms = 500;
+    let mut diff_time_limit_ms_per_kib = 0;
+    let mut min_diff_time_limit_ms = 20;
+    let mut lcs_max_tasks = 0;
+    let mut lcs_max_queue_size = 0;
+    let mut min_merge_similarity = 0.0;
+    let mut conflict_policy = ConflictPolicy::KeepVandalism;
+    let mut prefer_recent_restorations = false;
+    let mut merge_granularity = Granularity::Word;
+    let mut partial_lcs_on_timeout = false;
+    let mut unicode_word_boundaries = false;
+    let mut atomic_literal_blocks = false;
+    let mut max_consecutive_diff_timeouts = 3;
+    let mut max_revisions_scanned = WikiOptions::default().max_revisions_scanned;
+    let mut canonical_titles_file = "".to_string();
+    let mut canonical_title_ttl_seconds = WikiOptions::default().canonical_title_ttl_seconds;
+    let mut maxlag = 0;
+    let mut cache_ttl_seconds = 0;
+    let mut cache_rules = "".to_string();
+    let mut cache_backends = "redis".to_string();
+    let mut memory_cache_capacity = WikiOptions::default().memory_cache_capacity;
+    let mut api_max_retries = WikiOptions::default().api_max_retries;
+    let mut max_api_requests_per_second = 0;
+    let mut max_redirect_depth = WikiOptions::default().max_redirect_depth;
+    let mut api_formatversion = WikiOptions::default().format_version;
+    let mut http2 = false;
+    let mut http2_ca_file = WikiOptions::default().http2_ca_file;
+    let mut revision_fetch_parallelism = WikiOptions::default().revision_fetch_parallelism;
+    let mut maxlag_retries = WikiOptions::default().maxlag_retries;
+    let mut escalate_granularity = false;
+    let mut exclude_infoboxes = false;
+    let mut skip_reference_lists = false;
+    let mut section_selector = "".to_string();
+    let mut min_section_quality = 0.0;
+    let mut ignore_maintenance_templates = false;
+    let mut maintenance_templates = "".to_string();
+    let mut include_talk_page_reverts = false;
+    let mut target_revert_count = 0;
+    let mut vandalism_keywords = "".to_string();
+    let mut revert_detection = RevertDetection::Comment;
+    let mut follow_moves = false;
+    let mut min_corroborating_reverts = 1;
+    let mut page_cache = false;
+    let mut page_cache_size = 1000;
+    let mut page_cache_ttl_seconds = 300;
+    let mut share_skeleton_fetches = false;
+    let mut worker_threads = 32;
+    let mut section_cache_size = 0;
+    let mut max_concurrent_merges = 0;
+    let mut max_inflight_bytes = 0;
+    let mut live_poll_interval_ms = 10_000;
+    let mut live_timeout_ms = 60_000;
+    let mut max_live_requests = 64;
+    let mut max_response_bytes = 0;
+    // None if it's left to the wiki's profile, which defaults to true.
+    let mut capital_links = None;
+    let mut fragment_policy = FragmentPolicy::Redirect;
+    // None if it's left to the wiki's profile, which defaults to "Main_Page".
+    let mut empty_title_page = None;
+    let mut emit_stats_header = false;
+    let mut validate_render = false;
+    let mut marker_output = MarkerOutput::Span;
+    let mut severity_shading = false;
+    let mut show_restoration_footer = false;
+    let mut removal_placeholder = "".to_string();
+    let mut max_spans_per_page = 0;
+    let mut vandalism_style = "color: red".to_string();
+    let mut diff_tooltips = false;
+    let mut show_merge_timestamp = false;
+    let mut inject_toggle = false;
+    let mut mobile_requests = Skin::Desktop;
+    let mut event_log = "".to_string();
+    let mut event_webhook = "".to_string();
+    let mut capture_dir = "".to_string();
+    let mut capture_titles = "".to_string();
+    let mut snapshot_dir = "".to_string();
+    let mut max_snapshots = 10_000;
+    let mut replay = "".to_string();
+    let mut about_page = "".to_string();
+    let mut healthz_checks_upstream = false;
+    // The config file's values stand in for the defaults, so the flags parsed below override them.
+    let mut config_path = config::find_config_path(&env::args().collect::<Vec<_>>())
+        .unwrap_or("".to_string());
+    if config_path != "" {
+        let config = match Config::read(&config_path) {
+            Ok(config) => config,
+            Err(msg) => exit_with_usage_error(&msg),
+        };
+        macro_rules! apply_config {
+            ($($flag:ident),*) => { $(if let Some(value) = config.$flag { $flag = value; })* }
+        }
+        // Settings that are parsed from strings (or, for api_formatversion, a number), like their
+        // flags.
+        macro_rules! apply_parsed_config {
+            ($($flag:ident),*) => {
+                $(if let Some(value) = config.$flag {
+                    $flag = value.to_string().parse()
+                        .unwrap_or_else(|msg: String| exit_with_usage_error(&msg));
+                })*
+            }
+        }
+        apply_config!(port, wiki, wiki_profiles_dir, wiki_scheme, user_agent, redis_hostname,
+                      redis_port, max_redis_connections, cache_ttl_seconds, cache_rules,
+                      cache_backends, memory_cache_capacity, diff_size_limit, diff_time_limit_ms,
+                      diff_time_limit_ms_per_kib, min_diff_time_limit_ms, lcs_max_tasks,
+                      lcs_max_queue_size, min_merge_similarity, partial_lcs_on_timeout,
+                      prefer_recent_restorations, unicode_word_boundaries, atomic_literal_blocks,
+                      max_consecutive_diff_timeouts, max_revisions_scanned, canonical_titles_file,
+                      canonical_title_ttl_seconds, revision_fetch_parallelism, maxlag,
+                      max_api_requests_per_second, http2, http2_ca_file, max_redirect_depth,
+                      api_max_retries, maxlag_retries, escalate_granularity, exclude_infoboxes,
+                      skip_reference_lists, section_selector, min_section_quality,
+                      ignore_maintenance_templates,
+                      maintenance_templates, min_corroborating_reverts, target_revert_count,
+                      vandalism_keywords, follow_moves, include_talk_page_reverts,
+                      severity_shading, vandalism_style, diff_tooltips,
+                      show_merge_timestamp, inject_toggle, show_restoration_footer,
+                      max_spans_per_page, removal_placeholder, emit_stats_header, validate_render,
+                      worker_threads, share_skeleton_fetches, page_cache, page_cache_size,
+                      page_cache_ttl_seconds, section_cache_size, max_inflight_bytes,
+                      max_concurrent_merges, live_poll_interval_ms, live_timeout_ms,
+                      max_live_requests, max_response_bytes, event_log, event_webhook,
+                      capture_dir, capture_titles, snapshot_dir, max_snapshots, about_page,
+                      healthz_checks_upstream);
+        apply_parsed_config!(conflict_policy, merge_granularity, api_formatversion,
+                             revert_detection, fragment_policy, marker_output, mobile_requests);
+        if let Some(no_capital_links) = config.no_capital_links {
+            capital_links = Some(!no_capital_links);
+        }
+        if let Some(page) = config.empty_title_page {
+            empty_title_page = Some(page);
+        }
+    }
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description("TODO: Usage description");
+        parser.refer(&mut config_path).add_option(
+            &["--config"], Store,
+            "A TOML file of flag values (e.g. port = 8080), keyed by flag name without the leading \"--\". Flags given on the command line override it.");
+        parser.refer(&mut port).add_option(&["-p", "--port"], Store, "The port to serve HTTP on.");
+        parser.refer(&mut wiki).add_option(
+            &["--wiki"], Store,
+            "The hostname, hostname:port, or scheme://hostname:port of the wiki to mirror.");
+        parser.refer(&mut wiki_profiles_dir).add_option(
+            &["--wiki_profiles_dir"], Store,
+            "A directory of per-wiki profiles: JSON files named for the wiki's hostname (e.g. \"fr.wikipedia.org.json\") or a parent domain of it (e.g. \"wikipedia.org.json\"), with the wiki's settings for capital_links, main_page (see --empty_title_page), maintenance_templates (a list of names), removal_placeholder, about_page, and vandalism_keywords (a list of words). The most specific profile for --wiki is used, for the settings not given on the command line.");
+        parser.refer(&mut wiki_scheme).add_option(
+            &["--wiki_scheme"], Store,
+            "The scheme (http or https) to reach the wiki over. Ignored if --wiki includes a scheme.");
+        parser.refer(&mut user_agent).add_option(
+            &["--user_agent"], Store,
+            "The User-Agent header to send to the wiki. Wikimedia requires it to identify this mirror and how to contact its operator.");
+        parser.refer(&mut redis_hostname).add_option(
+            &["--redis_hostname"], Store,
+            "The hostname of the Redis server to use. Leave blank to disable Redis.");
+        parser.refer(&mut redis_port).add_option(
+            &["--redis_port"], Store,
+            "The port of the Redis server to use. Ignored if --redis_hostname is blank.");
+        parser.refer(&mut max_redis_connections).add_option(
+            &["--max_redis_connections"], Store,
+            "The most connections to the Redis server to have open at once. 0 for no limit.");
+        parser.refer(&mut cache_ttl_seconds).add_option(
+            &["--cache_ttl_seconds"], Store,
+            "How many seconds values cached in Redis live before they expire. 0 for them to never expire.");
+        parser.refer(&mut cache_rules).add_option(
+            &["--cache_rules"], Store,
+            "Comma-separated rules for which API calls to cache in Redis, on top of the defaults (query+revisions+content and parse), each ACTION, ACTION:SECONDS (to override --cache_ttl_seconds), or ACTION:off. ACTION is the API action, and for queries the query module, e.g. \"query+logevents\".");
+        parser.refer(&mut cache_backends).add_option(
+            &["--cache_backends"], Store,
+            "Comma-separated caches to keep API responses in, in the order they're checked: \"memory\" (this process's memory) and/or \"redis\" (the server given by --redis_hostname). A response found in a later cache is copied into the earlier ones, so \"memory,redis\" gives local lookups with Redis behind them.");
+        parser.refer(&mut memory_cache_capacity).add_option(
+            &["--memory_cache_capacity"], Store,
+            "The most API responses to hold in the memory cache. Ignored unless --cache_backends includes \"memory\".");
+        parser.refer(&mut diff_size_limit).add_option(
+            &["--diff_size_limit"], Store,
+            "The size in bytes at which a diff is considered too big, and is skipped.");
+        parser.refer(&mut diff_time_limit_ms).add_option(
+            &["--diff_time_limit_ms"], Store,
+            "The maximum time (in milliseconds) to attempt to compute a diff before giving up.");
+        parser.refer(&mut diff_time_limit_ms_per_kib).add_option(
+            &["--diff_time_limit_ms_per_kib"], Store,
+            "If nonzero, scale each diff's time limit to this many milliseconds per KiB of text, between --min_diff_time_limit_ms and --diff_time_limit_ms.");
+        parser.refer(&mut min_diff_time_limit_ms).add_option(
+            &["--min_diff_time_limit_ms"], Store,
+            "The smallest scaled diff time limit. Ignored if --diff_time_limit_ms_per_kib is 0.");
+        parser.refer(&mut lcs_max_tasks).add_option(
+            &["--lcs_max_tasks"], Store,
+            "Give up on a diff after this many steps of its longest-common-subsequence calculation, as well as after the time limit. Unlike the time limit, this bounds the work done the same way however loaded the CPU is. 0 for no limit.");
+        parser.refer(&mut lcs_max_queue_size).add_option(
+            &["--lcs_max_queue_size"], Store,
+            "Keep at most this many tasks queued in each diff's longest-common-subsequence calculation, dropping the least promising ones when there are more. This bounds the memory a pathological diff can use, but a diff may then miss its longest common subsequence, and restore less. 0 for no limit.");
+        parser.refer(&mut min_merge_similarity).add_option(
+            &["--min_merge_similarity"], Store,
+            "Skip merging a revision into a section, without diffing them, if the estimated fraction of runs of words they share is less than this (between 0 and 1). Dissimilar texts take the longest to diff, and mostly end in conflicts. 0 to always merge.");
+        parser.refer(&mut partial_lcs_on_timeout).add_option(
+            &["--partial_lcs_on_timeout"], StoreTrue,
+            "When a diff times out, merge with the part of it that was finished, instead of skipping the revision. For big, mostly-unchanged sections, that's usually most of the diff; the rest merges as conflicts.");
+        parser.refer(&mut conflict_policy).add_option(
+            &["--conflict_policy"], Store,
+            "What to keep where reverted vandalism changed the text, for debugging and comparison: \"keep_vandalism\" (the default; restore the vandalism), \"keep_clean\" (leave the current text), or \"keep_both\" (the current text, followed by the restored vandalism).");
+        parser.refer(&mut prefer_recent_restorations).add_option(
+            &["--prefer_recent_restorations"], StoreTrue,
+            "Where the vandalism reverted by two revisions conflicts, restore that of the more recent revert, which better reflects what's currently thought to be vandalism, instead of the older one.");
+        parser.refer(&mut merge_granularity).add_option(
+            &["--merge_granularity"], Store,
+            "The unit to diff revisions by: \"word\" (the default), \"line\", or \"paragraph\". Line-by-line merges are much faster, and cleaner for list- and table-heavy articles, but restore whole lines around each change. With --escalate_granularity, timed-out diffs are retried at the coarser granularities.");
+        parser.refer(&mut unicode_word_boundaries).add_option(
+            &["--unicode_word_boundaries"], StoreTrue,
+            "Split words for diffing on all Unicode whitespace (e.g. non-breaking and ideographic spaces), not just ASCII whitespace.");
+        parser.refer(&mut atomic_literal_blocks).add_option(
+            &["--atomic_literal_blocks"], StoreTrue,
+            "Diff each <nowiki>, <pre>, and <syntaxhighlight> block as a single word, so vandalism inside one restores the whole block instead of fragments of it.");
+        parser.refer(&mut max_consecutive_diff_timeouts).add_option(
+            &["--max_consecutive_diff_timeouts"], Store,
+            "The maximum number of consecutive diff-too-large or diff-timeout failures to accept before ceasing to merge a section.");
+        parser.refer(&mut max_revisions_scanned).add_option(
+            &["--max_revisions_scanned"], Store,
+            "The most revisions of a page to ever fetch when looking for reverted vandalism.");
+        parser.refer(&mut canonical_titles_file).add_option(
+            &["--canonical_titles_file"], Store,
+            "A file to persist canonical page titles (i.e. resolved redirects) to, and load them from at startup. Leave blank to disable.");
+        parser.refer(&mut canonical_title_ttl_seconds).add_option(
+            &["--canonical_title_ttl_seconds"], Store,
+            "How long to trust a canonical page title before looking it up again, since redirects change, in seconds. Expired titles are also dropped from --canonical_titles_file at startup. 0 to trust them forever.");
+        parser.refer(&mut revision_fetch_parallelism).add_option(
+            &["--revision_fetch_parallelism"], Store,
+            "If more than 1, fetch up to --max_revisions_scanned revisions of each page, in up to this many parallel queries, instead of only the 500 a single query returns. Revisions can be missed on pages with very dense histories.");
+        parser.refer(&mut maxlag).add_option(
+            &["--maxlag"], Store,
+            "Ask the wiki to reject API calls while its database replicas are more than this many seconds behind, and retry them after the wait it asks for. 0 to not send maxlag.");
+        parser.refer(&mut max_api_requests_per_second).add_option(
+            &["--max_api_requests_per_second"], Store,
+            "The most requests to send to the wiki per second, across all pages being served, to avoid tripping its throttling. 0 for no limit.");
+        parser.refer(&mut api_formatversion).add_option(
+            &["--api_formatversion"], Store,
+            "The version of the API's JSON format to ask for: 1 (the legacy format, which every wiki supports) or 2 (which needs MediaWiki 1.25 or later).");
+        parser.refer(&mut http2).add_option(
+            &["--http2"], StoreTrue,
+            "Send requests to the wiki over HTTP/2, multiplexed on one connection, instead of opening an HTTP/1.1 connection for each. Requests that can't be sent over HTTP/2 (e.g. because the wiki doesn't support it) fall back to HTTP/1.1.");
+        parser.refer(&mut http2_ca_file).add_option(
+            &["--http2_ca_file"], Store,
+            "The file of CA certificates to verify the wiki's certificate against, when sending requests over HTTP/2 to an https wiki.");
+        parser.refer(&mut max_redirect_depth).add_option(
+            &["--max_redirect_depth"], Store,
+            "The most redirects to follow from a requested title to its canonical title before giving up on the request.");
+        parser.refer(&mut api_max_retries).add_option(
+            &["--api_max_retries"], Store,
+            "The number of times to retry an API call that fails with a connection error or a 5xx status, waiting 100 ms before the first retry and twice as long before each one after.");
+        parser.refer(&mut maxlag_retries).add_option(
+            &["--maxlag_retries"], Store,
+            "The number of times to retry an API call rejected because of --maxlag before giving up.");
+        parser.refer(&mut escalate_granularity).add_option(
+            &["--escalate_granularity"], StoreTrue,
+            "When a diff times out, retry it line-by-line, and then paragraph-by-paragraph, before counting it as a timeout.");
+        parser.refer(&mut exclude_infoboxes).add_option(
+            &["--exclude_infoboxes"], StoreTrue,
+            "Only restore vandalism in the article body, not inside infobox or navbox templates.");
+        parser.refer(&mut skip_reference_lists).add_option(
+            &["--skip_reference_lists"], StoreTrue,
+            "Don't restore changes to sections holding the reference list (a <references/> tag or {{Reflist}} template), which is reordered whenever citations change.");
+        parser.refer(&mut section_selector).add_option(
+            &["--section_selector"], Store,
+            "Only restore vandalism in the sections whose titles match this regular expression (e.g. \"^(|Controversy)$\" for the lead, whose title is empty, and the Controversy section). Leave blank to restore it in every section.");
+        parser.refer(&mut min_section_quality).add_option(
+            &["--min_section_quality"], Store,
+            "Log a warning, and count it in /metrics, when a section's merges score below this quality, from 0 to 1: the fraction of changed chunks that merged without conflict, times the fraction of the text that was stable. Low scores point to poor alignments worth investigating. 0 to not check.");
+        parser.refer(&mut ignore_maintenance_templates).add_option(
+            &["--ignore_maintenance_templates"], StoreTrue,
+            "Don't restore the addition or removal of maintenance templates like {{citation needed}}, so that restored vandalism doesn't change which content is tagged.");
+        parser.refer(&mut maintenance_templates).add_option(
+            &["--maintenance_templates"], Store,
+            "A comma-separated list of the names of the templates --ignore_maintenance_templates applies to. Leave blank for the built-in list of common inline maintenance templates.");
+        parser.refer(&mut min_corroborating_reverts).add_option(
+            &["--min_corroborating_reverts"], Store,
+            "Only restore content that at least this many reverts, by different users, removed.");
+        parser.refer(&mut target_revert_count).add_option(
+            &["--target_revert_count"], Store,
+            "Fetch each page's revisions in growing batches (50, then 200, then 500 at a time), stopping once this many vandalism reverts are found. 0 to fetch them all at once.");
+        parser.refer(&mut vandalism_keywords).add_option(
+            &["--vandalism_keywords"], Store,
+            "A comma-separated list of words, any of which in a revision's edit summary (ignoring case) marks it as a revert of vandalism to restore. Leave blank for \"vandal\". Other languages' wikis need their own words, which can also be set in their profiles (see --wiki_profiles_dir).");
+        parser.refer(&mut revert_detection).add_option(
+            &["--revert_detection"], Store,
+            "How to find the revisions that reverted vandalism: by \"comment\" (edit summaries containing any of --vandalism_keywords), by \"revert\" (revisions tagged as rollbacks, undos or manual reverts, or restoring the exact content of an earlier revision), or by \"either\".");
+        parser.refer(&mut follow_moves).add_option(
+            &["--follow_moves"], StoreTrue,
+            "Also restore vandalism reverted on the draft an article was promoted from, if the move log shows it was moved from draft space.");
+        parser.refer(&mut include_talk_page_reverts).add_option(
+            &["--include_talk_page_reverts"], StoreTrue,
+            "Also restore vandalism reverted on the article's talk page, in sections whose titles match the article's.");
+        parser.refer(&mut capital_links).add_option(
+            &["--no_capital_links"], StoreConst(Some(false)),
+            "Don't uppercase the first letter of requested page titles (for wikis with $wgCapitalLinks off).");
+        parser.refer(&mut fragment_policy).add_option(
+            &["--fragment_policy"], Store,
+            "What to do with requests for titles with a fragment in them (e.g. /wiki/Foo%23History): \"redirect\" to the page with the fragment moved into the URL's fragment (e.g. /wiki/Foo#History), so the browser scrolls to it, or \"strip\" it and serve the page.");
+        parser.refer(&mut empty_title_page).add_option(
+            &["--empty_title_page"], StoreOption,
+            "The page to redirect requests without a title (/wiki/) to. Defaults to the main_page in the wiki's profile, or \"Main_Page\". Set it blank to reject them with a 400 instead.");
+        parser.refer(&mut marker_output).add_option(
+            &["--marker_output"], Store,
+            "How to render restored vandalism: \"span\" (highlighted inline) or \"details\" (collapsible).");
+        parser.refer(&mut severity_shading).add_option(
+            &["--severity_shading"], StoreTrue,
+            "Shade restored vandalism by size: the more text restored, the more saturated the red.");
+        parser.refer(&mut vandalism_style).add_option(
+            &["--vandalism_style"], Store,
+            "The inline CSS style of the spans restored vandalism is highlighted with (e.g. \"background: yellow\"), or \"\" for none, to style the \"vandalism-added\", \"vandalism-removed\" and \"vandalism-<revid>\" classes with a stylesheet instead. Ignored with --severity_shading.");
+        parser.refer(&mut diff_tooltips).add_option(
+            &["--diff_tooltips"], StoreTrue,
+            "Show the URL of the diff restored vandalism came from when it's hovered over. Every restored element has a \"data-revid\" attribute with its revision ID either way.");
+        parser.refer(&mut show_merge_timestamp).add_option(
+            &["--show_merge_timestamp"], StoreTrue,
+            "Append a footer to each article saying when its vandalism was merged in, which (with --page_cache) can be well before it's served.");
+        parser.refer(&mut inject_toggle).add_option(
+            &["--inject_toggle"], StoreTrue,
+            "Add a floating button to each page that hides and shows the restored vandalism, so readers can compare it with the clean article.");
+        parser.refer(&mut show_restoration_footer).add_option(
+            &["--show_restoration_footer"], StoreTrue,
+            "Append a footer to each article listing the edits whose vandalism was restored, with a link to each revision, who reverted it, and a snippet of the restored text.");
+        parser.refer(&mut max_spans_per_page).add_option(
+            &["--max_spans_per_page"], Store,
+            "The most runs of restored vandalism to highlight in a page. Restored vandalism past that is shown as plain text, so pathological pages stay readable and fast to render. 0 for no limit.");
+        parser.refer(&mut removal_placeholder).add_option(
+            &["--removal_placeholder"], Store,
+            "Text to show where restored vandalism deleted content (e.g. \"[removed]\"). Restored deletions are marked with the \"vandalism-removed\" class, and restored additions with \"vandalism-added\"; by default, a restored deletion's element is empty.");
+        parser.refer(&mut mobile_requests).add_option(
+            &["--mobile_requests"], Store,
+            "How to serve requests for the mobile site (to a mobile hostname like en.m.example.org, or with an \"amp\" query parameter): \"desktop\" (serve the desktop page) or \"mobile\" (fetch and serve the mobile page).");
+        parser.refer(&mut emit_stats_header).add_option(
+            &["--emit_stats_header"], StoreTrue,
+            "Add an X-WMW-Merge-Stats header, containing JSON statistics about the merge, to /wiki/ responses.");
+        parser.refer(&mut validate_render).add_option(
+            &["--validate_render"], StoreTrue,
+            "Check each merged page for MediaWiki error messages (e.g. cite errors, Lua errors, template loops), and serve the current version of any section whose restored content causes them. Costs two extra renders per merged section on pages with errors.");
+        parser.refer(&mut worker_threads).add_option(
+            &["--worker_threads"], Store,
+            "The number of threads that sections are merged and page skeletons fetched on, shared by all requests. Work waits in a queue until a thread is free.");
+        parser.refer(&mut share_skeleton_fetches).add_option(
+            &["--share_skeleton_fetches"], StoreTrue,
+            "Have concurrent requests for the same page share one fetch of the current page's HTML, instead of each downloading it.");
+        parser.refer(&mut page_cache).add_option(
+            &["--page_cache"], StoreTrue,
+            "Cache finished pages in memory by the article's latest revision, so they're served again without merging until the article is edited or --page_cache_ttl_seconds passes.");
+        parser.refer(&mut page_cache_size).add_option(
+            &["--page_cache_size"], Store,
+            "The number of finished pages --page_cache holds. When it's full, the oldest page is evicted.");
+        parser.refer(&mut page_cache_ttl_seconds).add_option(
+            &["--page_cache_ttl_seconds"], Store,
+            "How long --page_cache keeps a page, in seconds, so that new reverts of an article that hasn't been edited since are eventually merged in.");
+        parser.refer(&mut section_cache_size).add_option(
+            &["--section_cache_size"], Store,
+            "The number of merged sections to cache in memory. When a page changes, its sections that haven't changed (and that have no new reverts to merge) are reused instead of merged again. 0 disables the cache.");
+        parser.refer(&mut max_inflight_bytes).add_option(
+            &["--max_inflight_bytes"], Store,
+            "Turn away requests for uncached pages with a 503 while merges in progress are holding this many bytes of content, as a backstop against running out of memory. 0 for no limit.");
+        parser.refer(&mut max_concurrent_merges).add_option(
+            &["--max_concurrent_merges"], Store,
+            "The most uncached pages to merge at once; other requests for uncached pages wait. Cached pages are always served immediately. 0 for no limit.");
+        parser.refer(&mut live_poll_interval_ms).add_option(
+            &["--live_poll_interval_ms"], Store,
+            "How often, in milliseconds, to check pages that live update requests (/wiki/TITLE?view=live&since=REVID) are waiting on for new revisions.");
+        parser.refer(&mut live_timeout_ms).add_option(
+            &["--live_timeout_ms"], Store,
+            "How long, in milliseconds, to hold a live update request open waiting for a new revision before answering it with 204 No Content.");
+        parser.refer(&mut max_live_requests).add_option(
+            &["--max_live_requests"], Store,
+            "The most live update requests to hold open at once, each of which ties up a server thread; others are turned away with a 503. 0 for no limit.");
+        parser.refer(&mut max_response_bytes).add_option(
+            &["--max_response_bytes"], Store,
+            "The largest merged page to serve, in bytes. Larger pages are served without vandalism restored. 0 for no limit.");
+        parser.refer(&mut event_log).add_option(
+            &["--event_log"], Store,
+            "A file to append a JSON record of each restoration of vandalism to. Leave blank to disable.");
+        parser.refer(&mut event_webhook).add_option(
+            &["--event_webhook"], Store,
+            "A URL to POST a JSON record of each restoration of vandalism to. Leave blank to disable.");
+        parser.refer(&mut capture_dir).add_option(
+            &["--capture_dir"], Store,
+            "A directory to save the inputs and output of merging each of --capture_titles to, for replaying with --replay. Leave blank to disable.");
+        parser.refer(&mut capture_titles).add_option(
+            &["--capture_titles"], Store,
+            "A comma-separated list of the titles of the pages to capture merges of. Ignored if --capture_dir is blank.");
+        parser.refer(&mut snapshot_dir).add_option(
+            &["--snapshot_dir"], Store,
+            "A directory to save a snapshot of each page merged to. When the wiki can't be reached, the latest snapshot of the page requested is served instead of an error, with a banner and a Warning header saying it's stale. Leave blank to disable.");
+        parser.refer(&mut max_snapshots).add_option(
+            &["--max_snapshots"], Store,
+            "The most snapshots to keep in --snapshot_dir. Once there are more, the least recently saved ones are deleted. 0 for no limit.");
+        parser.refer(&mut about_page).add_option(
+            &["--about_page"], Store,
+            "An HTML file to serve at /about, explaining the mirror. Leave blank to serve the built-in page.");
+        parser.refer(&mut log_format).add_option(
+            &["--log_format"], Store,
+            "How to write logs: \"text\", as configured in log.toml, or \"json\", one JSON object per line on stdout, with the request ID, page title, and (for timings) phase and duration as fields of their own. Can't be set in --config, since logging starts before it's read.");
+        parser.refer(&mut healthz_checks_upstream).add_option(
+            &["--healthz_checks_upstream"], StoreTrue,
+            "Have the health check at /healthz ping the wiki's API and the Redis server (if there is one), and return 503 if either is down, instead of only checking that the server is up.");
+        parser.refer(&mut replay).add_option(
+            &["--replay"], Store,
+            "Instead of serving, re-run the merge captured in this file (see --capture_dir), print the merged wikitext, and exit.");
+        parser.parse_args_or_exit();
+    }
+    let (wiki_scheme, wiki_hostname, wiki_port) = match parse_wiki_address(&wiki, &wiki_scheme) {
+        Ok(address) => address,
+        Err(msg) => exit_with_usage_error(&msg),
+    };
+    let mut all_cache_rules = wiki::default_cache_rules();
+    match wiki::parse_cache_rules(&cache_rules) {
+        Ok(cache_rules) => all_cache_rules.extend(cache_rules),
+        Err(msg) => exit_with_usage_error(&msg),
+    }
+    let cache_backends = match cache::parse_cache_backends(&cache_backends) {
+        Ok(cache_backends) => cache_backends,
+        Err(msg) => exit_with_usage_error(&msg),
+    };
+    // The mobile site's pages are structured differently, so always fetch from the desktop site,
+    // and from the mobile site only for mobile requests with --mobile_requests=mobile.
+    let wiki_hostname = match wiki::get_desktop_hostname(&wiki_hostname) {
+        Some(desktop_hostname) => {
+            info!("Mirroring {} instead of mobile hostname {}", desktop_hostname, wiki_hostname);
+            desktop_hostname
+        },
+        None => wiki_hostname,
+    };
+    if wiki_profiles_dir != "" {
+        match profile::load_wiki_profile(&wiki_profiles_dir, &wiki_hostname) {
+            Ok(Some(wiki_profile)) => wiki_profile.apply(
+                &mut capital_links, &mut empty_title_page, &mut maintenance_templates,
+                &mut removal_placeholder, &mut about_page, &mut vandalism_keywords),
+            Ok(None) => warn!("No profile for {} in {}", wiki_hostname, wiki_profiles_dir),
+            Err(msg) => exit_with_usage_error(&msg),
+        }
+    }
+    let capital_links = capital_links.unwrap_or(true);
+    let empty_title_page = empty_title_page.unwrap_or("Main_Page".to_string());
+
+    let redis_connection_info = match parse_redis_address(&redis_hostname, redis_port) {
+        Ok(redis_connection_info) => redis_connection_info,
+        Err(msg) => exit_with_usage_error(&msg),
+    };
+
+    let mut event_sinks: Vec<Box<EventSink>> = Vec::new();
+    if event_log != "" {
+        match FileEventSink::new(&event_log) {
+            Ok(sink) => event_sinks.push(Box::new(sink)),
+            Err(msg) => exit_with_usage_error(&msg),
+        }
+    }
+    if event_webhook != "" {
+        event_sinks.push(Box::new(WebhookEventSink::new(event_webhook, Client::new())));
+    }
+
+    let wiki = Wiki::new(
+        wiki_scheme, wiki_hostname, wiki_port, Client::new(), user_agent, redis_connection_info,
+        WikiOptions {
+            max_revisions_scanned: max_revisions_scanned,
+            canonical_titles_file:
+                if canonical_titles_file == "" { None } else { Some(canonical_titles_file) },
+            canonical_title_ttl_seconds: canonical_title_ttl_seconds,
+            maxlag: if maxlag == 0 { None } else { Some(maxlag) },
+            maxlag_retries: maxlag_retries,
+            max_redis_connections: max_redis_connections,
+            cache_backends: cache_backends,
+            memory_cache_capacity: memory_cache_capacity,
+            revision_fetch_parallelism: revision_fetch_parallelism,
+            cache_ttl_seconds: if cache_ttl_seconds == 0 { None } else { Some(cache_ttl_seconds) },
+            api_max_retries: api_max_retries,
+            max_api_requests_per_second: max_api_requests_per_second,
+            max_redirect_depth: max_redirect_depth,
+            cache_rules: all_cache_rules,
+            format_version: api_formatversion,
+            http2: http2,
+            http2_ca_file: http2_ca_file,
+        });
+    match wiki.load_canonical_titles() {
+        Ok(count) => info!("Loaded {} canonical titles", count),
+        Err(msg) => warn!("Failed to load canonical titles: {}", msg),
+    }
+
+    let revision_watcher = watch_revisions(&wiki, live_poll_interval_ms);
+
+    let mobile_wiki = match mobile_requests {
+        Skin::Desktop => None,
+        Skin::Mobile => Some(wiki.with_hostname(wiki::get_mobile_hostname(&wiki.hostname))),
+    };
+
+    let mut merger = Merger::new(diff_size_limit, diff_time_limit_ms)
+        .with_unicode_whitespace(unicode_word_boundaries)
+        .with_atomic_literal_blocks(atomic_literal_blocks)
+        .with_lcs_max_tasks(lcs_max_tasks)
+        .with_lcs_max_queue_size(lcs_max_queue_size)
+        .with_min_similarity(min_merge_similarity)
+        .with_conflict_policy(conflict_policy)
+        .with_prefer_recent_restorations(prefer_recent_restorations)
+        .with_partial_lcs(partial_lcs_on_timeout)
+        .with_granularity(merge_granularity);
+    if diff_time_limit_ms_per_kib > 0 {
+        merger = merger.with_time_limit_scaling(TimeLimitScaling {
+            ms_per_kib: diff_time_limit_ms_per_kib,
+            min_ms: min_diff_time_limit_ms,
+        });
+    }
+
+    let section_selector = if section_selector == "" {
+        None
+    } else {
+        match Regex::new(&section_selector) {
+            Ok(section_selector) => Some(section_selector),
+            Err(err) => exit_with_usage_error(
+                &format!("Invalid --section_selector \"{}\": {}", section_selector, err)),
+        }
+    };
+    let maintenance_templates = if !ignore_maintenance_templates {
+        None
+    } else if maintenance_templates == "" {
+        Some(Arc::new(MAINTENANCE_TEMPLATES.iter().map(|name| name.to_string()).collect()))
+    } else {
+        Some(Arc::new(maintenance_templates.split(',').map(|name| name.trim().to_string())
+                      .filter(|name| !name.is_empty()).collect()))
+    };
+    let section_merge_options = SectionMergeOptions {
+        max_consecutive_diff_timeouts: max_consecutive_diff_timeouts,
+        escalate_granularity: escalate_granularity,
+        exclude_infoboxes: exclude_infoboxes,
+        skip_reference_lists: skip_reference_lists,
+        maintenance_templates: maintenance_templates.clone(),
+    };
+    if replay != "" {
+        let capture = match Capture::read(&replay) {
+            Ok(capture) => capture,
+            Err(msg) => exit_with_usage_error(&msg),
+        };
+        let merged_wikitext =
+            replay_capture(&merger, &section_merge_options, &section_selector, &capture);
+        if merged_wikitext != capture.merged_wikitext {
+            warn!("Replayed merge of \"{}\" differs from the captured merge", capture.title);
+        }
+        println!("{}", merged_wikitext);
+        return;
+    }
+
+    let about_page = match load_about_page(&about_page) {
+        Ok(about_page) => about_page,
+        Err(msg) => exit_with_usage_error(&msg),
+    };
+    let diff_hostname = if diff_tooltips { Some(wiki.hostname.clone()) } else { None };
+    if worker_threads == 0 {
+        exit_with_usage_error("--worker_threads must be at least 1");
+    }
+    let workers = WorkerPool::new("worker", worker_threads);
+    let skeleton_fetches = SkeletonFetches::new(share_skeleton_fetches, workers.clone());
+    let handler = WikipediaMinusWikipediansHandler {
+        max_consecutive_diff_timeouts: max_consecutive_diff_timeouts,
+        escalate_granularity: escalate_granularity,
+        exclude_infoboxes: exclude_infoboxes,
+        skip_reference_lists: skip_reference_lists,
+        section_selector: section_selector,
+        min_section_quality: min_section_quality,
+        maintenance_templates: maintenance_templates,
+        include_talk_page_reverts: include_talk_page_reverts,
+        target_revert_count: target_revert_count,
+        vandalism_keywords: parse_vandalism_keywords(&vandalism_keywords),
+        revert_detection: revert_detection,
+        follow_moves: follow_moves,
+        min_corroborating_reverts: min_corroborating_reverts,
+        capital_links: capital_links,
+        fragment_policy: fragment_policy,
+        empty_title_page: if empty_title_page == "" { None } else { Some(empty_title_page) },
+        emit_stats_header: emit_stats_header,
+        validate_render: validate_render,
+        render_options: RenderOptions {
+            marker_output: marker_output,
+            severity_shading: severity_shading,
+            restoration_footer: show_restoration_footer,
+            removal_placeholder: removal_placeholder,
+            max_spans: max_spans_per_page,
+            vandalism_span_style: vandalism_style,
+            diff_hostname: diff_hostname,
+            merge_timestamp: show_merge_timestamp,
+            inject_toggle: inject_toggle,
+        },
+        skeleton_fetches: skeleton_fetches,
+        page_cache: PageCache::new(if page_cache { page_cache_size } else { 0 }),
+        page_cache_ttl_seconds: page_cache_ttl_seconds,
+        section_cache: PageCache::new(section_cache_size),
+        merge_limiter: ConcurrencyLimiter::new(max_concurrent_merges),
+        max_response_bytes: max_response_bytes,
+        mobile_wiki: mobile_wiki,
+        capture_dir: if capture_dir == "" { None } else { Some(capture_dir) },
+        capture_titles: capture_titles.split(',').filter(|title| !title.is_empty())
+            .map(|title| wiki::normalize_title(title, capital_links)).collect(),
+        snapshots: if snapshot_dir == "" {
+            None
+        } else {
+            Some(SnapshotStore::new(snapshot_dir, max_snapshots))
+        },
+        about_page: about_page,
+        healthz_checks_upstream: healthz_checks_upstream,
+        inflight_bytes: MemoryAccountant::new(max_inflight_bytes),
+        revision_watcher: revision_watcher,
+        live_timeout_ms: live_timeout_ms,
+        live_restored_content: PageCache::new(LIVE_RESTORED_CONTENT_SIZE),
+        live_request_limiter: ConcurrencyLimiter::new(max_live_requests),
+        ..WikipediaMinusWikipediansHandler::new(wiki, Client::new(), merger,
+                                                EventLogger::new(event_sinks), workers)
+    };
+    Iron::new(handler).http(("0.0.0.0", port)).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SectionMergeOptions, SectionMerger, WikipediaMinusWikipediansHandler,
+                DEFAULT_ABOUT_PAGE, deduplicate_section_titles,
+                count_render_errors, format_merge_stats_header, format_multipart,
+                format_provenance_links, get_empty_title_response, get_error_response,
+                get_fragment_redirect_response, get_low_quality_sections, get_metrics_response,
+                get_about_response, get_passthrough_url, get_restored_revids, get_query_parameter,
+                get_requested_title, get_page_cache_key, get_section_cache_key,
+                get_section_fingerprint,
+                is_about_request, is_healthz_request, is_mobile_request, is_passthrough_query,
+                is_top_level_request,
+                is_antivandalism_revision, is_reference_list, parse_vandalism_keywords,
+                get_revision_source_titles, is_blank_article, keep_corroborated_changes,
+                limit_response_size, load_about_page, parse_redis_address, parse_wiki_address,
+                remove_excluded_templates, replay_capture, revert_sections_with_render_errors,
+                select_sections,
+                strip_markers_from_headings};
+    use capture::{Capture, CapturedRevision};
+    use config::Config;
+    use error::AppError;
+    use events::EventLogger;
+    use hyper::Client;
+    use iron;
+    use iron::Url;
+    use iron::headers::ContentType;
+    use iron::mime::{Attr, Mime, SubLevel, TopLevel, Value};
+    use std::collections::{HashMap, HashSet};
+    use std::io::Write;
+    use std::sync::Arc;
+    use json;
+    use json::JsonPathElement::Key;
+    use limiter::{ConcurrencyLimiter, MemoryAccountant};
+    use live::RevisionWatcher;
+    use metrics::{Counter, metrics};
+    use page::Skin;
+    use page_cache::PageCache;
+    use regex::Regex;
+    use rustc_serialize::json::Json;
+    use rustc_serialize::json::encode as encode_json;
+    use snapshot::SnapshotStore;
+    use tempdir::TempDir;
+    use tempfile::NamedTempFile;
+    use test_server::{content_response, http_response, revisions_response, serve_responses};
+    use wiki;
+    use wiki::Revision;
+    use wiki::Wiki;
+    use wiki::WikiOptions;
+    use wikipedia_minus_wikipedians::{END_MARKER, REMOVAL_MARKER, START_MARKER,
+                                      TITLE_COUNT_SEPARATOR};
+    use wikipedia_minus_wikipedians::MergeStats;
+    use wikipedia_minus_wikipedians::MergedArticle;
+    use wikipedia_minus_wikipedians::MergedSection;
+    use wikipedia_minus_wikipedians::Merger;
+    use worker_pool::WorkerPool;
+
+    #[test]
+    fn test_deduplicate_section_titles() {
+        let input = vec![("title1".to_owned(), "content1".to_owned()),
+                         ("title1".to_owned(), "content2".to_owned()),
+                         ("title2".to_owned(), "content3".to_owned()),
+                         ("title1".to_owned(), "content4".to_owned())];
+        let expected = vec![(format!("title1{}1", TITLE_COUNT_SEPARATOR), "content1".to_owned()),
+                            (format!("title1{}2", TITLE_COUNT_SEPARATOR), "content2".to_owned()),
+                            (format!("title2{}1", TITLE_COUNT_SEPARATOR), "content3".to_owned()),
+                            (format!("title1{}3", TITLE_COUNT_SEPARATOR), "content4".to_owned())];
+        assert_eq!(expected, deduplicate_section_titles(input));
+    }
+
+    #[test]
+    fn test_get_low_quality_sections() {
+        let section = |title: &str, stats: MergeStats| MergedSection {
+            title: title.to_string(),
+            content: "".to_string(),
+            stats: stats,
+        };
+        let merged_article = MergedArticle {
+            sections: vec![
+                section("Clean", MergeStats { sections_merged: 1, clean_chunks: 4,
+                                              stable_tokens: 90, old_tokens: 100,
+                                              ..MergeStats::default() }),
+                section("Garbled", MergeStats { sections_merged: 1, clean_chunks: 1, conflicts: 3,
+                                                stable_tokens: 20, old_tokens: 100,
+                                                ..MergeStats::default() }),
+                // Nothing was merged into this one.
+                section("Unmerged", MergeStats::default()),
+            ],
+        };
+        assert_eq!(vec!["Garbled"],
+                   get_low_quality_sections(&merged_article, 0.5).iter()
+                       .map(|section| &section.title[..]).collect::<Vec<_>>());
+        assert!(get_low_quality_sections(&merged_article, 0.0).is_empty());
+    }
+
+    #[test]
+    fn test_format_merge_stats_header() {
+        let stats =
+            MergeStats { sections_merged: 4, conflicts: 2, timeouts: 1, restored_bytes: 100,
+                         clean_chunks: 6, stable_tokens: 50, old_tokens: 100,
+                         ..MergeStats::default() };
+        let header = Json::from_str(&format_merge_stats_header(&stats, 1234)).unwrap();
+        assert_eq!(Some(0.375), header.find("quality").and_then(|quality| quality.as_f64()));
+        assert_eq!(Ok(4), json::get_json_number(&header, &[Key("sections_merged")]));
+        assert_eq!(Ok(2), json::get_json_number(&header, &[Key("conflicts")]));
+        assert_eq!(Ok(1), json::get_json_number(&header, &[Key("timeouts")]));
+        assert_eq!(Ok(1234), json::get_json_number(&header, &[Key("total_ms")]));
+    }
+
+    #[test]
+    fn test_parse_wiki_address() {
+        assert_eq!(Ok(("https".to_string(), "en.wikipedia.org".to_string(), 443)),
+                   parse_wiki_address("en.wikipedia.org", "https"));
+        assert_eq!(Ok(("http".to_string(), "localhost".to_string(), 80)),
+                   parse_wiki_address("localhost", "http"));
+        assert_eq!(Ok(("https".to_string(), "localhost".to_string(), 8443)),
+                   parse_wiki_address("localhost:8443", "https"));
+        assert_eq!(Ok(("http".to_string(), "localhost".to_string(), 8080)),
+                   parse_wiki_address("http://localhost:8080", "https"));
+    }
+
+    #[test]
+    fn test_parse_wiki_address_malformed() {
+        assert!(parse_wiki_address("localhost:notaport", "https").unwrap_err()
+                .starts_with("Invalid port \"notaport\" in wiki address \"localhost:notaport\""));
+        assert!(parse_wiki_address("localhost:99999", "https").is_err());
+        assert!(parse_wiki_address("localhost:", "https").is_err());
+        assert_eq!(Err("Invalid hostname \"\" in wiki address \":8080\"".to_string()),
+                   parse_wiki_address(":8080", "https"));
+        assert!(parse_wiki_address("localhost:80:80", "https").is_err());
+        assert!(parse_wiki_address("ftp://localhost", "https").is_err());
+        assert!(parse_wiki_address("http://localhost/wiki", "https").is_err());
+    }
+
+    #[test]
+    fn test_parse_redis_address() {
+        assert!(parse_redis_address("", 6379).unwrap().is_none());
+        assert!(parse_redis_address("localhost", 6379).unwrap().is_some());
+        assert!(parse_redis_address("localhost:6379", 6379).is_err());
+        assert!(parse_redis_address("redis://localhost", 6379).is_err());
+        assert!(parse_redis_address("localhost", 0).is_err());
+    }
+
+    #[test]
+    fn test_is_blank_article() {
+        assert!(is_blank_article(&[]));
+        assert!(is_blank_article(&wiki::parse_sections("")));
+        assert!(is_blank_article(&wiki::parse_sections(" \n\t\n")));
+        assert!(!is_blank_article(&wiki::parse_sections("asdf")));
+        assert!(!is_blank_article(&wiki::parse_sections("\n==test section==\n")));
+    }
+
+    #[test]
+    fn test_strip_markers_from_headings() {
+        let original = "Intro\n==History==\nText\n";
+        let merged = format!("Intro\n=={}12{}His{}12{}tory{}3==\nText\n",
+                             START_MARKER, START_MARKER, END_MARKER, END_MARKER,
+                             TITLE_COUNT_SEPARATOR);
+        let stripped = strip_markers_from_headings(&merged);
+        assert_eq!(original, stripped);
+        // The heading, and so its anchor, is unchanged by the merge.
+        assert_eq!(wiki::parse_sections(original)[1].0, wiki::parse_sections(&stripped)[1].0);
+    }
+
+    #[test]
+    fn test_strip_markers_from_headings_keeps_markers_balanced() {
+        let merged = format!("Intro{}5{}\n=={}7{}Hist{}5{}ory==\nText{}7{}\n",
+                             START_MARKER, START_MARKER, START_MARKER, START_MARKER,
+                             END_MARKER, END_MARKER, END_MARKER, END_MARKER);
+        let expected = format!("Intro{}5{}{}5{}\n==History==\n{}7{}Text{}7{}\n",
+                               START_MARKER, START_MARKER, END_MARKER, END_MARKER,
+                               START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        assert_eq!(expected, strip_markers_from_headings(&merged));
+    }
+
+    #[test]
+    fn test_remove_excluded_templates_restores_only_body_vandalism() {
+        let current = "{{Infobox country|capital=Paris}} France is a country.";
+        let clean = "{{Infobox country|capital=Paris}} France is a country.".to_string();
+        let vandalized = "{{Infobox country|capital=Lyon}} France is a big country.".to_string();
+        let (clean, vandalized) = remove_excluded_templates(clean, vandalized);
+        let (merged, timed_out) =
+            Merger::new(1000, 500).try_merge(&clean, current, &vandalized, "1");
+        assert!(!timed_out);
+        assert!(merged.contains("capital=Paris"));
+        assert!(!merged.contains("Lyon"));
+        assert!(merged.contains("big"));
+    }
+
+    /// Returns the default options for merging into a section.
+    fn section_merge_options() -> SectionMergeOptions {
+        SectionMergeOptions {
+            max_consecutive_diff_timeouts: 3,
+            escalate_granularity: false,
+            exclude_infoboxes: false,
+            skip_reference_lists: false,
+            maintenance_templates: None,
+        }
+    }
+
+    /// Merges the vandalism reverted from `vandalized` back to `clean` into the section `current`,
+    /// with `options`, and returns the merged section.
+    fn merge_section(options: SectionMergeOptions, current: &str, clean: &str, vandalized: &str)
+                     -> String {
+        let mut section_merger =
+            SectionMerger::new(Merger::new(1000, 500), options, current.to_string());
+        section_merger.merge(clean.to_string(), vandalized.to_string(), 1);
+        section_merger.finish().0
+    }
+
+    #[test]
+    fn test_reference_list_reordering_is_not_restored() {
+        let merge = |skip_reference_lists, current: &str, clean: &str, vandalized: &str| {
+            let options = SectionMergeOptions { skip_reference_lists: skip_reference_lists,
+                                                ..section_merge_options() };
+            merge_section(options, current, clean, vandalized)
+        };
+        let current = "== References ==\n{{Reflist|30em}}\n* Smith 2001.\n* Jones 1999.\n";
+        let reordered = "== References ==\n{{Reflist|30em}}\n* Jones 1999.\n* Smith 2001.\n";
+        assert!(merge(false, current, current, reordered).contains(START_MARKER));
+        assert_eq!(current, merge(true, current, current, reordered));
+
+        // Other sections are merged as usual.
+        let current = "== History ==\nFoxes jump.<ref>Smith 2001.</ref>\n";
+        let vandalized = "== History ==\nFoxes jump high.<ref>Smith 2001.</ref>\n";
+        assert!(merge(true, current, current, vandalized).contains("high"));
+    }
+
+    #[test]
+    fn test_select_sections() {
+        let sections = vec![("".to_string(), "Lead".to_string()),
+                            ("History".to_string(), "== History ==".to_string()),
+                            ("Controversy".to_string(), "== Controversy ==".to_string()),
+                            ("Controversy 2".to_string(), "== Controversy 2 ==".to_string())];
+        let (selected_sections, unselected_sections) =
+            select_sections(&sections, &Some(Regex::new("^(|Controversy)$").unwrap()));
+        assert_eq!(vec![sections[0].clone(), sections[2].clone()], selected_sections);
+        assert_eq!(2, unselected_sections.len());
+        assert_eq!(("== History ==".to_string(), MergeStats::default()),
+                   unselected_sections["History"]);
+        assert_eq!(("== Controversy 2 ==".to_string(), MergeStats::default()),
+                   unselected_sections["Controversy 2"]);
+
+        let (selected_sections, unselected_sections) = select_sections(&sections, &None);
+        assert_eq!(sections, selected_sections);
+        assert!(unselected_sections.is_empty());
+    }
+
+    #[test]
+    fn test_is_reference_list() {
+        assert!(is_reference_list("== Notes ==\n<references />\n"));
+        assert!(is_reference_list("== References ==\n{{reflist}}\n"));
+        assert!(is_reference_list("== References ==\n{{ Reflist | colwidth=30em }}\n"));
+        assert!(!is_reference_list("== Reflistings ==\n{{Reflistings}}\n"));
+        assert!(!is_reference_list("Foxes jump.<ref>Smith 2001.</ref>"));
+    }
+
+    #[test]
+    fn test_maintenance_template_changes_are_not_restored() {
+        let merge = |maintenance_templates: Option<Vec<String>>, current: &str, clean: &str,
+                     vandalized: &str| {
+            let options = SectionMergeOptions {
+                maintenance_templates: maintenance_templates.map(Arc::new),
+                ..section_merge_options()
+            };
+            merge_section(options, current, clean, vandalized)
+        };
+        let names = Some(vec!["Citation needed".to_string()]);
+
+        // A reverted edit that only added a tag.
+        let current = "The sky is green. It is.";
+        let vandalized = "The sky is green.{{citation needed}} It is.";
+        assert!(merge(None, current, current, vandalized).contains("{{citation needed}}"));
+        assert_eq!(current, merge(names.clone(), current, current, vandalized));
+
+        // A reverted edit that removed a tag, and vandalized the text.
+        let current = "The sky is green.{{citation needed}} It is.";
+        let vandalized = "The sky is green. It is not.";
+        let merged = merge(names, current, current, vandalized);
+        assert!(merged.contains("{{citation needed}}"));
+        assert!(merged.contains("not."));
+    }
+
+    #[test]
+    fn test_get_requested_title() {
+        fn path(segments: &[&str]) -> Vec<String> {
+            segments.iter().map(|segment| segment.to_string()).collect()
+        }
+        assert_eq!(Some("Foo"), get_requested_title(&path(&["wiki", "Foo"])));
+        assert_eq!(Some("Foo"), get_requested_title(&path(&["wiki", "Foo", ""])));
+        assert_eq!(Some("Foo"), get_requested_title(&path(&["wiki", "Foo", "", ""])));
+        assert_eq!(None, get_requested_title(&path(&["wiki", ""])));
+        assert_eq!(None, get_requested_title(&path(&["wiki", "Foo", "Bar"])));
+        assert_eq!(None, get_requested_title(&path(&["w", "index.php"])));
+    }
+
+    #[test]
+    fn test_is_about_request() {
+        fn path(segments: &[&str]) -> Vec<String> {
+            segments.iter().map(|segment| segment.to_string()).collect()
+        }
+        assert!(is_about_request(&path(&["about"])));
+        assert!(is_about_request(&path(&["about", ""])));
+        assert!(!is_about_request(&path(&["wiki", "about"])));
+        assert!(!is_about_request(&path(&["about", "Foo"])));
+        assert!(is_healthz_request(&path(&["healthz"])));
+        assert!(!is_healthz_request(&path(&["wiki", "healthz"])));
+        // Requests without a page title.
+        assert!(is_top_level_request(&path(&["wiki"]), "wiki"));
+        assert!(is_top_level_request(&path(&["wiki", ""]), "wiki"));
+        assert!(!is_top_level_request(&path(&["wiki", "Foo"]), "wiki"));
+    }
+
+    #[test]
+    fn test_healthz_response() {
+        let mut handler = test_handler();
+        let response = handler.get_healthz_response();
+        assert_eq!(Some(iron::status::Ok), response.status);
+        assert_eq!(Some(&ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![]))),
+                   response.headers.get::<ContentType>());
+
+        // The test handler's wiki is unreachable.
+        handler.healthz_checks_upstream = true;
+        assert_eq!(Some(iron::status::ServiceUnavailable),
+                   handler.get_healthz_response().status);
+
+        let body = r#"{"batchcomplete":"","query":{"general":{"sitename":"Wikipedia"}}}"#;
+        let (port, _) = serve_responses(vec![http_response("", body)]);
+        handler.wiki = local_wiki(port);
+        assert_eq!(Some(iron::status::Ok), handler.get_healthz_response().status);
+    }
+
+    /// Returns a Wiki on the fake wiki (see `test_server`) at `port`.
+    fn local_wiki(port: u16) -> Wiki {
+        Wiki::new("http".to_string(), "127.0.0.1".to_string(), port, Client::new(),
+                  "test-agent/1.0".to_string(), None, WikiOptions::default())
+    }
+
+    #[test]
+    fn test_about_page() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "<html>About this mirror</html>").unwrap();
+        let about_page = load_about_page(file.path().to_str().unwrap()).unwrap();
+        assert_eq!("<html>About this mirror</html>", about_page);
+        assert_eq!(Ok(DEFAULT_ABOUT_PAGE.to_string()), load_about_page(""));
+
+        let response = get_about_response(&about_page);
+        assert_eq!(Some(iron::status::Ok), response.status);
+        assert_eq!(Some(&ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![]))),
+                   response.headers.get::<ContentType>());
+    }
+
+    #[test]
+    fn test_empty_title_response() {
+        let response = get_empty_title_response(&Some("Main_Page".to_string()));
+        assert_eq!(Some(iron::status::Found), response.status);
+        assert_eq!(Some(&[b"/wiki/Main_Page".to_vec()][..]), response.headers.get_raw("Location"));
+
+        let response = get_empty_title_response(&None);
+        assert_eq!(Some(iron::status::BadRequest), response.status);
+        assert!(response.headers.get_raw("Location").is_none());
+    }
+
+    #[test]
+    fn test_error_responses() {
+        assert_eq!(Some(iron::status::NotFound),
+                   get_error_response(&AppError::PageNotFound).status);
+        assert_eq!(Some(iron::status::ServiceUnavailable),
+                   get_error_response(&AppError::Overloaded).status);
+        assert_eq!(Some(iron::status::BadGateway),
+                   get_error_response(&AppError::Upstream("API error".to_string())).status);
+        let response = get_error_response(&AppError::Internal("Bad HTML".to_string()));
+        assert_eq!(Some(iron::status::InternalServerError), response.status);
+        assert_eq!(Some(&ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![]))),
+                   response.headers.get::<ContentType>());
+    }
+
+    #[test]
+    fn test_missing_page_is_not_found() {
+        let mut handler = test_handler();
+        let body = r#"{"query":{"pages":{"-1":{"ns":0,"title":"Foo","missing":""}}}}"#;
+        let (port, _) = serve_responses(vec![http_response("", body)]);
+        handler.wiki = local_wiki(port);
+        assert_eq!(Err(AppError::PageNotFound), handler.get_page("Foo", Skin::Desktop));
+    }
+
+    #[test]
+    fn test_stale_snapshot_served_when_wiki_is_unreachable() {
+        let dir = TempDir::new("snapshots").unwrap();
+        let snapshots = SnapshotStore::new(dir.path().to_str().unwrap().to_string(), 0);
+        snapshots.save("Foo", "<html><body>Foo</body></html>").unwrap();
+        let mut handler = test_handler();
+        handler.snapshots = Some(snapshots);
+        let upstream_error = AppError::Upstream("Error calling Wikimedia API".to_string());
+
+        // The test handler's wiki is unreachable.
+        let response =
+            handler.get_stale_snapshot_response("Foo", Skin::Desktop, &upstream_error).unwrap();
+        assert_eq!(Some(iron::status::Ok), response.status);
+        assert_eq!(Some(&[b"110 - \"Response is Stale\"".to_vec()][..]),
+                   response.headers.get_raw("Warning"));
+        assert!(handler.get_stale_snapshot_response("Bar", Skin::Desktop, &upstream_error)
+                .is_none());
+        assert!(handler.get_stale_snapshot_response("Foo", Skin::Desktop, &AppError::PageNotFound)
+                .is_none());
+    }
+
+    #[test]
+    fn test_fragment_redirect_response() {
+        let response = get_fragment_redirect_response("Foo", "History", &None);
+        assert_eq!(Some(iron::status::Found), response.status);
+        assert_eq!(Some(&[b"/wiki/Foo#History".to_vec()][..]),
+                   response.headers.get_raw("Location"));
+
+        let response =
+            get_fragment_redirect_response("Foo", "History", &Some("view=review".to_string()));
+        assert_eq!(Some(&[b"/wiki/Foo?view=review#History".to_vec()][..]),
+                   response.headers.get_raw("Location"));
+    }
+
+    #[test]
+    fn test_get_revision_source_titles() {
+        assert_eq!(vec!["Foo".to_string()], get_revision_source_titles("Foo", false));
+        assert_eq!(vec!["Foo".to_string(), "Talk%3AFoo".to_string()],
+                   get_revision_source_titles("Foo", true));
+        assert_eq!(vec!["Talk%3AFoo".to_string()], get_revision_source_titles("Talk%3AFoo", true));
+    }
+
+    #[test]
+    fn test_keep_corroborated_changes() {
+        fn revision(revid: u64, user: &str) -> Revision {
+            Revision { revid: revid, parentid: revid - 1, comment: "rv vandalism".to_string(),
+                       user: user.to_string(), tags: Vec::new(), sha1: None }
+        }
+        let clean = "The fox jumped over the dog.";
+        // Two users reverted "quick", but only one reverted "lazy".
+        let revisions = vec![
+            (clean.to_string(), "The quick fox jumped over the dog.".to_string(), revision(3, "A")),
+            (clean.to_string(), "The quick fox jumped over the dog.".to_string(), revision(5, "B")),
+            (clean.to_string(), "The fox jumped over the lazy dog.".to_string(), revision(7, "C")),
+            (clean.to_string(), "The fox jumped over the lazy dog.".to_string(), revision(9, "C"))];
+        let filtered = keep_corroborated_changes(&Merger::new(1000, 500), revisions, 2);
+        let vandalized_contents = filtered.iter().map(|&(_, ref vandalized, _)| &vandalized[..])
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["The quick fox jumped over the dog.", "The quick fox jumped over the dog.",
+                        clean, clean],
+                   vandalized_contents);
+    }
+
+    #[test]
+    fn test_count_render_errors() {
+        assert_eq!(0, count_render_errors("<p>Fine.</p>"));
+        assert_eq!(2, count_render_errors(
+            "<span class=\"error mw-ext-cite-error\">Cite error</span> <strong class=\"error\">\
+             Expression error</strong>"));
+    }
+
+    #[test]
+    fn test_render_errors_revert_sections() {
+        // Renders "BROKEN" as a Lua error, like a module choking on a broken invocation would.
+        let render = |wikitext: &str| -> Result<String, String> {
+            Ok(format!("<p>{}</p>", wikitext.replace(
+                "BROKEN", "<strong class=\"error\"><span class=\"scribunto-error\">Lua error\
+                           </span></strong>")))
+        };
+        let unmerged_sections = vec![("".to_string(), "Intro ".to_string()),
+                                     ("History".to_string(), "== History ==\nFine".to_string()),
+                                     ("Legacy".to_string(), "== Legacy ==\nBROKEN".to_string())];
+        let section = |title: &str, content: String| {
+            MergedSection {
+                title: title.to_string(),
+                content: content,
+                stats: MergeStats { restored_bytes: 6, ..MergeStats::default() },
+            }
+        };
+        let mut sections = vec![
+            // This restoration breaks the section.
+            section("", format!("Intro {}1{}BROKEN{}1{}", START_MARKER, START_MARKER, END_MARKER,
+                                END_MARKER)),
+            section("History", format!("== History ==\nFine {}1{}enough{}1{}", START_MARKER,
+                                       START_MARKER, END_MARKER, END_MARKER)),
+            // This section was already broken, so the restoration isn't to blame.
+            section("Legacy", format!("== Legacy ==\nBROKEN {}1{}more{}1{}", START_MARKER,
+                                      START_MARKER, END_MARKER, END_MARKER)),
+        ];
+        let expected_sections = vec![
+            MergedSection {
+                title: "".to_string(),
+                content: "Intro ".to_string(),
+                stats: MergeStats::default(),
+            },
+            sections[1].clone(),
+            sections[2].clone(),
+        ];
+        assert_eq!(Ok(true),
+                   revert_sections_with_render_errors(&mut sections, &unmerged_sections, &render));
+        assert_eq!(expected_sections, sections);
+
+        // Nothing more to revert.
+        assert_eq!(Ok(false),
+                   revert_sections_with_render_errors(&mut sections, &unmerged_sections, &render));
+    }
+
+    #[test]
+    fn test_provenance_links() {
+        let wikitext = format!("Foo {}12{}bar{}12{} baz {}34{}{}{}34{}{} {}12{}qux{}12{}",
+                               START_MARKER, START_MARKER, END_MARKER, END_MARKER,
+                               START_MARKER, REMOVAL_MARKER, START_MARKER,
+                               END_MARKER, REMOVAL_MARKER, END_MARKER,
+                               START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        let revids = get_restored_revids(&wikitext);
+        assert_eq!(vec![12, 34], revids);
+        assert_eq!(vec![b"</w/index.php?diff=12>; rel=\"vandalism-source\"".to_vec(),
+                        b"</w/index.php?diff=34>; rel=\"vandalism-source\"".to_vec()],
+                   format_provenance_links(&revids));
+        assert!(get_restored_revids("Nothing restored.").is_empty());
+    }
+
+    #[test]
+    fn test_cached_pages_are_served_when_merges_are_saturated() {
+        let mut handler = test_handler();
+        handler.wiki = wiki_with_latest_revision(12, 1);
+        handler.merge_limiter = ConcurrencyLimiter::new(1);
+        let page = ("<html>cached</html>".to_string(), MergeStats::default(), vec![]);
+        handler.cache_page(&get_page_cache_key("Foo", 12, Skin::Desktop), &page);
+        let _permit = handler.merge_limiter.acquire();
+        assert!(handler.merge_limiter.try_acquire().is_none());
+        assert_eq!(Ok(page), handler.get_page("Foo", Skin::Desktop));
+    }
+
+    #[test]
+    fn test_merge_jobs_are_bounded_by_worker_threads() {
+        let mut handler = test_handler();
+        handler.workers = WorkerPool::new("test-worker", 2);
+        let sections = (0..20).map(|i| (format!("Section {}", i), format!("Content {}", i)));
+        let (jobs, receivers) = handler.start_merge_jobs("Foo", sections);
+        // As when merging a page, every job is submitted before any output is waited for.
+        for (_, job) in jobs {
+            job.submit();
+        }
+        for (section_title, receiver) in receivers {
+            let (content, _) = receiver.recv().unwrap();
+            assert_eq!(section_title.replace("Section", "Content"), content);
+        }
+        assert!(handler.workers.peak_busy_workers() <= 2);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let page_cache_hits = metrics().get(Counter::PageCacheHits);
+        let mut handler = test_handler();
+        handler.wiki = wiki_with_latest_revision(12, 2);
+        let page = ("<html>cached</html>".to_string(), MergeStats::default(), vec![]);
+        handler.cache_page(&get_page_cache_key("Foo", 12, Skin::Desktop), &page);
+        assert_eq!(Ok(page.clone()), handler.get_page("Foo", Skin::Desktop));
+        assert_eq!(Ok(page), handler.get_page("Foo", Skin::Desktop));
+        // Other tests run concurrently, and may move the counter too.
+        assert!(metrics().get(Counter::PageCacheHits) >= page_cache_hits + 2);
+
+        let response = get_metrics_response();
+        assert_eq!(Some(iron::status::Ok), response.status);
+        assert_eq!(Some(&ContentType(Mime(TopLevel::Text, SubLevel::Plain,
+                                          vec![(Attr::Ext("version".to_string()),
+                                                Value::Ext("0.0.4".to_string()))]))),
+                   response.headers.get::<ContentType>());
+    }
+
+    #[test]
+    fn test_requests_are_rejected_when_too_much_content_is_in_flight() {
+        let mut handler = test_handler();
+        handler.wiki = wiki_with_latest_revision(12, 2);
+        handler.inflight_bytes = MemoryAccountant::new(1000);
+        let page = ("<html>cached</html>".to_string(), MergeStats::default(), vec![]);
+        handler.cache_page(&get_page_cache_key("Foo", 12, Skin::Desktop), &page);
+
+        let _reservation = handler.inflight_bytes.reserve(5000);
+        assert_eq!(Ok(page), handler.get_page("Foo", Skin::Desktop));
+        // The mobile page isn't cached, so it would have to be merged.
+        assert_eq!(Err(AppError::Overloaded), handler.get_page("Foo", Skin::Mobile));
+    }
+
+    #[test]
+    fn test_live_update_requests_are_rejected_when_overloaded() {
+        let mut handler = test_handler();
+        handler.live_request_limiter = ConcurrencyLimiter::new(1);
+        let permit = handler.live_request_limiter.acquire();
+        assert_eq!(Err(AppError::Overloaded), handler.get_live_update("Foo", Some(12)));
+        drop(permit);
+
+        handler.wiki = wiki_with_latest_revision(12, 0);
+        handler.inflight_bytes = MemoryAccountant::new(1000);
+        let _reservation = handler.inflight_bytes.reserve(5000);
+        assert_eq!(Err(AppError::Overloaded), handler.get_live_update("Foo", None));
+    }
+
+    #[test]
+    fn test_review_and_live_error_responses() {
+        // The test handler's wiki is unreachable.
+        let mut handler = test_handler();
+        assert_eq!(Some(iron::status::BadGateway), handler.get_review_response("Foo").status);
+        assert_eq!(Some(iron::status::BadGateway), handler.get_live_response("Foo", &None).status);
+
+        let body = r#"{"query":{"pages":{"-1":{"ns":0,"title":"Foo","missing":""}}}}"#;
+        let (port, _) = serve_responses(vec![http_response("", body), http_response("", body)]);
+        handler.wiki = local_wiki(port);
+        assert_eq!(Some(iron::status::NotFound), handler.get_review_response("Foo").status);
+        assert_eq!(Some(iron::status::NotFound), handler.get_live_response("Foo", &None).status);
+
+        handler.wiki = wiki_with_latest_revision(12, 0);
+        handler.inflight_bytes = MemoryAccountant::new(1000);
+        let _reservation = handler.inflight_bytes.reserve(5000);
+        assert_eq!(Some(iron::status::ServiceUnavailable),
+                   handler.get_review_response("Foo").status);
+        handler.live_request_limiter = ConcurrencyLimiter::new(1);
+        let _permit = handler.live_request_limiter.acquire();
+        assert_eq!(Some(iron::status::ServiceUnavailable),
+                   handler.get_live_response("Foo", &None).status);
+    }
+
+    #[test]
+    fn test_page_cache() {
+        let mut handler = test_handler();
+        let page = ("<html>cached</html>".to_string(), MergeStats::default(), vec![]);
+        let key = get_page_cache_key("Foo", 123, Skin::Desktop);
+        handler.cache_page(&key, &page);
+        assert_eq!(Some(page.clone()), handler.get_cached_page(&key));
+        // A new revision, or the other skin, misses.
+        assert_eq!(None, handler.get_cached_page(&get_page_cache_key("Foo", 124, Skin::Desktop)));
+        assert_eq!(None, handler.get_cached_page(&get_page_cache_key("Foo", 123, Skin::Mobile)));
+
+        // Expired pages aren't served.
+        handler.page_cache_ttl_seconds = 0;
+        handler.cache_page(&key, &page);
+        assert_eq!(None, handler.get_cached_page(&key));
+    }
+
+    /// Returns a Wiki on a fake wiki (see `test_server`) where the latest revision of "Foo" is
+    /// `revid`, for `requests` requests for the page: the first also resolves its canonical title.
+    fn wiki_with_latest_revision(revid: u64, requests: usize) -> Wiki {
+        let mut responses =
+            vec![revisions_response(&[revid], "Edit", None), content_response(revid, "Text")];
+        responses.extend((0..requests).map(|_| revisions_response(&[revid], "Edit", None)));
+        local_wiki(serve_responses(responses).0)
+    }
+
+    /// Returns a handler for an unreachable wiki, so that only cached pages can be served.
+    fn test_handler() -> WikipediaMinusWikipediansHandler {
+        // Unreachable, so only cached pages can be served.
+        let wiki = Wiki::new("http".to_string(), "wiki.invalid".to_string(), 80, Client::new(),
+                             "test-agent/1.0".to_string(), None, WikiOptions::default());
+        WikipediaMinusWikipediansHandler {
+            page_cache: PageCache::new(10),
+            page_cache_ttl_seconds: 60,
+            section_cache: PageCache::new(10),
+            revision_watcher: RevisionWatcher::new(10, Box::new(|_: &str| Ok(1))),
+            live_timeout_ms: 1000,
+            live_restored_content: PageCache::new(10),
+            live_request_limiter: ConcurrencyLimiter::new(0),
+            ..WikipediaMinusWikipediansHandler::new(wiki, Client::new(), Merger::new(1000, 500),
+                                                    EventLogger::new(vec![]),
+                                                    WorkerPool::new("test-worker", 4))
+        }
+    }
+
+    #[test]
+    fn test_limit_response_size() {
+        fn fallback() -> Result<String, String> {
+            Ok("<html>unmodified</html>".to_string())
+        }
+        assert_eq!(Ok("<html>merged</html>".to_string()),
+                   limit_response_size("<html>merged</html>".to_string(), 100, fallback));
+        assert_eq!(Ok("<html>unmodified</html>".to_string()),
+                   limit_response_size("<html>merged</html>".to_string(), 10, fallback));
+        // 0 means no limit.
+        assert_eq!(Ok("<html>merged</html>".to_string()),
+                   limit_response_size("<html>merged</html>".to_string(), 0, fallback));
+    }
+
+    #[test]
+    fn test_format_multipart() {
+        let expected_parts =
+            [("clean", "<html>clean</html>"), ("restored", "<html>restored</html>")];
+        let body = format_multipart("BOUNDARY", &expected_parts);
+        assert!(body.starts_with("--BOUNDARY\r\n"));
+        assert!(body.ends_with("\r\n--BOUNDARY--\r\n"));
+        let parts = body["--BOUNDARY\r\n".len()..body.len() - "\r\n--BOUNDARY--\r\n".len()]
+            .split("\r\n--BOUNDARY\r\n").collect::<Vec<_>>();
+        assert_eq!(2, parts.len());
+        for (part, &(name, html)) in parts.iter().zip(expected_parts.iter()) {
+            let (headers, part_body) = part.split_at(part.find("\r\n\r\n").unwrap());
+            assert!(headers.contains("Content-Type: text/html; charset=utf-8"));
+            assert!(headers.contains(&format!("Content-Disposition: inline; name=\"{}\"", name)));
+            assert_eq!(html, &part_body[4..]);
+        }
+    }
+
+    #[test]
+    fn test_get_query_parameter() {
+        let query = Some("action=view&format=multipart".to_string());
+        assert_eq!(Some("multipart".to_string()), get_query_parameter(&query, "format"));
+        assert_eq!(None, get_query_parameter(&query, "oldid"));
+        assert_eq!(None, get_query_parameter(&None, "format"));
+    }
+
+    #[test]
+    fn test_is_mobile_request() {
+        assert!(is_mobile_request("en.m.wikipedia.org", &None));
+        assert!(is_mobile_request("en.wikipedia.org", &Some("amp=1".to_string())));
+        assert!(!is_mobile_request("en.wikipedia.org", &None));
+        assert!(!is_mobile_request("en.wikipedia.org", &Some("format=multipart".to_string())));
+    }
+
+    #[test]
+    fn test_captured_merge_replays_to_same_output() {
+        let intro_title = format!("{}1", TITLE_COUNT_SEPARATOR);
+        let mut capture = Capture {
+            title: "Foo".to_string(),
+            latest_sections: vec![
+                (intro_title.clone(), "The fox jumped over the dog.\n".to_string()),
+                (format!("History{}1", TITLE_COUNT_SEPARATOR),
+                 "==History==\nFoxes jump.\n".to_string())],
+            revisions: vec![CapturedRevision {
+                section_title: intro_title,
+                clean_content: "The fox jumped over the dog.\n".to_string(),
+                vandalized_content: "The fox jumped over the lazy dog.\n".to_string(),
+                revid: 123,
+            }],
+            merged_wikitext: "".to_string(),
+        };
+        let merger = Merger::new(1000, 500);
+        let options = section_merge_options();
+        capture.merged_wikitext = replay_capture(&merger, &options, &None, &capture);
+        assert!(capture.merged_wikitext.contains("lazy"));
+        assert!(capture.merged_wikitext.ends_with("==History==\nFoxes jump.\n"));
+
+        let dir = TempDir::new("captures").unwrap();
+        let path = capture.write_to_dir(dir.path().to_str().unwrap()).unwrap();
+        let replayed_capture = Capture::read(&path).unwrap();
+        assert_eq!(capture.merged_wikitext,
+                   replay_capture(&merger, &options, &None, &replayed_capture));
+
+        // Sections --section_selector doesn't match are left unmerged, as they were live.
+        let section_selector = Some(Regex::new("^History").unwrap());
+        assert_eq!("The fox jumped over the dog.\n==History==\nFoxes jump.\n",
+                   replay_capture(&merger, &options, &section_selector, &replayed_capture));
+    }
+
+    #[test]
+    fn test_get_passthrough_url() {
+        let request_url = Url::parse("https://localhost:3000/w/load.php?lang=en").unwrap();
+        let wiki = Wiki::new("http".to_string(), "localhost".to_string(), 8080, Client::new(),
+                             "test-agent/1.0".to_string(), None, WikiOptions::default());
+        assert_eq!("http://localhost:8080/w/load.php?lang=en",
+                   get_passthrough_url(&request_url, &wiki));
+    }
+
+    #[test]
+    fn test_is_antivandalism_revision() {
+        let revision = |comment: &str| {
+            Revision { revid: 12, parentid: 11, comment: comment.to_string(),
+                       user: "User".to_string(), tags: Vec::new(), sha1: None }
+        };
+        let default_keywords = parse_vandalism_keywords("");
+        assert_eq!(vec!["vandal".to_string()], default_keywords);
+        assert!(is_antivandalism_revision(&revision("Reverted vandalism"), &default_keywords));
+        assert!(is_antivandalism_revision(&revision("Revert Vandalism"), &default_keywords));
+        assert!(!is_antivandalism_revision(&revision("Copyedit"), &default_keywords));
+
+        let german_comment = "Änderungen von 1.2.3.4 rückgängig gemacht (Vandalismus entfernt)";
+        let german_keywords = parse_vandalism_keywords("Rückgängig, Vandalismus");
+        assert_eq!(vec!["rückgängig".to_string(), "vandalismus".to_string()], german_keywords);
+        assert!(is_antivandalism_revision(&revision(german_comment), &german_keywords));
+        assert!(is_antivandalism_revision(&revision("Rückgängig gemacht"), &german_keywords));
+        assert!(!is_antivandalism_revision(&revision("Reverted vandalism"), &german_keywords));
+    }
+
+    #[test]
+    fn test_is_passthrough_query() {
+        let query = |query: &str| Some(query.to_string());
+        assert!(!is_passthrough_query(&None));
+        assert!(!is_passthrough_query(&query("format=multipart")));
+        assert!(!is_passthrough_query(&query("action=view")));
+        assert!(is_passthrough_query(&query("oldid=123")));
+        assert!(is_passthrough_query(&query("action=raw")));
+        assert!(is_passthrough_query(&query("diff=124&oldid=123")));
+    }
+
+    #[test]
+    fn test_passthrough_response() {
+        let mut handler = test_handler();
+        let body = "Foo is a word.";
+        let (port, _) = serve_responses(vec![http_response("", body)]);
+        handler.wiki = local_wiki(port);
+        let request_url = Url::parse("http://localhost:3000/wiki/Foo?action=raw").unwrap();
+        let response = handler.get_passthrough_response(&request_url);
+        assert_eq!(Some(iron::status::Ok), response.status);
+        // An unreachable wiki is an error.
+        let response = test_handler().get_passthrough_response(&request_url);
+        assert_eq!(Some(iron::status::InternalServerError), response.status);
+    }
+
+    #[test]
+    fn test_only_changed_sections_are_merged_again() {
+        let handler = test_handler();
+        let revision = |revid| {
+            ("Foo".to_string(), Revision { revid: revid, parentid: revid - 1,
+                                           comment: "Reverted vandalism".to_string(),
+                                           user: "User".to_string(), tags: Vec::new(),
+                                           sha1: None })
+        };
+        let revisions = vec![revision(12)];
+        let sections = vec![("".to_string(), "Intro".to_string()),
+                            ("History".to_string(), "== History ==\nOld".to_string())];
+        assert_eq!(sections, handler.get_cached_sections("Foo", &sections, &revisions).1);
+        for &(ref section_title, ref section_content) in &sections {
+            handler.cache_merged_section(
+                "Foo", section_title, get_section_fingerprint(section_content, &revisions),
+                &(format!("Merged {}", section_content), MergeStats::default()));
+        }
+
+        let changed_sections = vec![sections[0].clone(),
+                                    ("History".to_string(), "== History ==\nNew".to_string())];
+        let (cached_sections, sections_to_merge) =
+            handler.get_cached_sections("Foo", &changed_sections, &revisions);
+        assert_eq!(vec![changed_sections[1].clone()], sections_to_merge);
+        assert_eq!(1, cached_sections.len());
+        assert_eq!(Some(&("Merged Intro".to_string(), MergeStats::default())),
+                   cached_sections.get(""));
+
+        // A new revert has to be merged into every section.
+        let new_revisions = vec![revision(14), revision(12)];
+        assert_eq!(sections, handler.get_cached_sections("Foo", &sections, &new_revisions).1);
+    }
+
+    #[test]
+    fn test_timed_out_sections_are_not_cached() {
+        let handler = test_handler();
+        let sections = vec![("".to_string(), "Intro".to_string())];
+        let fingerprint = get_section_fingerprint(&sections[0].1, &[]);
+        let timed_out_stats = MergeStats { timeouts: 1, ..MergeStats::default() };
+        handler.cache_merged_section("Foo", "", fingerprint,
+                                     &("Intro".to_string(), timed_out_stats));
+        assert_eq!(sections, handler.get_cached_sections("Foo", &sections, &[]).1);
+
+        handler.cache_merged_section("Foo", "", fingerprint,
+                                     &("Merged intro".to_string(), MergeStats::default()));
+        assert!(handler.get_cached_sections("Foo", &sections, &[]).1.is_empty());
+    }
+
+    /// Checks that every flag can be set in a config file, and that every setting in one is
+    /// applied, since both lists are kept by hand.
+    #[test]
+    fn test_config_covers_every_flag() {
+        let source = include_str!("main.rs");
+        // The flags that can't be set in a config file (see `Config`).
+        let excluded_flags = ["config", "log_format", "replay"];
+        let flags = Regex::new(r#"&\[(?:"-[a-z]", )?"--([a-z0-9_]+)"\]"#).unwrap()
+            .captures_iter(source)
+            .map(|captures| captures.at(1).unwrap().to_string())
+            .filter(|flag| !excluded_flags.contains(&&flag[..]))
+            .collect::<HashSet<_>>();
+        // Every field is encoded, as null if it's None.
+        let config = Json::from_str(&encode_json(&Config::default()).unwrap()).unwrap();
+        let settings = config.as_object().unwrap().keys().cloned().collect::<HashSet<_>>();
+        assert_eq!(flags, settings);
+
+        let mut applied_settings = HashSet::new();
+        for captures in Regex::new(r"apply_(?:parsed_)?config!\(([a-z0-9_,\s]+)\);").unwrap()
+            .captures_iter(source) {
+            applied_settings.extend(
+                captures.at(1).unwrap().split(',').map(|setting| setting.trim().to_string()));
+        }
+        // Applied by hand, since they're Options until the wiki's profile is applied.
+        applied_settings.insert("no_capital_links".to_string());
+        applied_settings.insert("empty_title_page".to_string());
+        assert_eq!(settings, applied_settings);
     }
 }
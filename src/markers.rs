@@ -0,0 +1,96 @@
+//! To mark areas of the merged text that were merged in from vandalized edits, `Merger` uses
+//! placeholder characters at the start and end of each merged region.
+//!
+//! These characters are taken from a Unicode Private Use Area, so they should never appear in
+//! actual Wikipedia text. Vandals can insert any characters they like, though, so text from the
+//! wiki should go through `remove_markers()` before it's merged.
+
+use std::borrow::Cow;
+
+pub const START_MARKER: &'static str = "\u{E000}";
+pub const END_MARKER: &'static str = "\u{E001}";
+/// Follows the marker inside both the start and end of a region that restores a deletion (i.e.,
+/// where the vandal removed text, so the region is empty), to tell it apart from a region that
+/// restores added text.
+pub const REMOVAL_MARKER: &'static str = "\u{E003}";
+/// Follows the marker inside both the start and end of a region of current text, kept beside the
+/// vandalism restored in its place with the same marker (see `ConflictPolicy::KeepBoth`), to tell
+/// it apart from the restored region.
+pub const CURRENT_MARKER: &'static str = "\u{E004}";
+/// Separates a section title from the count that tells it apart from earlier sections with the
+/// same title (see `deduplicate_section_titles()` in main.rs). It's removed from wiki text with
+/// the markers, so that a section title from the wiki can't collide with a deduplicated one.
+pub const TITLE_COUNT_SEPARATOR: &'static str = "\u{E002}";
+
+/// Returns whether `c` is one of the marker characters, or `TITLE_COUNT_SEPARATOR`.
+fn is_marker_char(c: char) -> bool {
+    c == '\u{E000}' || c == '\u{E001}' || c == '\u{E002}' || c == '\u{E003}' || c == '\u{E004}'
+}
+
+/// Returns the markers of the merged regions that start or end in `text`, in order, without their
+/// `REMOVAL_MARKER`s and `CURRENT_MARKER`s. `text` must not split the characters around a marker.
+pub fn get_region_markers(text: &str) -> Vec<String> {
+    let mut markers = Vec::new();
+    // The marker being read, if any, and the character that will close it.
+    let mut current: Option<(char, String)> = None;
+    for c in text.chars() {
+        current = match current.take() {
+            Some((delimiter, mut marker)) => {
+                if c == delimiter {
+                    markers.push(marker);
+                    None
+                } else {
+                    if c != '\u{E003}' && c != '\u{E004}' {
+                        marker.push(c);
+                    }
+                    Some((delimiter, marker))
+                }
+            },
+            None if c == '\u{E000}' || c == '\u{E001}' => Some((c, String::new())),
+            None => None,
+        };
+    }
+    markers
+}
+
+/// Returns `text` without the start and end markers of its regions of current text (see
+/// `CURRENT_MARKER`), leaving the text inside them and every other region as they are.
+pub fn remove_current_text_markers(text: &str) -> String {
+    let mut result = String::new();
+    // The offset of the marker being read, if any, and the character that will close it.
+    let mut current: Option<(usize, char)> = None;
+    for (index, c) in text.char_indices() {
+        current = match current.take() {
+            Some((start, delimiter)) => {
+                if c != delimiter {
+                    Some((start, delimiter))
+                } else {
+                    let marker = &text[start..index + c.len_utf8()];
+                    if !marker.contains('\u{E004}') {
+                        result.push_str(marker);
+                    }
+                    None
+                }
+            },
+            None if c == '\u{E000}' || c == '\u{E001}' => Some((index, c)),
+            None => {
+                result.push(c);
+                None
+            },
+        };
+    }
+    if let Some((start, _)) = current {
+        result.push_str(&text[start..]);
+    }
+    result
+}
+
+/// Returns `text` with any marker characters removed, so that they can't be mistaken for the
+/// markers of a merged region.
+pub fn remove_markers(text: &str) -> Cow<str> {
+    if text.chars().any(is_marker_char) {
+        Cow::Owned(text.chars().filter(|c| !is_marker_char(*c)).collect())
+    } else {
+        Cow::Borrowed(text)
+    }
+}
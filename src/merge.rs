@@ -6,15 +6,25 @@
 
 extern crate num;
 
+use std::ascii::AsciiExt;
+use std::cmp;
 use std::cmp::Ordering;
+use std::iter;
 use std::iter::FromIterator;
-use std::str::CharIndices;
+use std::ops::Range;
+use std::str;
+use std::str::FromStr;
 
-use ::START_MARKER;
-use ::END_MARKER;
-use ::longest_common_subsequence;
-use ::longest_common_subsequence::CommonSubsequence;
-use timer::Timer;
+use longest_common_subsequence;
+use longest_common_subsequence::CommonRegion;
+use longest_common_subsequence::CommonSubsequence;
+use markers::CURRENT_MARKER;
+use markers::END_MARKER;
+use markers::REMOVAL_MARKER;
+use markers::START_MARKER;
+use markers::get_region_markers;
+use markers::remove_markers;
+use similarity;
 
 /// Represents the states of a 4-state machine representing the traversal through `old` to find
 /// stable and unstable chunks: at any given moment, the part of `old` under consideration is either
@@ -114,19 +124,49 @@ enum Chunk {
     Unstable((usize, usize), (usize, usize), (usize, usize)),
 }
 
+/// Iterates over the words in a string. Each word includes the whitespace following it.
 #[derive(Clone)]
-struct Words<'a> {
+pub struct Words<'a> {
     underlying_string: &'a str,
-    char_indices: CharIndices<'a>,
+    /// The byte offset of the start of the next word.
     current_index: usize,
+    /// Whether all Unicode whitespace (e.g. non-breaking and ideographic spaces) separates words,
+    /// rather than just ASCII spaces, tabs, and newlines.
+    unicode_whitespace: bool,
+    /// Whether each literal block (see LITERAL_BLOCK_TAGS) is a single word.
+    atomic_literal_blocks: bool,
 }
 
+/// The tags whose contents are literal text. Splitting these into words would let a merge restore
+/// vandalism into the middle of a code block, breaking its formatting.
+const LITERAL_BLOCK_TAGS: [&'static str; 3] = ["nowiki", "pre", "syntaxhighlight"];
+
 impl<'a> Words<'a> {
-    fn new(underlying_string: &'a str) -> Words<'a> {
+    pub fn new(underlying_string: &'a str) -> Words<'a> {
         Words {
             underlying_string: underlying_string,
-            char_indices: underlying_string.char_indices(),
             current_index: 0,
+            unicode_whitespace: false,
+            atomic_literal_blocks: false,
+        }
+    }
+
+    /// Like `new()`, but words are separated by any Unicode whitespace.
+    pub fn new_unicode(underlying_string: &'a str) -> Words<'a> {
+        Words { unicode_whitespace: true, ..Words::new(underlying_string) }
+    }
+
+    /// Returns this iterator, changed to return each literal block (e.g. "<pre>...</pre>"), and the
+    /// whitespace after it, as a single word if `atomic_literal_blocks` is set.
+    pub fn with_atomic_literal_blocks(self, atomic_literal_blocks: bool) -> Words<'a> {
+        Words { atomic_literal_blocks: atomic_literal_blocks, ..self }
+    }
+
+    fn is_whitespace(&self, ch: char) -> bool {
+        if self.unicode_whitespace {
+            ch.is_whitespace()
+        } else {
+            ch == ' ' || ch == '\r' || ch == '\n' || ch == '\t'
         }
     }
 }
@@ -134,90 +174,657 @@ impl<'a> Words<'a> {
 impl<'a> Iterator for Words<'a> {
     type Item = &'a [u8];
 
-    // TODO: these iterators have .clone().next() called a bunch of times. Might be better to
-    // pre-calculate the next result.
-
     fn next(&mut self) -> Option<&'a [u8]> {
         let start = self.current_index;
-        // Find the next space
-        let mut looped = false;
-        loop {
-            match self.char_indices.next() {
-                Some((_, ch)) if ch == ' ' || ch == '\r' || ch == '\n' || ch == '\t' => { break; },
-                Some((_, ch)) => { looped = true; },
-                None => {
-                    if looped {
-                        // TODO: Does this make a copy, and then take a reference to the copy? That
-                        // wouldn't be ideal.
-                        return Some(&self.underlying_string.as_bytes()[start..]);
-                    } else {
-                        return None;
-                    }
+        if start >= self.underlying_string.len() {
+            return None;
+        }
+        // A literal block is never split, even at whitespace.
+        let word_start = if self.atomic_literal_blocks {
+            start + get_literal_block_length(&self.underlying_string[start..]).unwrap_or(0)
+        } else {
+            start
+        };
+        // Find the end of the word, and then the end of the whitespace after it, in one pass.
+        let mut in_whitespace = false;
+        let mut end = self.underlying_string.len();
+        for (i, ch) in self.underlying_string[word_start..].char_indices() {
+            if self.is_whitespace(ch) {
+                in_whitespace = true;
+            } else if in_whitespace {
+                end = word_start + i;
+                break;
+            }
+        }
+        self.current_index = end;
+        Some(&self.underlying_string.as_bytes()[start..end])
+    }
+}
+
+/// Counts of what happened while merging revisions into a section. `MergeStats` for several
+/// sections can be summed with `add()` to describe a whole article.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MergeStats {
+    /// The number of sections that had at least one revision merged into them without timing out.
+    pub sections_merged: u64,
+    /// The number of truly conflicting chunks, where `other` was kept over `new`.
+    pub conflicts: u64,
+    /// The number of merges that were skipped for being too large, or that timed out.
+    pub timeouts: u64,
+    /// The number of bytes of content merged in from `other`.
+    pub restored_bytes: u64,
+    /// The number of merges that were skipped because `old` and `other` were too dissimilar.
+    pub dissimilar_skips: u64,
+    /// The number of changed chunks that merged without conflicting: those changed in only one of
+    /// `new` and `other`, or identically in both.
+    pub clean_chunks: u64,
+    /// The number of tokens of `old` in stable chunks, unchanged in both `new` and `other`.
+    pub stable_tokens: u64,
+    /// The number of tokens of `old` in all chunks.
+    pub old_tokens: u64,
+}
+
+impl MergeStats {
+    pub fn add(&mut self, other: &MergeStats) {
+        self.sections_merged += other.sections_merged;
+        self.conflicts += other.conflicts;
+        self.timeouts += other.timeouts;
+        self.restored_bytes += other.restored_bytes;
+        self.dissimilar_skips += other.dissimilar_skips;
+        self.clean_chunks += other.clean_chunks;
+        self.stable_tokens += other.stable_tokens;
+        self.old_tokens += other.old_tokens;
+    }
+
+    /// Returns the fraction of changed chunks that merged without conflicting, or 1 if nothing
+    /// changed.
+    pub fn clean_chunk_ratio(&self) -> f64 {
+        let changed_chunks = self.clean_chunks + self.conflicts;
+        if changed_chunks == 0 { 1.0 } else { self.clean_chunks as f64 / changed_chunks as f64 }
+    }
+
+    /// Returns the fraction of `old`'s tokens in stable chunks, or 1 if nothing was diffed.
+    pub fn stable_fraction(&self) -> f64 {
+        if self.old_tokens == 0 { 1.0 } else { self.stable_tokens as f64 / self.old_tokens as f64 }
+    }
+
+    /// Returns a score of how well the merges aligned, from 0 to 1: `clean_chunk_ratio()` times
+    /// `stable_fraction()`. A low score (lots of conflicts, little stable text) suggests a poor
+    /// alignment, whose restored content is likely to be garbled.
+    pub fn quality(&self) -> f64 {
+        self.clean_chunk_ratio() * self.stable_fraction()
+    }
+}
+
+/// One section of an article, after merging.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergedSection {
+    /// The section's title, as deduplicated by `deduplicate_section_titles()`.
+    pub title: String,
+    /// The section's merged wikitext, including its heading and merge markers.
+    pub content: String,
+    pub stats: MergeStats,
+}
+
+/// An article after merging, section by section.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MergedArticle {
+    /// The article's sections, in order.
+    pub sections: Vec<MergedSection>,
+}
+
+impl MergedArticle {
+    /// Returns the wikitext of the whole article.
+    pub fn to_wikitext(&self) -> String {
+        self.sections.iter().map(|section| &section.content[..]).collect::<Vec<_>>().concat()
+    }
+
+    /// Returns the statistics from merging all the sections of the article.
+    pub fn stats(&self) -> MergeStats {
+        let mut stats = MergeStats::default();
+        for section in &self.sections {
+            stats.add(&section.stats);
+        }
+        stats
+    }
+}
+
+/// A piece of the text produced by a merge.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MergeSegment {
+    /// Text that came from `old` or `new`.
+    Clean(String),
+    /// Text restored from `other`, which is marked with `marker`. Empty where `other` deleted text.
+    Vandalism { text: String, marker: String },
+    /// Text from `new`, kept beside the vandalism restored in its place with the same `marker`.
+    /// See `ConflictPolicy::KeepBoth`.
+    Current { text: String, marker: String },
+}
+
+/// The result of a 3-way merge, as the sequence of segments making up the merged text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergeResult {
+    pub segments: Vec<MergeSegment>,
+    /// Whether the merge timed out, in which case the only segment is `new`, unchanged.
+    pub timed_out: bool,
+}
+
+impl MergeResult {
+    /// Returns the result of a merge that kept `new` as it is.
+    fn unmerged(new: &str, timed_out: bool) -> MergeResult {
+        MergeResult { segments: vec![MergeSegment::Clean(new.to_owned())], timed_out: timed_out }
+    }
+
+    /// Appends `bytes`, a run of whole tokens of `old` or `new`, to the last clean segment.
+    fn push_clean(&mut self, bytes: &[u8]) {
+        let text = str::from_utf8(bytes).unwrap();
+        if let Some(&mut MergeSegment::Clean(ref mut clean)) = self.segments.last_mut() {
+            clean.push_str(text);
+            return;
+        }
+        self.segments.push(MergeSegment::Clean(text.to_owned()));
+    }
+
+    /// Returns the merged text, with each vandalism segment between merge markers: `START_MARKER`,
+    /// its marker, and `START_MARKER` before it, and `END_MARKER`, its marker, and `END_MARKER`
+    /// after it. An empty segment (a restored deletion) has `REMOVAL_MARKER` after its marker in
+    /// both, so it can be told apart from one that restores added text, and a current segment has
+    /// `CURRENT_MARKER` there instead.
+    pub fn to_marked_string(&self) -> String {
+        let mut marked = String::new();
+        for segment in &self.segments {
+            match *segment {
+                MergeSegment::Clean(ref text) => marked.push_str(text),
+                MergeSegment::Vandalism { ref text, ref marker } => {
+                    let kind = if text.is_empty() { REMOVAL_MARKER } else { "" };
+                    marked.push_str(&format!("{}{}{}{}{}{}{}{}{}", START_MARKER, marker, kind,
+                                             START_MARKER, text, END_MARKER, marker, kind,
+                                             END_MARKER));
+                },
+                MergeSegment::Current { ref text, ref marker } => {
+                    marked.push_str(&format!("{}{}{}{}{}{}{}{}{}", START_MARKER, marker,
+                                             CURRENT_MARKER, START_MARKER, text, END_MARKER,
+                                             marker, CURRENT_MARKER, END_MARKER));
                 },
             }
         }
-        // Then, find the beginning of the next word
+        marked
+    }
+}
+
+/// What a merge keeps where `other` changed the text: the current text (`new`'s chunk), the
+/// vandalism (`other`'s chunk), or both, one after the other. Where both are kept, the current text
+/// is marked too, as a `MergeSegment::Current`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    KeepVandalism,
+    KeepClean,
+    KeepBoth,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ConflictPolicy, String> {
+        match s {
+            "keep_vandalism" => Ok(ConflictPolicy::KeepVandalism),
+            "keep_clean" => Ok(ConflictPolicy::KeepClean),
+            "keep_both" => Ok(ConflictPolicy::KeepBoth),
+            _ => Err(format!("Unknown conflict policy \"{}\"", s)),
+        }
+    }
+}
+
+/// The unit that text is split into for diffing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Granularity {
+    Word,
+    /// Coarser, and much faster, than words. Lists and tables merge more cleanly line by line.
+    Line,
+    Paragraph,
+}
+
+impl FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Granularity, String> {
+        match s {
+            "word" => Ok(Granularity::Word),
+            "line" => Ok(Granularity::Line),
+            "paragraph" => Ok(Granularity::Paragraph),
+            _ => Err(format!("Unknown merge granularity \"{}\"", s)),
+        }
+    }
+}
+
+/// If `text` starts with a literal block (one of LITERAL_BLOCK_TAGS, from its opening tag through
+/// its closing tag), returns the block's length in bytes.
+fn get_literal_block_length(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    for tag in LITERAL_BLOCK_TAGS.iter() {
+        let opening = format!("<{}", tag);
+        if bytes.len() <= opening.len() ||
+            !bytes[..opening.len()].eq_ignore_ascii_case(opening.as_bytes()) {
+            continue;
+        }
+        match bytes[opening.len()] {
+            b'>' | b' ' | b'\t' | b'\n' => (),
+            // A different tag that starts the same way.
+            _ => continue,
+        }
+        let tag_end = match bytes.iter().position(|&byte| byte == b'>') {
+            Some(tag_end) => tag_end,
+            None => return None,
+        };
+        if bytes[tag_end - 1] == b'/' {
+            // A self-closing tag (e.g. "<nowiki />") has no contents.
+            return None;
+        }
+        let closing = format!("</{}>", tag);
+        return bytes[tag_end..].windows(closing.len())
+            .position(|window| window.eq_ignore_ascii_case(closing.as_bytes()))
+            .map(|index| tag_end + index + closing.len());
+    }
+    None
+}
+
+/// Iterates over the lines in a string. Each line includes its trailing newline.
+#[derive(Clone)]
+pub struct Lines<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> Lines<'a> {
+    pub fn new(underlying_string: &'a str) -> Lines<'a> {
+        Lines { remaining: underlying_string.as_bytes() }
+    }
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.remaining.is_empty() {
+            return None;
+        }
+        let end = match self.remaining.iter().position(|&byte| byte == b'\n') {
+            Some(newline_index) => newline_index + 1,
+            None => self.remaining.len(),
+        };
+        let line = &self.remaining[..end];
+        self.remaining = &self.remaining[end..];
+        Some(line)
+    }
+}
+
+/// Iterates over the paragraphs (runs of lines separated by blank lines) in a string. Each paragraph
+/// includes the newlines following it.
+#[derive(Clone)]
+pub struct Paragraphs<'a> {
+    lines: Lines<'a>,
+}
+
+impl<'a> Paragraphs<'a> {
+    pub fn new(underlying_string: &'a str) -> Paragraphs<'a> {
+        Paragraphs { lines: Lines::new(underlying_string) }
+    }
+}
+
+impl<'a> Iterator for Paragraphs<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let start = self.lines.remaining;
+        let mut length = 0;
+        let mut in_blank_lines = false;
         loop {
-            match self.char_indices.clone().next() {
-                Some((i, ch)) if !(ch == ' ' || ch == '\r' || ch == '\n' || ch == '\t') => {
-                    self.current_index = i;
-                    return Some(&self.underlying_string.as_bytes()[start..i]);
-                },
-                Some((_, ch)) => { self.char_indices.next(); },
-                None => { return Some(&self.underlying_string.as_bytes()[start..]); },
+            match self.lines.clone().next() {
+                Some(line) if line == b"\n" => in_blank_lines = true,
+                Some(_) if in_blank_lines => break,
+                Some(_) => (),
+                None => break,
             }
+            length += self.lines.next().unwrap().len();
+        }
+        if length == 0 {
+            None
+        } else {
+            Some(&start[..length])
         }
     }
 }
 
+/// Scales the time limit for each diff with the size of the text being diffed, so that small
+/// sections give up quickly and large ones get proportionally more time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeLimitScaling {
+    /// The time limit (in milliseconds) per KiB of the largest of the texts being merged.
+    pub ms_per_kib: u64,
+    /// The smallest time limit (in milliseconds) to use, no matter how small the texts are.
+    pub min_ms: u64,
+}
+
+/// A change between two texts, found by `Merger::get_changes()`: the bytes of the first text in
+/// `old_range` were replaced by the bytes of the second text in `other_range`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    pub old_range: Range<usize>,
+    pub other_range: Range<usize>,
+}
+
 #[derive(Clone)]
 pub struct Merger {
     /// The size (in bytes) above which a diff is automatically skipped, without any attempt to
     /// merge.
     diff_size_limit: usize,
+    /// The time limit for each diff. If `time_limit_scaling` is set, this is the upper bound on the
+    /// scaled time limit.
     diff_time_limit_ms: u64,
+    time_limit_scaling: Option<TimeLimitScaling>,
+    /// The most tasks each longest-common-subsequence calculation may execute before it's
+    /// abandoned, like a timeout, or 0 for no limit.
+    lcs_max_tasks: u64,
+    /// The most tasks each longest-common-subsequence calculation may queue up before dropping the
+    /// least promising ones, or 0 for no limit.
+    lcs_max_queue_size: usize,
+    /// Whether all Unicode whitespace separates words. See `Words::new_unicode()`.
+    unicode_whitespace: bool,
+    /// Whether literal blocks are diffed as single words. See
+    /// `Words::with_atomic_literal_blocks()`.
+    atomic_literal_blocks: bool,
+    /// The least similar (see `similarity::estimate_similarity()`) `old` and `other` can be for a
+    /// merge to be attempted, or 0.0 to always attempt it.
+    min_similarity: f64,
+    /// What to keep where `other` changed the text, whether or not `new` changed it too.
+    conflict_policy: ConflictPolicy,
+    /// Whether to merge with the partial longest common subsequences found when a diff times out,
+    /// instead of giving up on the merge.
+    partial_lcs: bool,
+    /// The unit that `try_merge()` and the other merge methods diff by.
+    granularity: Granularity,
+    /// Whether to keep restorations from more recent revisions where `other` conflicts with them.
+    /// See `with_prefer_recent_restorations()`.
+    prefer_recent_restorations: bool,
 }
 
 impl Merger {
     pub fn new(diff_size_limit: usize, diff_time_limit_ms: u64) -> Merger {
-        Merger { diff_size_limit: diff_size_limit, diff_time_limit_ms: diff_time_limit_ms }
+        Merger {
+            diff_size_limit: diff_size_limit,
+            diff_time_limit_ms: diff_time_limit_ms,
+            time_limit_scaling: None,
+            lcs_max_tasks: 0,
+            lcs_max_queue_size: 0,
+            unicode_whitespace: false,
+            atomic_literal_blocks: false,
+            min_similarity: 0.0,
+            conflict_policy: ConflictPolicy::KeepVandalism,
+            partial_lcs: false,
+            granularity: Granularity::Word,
+            prefer_recent_restorations: false,
+        }
+    }
+
+    /// Returns this Merger, changed to diff by `granularity` instead of by word.
+    pub fn with_granularity(self, granularity: Granularity) -> Merger {
+        Merger { granularity: granularity, ..self }
+    }
+
+    /// Returns this Merger, changed to split words on all Unicode whitespace if
+    /// `unicode_whitespace` is set.
+    pub fn with_unicode_whitespace(self, unicode_whitespace: bool) -> Merger {
+        Merger { unicode_whitespace: unicode_whitespace, ..self }
+    }
+
+    /// Returns this Merger, changed to diff each <nowiki>, <pre>, and <syntaxhighlight> block as a
+    /// single word if `atomic_literal_blocks` is set, so that vandalism inside one is restored by
+    /// restoring the whole block.
+    pub fn with_atomic_literal_blocks(self, atomic_literal_blocks: bool) -> Merger {
+        Merger { atomic_literal_blocks: atomic_literal_blocks, ..self }
+    }
+
+    /// Returns an iterator over the words in `text`.
+    fn words<'a>(&self, text: &'a str) -> Words<'a> {
+        let words = if self.unicode_whitespace {
+            Words::new_unicode(text)
+        } else {
+            Words::new(text)
+        };
+        words.with_atomic_literal_blocks(self.atomic_literal_blocks)
+    }
+
+    /// Returns this Merger, changed to scale its time limit as specified by `time_limit_scaling`.
+    pub fn with_time_limit_scaling(self, time_limit_scaling: TimeLimitScaling) -> Merger {
+        Merger { time_limit_scaling: Some(time_limit_scaling), ..self }
+    }
+
+    /// Returns this Merger, changed to abandon each diff after `lcs_max_tasks` steps of its
+    /// longest-common-subsequence calculation (0 for no limit), as well as after its time limit.
+    pub fn with_lcs_max_tasks(self, lcs_max_tasks: u64) -> Merger {
+        Merger { lcs_max_tasks: lcs_max_tasks, ..self }
+    }
+
+    /// Returns this Merger, changed to keep at most `lcs_max_queue_size` tasks queued in each
+    /// longest-common-subsequence calculation (0 for no limit). This bounds the memory a diff can
+    /// use, at the cost of sometimes finding a shorter common subsequence than the longest.
+    pub fn with_lcs_max_queue_size(self, lcs_max_queue_size: usize) -> Merger {
+        Merger { lcs_max_queue_size: lcs_max_queue_size, ..self }
+    }
+
+    /// Returns this Merger, changed to skip merges where `old` and `other` are less similar than
+    /// `min_similarity` (between 0.0 and 1.0), without diffing them. Such merges would almost all
+    /// conflict anyway.
+    pub fn with_min_similarity(self, min_similarity: f64) -> Merger {
+        Merger { min_similarity: min_similarity, ..self }
+    }
+
+    /// Returns this Merger, changed to resolve chunks that `other` changed as `conflict_policy`
+    /// says. Anything but `ConflictPolicy::KeepVandalism` is for debugging, and comparing merges.
+    pub fn with_conflict_policy(self, conflict_policy: ConflictPolicy) -> Merger {
+        Merger { conflict_policy: conflict_policy, ..self }
+    }
+
+    /// Returns this Merger, changed to keep a region already restored into `new` where `other`
+    /// conflicts with it, instead of `other`'s change, if `prefer_recent_restorations` is set and
+    /// the region was restored from a more recent revision than `other`. Revisions are merged
+    /// newest first, so otherwise the oldest revert of a region wins, though the newest better
+    /// reflects what's currently thought to be vandalism.
+    ///
+    /// Markers are compared as revision IDs, which MediaWiki assigns in increasing order, so a
+    /// greater marker is from a more recent revision. A marker that isn't a number is never more
+    /// recent.
+    pub fn with_prefer_recent_restorations(self, prefer_recent_restorations: bool) -> Merger {
+        Merger { prefer_recent_restorations: prefer_recent_restorations, ..self }
+    }
+
+    /// Returns this Merger, changed to merge with whatever longest common subsequences it has found
+    /// when a diff times out if `partial_lcs` is set. For big texts that mostly match, that's
+    /// usually most of the real one. Past the last stretch of text that's unchanged in all three
+    /// texts, `new` is kept as it is.
+    pub fn with_partial_lcs(self, partial_lcs: bool) -> Merger {
+        Merger { partial_lcs: partial_lcs, ..self }
+    }
+
+    /// Returns the time limit (in milliseconds) for diffing texts of which the largest is `size`
+    /// bytes.
+    pub fn time_limit_ms(&self, size: usize) -> u64 {
+        match self.time_limit_scaling {
+            Some(scaling) => {
+                let scaled_ms = size as u64 * scaling.ms_per_kib / 1024;
+                cmp::min(cmp::max(scaled_ms, scaling.min_ms), self.diff_time_limit_ms)
+            },
+            None => self.diff_time_limit_ms,
+        }
+    }
+
+    /// Diffs `old` and `other` by word, and returns the changes from `old` to `other`, in order.
+    /// Returns None if the diff times out.
+    pub fn get_changes(&self, old: &str, other: &str) -> Option<Vec<Change>> {
+        fn get_offsets(words: Words) -> Vec<usize> {
+            iter::once(0).chain(words.scan(0, |offset, word| {
+                *offset += word.len();
+                Some(*offset)
+            })).collect()
+        }
+
+        let time_limit_ms = self.time_limit_ms(cmp::max(old.len(), other.len()));
+        let lcs = match longest_common_subsequence::get_longest_common_subsequence(
+            self.words(old), self.words(other), time_limit_ms, self.lcs_max_tasks,
+            self.lcs_max_queue_size) {
+            Some(lcs) => lcs,
+            None => return None,
+        };
+        // The byte offset of the start of each word (and of the end of the text).
+        let old_offsets = get_offsets(self.words(old));
+        let other_offsets = get_offsets(self.words(other));
+
+        let mut changes = Vec::new();
+        let (mut old_position, mut other_position) = (0, 0);
+        let end = CommonRegion::new(old_offsets.len() - 1, other_offsets.len() - 1, 0);
+        for region in lcs.common_regions.iter().chain(iter::once(&end)) {
+            if region.iter1_offset > old_position || region.iter2_offset > other_position {
+                changes.push(Change {
+                    old_range: old_offsets[old_position]..old_offsets[region.iter1_offset],
+                    other_range: other_offsets[other_position]..other_offsets[region.iter2_offset],
+                });
+            }
+            old_position = region.iter1_offset + region.size;
+            other_position = region.iter2_offset + region.size;
+        }
+        Some(changes)
     }
 
     /// Attempts a 3-way merge, merging `new` and `other` under the assumption that both diverged from
     /// `old`. If the strings do not merge together cleanly, returns `new`. Marks regions merged from
     /// `other` by putting `START_MARKER`, then `marker`, then `START_MARKER` at the beginning, and
-    /// `END_MARKER`, `marker`, and `END_MARKER` at the end.
+    /// `END_MARKER`, `marker`, and `END_MARKER` at the end. Where `other` deleted text, the empty
+    /// region's `marker` is followed by `REMOVAL_MARKER` in both. Marker characters in `old`,
+    /// `other`, and `marker` are removed first; any in `new` are taken to be from earlier merges
+    /// into it, so text from the wiki should go through `remove_markers()` before it's first
+    /// merged.
     /// TODO: describe return value
     pub fn try_merge(&self, old: &str, new: &str, other: &str, marker: &str) -> (String, bool) {
-        let mut old_words = Words::new(old);
-        let mut new_words = Words::new(new);
-        let mut other_words = Words::new(other);
+        self.try_merge_with_stats(old, new, other, marker, &mut MergeStats::default())
+    }
+
+    /// Identical to `try_merge()`, but returns the merged text as segments, instead of marking the
+    /// regions merged from `other` in it.
+    pub fn try_merge_detailed(&self, old: &str, new: &str, other: &str, marker: &str)
+                              -> MergeResult {
+        self.merge_at_granularity(old, new, other, marker, self.granularity,
+                                  &mut MergeStats::default())
+    }
+
+    /// Identical to `try_merge()`, but also counts conflicts and timeouts into `stats`.
+    pub fn try_merge_with_stats(&self, old: &str, new: &str, other: &str, marker: &str,
+                                stats: &mut MergeStats) -> (String, bool) {
+        self.try_merge_at_granularity(old, new, other, marker, self.granularity, stats)
+    }
 
+    /// Identical to `try_merge_with_stats()`, but if the merge times out at word granularity, tries
+    /// again at line granularity, and then at paragraph granularity. Coarser merges are much faster,
+    /// so they can often finish when a word-level merge can't. Only the final attempt is counted in
+    /// `stats`. Granularities finer than `granularity` aren't tried.
+    pub fn try_merge_escalating(&self, old: &str, new: &str, other: &str, marker: &str,
+                                stats: &mut MergeStats) -> (String, bool) {
+        let all_granularities = [Granularity::Word, Granularity::Line, Granularity::Paragraph];
+        let first = all_granularities.iter()
+            .position(|granularity| *granularity == self.granularity).unwrap();
+        let granularities = &all_granularities[first..];
+        for (i, granularity) in granularities.iter().enumerate() {
+            let mut attempt_stats = MergeStats::default();
+            let (merge_result, timed_out) =
+                self.try_merge_at_granularity(old, new, other, marker, *granularity,
+                                              &mut attempt_stats);
+            if !timed_out || i == granularities.len() - 1 {
+                stats.add(&attempt_stats);
+                return (merge_result, timed_out);
+            }
+            info!("Merge timed out at {:?} granularity; escalating", granularity);
+        }
+        unreachable!()
+    }
+
+    /// Identical to `try_merge_with_stats()`, but diffs at `granularity` instead of the Merger's
+    /// own.
+    pub fn try_merge_at_granularity(&self, old: &str, new: &str, other: &str, marker: &str,
+                                    granularity: Granularity, stats: &mut MergeStats)
+                                    -> (String, bool) {
+        let result = self.merge_at_granularity(old, new, other, marker, granularity, stats);
+        (result.to_marked_string(), result.timed_out)
+    }
+
+    /// Does the work of `try_merge_at_granularity()`, returning the merged text as segments.
+    fn merge_at_granularity(&self, old: &str, new: &str, other: &str, marker: &str,
+                            granularity: Granularity, stats: &mut MergeStats) -> MergeResult {
+        // Markers in `new` are from earlier merges into it, but any in the other inputs were put
+        // there by a vandal, and would corrupt the regions merged from `other`.
+        let (old, other, marker) = (remove_markers(old), remove_markers(other),
+                                    remove_markers(marker));
+        let (old, other, marker) = (&old[..], &other[..], &marker[..]);
         // It entirely too long to calculate diffs this large. Our latency budget doesn't cover it.
         if num::abs(old.len() as i64 - other.len() as i64) > self.diff_size_limit as i64 {
             info!("Skipped large diff");
-            return (new.to_owned(), true);
+            stats.timeouts += 1;
+            return MergeResult::unmerged(new, true);
+        }
+        if self.min_similarity > 0.0 &&
+            similarity::estimate_similarity(self.words(old), self.words(other)) <
+            self.min_similarity {
+            info!("Skipped merge of dissimilar texts");
+            stats.dissimilar_skips += 1;
+            return MergeResult::unmerged(new, false);
         }
 
-        let new_lcs = longest_common_subsequence::get_longest_common_subsequence(
-            old_words.clone(), new_words.clone(), self.diff_time_limit_ms);
-        let other_lcs = longest_common_subsequence::get_longest_common_subsequence(
-            old_words.clone(), other_words.clone(), self.diff_time_limit_ms);
-        let (new_lcs, other_lcs) = match (new_lcs, other_lcs) {
-            (Some(new_lcs), Some(other_lcs)) => (new_lcs, other_lcs),
-            _ => { info!("Timed out computing LCS"); return (new.to_owned(), true); },
+        let time_limit_ms =
+            self.time_limit_ms(cmp::max(old.len(), cmp::max(new.len(), other.len())));
+        match granularity {
+            Granularity::Word => self.merge_tokens(
+                new, self.words(old), self.words(new), self.words(other), marker, time_limit_ms,
+                stats),
+            Granularity::Line => self.merge_tokens(
+                new, Lines::new(old), Lines::new(new), Lines::new(other), marker, time_limit_ms,
+                stats),
+            Granularity::Paragraph => self.merge_tokens(
+                new, Paragraphs::new(old), Paragraphs::new(new), Paragraphs::new(other), marker,
+                time_limit_ms, stats),
+        }
+    }
+
+    /// Does the work of `merge_at_granularity()`, given iterators over the tokens of `old`, `new`,
+    /// and `other`.
+    fn merge_tokens<'a, I>(&self, new: &str, mut old_words: I, mut new_words: I,
+                           mut other_words: I, marker: &str, time_limit_ms: u64,
+                           stats: &mut MergeStats) -> MergeResult
+        where I: Iterator<Item=&'a [u8]> + Clone {
+        let new_lcs = self.get_lcs(old_words.clone(), new_words.clone(), time_limit_ms);
+        let other_lcs = self.get_lcs(old_words.clone(), other_words.clone(), time_limit_ms);
+        let (new_lcs, other_lcs, partial) = match (new_lcs, other_lcs) {
+            (Some((new_lcs, new_partial)), Some((other_lcs, other_partial))) =>
+                (new_lcs, other_lcs, new_partial || other_partial),
+            _ => {
+                info!("Timed out computing LCS");
+                stats.timeouts += 1;
+                return MergeResult::unmerged(new, true);
+            },
         };
 
-        let mut bytes = Vec::<u8>::new();
+        let mut result = MergeResult { segments: vec![], timed_out: false };
         // TODO: See if these count()s are taking too long (they probably are). If they are, get the
         // iterator sizes in some other way, piggybacking off the iterator traversals in either this
         // file or longest_common_subsequence.rs.
-        for chunk in parse(new_lcs, other_lcs, old_words.clone().count(), new_words.clone().count(),
-                           other_words.clone().count()) {
+        let chunks = parse(new_lcs, other_lcs, old_words.clone().count(),
+                           new_words.clone().count(), other_words.clone().count());
+        let last_chunk_index = chunks.len().saturating_sub(1);
+        // The markers of the regions restored into `new` that are open where the chunk starts.
+        let mut open_regions: Vec<String> = vec![];
+        for (chunk_index, chunk) in chunks.into_iter().enumerate() {
             match chunk {
                 Chunk::Stable(start, length) => {
+                    stats.stable_tokens += length as u64;
+                    stats.old_tokens += length as u64;
                     for _ in 0..length {
-                        bytes.extend(old_words.next().unwrap());
+                        result.push_clean(old_words.next().unwrap());
                         new_words.next().unwrap();
                         other_words.next().unwrap();
                     }
@@ -236,40 +843,127 @@ impl Merger {
                     for _ in 0..other_length {
                         other_chunk.extend(other_words.next().unwrap());
                     }
+                    stats.old_tokens += old_length as u64;
+                    // Stable tokens never have markers, since they're removed from `old`.
+                    let enclosing_regions = if self.prefer_recent_restorations {
+                        let enclosing_regions = open_regions.clone();
+                        update_open_regions(&mut open_regions, &new_chunk);
+                        enclosing_regions
+                    } else {
+                        vec![]
+                    };
 
-                    if old_chunk == new_chunk && old_chunk != other_chunk {
+                    if partial && chunk_index == last_chunk_index {
+                        // A partial LCS stops short of the end of the texts, so everything after
+                        // the last stable chunk is unstable only because it was never compared.
+                        // Taking `other` there would throw away every later edit in `new`.
+                        result.push_clean(&new_chunk);
+                    } else if old_chunk == new_chunk && old_chunk != other_chunk {
                         // Changed only in other
-                        bytes.extend(START_MARKER.as_bytes());
-                        bytes.extend(marker.as_bytes());
-                        bytes.extend(START_MARKER.as_bytes());
-                        bytes.extend(other_chunk);
-                        bytes.extend(END_MARKER.as_bytes());
-                        bytes.extend(marker.as_bytes());
-                        bytes.extend(END_MARKER.as_bytes());
+                        stats.clean_chunks += 1;
+                        self.resolve_change(&mut result, new_chunk, other_chunk, marker, stats);
                     } else if old_chunk != new_chunk && old_chunk == other_chunk {
                         // Changed only in new
-                        bytes.extend(new_chunk);
+                        stats.clean_chunks += 1;
+                        result.push_clean(&new_chunk);
                     } else if old_chunk != new_chunk && new_chunk == other_chunk {
                         // Falsely conflicting, i.e. changed identically in both new and other
-                        bytes.extend(new_chunk);
+                        stats.clean_chunks += 1;
+                        result.push_clean(&new_chunk);
                     } else if (old_chunk != new_chunk && old_chunk != other_chunk &&
                                new_chunk != other_chunk) {
                         // Truly conflicting
                         // In a normal 3-way merge program, this means a failed merge requiring user
                         // intervention. Since we have no user to intervene and want to keep as much
-                        // vandalism as possible, we keep other_chunk here and keep going.
-                        bytes.extend(START_MARKER.as_bytes());
-                        bytes.extend(marker.as_bytes());
-                        bytes.extend(START_MARKER.as_bytes());
-                        bytes.extend(other_chunk);
-                        bytes.extend(END_MARKER.as_bytes());
-                        bytes.extend(marker.as_bytes());
-                        bytes.extend(END_MARKER.as_bytes());
+                        // vandalism as possible, we keep other_chunk here (unless `conflict_policy`
+                        // says otherwise) and keep going.
+                        stats.conflicts += 1;
+                        if self.prefer_recent_restorations &&
+                            has_more_recent_restoration(&new_chunk, &enclosing_regions, marker) {
+                            result.push_clean(&new_chunk);
+                        } else {
+                            self.resolve_change(&mut result, new_chunk, other_chunk, marker,
+                                                stats);
+                        }
                     }
                 },
             }
         }
-        (String::from_utf8(bytes).unwrap(), false)
+        result
+    }
+
+    /// Returns the longest common subsequence of `iter1` and `iter2`, and false, or None if
+    /// calculating it times out. If `partial_lcs` is set, a timeout returns the partial subsequence
+    /// and true instead.
+    fn get_lcs<T, I>(&self, iter1: I, iter2: I, time_limit_ms: u64)
+                     -> Option<(CommonSubsequence, bool)>
+        where I: Iterator<Item=T> + Clone,
+              T: Eq {
+        if !self.partial_lcs {
+            return longest_common_subsequence::get_longest_common_subsequence(
+                iter1, iter2, time_limit_ms, self.lcs_max_tasks, self.lcs_max_queue_size)
+                .map(|lcs| (lcs, false));
+        }
+        match longest_common_subsequence::get_longest_common_subsequence_or_partial(
+            iter1, iter2, time_limit_ms, self.lcs_max_tasks, self.lcs_max_queue_size) {
+            Ok(lcs) => Some((lcs, false)),
+            Err(timeout) => {
+                info!("Timed out computing LCS; merging with a partial LCS of size {}",
+                      timeout.partial.size);
+                Some((timeout.partial, true))
+            },
+        }
+    }
+
+    /// Appends `new_chunk`, `other_chunk`, or both to `result`, as `conflict_policy` says, for a
+    /// chunk that `other` changed. `other_chunk` is marked with `marker`, and so is `new_chunk`, as
+    /// current text, if both are kept.
+    fn resolve_change(&self, result: &mut MergeResult, new_chunk: Vec<u8>, other_chunk: Vec<u8>,
+                      marker: &str, stats: &mut MergeStats) {
+        match self.conflict_policy {
+            ConflictPolicy::KeepVandalism => {},
+            ConflictPolicy::KeepClean => result.push_clean(&new_chunk),
+            ConflictPolicy::KeepBoth if new_chunk.is_empty() => {},
+            ConflictPolicy::KeepBoth => {
+                result.segments.push(MergeSegment::Current {
+                    text: String::from_utf8(new_chunk).unwrap(),
+                    marker: marker.to_owned(),
+                });
+            },
+        }
+        if self.conflict_policy != ConflictPolicy::KeepClean {
+            stats.restored_bytes += other_chunk.len() as u64;
+            result.segments.push(MergeSegment::Vandalism {
+                text: String::from_utf8(other_chunk).unwrap(),
+                marker: marker.to_owned(),
+            });
+        }
+    }
+}
+
+/// Returns whether `text` has (the start or end of) a region restored from a more recent revision
+/// than the one `marker` marks, or is inside one, going by `enclosing_regions`, the markers of the
+/// regions open where it starts. See `Merger::with_prefer_recent_restorations()`.
+fn has_more_recent_restoration(text: &[u8], enclosing_regions: &[String], marker: &str) -> bool {
+    let revid = match marker.parse::<u64>() {
+        Ok(revid) => revid,
+        Err(_) => return false,
+    };
+    get_region_markers(str::from_utf8(text).unwrap()).iter().chain(enclosing_regions)
+        .any(|region_marker| region_marker.parse::<u64>().map(|region_revid| region_revid > revid)
+             .unwrap_or(false))
+}
+
+/// Updates `open_regions`, the markers of the regions open where `text` starts, to those open where
+/// it ends.
+fn update_open_regions(open_regions: &mut Vec<String>, text: &[u8]) {
+    // A region's start and end have the same marker, and a region never nests in one with the same
+    // marker, so each marker opens or closes one.
+    for region_marker in get_region_markers(str::from_utf8(text).unwrap()) {
+        match open_regions.iter().position(|open_marker| *open_marker == region_marker) {
+            Some(index) => { open_regions.remove(index); },
+            None => open_regions.push(region_marker),
+        }
     }
 }
 
@@ -407,9 +1101,12 @@ fn calculate_next_state(match_state: &MatchState, transition: &MatchStateTransit
 
 #[cfg(test)]
 mod tests {
-    use super::{Chunk, calculate_match_state_transitions, parse, try_merge, Words};
+    use super::{Chunk, calculate_match_state_transitions, parse, try_merge, Words, MergeStats,
+                Merger, Lines, Paragraphs, MergedArticle, MergedSection, TimeLimitScaling, Change,
+                MergeResult, MergeSegment, ConflictPolicy, Granularity};
     use super::MatchStateTransition::*;
-    use ::{START_MARKER, END_MARKER};
+    use markers::{START_MARKER, END_MARKER, CURRENT_MARKER, REMOVAL_MARKER, TITLE_COUNT_SEPARATOR,
+                  remove_current_text_markers, remove_markers};
     use longest_common_subsequence::{CommonSubsequence, CommonRegion};
     use regex::Regex;
 
@@ -456,7 +1153,205 @@ mod tests {
         assert_eq!(None, words.next());
     }
 
-    // TODO: Add test for timeout
+    #[test]
+    fn test_words_with_unicode_whitespace() {
+        // Non-breaking and ideographic spaces only separate words in Unicode mode.
+        let text = "100\u{A0}km 東京\u{3000}大阪";
+        let mut words = Words::new(text);
+        assert_eq!(Some("100\u{A0}km ".as_bytes()), words.next());
+        assert_eq!(Some("東京\u{3000}大阪".as_bytes()), words.next());
+        assert_eq!(None, words.next());
+
+        let mut words = Words::new_unicode(text);
+        assert_eq!(Some("100\u{A0}".as_bytes()), words.next());
+        assert_eq!(Some("km ".as_bytes()), words.next());
+        assert_eq!(Some("東京\u{3000}".as_bytes()), words.next());
+        assert_eq!(Some("大阪".as_bytes()), words.next());
+        assert_eq!(None, words.next());
+    }
+
+    #[test]
+    fn test_words_with_only_whitespace() {
+        let mut words = Words::new_unicode(" \u{A0}\u{3000}");
+        assert_eq!(Some(" \u{A0}\u{3000}".as_bytes()), words.next());
+        assert_eq!(None, words.next());
+    }
+
+    #[test]
+    fn test_words_with_atomic_literal_blocks() {
+        let text = "Code: <pre style=\"x\">a = b\n</PRE> <nowiki/> <nowiki>[[a]] b</nowiki>";
+        let mut words = Words::new(text).with_atomic_literal_blocks(true);
+        assert_eq!(Some("Code: ".as_bytes()), words.next());
+        assert_eq!(Some("<pre style=\"x\">a = b\n</PRE> ".as_bytes()), words.next());
+        assert_eq!(Some("<nowiki/> ".as_bytes()), words.next());
+        assert_eq!(Some("<nowiki>[[a]] b</nowiki>".as_bytes()), words.next());
+        assert_eq!(None, words.next());
+
+        // Unterminated blocks, and tags that only start like literal block tags, are split as
+        // usual.
+        let mut words = Words::new("<pre>a b <prefix c>").with_atomic_literal_blocks(true);
+        assert_eq!(Some("<pre>a ".as_bytes()), words.next());
+        assert_eq!(Some("b ".as_bytes()), words.next());
+        assert_eq!(Some("<prefix ".as_bytes()), words.next());
+        assert_eq!(Some("c>".as_bytes()), words.next());
+        assert_eq!(None, words.next());
+    }
+
+    #[test]
+    fn test_try_merge_restores_literal_block_atomically() {
+        let old = "Intro. Code: <pre>x = 1</pre> end";
+        let new = "Intro changed. Code: <pre>x = 1</pre> end";
+        let other = "Intro. Code: <pre>x = 1 HACKED</pre> end";
+        let merger = Merger::new(1000, 500).with_atomic_literal_blocks(true);
+        let expected = format!("Intro changed. Code: {}1{}<pre>x = 1 HACKED</pre> {}1{}end",
+                               START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        assert_eq!((expected, false), merger.try_merge(old, new, other, "1"));
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut lines = Lines::new("line 1\nline 2\n\nline 3");
+        assert_eq!(Some("line 1\n".as_bytes()), lines.next());
+        assert_eq!(Some("line 2\n".as_bytes()), lines.next());
+        assert_eq!(Some("\n".as_bytes()), lines.next());
+        assert_eq!(Some("line 3".as_bytes()), lines.next());
+        assert_eq!(None, lines.next());
+    }
+
+    #[test]
+    fn test_paragraphs() {
+        let mut paragraphs = Paragraphs::new("line 1\nline 2\n\n\nline 3\n\nline 4\n");
+        assert_eq!(Some("line 1\nline 2\n\n\n".as_bytes()), paragraphs.next());
+        assert_eq!(Some("line 3\n\n".as_bytes()), paragraphs.next());
+        assert_eq!(Some("line 4\n".as_bytes()), paragraphs.next());
+        assert_eq!(None, paragraphs.next());
+    }
+
+    #[test]
+    fn test_try_merge_removes_injected_markers() {
+        let old = "Intro. Body text here. End.";
+        let new = "Intro changed. Body text here. End.";
+        // The vandal's text includes a fake region, which would otherwise nest inside the real one
+        // and end it early.
+        let other = format!("Intro. Body {}9{}text{}9{} HACKED here. End.", START_MARKER,
+                            START_MARKER, END_MARKER, END_MARKER);
+        let expected = format!("Intro changed. Body {}1{}9text9 HACKED {}1{}here. End.",
+                               START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        let merger = Merger::new(1000, 500);
+        assert_eq!((expected.clone(), false), merger.try_merge(old, new, &other, "1"));
+        // Markers in the marker itself are removed too.
+        assert_eq!((expected, false),
+                   merger.try_merge(old, new, &other, &format!("1{}", END_MARKER)));
+    }
+
+    #[test]
+    fn test_remove_markers() {
+        assert_eq!("plain text", remove_markers("plain text"));
+        assert_eq!("a b c", remove_markers(&format!("a {}b{} c{}", START_MARKER, END_MARKER,
+                                                    REMOVAL_MARKER)));
+        assert_eq!("History1", remove_markers(&format!("History{}1", TITLE_COUNT_SEPARATOR)));
+    }
+
+    #[test]
+    fn test_remove_current_text_markers() {
+        let marked = format!("a {}1{}{}b{}1{}{} {}1{}c{}1{} d", START_MARKER, CURRENT_MARKER,
+                             START_MARKER, END_MARKER, CURRENT_MARKER, END_MARKER, START_MARKER,
+                             START_MARKER, END_MARKER, END_MARKER);
+        assert_eq!(format!("a b {}1{}c{}1{} d", START_MARKER, START_MARKER, END_MARKER,
+                           END_MARKER),
+                   remove_current_text_markers(&marked));
+        assert_eq!("plain text", remove_current_text_markers("plain text"));
+    }
+
+    #[test]
+    fn test_try_merge_escalating_succeeds_at_line_granularity() {
+        // Every fifth word of every line is changed in new, which makes a word-level LCS take many
+        // tasks, but there are only three lines to diff at line granularity.
+        let line = |line_number: usize, changed_word: &str| (0..2000).map(|i| {
+            if i % 5 == 0 { changed_word.to_string() } else { format!("w{}-{}", line_number, i) }
+        }).collect::<Vec<_>>().join(" ") + "\n";
+        let old = line(1, "old") + &line(2, "old") + &line(3, "old");
+        let new = line(1, "new") + &line(2, "new") + &line(3, "new");
+        let other = line(1, "old") + "vandalized line\n" + &line(3, "old");
+        let merger = Merger::new(1_000_000, 60_000).with_lcs_max_tasks(1000);
+        assert!(merger.try_merge(&old, &new, &other, "1").1);
+
+        let mut stats = MergeStats::default();
+        let (merged, timed_out) =
+            merger.try_merge_escalating(&old, &new, &other, "1", &mut stats);
+        assert!(!timed_out);
+        assert!(merged.contains("vandalized line"));
+        assert_eq!(0, stats.timeouts);
+    }
+
+    #[test]
+    fn test_try_merge_with_partial_lcs() {
+        let old = "The quick brown fox jumps over the lazy dog.";
+        let new = "The quick brown fox jumps over the lazy dog. It naps.";
+        let other = "The quick brown fox jumps over the lazy cat.";
+        let merger = Merger::new(1000, 500).with_lcs_max_tasks(1);
+        assert_eq!((new.to_string(), true), merger.try_merge(old, new, other, "1"));
+
+        // Only the first task ran, so the texts were only compared as far as the common prefix,
+        // and new is kept as it is past that.
+        let (merged, timed_out) = merger.with_partial_lcs(true).try_merge(old, new, other, "1");
+        assert!(!timed_out);
+        assert_eq!(new, merged);
+    }
+
+    #[test]
+    fn test_try_merge_with_partial_lcs_keeps_new_past_frontier() {
+        let old = "The quick brown fox jumps over the lazy dog. It naps in the sun all day.";
+        let new = "The quick brown fox jumps over the lazy dog. It naps in the shade all day.";
+        let other = "The slow brown fox jumps over the lazy dog. It naps in the sun all night.";
+        let merger = Merger::new(1000, 500).with_lcs_max_tasks(12).with_partial_lcs(true);
+        // The LCS reached past "slow", but not "night", so the current text's "shade all day." is
+        // kept instead of being replaced by other's ending.
+        let (merged, timed_out) = merger.try_merge(old, new, other, "1");
+        assert!(!timed_out);
+        assert_eq!(format!("The {}1{}slow {}1{}brown fox jumps over the lazy dog. It naps in the \
+                            shade all day.", START_MARKER, START_MARKER, END_MARKER, END_MARKER),
+                   merged);
+    }
+
+    #[test]
+    fn test_try_merge_skips_dissimilar_texts() {
+        let merger = Merger::new(1000, 500).with_min_similarity(0.3);
+        let old = "The quick brown fox jumps over the lazy dog.";
+        let new = "The quick brown fox jumps over the sleeping dog.";
+
+        // A total rewrite isn't merged at all.
+        let mut stats = MergeStats::default();
+        let other = "Lorem ipsum dolor sit amet, consectetur adipiscing elit.";
+        assert_eq!((new.to_string(), false),
+                   merger.try_merge_with_stats(old, new, other, "1", &mut stats));
+        assert_eq!(1, stats.dissimilar_skips);
+
+        // A small change is.
+        let mut stats = MergeStats::default();
+        let other = "The quick brown fox jumps over the lazy cat.";
+        let (merged, timed_out) = merger.try_merge_with_stats(old, new, other, "1", &mut stats);
+        assert!(!timed_out);
+        assert!(merged.contains(START_MARKER));
+        assert_eq!(0, stats.dissimilar_skips);
+    }
+
+    #[test]
+    fn test_try_merge_times_out() {
+        // Every fifth word is changed in new, and every seventh in other, so both LCSes take many
+        // tasks.
+        let text = |changed_word: &str, period: usize| (0..5000).map(|i| {
+            if i % period == 0 { changed_word.to_string() } else { format!("w{}", i) }
+        }).collect::<Vec<_>>().join(" ");
+        let old = (0..5000).map(|i| format!("w{}", i)).collect::<Vec<_>>().join(" ");
+        let new = text("new", 5);
+        let other = text("other", 7);
+        let mut stats = MergeStats::default();
+        assert_eq!((new.clone(), true),
+                   Merger::new(usize::max_value(), 60_000).with_lcs_max_tasks(1000)
+                       .try_merge_with_stats(&old, &new, &other, "1", &mut stats));
+        assert_eq!(1, stats.timeouts);
+    }
 
     #[test]
     fn test_try_merge_empty() {
@@ -485,6 +1380,100 @@ mod tests {
         assert_eq!((expected, false), try_merge(old, new, other, "123"));
     }
 
+    #[test]
+    fn test_try_merge_conflict_policies() {
+        let old = "First sentence. Second sentence.";
+        let new = "First sentence. Second sentence changed one way.";
+        let other = "First sentence changed. Second sentence changed a different way.";
+        let merge = |conflict_policy| {
+            Merger::new(1000, 500).with_conflict_policy(conflict_policy)
+                .try_merge(old, new, other, "123")
+        };
+        assert_eq!(try_merge(old, new, other, "123"), merge(ConflictPolicy::KeepVandalism));
+        assert_eq!((new.to_string(), false), merge(ConflictPolicy::KeepClean));
+        let expected = format!(
+            "First {}123{}{}sentence. {}123{}{}{}123{}sentence changed. {}123{}Second {}123{}{}sentence changed one way.{}123{}{}{}123{}sentence changed a different way.{}123{}",
+            START_MARKER, CURRENT_MARKER, START_MARKER, END_MARKER, CURRENT_MARKER, END_MARKER,
+            START_MARKER, START_MARKER, END_MARKER, END_MARKER,
+            START_MARKER, CURRENT_MARKER, START_MARKER, END_MARKER, CURRENT_MARKER, END_MARKER,
+            START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        assert_eq!((expected.clone(), false), merge(ConflictPolicy::KeepBoth));
+        // Without the markers of the current text, it's the current text, then the vandalism.
+        assert!(remove_current_text_markers(&expected).starts_with(
+            &format!("First sentence. {}123{}sentence changed.", START_MARKER, START_MARKER)));
+    }
+
+    #[test]
+    fn test_try_merge_prefers_recent_restorations() {
+        let current = "The sky is blue.";
+        let merge = |prefer_recent_restorations| {
+            let merger = Merger::new(1000, 500)
+                .with_prefer_recent_restorations(prefer_recent_restorations);
+            // Revision 200 reverted "green", and then revision 100 (made earlier) "purple". Both
+            // restorations change the same word, so they conflict.
+            let (merged, _) = merger.try_merge(current, current, "The sky is green.", "200");
+            merger.try_merge(current, &merged, "The sky is purple.", "100")
+        };
+        let recent = format!("The sky is {}200{}green.{}200{}",
+                             START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        assert_eq!((recent, false), merge(true));
+        let oldest = format!("The sky is {}100{}purple.{}100{}",
+                             START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        assert_eq!((oldest, false), merge(false));
+    }
+
+    #[test]
+    fn test_try_merge_prefers_recent_restorations_around_the_conflict() {
+        let old = "It's true. The sky is blue. Really. Yes.";
+        // The restored region around "green." has words in common with `old`, so its markers are
+        // in other chunks than the one that conflicts.
+        let new = format!("It's true. {}200{}Oh, sky is green. Really. Okay.{}200{} Yes.",
+                          START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        let other = "It's true. The sky is purple. Really. Yes.";
+        let merge = |prefer_recent_restorations| {
+            Merger::new(1000, 500).with_prefer_recent_restorations(prefer_recent_restorations)
+                .try_merge(old, &new, other, "100")
+        };
+        assert_eq!((new.clone(), false), merge(true));
+        assert!(merge(false).0.contains("purple."));
+    }
+
+    #[test]
+    fn test_try_merge_with_stats_counts_conflicts() {
+        let old = "First sentence. Second sentence.";
+        let new = "First sentence. Second sentence changed one way.";
+        let other = "First sentence changed. Second sentence changed a different way.";
+        let mut stats = MergeStats::default();
+        Merger::new(1000, 500).try_merge_with_stats(old, new, other, "123", &mut stats);
+        assert_eq!(1, stats.conflicts);
+        assert_eq!(0, stats.timeouts);
+    }
+
+    #[test]
+    fn test_merge_quality() {
+        let merger = Merger::new(1000, 500);
+        // One word changed in each of new and other, far apart: nearly all stable, and clean.
+        let old = "The quick brown fox jumps over the lazy dog by the river bank.";
+        let new = "The quick brown fox jumps over the lazy cat by the river bank.";
+        let other = "The slow brown fox jumps over the lazy dog by the river bank.";
+        let mut stable_stats = MergeStats::default();
+        merger.try_merge_with_stats(old, new, other, "1", &mut stable_stats);
+        assert_eq!(0, stable_stats.conflicts);
+        assert_eq!(2, stable_stats.clean_chunks);
+        assert!(stable_stats.quality() > 0.8);
+
+        // Every word changed differently in new and other: one big conflict, nothing stable.
+        let new = "A speedy red wolf leaps above a sleepy cat near a stream's edge.";
+        let other = "One sluggish grey hound trots past one idle mouse beside some lake shore.";
+        let mut conflicting_stats = MergeStats::default();
+        merger.try_merge_with_stats(old, new, other, "1", &mut conflicting_stats);
+        assert!(conflicting_stats.conflicts > 0);
+        assert!(conflicting_stats.quality() < 0.2);
+
+        // Nothing merged scores perfectly.
+        assert_eq!(1.0, MergeStats::default().quality());
+    }
+
     #[test]
     fn test_try_merge_with_change_at_end() {
         let old = "Test string. ";
@@ -495,6 +1484,41 @@ mod tests {
         assert_eq!((expected, false), try_merge(old, new, other, "test"));
     }
 
+    #[test]
+    fn test_try_merge_marks_deletions_as_removals() {
+        let old = "One two three four.";
+        let new = "One two three four five.";
+        let other = "One three four.";
+        let expected = format!("One {}test{}{}{}test{}{}three four five.",
+                               START_MARKER, REMOVAL_MARKER, START_MARKER,
+                               END_MARKER, REMOVAL_MARKER, END_MARKER);
+        assert_eq!((expected, false), try_merge(old, new, other, "test"));
+    }
+
+    #[test]
+    fn test_try_merge_detailed() {
+        let old = "One two three four five six seven.";
+        let new = "One two three four five six eight.";
+        let other = "One 2 three four six seven.";
+        let merger = Merger::new(1000, 500);
+        let result = merger.try_merge_detailed(old, new, other, "1");
+        assert_eq!(MergeResult {
+                       segments: vec![
+                           MergeSegment::Clean("One ".to_string()),
+                           MergeSegment::Vandalism { text: "2 ".to_string(),
+                                                     marker: "1".to_string() },
+                           MergeSegment::Clean("three four ".to_string()),
+                           // "five" was deleted.
+                           MergeSegment::Vandalism { text: "".to_string(),
+                                                     marker: "1".to_string() },
+                           MergeSegment::Clean("six eight.".to_string()),
+                       ],
+                       timed_out: false,
+                   },
+                   result);
+        assert_eq!((result.to_marked_string(), false), merger.try_merge(old, new, other, "1"));
+    }
+
     #[test]
     fn test_try_merge_special_characters() {
         let old = "First sentence. Second sentence.";
@@ -506,6 +1530,93 @@ mod tests {
         assert_eq!((expected, false), try_merge(old, new, other, "test"));
     }
 
+    /// Merges by line, with limits that are never reached.
+    fn try_merge_lines(old: &str, new: &str, other: &str, marker: &str) -> (String, bool) {
+        Merger::new(1000, 500).with_granularity(Granularity::Line)
+            .try_merge(old, new, other, marker)
+    }
+
+    #[test]
+    fn test_try_merge_lines_empty() {
+        assert_eq!(("".to_string(), false), try_merge_lines("", "", "", ""));
+    }
+
+    #[test]
+    fn test_try_merge_lines_clean() {
+        let old = "First line.\nSecond line.\nThird line.\n";
+        let new = "First line.\nSecond line.\nThird line changed.\n";
+        let other = "First line changed.\nSecond line.\nThird line.\n";
+        let expected = format!(
+            "{}test{}First line changed.\n{}test{}Second line.\nThird line changed.\n",
+            START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        assert_eq!((expected, false), try_merge_lines(old, new, other, "test"));
+    }
+
+    #[test]
+    fn test_try_merge_lines_conflicting() {
+        let old = "First line.\nSecond line.\nThird line.\n";
+        let new = "First line.\nSecond line.\nThird line changed one way.\n";
+        let other = "First line changed.\nSecond line.\nThird line changed a different way.\n";
+        let expected = format!(
+            "{}123{}First line changed.\n{}123{}Second line.\n{}123{}Third line changed a different way.\n{}123{}",
+            START_MARKER, START_MARKER, END_MARKER, END_MARKER,
+            START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        assert_eq!((expected, false), try_merge_lines(old, new, other, "123"));
+    }
+
+    #[test]
+    fn test_try_merge_lines_with_change_at_end() {
+        let old = "Test line.\nEnd.\n";
+        let new = "Test 1 line.\nEnd.\n";
+        let other = "Test line.\nEnd.\n2";
+        let expected = format!("Test 1 line.\nEnd.\n{}test{}2{}test{}",
+                               START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        assert_eq!((expected, false), try_merge_lines(old, new, other, "test"));
+    }
+
+    #[test]
+    fn test_try_merge_lines_marks_deletions_as_removals() {
+        let old = "* One\n* Two\n* Three\n";
+        let new = "* One\n* Two\n* Three\n* Four\n";
+        let other = "* One\n* Three\n";
+        let expected = format!("* One\n{}test{}{}{}test{}{}* Three\n* Four\n",
+                               START_MARKER, REMOVAL_MARKER, START_MARKER,
+                               END_MARKER, REMOVAL_MARKER, END_MARKER);
+        assert_eq!((expected, false), try_merge_lines(old, new, other, "test"));
+    }
+
+    #[test]
+    fn test_try_merge_lines_restores_whole_lines() {
+        // A one-word change restores the whole line it's on.
+        let old = "{| class=\"wikitable\"\n! City !! Country\n| Paris || France\n|}\n";
+        let new =
+            "{| class=\"wikitable\" style=\"width: 50%\"\n! City !! Country\n| Paris || France\n|}\n";
+        let other = "{| class=\"wikitable\"\n! City !! Country\n| Paris || Germany\n|}\n";
+        let expected = format!(
+            "{{| class=\"wikitable\" style=\"width: 50%\"\n! City !! Country\n{}test{}| Paris || Germany\n{}test{}|}}\n",
+            START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        assert_eq!((expected, false), try_merge_lines(old, new, other, "test"));
+    }
+
+    #[test]
+    fn test_try_merge_lines_special_characters() {
+        let old = "First line.\nSecond line.\nThird line.\n";
+        let new = "First line.\nSecond line.\nThird line 𐅃.\n";
+        let other = "First line さようなら.\nSecond line.\nThird line.\n";
+        let expected = format!(
+            "{}test{}First line さようなら.\n{}test{}Second line.\nThird line 𐅃.\n",
+            START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        assert_eq!((expected, false), try_merge_lines(old, new, other, "test"));
+    }
+
+    #[test]
+    fn test_granularity_from_str() {
+        assert_eq!(Ok(Granularity::Line), "line".parse());
+        assert_eq!(Ok(Granularity::Word), "word".parse());
+        assert_eq!(Ok(Granularity::Paragraph), "paragraph".parse());
+        assert!("sentence".parse::<Granularity>().is_err());
+    }
+
     #[test]
     fn test_calculate_match_state_transitions() {
         // This test case uses the strings from figure 1 of Khanna, Kunal, and Pierce 2007.
@@ -550,4 +1661,56 @@ mod tests {
                             Chunk::Unstable((6, 0), (6, 0), (6, 1))];
         assert_eq!(expected, parse(new_lcs, other_lcs, 6, 6, 7));
     }
+
+    #[test]
+    fn test_merged_article_to_wikitext() {
+        let sections = vec![("", "Intro\n"), ("History", "==History==\nOld\n"),
+                            ("See also", "==See also==\n* [[Link]]")];
+        let merged_article = MergedArticle {
+            sections: sections.iter().enumerate().map(|(i, &(title, content))| MergedSection {
+                title: title.to_string(),
+                content: content.to_string(),
+                stats: MergeStats { sections_merged: 1, conflicts: i as u64, restored_bytes: 10,
+                                    ..MergeStats::default() },
+            }).collect(),
+        };
+        let mut concatenated = String::new();
+        for &(_, content) in &sections {
+            concatenated.push_str(content);
+        }
+        assert_eq!(concatenated, merged_article.to_wikitext());
+        assert_eq!(MergeStats { sections_merged: 3, conflicts: 3, restored_bytes: 30,
+                                ..MergeStats::default() },
+                   merged_article.stats());
+    }
+
+    #[test]
+    fn test_time_limit_ms_scales_with_size() {
+        let merger = Merger::new(1000, 500);
+        assert_eq!(500, merger.time_limit_ms(10));
+        assert_eq!(500, merger.time_limit_ms(1000000));
+
+        let merger =
+            merger.with_time_limit_scaling(TimeLimitScaling { ms_per_kib: 100, min_ms: 20 });
+        // Small sections get the minimum.
+        assert_eq!(20, merger.time_limit_ms(10));
+        // Medium sections get time in proportion to their size.
+        assert_eq!(100, merger.time_limit_ms(1024));
+        assert_eq!(200, merger.time_limit_ms(2048));
+        // Large sections are capped at the diff time limit.
+        assert_eq!(500, merger.time_limit_ms(1000000));
+    }
+
+    #[test]
+    fn test_get_changes() {
+        let old = "The fox jumped over the dog.";
+        let other = "The quick fox jumped over a dog.";
+        let changes = Merger::new(1000, 500).get_changes(old, other).unwrap();
+        assert_eq!(vec![Change { old_range: 4..4, other_range: 4..10 },
+                        Change { old_range: 20..24, other_range: 26..28 }],
+                   changes);
+        assert_eq!("quick ", &other[changes[0].other_range.clone()]);
+        assert_eq!("the ", &old[changes[1].old_range.clone()]);
+        assert_eq!("a ", &other[changes[1].other_range.clone()]);
+    }
 }
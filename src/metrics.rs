@@ -0,0 +1,208 @@
+//! Metrics about the server's work: counters of the requests served, the merges attempted, and
+//! how the caches fared, and histograms of how long each phase of serving a page (see `Timer`)
+//! takes. They're kept in one registry for the whole process, and served at /metrics in the
+//! Prometheus text format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A count of something that has happened since the server started.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Counter {
+    /// Requests for /wiki/ pages.
+    RequestsServed,
+    /// Merges of a revert into a section.
+    MergesAttempted,
+    /// Merges that gave up because a diff took too long.
+    DiffTimeouts,
+    /// Requests for finished pages found in the page cache.
+    PageCacheHits,
+    /// Requests for finished pages not found in the page cache.
+    PageCacheMisses,
+    /// Cacheable API calls whose responses were found in the API cache.
+    ApiCacheHits,
+    /// Cacheable API calls whose responses had to be fetched from the wiki.
+    ApiCacheMisses,
+    /// Merged sections whose merges scored below --min_section_quality.
+    LowQualitySections,
+}
+
+/// All the counters, in the order they're rendered.
+const COUNTERS: [Counter; 8] =
+    [Counter::RequestsServed, Counter::MergesAttempted, Counter::DiffTimeouts,
+     Counter::PageCacheHits, Counter::PageCacheMisses, Counter::ApiCacheHits,
+     Counter::ApiCacheMisses, Counter::LowQualitySections];
+
+impl Counter {
+    /// Returns the counter's metric name.
+    fn name(&self) -> &'static str {
+        match *self {
+            Counter::RequestsServed => "wmw_requests_served_total",
+            Counter::MergesAttempted => "wmw_merges_attempted_total",
+            Counter::DiffTimeouts => "wmw_diff_timeouts_total",
+            Counter::PageCacheHits => "wmw_page_cache_hits_total",
+            Counter::PageCacheMisses => "wmw_page_cache_misses_total",
+            Counter::ApiCacheHits => "wmw_api_cache_hits_total",
+            Counter::ApiCacheMisses => "wmw_api_cache_misses_total",
+            Counter::LowQualitySections => "wmw_low_quality_sections_total",
+        }
+    }
+
+    /// Returns the counter's description, for its HELP line.
+    fn help(&self) -> &'static str {
+        match *self {
+            Counter::RequestsServed => "Requests for /wiki/ pages.",
+            Counter::MergesAttempted => "Merges of a revert into a section.",
+            Counter::DiffTimeouts => "Merges that gave up because a diff took too long.",
+            Counter::PageCacheHits => "Requests for finished pages found in the page cache.",
+            Counter::PageCacheMisses => "Requests for finished pages not in the page cache.",
+            Counter::ApiCacheHits => "Cacheable API calls answered from the API cache.",
+            Counter::ApiCacheMisses => "Cacheable API calls that had to go to the wiki.",
+            Counter::LowQualitySections => "Merged sections scoring below --min_section_quality.",
+        }
+    }
+}
+
+/// The name of the histograms of phase durations.
+const DURATION_METRIC: &'static str = "wmw_phase_duration_milliseconds";
+
+/// The upper bounds of the buckets of the duration histograms, in milliseconds. Durations over the
+/// last go in the implicit "+Inf" bucket.
+const DURATION_BUCKETS_MS: [u64; 10] = [10, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000];
+
+/// The durations of one phase.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Histogram {
+    /// The number of durations in each bucket of DURATION_BUCKETS_MS (not counting those in
+    /// earlier buckets).
+    bucket_counts: [u64; 10],
+    /// The number of durations.
+    count: u64,
+    /// The sum of the durations, in milliseconds.
+    sum_ms: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration_ms: u64) {
+        if let Some(bucket) = DURATION_BUCKETS_MS.iter().position(|&bound| duration_ms <= bound) {
+            self.bucket_counts[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum_ms += duration_ms;
+    }
+}
+
+/// The registry of metrics.
+pub struct Metrics {
+    /// The value of each counter, indexed by the counter's position in COUNTERS.
+    counters: Vec<AtomicUsize>,
+    /// The durations of each phase timed, by phase.
+    durations: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        Metrics {
+            counters: COUNTERS.iter().map(|_| AtomicUsize::new(0)).collect(),
+            durations: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Adds one to `counter`.
+    pub fn increment(&self, counter: Counter) {
+        self.counters[counter as usize].fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Returns the value of `counter`.
+    pub fn get(&self, counter: Counter) -> usize {
+        self.counters[counter as usize].load(Ordering::SeqCst)
+    }
+
+    /// Records that the phase `phase` took `duration_ms` milliseconds.
+    pub fn observe_duration(&self, phase: &'static str, duration_ms: u64) {
+        self.durations.lock().unwrap().entry(phase).or_insert_with(Histogram::default)
+            .observe(duration_ms);
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for counter in COUNTERS.iter() {
+            write!(text, "# HELP {} {}\n# TYPE {} counter\n{} {}\n", counter.name(),
+                   counter.help(), counter.name(), counter.name(), self.get(*counter)).unwrap();
+        }
+        write!(text, "# HELP {} How long each phase of serving a page took.\n# TYPE {} histogram\n",
+               DURATION_METRIC, DURATION_METRIC).unwrap();
+        for (phase, histogram) in self.durations.lock().unwrap().iter() {
+            let mut cumulative_count = 0;
+            for (bound, bucket_count) in DURATION_BUCKETS_MS.iter().zip(&histogram.bucket_counts) {
+                cumulative_count += *bucket_count;
+                write!(text, "{}_bucket{{phase=\"{}\",le=\"{}\"}} {}\n", DURATION_METRIC, phase,
+                       bound, cumulative_count).unwrap();
+            }
+            write!(text, "{}_bucket{{phase=\"{}\",le=\"+Inf\"}} {}\n", DURATION_METRIC, phase,
+                   histogram.count).unwrap();
+            write!(text, "{}_sum{{phase=\"{}\"}} {}\n", DURATION_METRIC, phase, histogram.sum_ms)
+                .unwrap();
+            write!(text, "{}_count{{phase=\"{}\"}} {}\n", DURATION_METRIC, phase, histogram.count)
+                .unwrap();
+        }
+        text
+    }
+}
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics::new();
+}
+
+/// Returns the process's registry of metrics.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Counter, Histogram, Metrics, metrics};
+    use timer::Timer;
+
+    #[test]
+    fn test_histogram_observe() {
+        let mut histogram = Histogram::default();
+        histogram.observe(5);
+        histogram.observe(10);
+        histogram.observe(700);
+        histogram.observe(60000);
+        assert_eq!([2, 0, 0, 0, 0, 1, 0, 0, 0, 0], histogram.bucket_counts);
+        assert_eq!(4, histogram.count);
+        assert_eq!(60715, histogram.sum_ms);
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.increment(Counter::RequestsServed);
+        metrics.increment(Counter::RequestsServed);
+        metrics.observe_duration("parse_wikitext", 75);
+        let text = metrics.render();
+        assert!(text.contains("# TYPE wmw_requests_served_total counter\n\
+                               wmw_requests_served_total 2\n"));
+        assert!(text.contains("\nwmw_diff_timeouts_total 0\n"));
+        assert!(text.contains(
+            "wmw_phase_duration_milliseconds_bucket{phase=\"parse_wikitext\",le=\"50\"} 0\n\
+             wmw_phase_duration_milliseconds_bucket{phase=\"parse_wikitext\",le=\"100\"} 1\n"));
+        assert!(text.contains(
+            "wmw_phase_duration_milliseconds_bucket{phase=\"parse_wikitext\",le=\"+Inf\"} 1\n\
+             wmw_phase_duration_milliseconds_sum{phase=\"parse_wikitext\"} 75\n\
+             wmw_phase_duration_milliseconds_count{phase=\"parse_wikitext\"} 1\n"));
+    }
+
+    #[test]
+    fn test_timers_record_durations() {
+        drop(Timer::new("test_timers_record_durations", "Test timer".to_string()));
+        drop(Timer::new("test_timers_record_durations", "Test timer".to_string()));
+        assert!(metrics().render().contains(
+            "wmw_phase_duration_milliseconds_count{phase=\"test_timers_record_durations\"} 2\n"));
+    }
+}
@@ -7,9 +7,12 @@ extern crate html5ever_dom_sink;
 extern crate rand;
 extern crate tendril;
 
+use std::cmp;
+use std::collections::HashMap;
+use std::iter;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
 
 use html5ever::Attribute;
 use html5ever::tree_builder::interface::TreeSink;
@@ -18,11 +21,111 @@ use html5ever_dom_sink::rcdom::Handle;
 use html5ever_dom_sink::rcdom::RcDom;
 use regex::Captures;
 use regex::Regex;
+use time::Tm;
 
+use error::AppError;
 use wiki::Wiki;
+use wikipedia_minus_wikipedians::END_MARKER;
+use wikipedia_minus_wikipedians::REMOVAL_MARKER;
+use wikipedia_minus_wikipedians::START_MARKER;
+use worker_pool::WorkerPool;
 
-use ::START_MARKER;
-use ::END_MARKER;
+/// How restored vandalism is marked up in the rendered page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarkerOutput {
+    /// Each restored region is wrapped in a <span> highlighting it.
+    Span,
+    /// Each restored region is wrapped in a collapsible <details> element.
+    Details,
+}
+
+impl FromStr for MarkerOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MarkerOutput, String> {
+        match s {
+            "span" => Ok(MarkerOutput::Span),
+            "details" => Ok(MarkerOutput::Details),
+            _ => Err(format!("Unknown marker output \"{}\"", s)),
+        }
+    }
+}
+
+/// The skin of the page skeleton the article body is inserted into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Skin {
+    /// The desktop skin, where the article body is the "mw-content-text" node.
+    Desktop,
+    /// The mobile skin (as served from e.g. en.m.wikipedia.org). This splits the article body into
+    /// collapsible sections inside "mw-content-text", so the whole "bodyContent" node is replaced.
+    Mobile,
+}
+
+impl Skin {
+    /// Returns the ID of the node whose contents are replaced with the article body.
+    fn content_node_id(&self) -> &'static str {
+        match *self {
+            Skin::Desktop => "mw-content-text",
+            Skin::Mobile => "bodyContent",
+        }
+    }
+}
+
+impl FromStr for Skin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Skin, String> {
+        match s {
+            "desktop" => Ok(Skin::Desktop),
+            "mobile" => Ok(Skin::Mobile),
+            _ => Err(format!("Unknown skin \"{}\"", s)),
+        }
+    }
+}
+
+/// Settings that control how merge markers are turned into HTML.
+#[derive(Clone)]
+pub struct RenderOptions {
+    pub marker_output: MarkerOutput,
+    /// Whether to shade restored regions by how much text they contain (more saturated red for
+    /// bigger regions), and record that size in a "--restore-size" CSS custom property.
+    pub severity_shading: bool,
+    /// Whether to append a footer to the article listing each edit whose vandalism was restored.
+    pub restoration_footer: bool,
+    /// Text to show inside a restored deletion, which otherwise has no content of its own.
+    pub removal_placeholder: String,
+    /// The most runs of restored text to mark up in a page, or 0 for no limit. Runs after that
+    /// many are left as plain text, so a page with thousands of them stays readable and fast to
+    /// render.
+    pub max_spans: usize,
+    /// The inline style of the spans restored vandalism is wrapped in (with `MarkerOutput::Span`),
+    /// e.g. "background: yellow", or "" for none, to leave the styling to a stylesheet. Overridden
+    /// by `severity_shading`.
+    pub vandalism_span_style: String,
+    /// The hostname of the wiki to link each restored element's hover text to the diff of its
+    /// revision on (e.g. "en.wikipedia.org"), or None for no hover text.
+    pub diff_hostname: Option<String>,
+    /// Whether to append a footer to the article saying when it was merged.
+    pub merge_timestamp: bool,
+    /// Whether to add a button to the page that shows and hides the restored vandalism.
+    pub inject_toggle: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            marker_output: MarkerOutput::Span,
+            severity_shading: false,
+            restoration_footer: false,
+            removal_placeholder: "".to_string(),
+            max_spans: 0,
+            vandalism_span_style: "color: red".to_string(),
+            diff_hostname: None,
+            merge_timestamp: false,
+            inject_toggle: false,
+        }
+    }
+}
 
 /// Represents, and owns all behavior related to, the contents of the HTML page shown to the
 /// user. This includes fetching the rendered article from Wikipedia, replacing its contents with
@@ -36,51 +139,171 @@ pub struct Page {
     /// The string used as a placeholder for the article body in the page skeleton.
     placeholder: String,
     /// The Receiver that will receive the page skeleton when it's been fetched and processed.
-    page_skeleton_receiver: Receiver<Result<String, String>>,
+    page_skeleton_receiver: Receiver<Result<String, AppError>>,
+    render_options: RenderOptions,
+    /// The hostname of the wiki the page is fetched from. Links to its articles are rewritten to
+    /// point at the mirror.
+    wiki_hostname: String,
 }
 
 impl Page {
-    /// Creates a new Page representing the article at `title`. This kicks off a background thread
-    /// that fetches the current article HTML from Wikipedia. Because of that, it should be called
-    /// as early as possible (as soon as the title being served is known), so that the page fetch
-    /// stays off the critical path for page load.
-    pub fn new(title: &str, wiki: Wiki) -> Page {
-        let placeholder = format!("WMW_PLACEHOLDER_{}", rand::random::<u64>());
-        let page_skeleton_receiver =
-            Page::spawn_page_skeleton_fetch_thread(title, placeholder.clone(), wiki);
+    /// Creates a new Page representing the article at `title`, whose latest revision is `revid`.
+    /// This kicks off a background job that fetches the current article HTML, in `skin`, from
+    /// Wikipedia (or joins a fetch of it already in progress, in `skeleton_fetches`). Because of
+    /// that, it should be called as early as possible (as soon as the title being served is
+    /// known), so that the page fetch stays off the critical path for page load.
+    pub fn new(title: &str, revid: u64, wiki: Wiki, skin: Skin, render_options: RenderOptions,
+               skeleton_fetches: &SkeletonFetches) -> Page {
+        let wiki_hostname = wiki.hostname.clone();
+        let (placeholder, page_skeleton_receiver) =
+            skeleton_fetches.fetch(title, revid, wiki, skin);
         Page {
             placeholder: placeholder,
             page_skeleton_receiver: page_skeleton_receiver,
+            render_options: render_options,
+            wiki_hostname: wiki_hostname,
         }
     }
 
     /// This finishes the HTML processing - it replaces the merge markers in `article_body` with
-    /// HTML tags, and inserts the resulting HTML into the page skeleton.
-    pub fn replace_body_and_remove_merge_markers(&self, article_body: String)
-                                                 -> Result<String, String> {
+    /// HTML tags, inserts the resulting HTML into the page skeleton, and points the links to the
+    /// wiki's articles at the mirror.
+    ///
+    /// `reverting_users` maps the ID of each revision merged into the article to the user who made
+    /// it, for the restoration footer. `merged_at` is when the article was merged, for the merge
+    /// timestamp.
+    pub fn replace_body_and_remove_merge_markers(&self, article_body: String,
+                                                 reverting_users: &HashMap<u64, String>,
+                                                 merged_at: &Tm) -> Result<String, AppError> {
         match self.page_skeleton_receiver.recv() {
             Ok(Ok(page_skeleton)) => {
-                let finished_article_body = process_merge_markers(article_body);
-                Ok(page_skeleton.replace(&self.placeholder, &finished_article_body))
+                let finished_article_body = finish_article_body(
+                    article_body, reverting_users, &self.render_options, merged_at);
+                let mut page = page_skeleton.replace(&self.placeholder, &finished_article_body);
+                if self.render_options.inject_toggle {
+                    page = insert_vandalism_toggle(&page);
+                }
+                Ok(rewrite_article_links(&page, &self.wiki_hostname))
             },
-            Ok(Err(msg))=> Err(msg),
-            Err(err) => Err(format!("error: {}", err)),
+            Ok(Err(err)) => Err(err),
+            Err(err) => Err(AppError::Internal(format!("Failed to get page skeleton: {}", err))),
+        }
+    }
+}
+
+/// The page skeleton fetches in progress, shared between Pages, so that concurrent requests for
+/// the same page can wait for one fetch, instead of each downloading the whole page.
+#[derive(Clone)]
+pub struct SkeletonFetches {
+    /// Whether concurrent fetches of the same page are shared. If not, every Page fetches its own
+    /// skeleton.
+    shared: bool,
+    /// Maps the key (see `get_skeleton_fetch_key()`) of each fetch in progress to the placeholder
+    /// in its skeleton, and the Senders of the Pages that joined it.
+    in_progress: Arc<Mutex<HashMap<String, (String, Vec<Sender<Result<String, AppError>>>)>>>,
+    /// The workers the fetches run on.
+    workers: WorkerPool,
+}
+
+impl SkeletonFetches {
+    pub fn new(shared: bool, workers: WorkerPool) -> SkeletonFetches {
+        SkeletonFetches {
+            shared: shared,
+            in_progress: Arc::new(Mutex::new(HashMap::new())),
+            workers: workers,
         }
     }
 
-    fn spawn_page_skeleton_fetch_thread(title: &str, placeholder: String, wiki: Wiki)
-                                  -> Receiver<Result<String, String>> {
-        let (page_skeleton_sender, page_skeleton_receiver) = channel::<Result<String, String>>();
-        let title = title.to_owned().clone();
-        thread::Builder::new().name(format!("fetch-skeleton-{}", title)).spawn(move|| {
-            page_skeleton_sender.send(
-                match wiki.get_current_page_content(&title) {
-                    Ok(content) =>
-                        replace_node_with_placeholder(&content, "mw-content-text", &placeholder),
-                    Err(msg) => Err(msg),
-                }).unwrap();
+    /// Starts fetching the skeleton of the page `title` (whose latest revision is `revid`) from
+    /// `wiki`, in `skin`, on a worker from `workers`, or joins a fetch of it that's already in
+    /// progress. Returns the placeholder for the article body in the skeleton, and a Receiver for
+    /// the skeleton.
+    fn fetch(&self, title: &str, revid: u64, wiki: Wiki, skin: Skin)
+             -> (String, Receiver<Result<String, AppError>>) {
+        let (page_skeleton_sender, page_skeleton_receiver) = channel::<Result<String, AppError>>();
+        let key = get_skeleton_fetch_key(&wiki.hostname, title, revid, skin);
+        let mut in_progress = self.in_progress.lock().unwrap();
+        if self.shared {
+            if let Some(&mut (ref placeholder, ref mut senders)) = in_progress.get_mut(&key) {
+                info!("Joining the fetch in progress of the skeleton of \"{}\"", title);
+                senders.push(page_skeleton_sender);
+                return (placeholder.clone(), page_skeleton_receiver);
+            }
+        }
+        let placeholder = format!("WMW_PLACEHOLDER_{}", rand::random::<u64>());
+        if self.shared {
+            in_progress.insert(key.clone(), (placeholder.clone(), vec![]));
+        }
+        drop(in_progress);
+
+        let mut finished_fetch = FinishedFetch {
+            skeleton_fetches: self.clone(),
+            key: key,
+            sender: page_skeleton_sender,
+            page_skeleton: None,
+        };
+        let thread_placeholder = placeholder.clone();
+        let title = title.to_string();
+        self.workers.execute(move|| {
+            finished_fetch.page_skeleton = Some(match wiki.get_current_page_content(&title) {
+                Ok(content) => Ok(insert_placeholder(&content, &thread_placeholder, skin)),
+                Err(msg) => Err(AppError::Upstream(msg)),
+            });
         });
-        page_skeleton_receiver
+        (placeholder, page_skeleton_receiver)
+    }
+}
+
+/// Finishes a skeleton fetch when dropped: removes it from the fetches in progress, and sends the
+/// skeleton to every Page waiting for it. If the fetch panicked before it got the skeleton, they're
+/// sent an error instead, so none of them wait forever.
+struct FinishedFetch {
+    skeleton_fetches: SkeletonFetches,
+    /// The key of the fetch (see `get_skeleton_fetch_key()`).
+    key: String,
+    /// The Sender of the Page that started the fetch.
+    sender: Sender<Result<String, AppError>>,
+    /// The skeleton, or the error fetching it, once the fetch is done.
+    page_skeleton: Option<Result<String, AppError>>,
+}
+
+impl Drop for FinishedFetch {
+    fn drop(&mut self) {
+        let page_skeleton = self.page_skeleton.take().unwrap_or_else(
+            || Err(AppError::Internal(format!("Fetch of page skeleton {} failed", self.key))));
+        let joined_senders = if self.skeleton_fetches.shared {
+            // The lock is never held while fetching, but a panic while holding it shouldn't leave
+            // the waiting Pages stuck.
+            let mut in_progress = self.skeleton_fetches.in_progress.lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            in_progress.remove(&self.key).map_or(vec![], |(_, senders)| senders)
+        } else {
+            vec![]
+        };
+        for sender in iter::once(&self.sender).chain(joined_senders.iter()) {
+            // A Page whose request failed before it needed its skeleton is already gone.
+            let _ = sender.send(page_skeleton.clone());
+        }
+    }
+}
+
+/// Returns the key that fetches of the skeleton of the page `title`, from the wiki at `hostname`,
+/// as of revision `revid`, in `skin`, are shared under.
+fn get_skeleton_fetch_key(hostname: &str, title: &str, revid: u64, skin: Skin) -> String {
+    format!("{}/{}/{}/{:?}", hostname, title, revid, skin)
+}
+
+/// Replaces the contents of the article's content node (see `Skin::content_node_id`) with
+/// `placeholder`. If that node can't be found (e.g. on special pages and error pages, or because
+/// the response was truncated), returns `original_html` unmodified, so the page is served as the
+/// wiki returned it.
+fn insert_placeholder(original_html: &str, placeholder: &str, skin: Skin) -> String {
+    match replace_node_with_placeholder(original_html, skin.content_node_id(), placeholder) {
+        Ok(html) => html,
+        Err(msg) => {
+            warn!("Unexpected page structure, serving the page unmodified: {}", msg);
+            original_html.to_string()
+        },
     }
 }
 
@@ -124,31 +347,323 @@ fn find_node_by_id(handle: &Handle, id: &str) -> Result<Handle, String> {
     }
 }
 
-/// Removes merge markers that are inside HTML tags, and replaces the others with <span> tags.
-fn process_merge_markers(html: String) -> String {
-    let start_regex = Regex::new(&format!("{}([0-9]+){}", START_MARKER, START_MARKER)).unwrap();
-    let end_regex = Regex::new(&format!("{}[0-9]+{}", END_MARKER, END_MARKER)).unwrap();
+/// Removes merge markers that are inside HTML tags, and replaces the others with HTML tags as
+/// specified by `render_options`.
+///
+/// Later merges can restore content in the middle of, or overlapping, a region restored by an
+/// earlier merge, so merge markers can nest and overlap. Each maximal run of text inside at least
+/// one pair of markers becomes a single element, which lists every revision that contributed to it
+/// in its class and its "data-revids" attribute, and the revision whose region it starts with in
+/// its "data-revid" attribute. Its class also says whether it restores added text
+/// ("vandalism-added"), a deletion ("vandalism-removed"), or both. Runs past
+/// `render_options.max_spans` are left as plain text.
+fn process_merge_markers(html: String, render_options: &RenderOptions) -> String {
+    let marker_regex = Regex::new(&format!(
+        "{}([0-9]+{}?){}|{}([0-9]+{}?){}", START_MARKER, REMOVAL_MARKER, START_MARKER, END_MARKER,
+        REMOVAL_MARKER, END_MARKER)).unwrap();
 
     let html = remove_merge_markers(html);
-    let html = start_regex.replace_all(
-        &html, |captures: &Captures| format!("<span style=\"color: red\" class=\"vandalism-{}\">",
-                                             captures.at(1).unwrap()));
-    end_regex.replace_all(&html, "</span>")
+    let mut result = String::new();
+    // The IDs of the regions that have been started but not yet ended.
+    let mut open_ids: Vec<&str> = vec![];
+    // The revision IDs of every region that has contributed to the current run of restored text.
+    let mut run_ids: Vec<&str> = vec![];
+    // The classes of the kinds of region that have contributed to the current run.
+    let mut run_kinds: Vec<&str> = vec![];
+    // The position in `result` at which the current run of restored text started.
+    let mut run_start = 0;
+    // The number of runs of restored text marked up so far.
+    let mut span_count = 0;
+    let mut last_match_end = 0;
+    for captures in marker_regex.captures_iter(&html) {
+        let (match_start, match_end) = captures.pos(0).unwrap();
+        result.push_str(&html[last_match_end..match_start]);
+        last_match_end = match_end;
+        match (captures.at(1), captures.at(2)) {
+            (Some(id), _) => {
+                if open_ids.is_empty() {
+                    run_start = result.len();
+                    run_ids.clear();
+                    run_kinds.clear();
+                }
+                open_ids.push(id);
+                let revid = id.trim_right_matches(REMOVAL_MARKER);
+                if !run_ids.contains(&revid) {
+                    run_ids.push(revid);
+                }
+                let kind =
+                    if revid.len() < id.len() { "vandalism-removed" } else { "vandalism-added" };
+                if !run_kinds.contains(&kind) {
+                    run_kinds.push(kind);
+                }
+            },
+            (_, Some(id)) => {
+                if let Some(index) = open_ids.iter().position(|open_id| *open_id == id) {
+                    open_ids.remove(index);
+                    if open_ids.is_empty() &&
+                        (render_options.max_spans == 0 || span_count < render_options.max_spans) {
+                        let run = result[run_start..].to_owned();
+                        result.truncate(run_start);
+                        result.push_str(
+                            &format_restored_run(&run, &run_ids, &run_kinds, render_options));
+                        span_count += 1;
+                    }
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+    result.push_str(&html[last_match_end..]);
+    match render_options.marker_output {
+        MarkerOutput::Span => result,
+        MarkerOutput::Details => move_details_out_of_paragraphs(&result),
+    }
+}
+
+/// Moves the restored runs that `format_restored_run()` wrapped in <details> elements inside
+/// paragraphs in `html` to just after those paragraphs, in order. A <details> element can't be
+/// inside a <p>, so browsers would end the paragraph at it, leaving the rest unstyled.
+fn move_details_out_of_paragraphs(html: &str) -> String {
+    let paragraph_regex = regex!(r"(?s)<p(\s[^>]*)?>.*?</p>");
+    let details_regex = regex!(r#"(?s)<details[^>]* class="vandalism-[^>]*>.*?</details>"#);
+    paragraph_regex.replace_all(html, |captures: &Captures| {
+        let paragraph = captures.at(0).unwrap();
+        let details = details_regex.captures_iter(paragraph)
+            .map(|details| details.at(0).unwrap()).collect::<String>();
+        format!("{}{}", details_regex.replace_all(paragraph, ""), details)
+    })
+}
+
+/// Wraps `run`, a run of restored text that came from the revisions `revids` and from regions of
+/// the kinds whose classes are `kinds`, in HTML tags as specified by `render_options`.
+fn format_restored_run(run: &str, revids: &[&str], kinds: &[&str], render_options: &RenderOptions)
+                       -> String {
+    let classes = kinds.iter().map(|kind| kind.to_string())
+        .chain(revids.iter().map(|revid| format!("vandalism-{}", revid)))
+        .collect::<Vec<_>>().join(" ");
+    // The diff of the first revision is shown on hover, if there's a wiki to link it on.
+    let diff_title = match render_options.diff_hostname {
+        Some(ref hostname) => {
+            format!(" title=\"https://{}/w/index.php?diff={}\"", escape_html(hostname), revids[0])
+        },
+        None => "".to_string(),
+    };
+    let revid_attributes =
+        format!("data-revid=\"{}\" data-revids=\"{}\"{}", revids[0], revids.join(" "), diff_title);
+    let placeholder;
+    let run = if run.is_empty() && kinds.contains(&"vandalism-removed") {
+        placeholder = escape_html(&render_options.removal_placeholder);
+        &placeholder[..]
+    } else {
+        run
+    };
+    let (span_style, size_style) = if render_options.severity_shading {
+        let restored_size = get_text_size(run);
+        (format!("color: hsl(0, {}%, 45%)", get_shading_saturation(restored_size)),
+         format!("--restore-size: {}", restored_size))
+    } else {
+        (render_options.vandalism_span_style.clone(), "".to_string())
+    };
+    match render_options.marker_output {
+        MarkerOutput::Span => {
+            let style = [span_style, size_style].iter().filter(|style| !style.is_empty())
+                .cloned().collect::<Vec<_>>().join("; ");
+            let style = if style.is_empty() {
+                "".to_string()
+            } else {
+                format!(" style=\"{}\"", escape_html(&style))
+            };
+            format!("<span{} class=\"{}\" {}>{}</span>", style, classes, revid_attributes, run)
+        },
+        MarkerOutput::Details => {
+            let style = if size_style.is_empty() {
+                "".to_string()
+            } else {
+                format!(" style=\"{}\"", size_style)
+            };
+            format!("<details{} class=\"{}\" {}><summary>restored content</summary>{}</details>",
+                    style, classes, revid_attributes, run)
+        },
+    }
+}
+
+/// Replaces the merge markers in `article_body` with HTML tags, and adds the footers
+/// `render_options` asks for. `merged_at` is when the article was merged.
+fn finish_article_body(article_body: String, reverting_users: &HashMap<u64, String>,
+                       render_options: &RenderOptions, merged_at: &Tm) -> String {
+    let restoration_footer = if render_options.restoration_footer {
+        format_restoration_footer(&article_body, reverting_users)
+    } else {
+        "".to_string()
+    };
+    let merge_timestamp = if render_options.merge_timestamp {
+        format_merge_timestamp(merged_at)
+    } else {
+        "".to_string()
+    };
+    process_merge_markers(article_body, render_options) + &restoration_footer + &merge_timestamp
+}
+
+/// Returns a footer saying the article was merged at `merged_at`, so that readers (and operators
+/// debugging caching) can tell how fresh it is.
+fn format_merge_timestamp(merged_at: &Tm) -> String {
+    let timestamp = format!("{}", merged_at.to_utc().rfc3339());
+    format!("<p id=\"merge-timestamp\">Merged at <time datetime=\"{}\">{}</time></p>",
+            timestamp, timestamp)
+}
+
+/// The most characters of restored text quoted for each edit in the restoration footer.
+const FOOTER_SNIPPET_CHARS: usize = 80;
+
+/// An edit listed in the restoration footer.
+struct RestoredEdit {
+    revid: u64,
+    /// The number of bytes of text restored from the edit, across all its regions.
+    size: usize,
+    /// The text of the first region restored from the edit.
+    snippet: String,
+}
+
+/// Returns a footer listing each edit whose vandalism was restored in `html` (which still contains
+/// its merge markers), in the order the edits first appear in the page. Each entry links to the
+/// revision's diff, and names the user in `reverting_users` who reverted it. Returns an empty
+/// string if nothing was restored.
+fn format_restoration_footer(html: &str, reverting_users: &HashMap<u64, String>) -> String {
+    let marker_regex = Regex::new(&format!(
+        "{}([0-9]+){}?{}|{}([0-9]+){}?{}", START_MARKER, REMOVAL_MARKER, START_MARKER, END_MARKER,
+        REMOVAL_MARKER, END_MARKER)).unwrap();
+
+    let html = remove_merge_markers(html.to_owned());
+    let mut restored_edits: Vec<RestoredEdit> = vec![];
+    // The (ID, start of contents) pairs of the regions that have been started but not yet ended.
+    let mut open_regions: Vec<(u64, usize)> = vec![];
+    for captures in marker_regex.captures_iter(&html) {
+        let (match_start, match_end) = captures.pos(0).unwrap();
+        match (captures.at(1), captures.at(2)) {
+            (Some(id), _) => open_regions.push((id.parse().unwrap(), match_end)),
+            (_, Some(id)) => {
+                let revid = id.parse().unwrap();
+                let index = match open_regions.iter().position(|&(open_id, _)| open_id == revid) {
+                    Some(index) => index,
+                    None => continue,
+                };
+                let (_, region_start) = open_regions.remove(index);
+                let text = regex!(r"<[^>]*>").replace_all(
+                    &marker_regex.replace_all(&html[region_start..match_start], ""), "");
+                let text = regex!(r"\s+").replace_all(text.trim(), " ");
+                if text.is_empty() {
+                    continue;
+                }
+                match restored_edits.iter().position(|edit| edit.revid == revid) {
+                    Some(index) => restored_edits[index].size += text.len(),
+                    None => restored_edits.push(RestoredEdit {
+                        revid: revid,
+                        size: text.len(),
+                        snippet: text,
+                    }),
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+    if restored_edits.is_empty() {
+        return "".to_string();
+    }
+
+    let items = restored_edits.iter().map(|edit| {
+        let snippet = if edit.snippet.chars().count() > FOOTER_SNIPPET_CHARS {
+            format!("{}…", edit.snippet.chars().take(FOOTER_SNIPPET_CHARS).collect::<String>())
+        } else {
+            edit.snippet.clone()
+        };
+        let user = reverting_users.get(&edit.revid).map(|user| &user[..]).unwrap_or("unknown");
+        format!("<li><a href=\"/w/index.php?diff=prev&amp;oldid={}\">Revision {}</a> (reverted by {}, {} bytes): <q>{}</q></li>",
+                edit.revid, edit.revid, escape_html(user), edit.size, snippet)
+    }).collect::<Vec<_>>().join("");
+    format!("<div id=\"restoration-footer\"><h2>Restored edits</h2><ul>{}</ul></div>", items)
+}
+
+/// The markup of the button that shows and hides the restored vandalism in a page. Everything in it
+/// is prefixed with "wmw-", and the script keeps to its own scope, so it can't collide with the
+/// wiki's own styles and scripts.
+const VANDALISM_TOGGLE: &'static str = concat!(
+    "<style>",
+    "#wmw-vandalism-toggle{position:fixed;right:1em;bottom:1em;z-index:1000;}",
+    "body.wmw-hide-vandalism .vandalism-added,",
+    "body.wmw-hide-vandalism .vandalism-removed{display:none;}",
+    "</style>",
+    "<button id=\"wmw-vandalism-toggle\" type=\"button\">Hide vandalism</button>",
+    "<script>(function() {",
+    "var button = document.getElementById(\"wmw-vandalism-toggle\");",
+    "button.addEventListener(\"click\", function() {",
+    "var hidden = document.body.classList.toggle(\"wmw-hide-vandalism\");",
+    "button.textContent = hidden ? \"Show vandalism\" : \"Hide vandalism\";",
+    "});",
+    "})();</script>");
+
+/// Inserts the vandalism toggle (see `VANDALISM_TOGGLE`) at the end of the body of the page `html`,
+/// or at the end of the page if it has no "</body>" tag. Does nothing if it's already there.
+fn insert_vandalism_toggle(html: &str) -> String {
+    if html.contains("id=\"wmw-vandalism-toggle\"") {
+        return html.to_string();
+    }
+    match html.rfind("</body>") {
+        Some(index) => format!("{}{}{}", &html[..index], VANDALISM_TOGGLE, &html[index..]),
+        None => format!("{}{}", html, VANDALISM_TOGGLE),
+    }
+}
+
+/// Rewrites the absolute links in `html` to articles on the wiki at `hostname` (e.g.
+/// "https://en.wikipedia.org/wiki/Foo") as relative links (e.g. "/wiki/Foo"), so that following
+/// them stays on the mirror. Links to the wiki's other pages (e.g. "/w/index.php" and static
+/// assets), protocol-relative links, and links to other sites are left alone, as are <link> tags
+/// (e.g. the rel=canonical one), which describe the page on the wiki rather than link out of it.
+fn rewrite_article_links(html: &str, hostname: &str) -> String {
+    // The hostname is checked for each link, so that the regex is only compiled once.
+    let article_link_regex =
+        regex!(r#"(<([a-zA-Z]+)\s[^>]*?)href="https?://([^/"]+)(/wiki/[^"]*)""#);
+    article_link_regex.replace_all(html, |captures: &Captures| {
+        let tag = captures.at(2).unwrap();
+        if captures.at(3) == Some(hostname) && tag.to_lowercase() != "link" {
+            format!("{}href=\"{}\"", captures.at(1).unwrap(), captures.at(4).unwrap())
+        } else {
+            captures.at(0).unwrap().to_string()
+        }
+    })
+}
+
+/// Escapes the characters in `text` that are special in HTML.
+pub fn escape_html(text: &str) -> String {
+    text.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;").replace("\"", "&quot;")
+}
+
+/// Returns the number of bytes of text (i.e., not counting tags) in `html`.
+fn get_text_size(html: &str) -> usize {
+    regex!(r"<[^>]*>").replace_all(html, "").len()
+}
+
+/// Returns the saturation (as a percentage) to shade a restored region of `restored_size` bytes
+/// with. It grows linearly from 40% to 100% at 500 bytes.
+fn get_shading_saturation(restored_size: usize) -> usize {
+    40 + cmp::min(restored_size, 500) * 60 / 500
 }
 
 fn remove_merge_markers(html: String) -> String {
     // Finds markers where the end, but not the start, is inside a tag.
     let regex1 = Regex::new(&format!(
-        r"{}[0-9]+{}([^{}]*?)<([^>]*?){}[0-9]+{}([^>]*?)>",
-        START_MARKER, START_MARKER, END_MARKER, END_MARKER, END_MARKER)).unwrap();
+        r"{}[0-9]+{}?{}([^{}]*?)<([^>]*?){}[0-9]+{}?{}([^>]*?)>",
+        START_MARKER, REMOVAL_MARKER, START_MARKER, END_MARKER, END_MARKER, REMOVAL_MARKER,
+        END_MARKER)).unwrap();
     // Finds markers where the start, but not the end, is inside a tag.
     let regex2 = Regex::new(&format!(
-        r"<([^>]*?){}[0-9]+{}([^>]*?)>([^{}]*?){}[0-9]+{}",
-        START_MARKER, START_MARKER, END_MARKER, END_MARKER, END_MARKER)).unwrap();
+        r"<([^>]*?){}[0-9]+{}?{}([^>]*?)>([^{}]*?){}[0-9]+{}?{}",
+        START_MARKER, REMOVAL_MARKER, START_MARKER, END_MARKER, END_MARKER, REMOVAL_MARKER,
+        END_MARKER)).unwrap();
     // Finds markers where both the start and end are inside tags.
     let regex3 = Regex::new(&format!(
-        r"<([^>]*?){}[0-9]+{}([^>]*?)>([^{}{}]*?)<([^>]*?){}[0-9]+{}([^>]*?)>",
-        START_MARKER, START_MARKER, START_MARKER, END_MARKER, END_MARKER, END_MARKER)).unwrap();
+        r"<([^>]*?){}[0-9]+{}?{}([^>]*?)>([^{}{}]*?)<([^>]*?){}[0-9]+{}?{}([^>]*?)>",
+        START_MARKER, REMOVAL_MARKER, START_MARKER, START_MARKER, END_MARKER, END_MARKER,
+        REMOVAL_MARKER, END_MARKER)).unwrap();
     let html = regex1.replace_all(
         &html, |captures: &Captures|
         format!("{}<{}{}>", captures.at(1).unwrap(), captures.at(2).unwrap(),
@@ -165,9 +680,23 @@ fn remove_merge_markers(html: String) -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::{remove_merge_markers, replace_node_with_placeholder};
-    use ::START_MARKER;
-    use ::END_MARKER;
+    use super::{FinishedFetch, MarkerOutput, Page, RenderOptions, SkeletonFetches, Skin,
+                VANDALISM_TOGGLE, finish_article_body, format_restoration_footer,
+                insert_placeholder, insert_vandalism_toggle, process_merge_markers,
+                remove_merge_markers, replace_node_with_placeholder, rewrite_article_links};
+    use hyper::Client;
+    use std::collections::HashMap;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::time::Duration;
+    use regex::Regex;
+    use test_server::{ReceivedRequest, http_response, serve_slowly};
+    use time;
+    use time::Timespec;
+    use wiki::{Wiki, WikiOptions};
+    use wikipedia_minus_wikipedians::END_MARKER;
+    use wikipedia_minus_wikipedians::REMOVAL_MARKER;
+    use wikipedia_minus_wikipedians::START_MARKER;
+    use worker_pool::WorkerPool;
 
     fn test_process_merge_markers() {
         let html = format!(
@@ -179,6 +708,68 @@ mod tests {
         assert!(expected_regex.is_match(&html));
     }
 
+    #[test]
+    fn test_process_merge_markers_details() {
+        let html = format!(
+            "<html><body><p>Before {}456{}restored text{}456{} after.</p></body></html>",
+            START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        let render_options =
+            RenderOptions { marker_output: MarkerOutput::Details, ..RenderOptions::default() };
+        // The <details> element can't be inside the paragraph, so it follows it.
+        assert_eq!(
+            "<html><body><p>Before  after.</p><details class=\"vandalism-added vandalism-456\" data-revid=\"456\" data-revids=\"456\"><summary>restored content</summary>restored text</details></body></html>",
+            process_merge_markers(html, &render_options));
+    }
+
+    #[test]
+    fn test_process_merge_markers_nested_regions() {
+        let html = format!("<p>a {}1{}b {}2{}c{}2{} d{}1{} e</p>",
+                           START_MARKER, START_MARKER, START_MARKER, START_MARKER,
+                           END_MARKER, END_MARKER, END_MARKER, END_MARKER);
+        assert_eq!(
+            "<p>a <span style=\"color: red\" class=\"vandalism-added vandalism-1 vandalism-2\" data-revid=\"1\" data-revids=\"1 2\">b c d</span> e</p>",
+            process_merge_markers(html, &RenderOptions::default()));
+    }
+
+    #[test]
+    fn test_process_merge_markers_overlapping_regions() {
+        let html = format!("<p>{}1{}b {}2{}c{}1{} d{}2{}</p>",
+                           START_MARKER, START_MARKER, START_MARKER, START_MARKER,
+                           END_MARKER, END_MARKER, END_MARKER, END_MARKER);
+        assert_eq!(
+            "<p><span style=\"color: red\" class=\"vandalism-added vandalism-1 vandalism-2\" data-revid=\"1\" data-revids=\"1 2\">b c d</span></p>",
+            process_merge_markers(html, &RenderOptions::default()));
+    }
+
+    #[test]
+    fn test_process_merge_markers_removals() {
+        let html = format!("<p>a {}1{}b{}1{} c{}2{}{}{}2{}{} d</p>",
+                           START_MARKER, START_MARKER, END_MARKER, END_MARKER,
+                           START_MARKER, REMOVAL_MARKER, START_MARKER,
+                           END_MARKER, REMOVAL_MARKER, END_MARKER);
+        assert_eq!(
+            "<p>a <span style=\"color: red\" class=\"vandalism-added vandalism-1\" data-revid=\"1\" data-revids=\"1\">b</span> c<span style=\"color: red\" class=\"vandalism-removed vandalism-2\" data-revid=\"2\" data-revids=\"2\"></span> d</p>",
+            process_merge_markers(html.clone(), &RenderOptions::default()));
+
+        let render_options = RenderOptions {
+            removal_placeholder: "[removed]".to_string(),
+            ..RenderOptions::default()
+        };
+        assert!(process_merge_markers(html, &render_options).contains(
+            "class=\"vandalism-removed vandalism-2\" data-revid=\"2\" data-revids=\"2\">[removed]</span>"));
+    }
+
+    #[test]
+    fn test_process_merge_markers_max_spans() {
+        let html = (1..6).map(|i| format!("<p>{}{}{}text {}{}{}{}</p>", START_MARKER, i,
+                                          START_MARKER, i, END_MARKER, i, END_MARKER))
+            .collect::<Vec<_>>().concat();
+        let render_options = RenderOptions { max_spans: 2, ..RenderOptions::default() };
+        let processed = process_merge_markers(html, &render_options);
+        assert_eq!(2, processed.matches("<span").count());
+        assert!(processed.ends_with("<p>text 3</p><p>text 4</p><p>text 5</p>"));
+    }
+
     #[test]
     fn test_remove_merge_markers_keep() {
         let html = format!("<html><body>{}456{}<img src=\"asdf.jpg\">{}456{}</body></html>",
@@ -229,4 +820,226 @@ mod tests {
         let processed_html = replace_node_with_placeholder(original_html, "mw-content-text", "replaced text").unwrap();
         assert_eq!(expected_html, processed_html);
     }
+
+    #[test]
+    fn test_insert_placeholder_mobile() {
+        let original_html = "<html><head></head><body><div id=\"content\"><div id=\"bodyContent\"><div id=\"mw-content-text\"><section><p>original text</p></section></div></div></div></body></html>";
+        let expected_html = "<html><head></head><body><div id=\"content\"><div id=\"bodyContent\">replaced text</div></div></body></html>";
+        assert_eq!(expected_html, insert_placeholder(original_html, "replaced text", Skin::Mobile));
+    }
+
+    #[test]
+    fn test_insert_placeholder_without_content_node() {
+        let original_html = "<html><head></head><body><div id=\"content\"><p>original text</p></div></body></html>";
+        assert_eq!(original_html,
+                   insert_placeholder(original_html, "replaced text", Skin::Desktop));
+    }
+
+    #[test]
+    fn test_insert_placeholder_truncated_page() {
+        let original_html = "<html><head></head><body><div id=\"content\"><p>orig";
+        assert_eq!(original_html,
+                   insert_placeholder(original_html, "replaced text", Skin::Desktop));
+    }
+
+    #[test]
+    fn test_process_merge_markers_severity_shading() {
+        let render_options =
+            RenderOptions { severity_shading: true, ..RenderOptions::default() };
+        let html = format!("<p>{}1{}small{}1{} text {}2{}a <b>much</b> larger region{}2{}</p>",
+                           START_MARKER, START_MARKER, END_MARKER, END_MARKER,
+                           START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        let processed_html = process_merge_markers(html, &render_options);
+
+        let span_regex =
+            Regex::new(r"color: hsl\(0, ([0-9]+)%, 45%\); --restore-size: ([0-9]+)").unwrap();
+        let styles = span_regex.captures_iter(&processed_html).map(
+            |captures| (captures.at(1).unwrap().parse::<u64>().unwrap(),
+                        captures.at(2).unwrap().parse::<u64>().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(2, styles.len());
+        // Sizes don't count tags.
+        assert_eq!(5, styles[0].1);
+        assert_eq!(20, styles[1].1);
+        assert!(styles[1].0 > styles[0].0);
+    }
+
+    #[test]
+    fn test_process_merge_markers_revid_attributes() {
+        let html = format!("<p>{}5{}a {}7{}b{}7{}{}5{}</p>", START_MARKER, START_MARKER,
+                           START_MARKER, START_MARKER, END_MARKER, END_MARKER, END_MARKER,
+                           END_MARKER);
+        let processed_html = process_merge_markers(html.clone(), &RenderOptions::default());
+        assert!(processed_html.contains("data-revid=\"5\" data-revids=\"5 7\">a b</span>"));
+        assert!(!processed_html.contains("title="));
+
+        let render_options = RenderOptions {
+            diff_hostname: Some("en.wikipedia.org".to_string()),
+            ..RenderOptions::default()
+        };
+        let processed_html = process_merge_markers(html, &render_options);
+        assert!(processed_html.contains(concat!(
+            "data-revid=\"5\" data-revids=\"5 7\" ",
+            "title=\"https://en.wikipedia.org/w/index.php?diff=5\">a b</span>")));
+    }
+
+    #[test]
+    fn test_process_merge_markers_vandalism_span_style() {
+        let html = format!("<p>a {}1{}b{}1{} c</p>", START_MARKER, START_MARKER, END_MARKER,
+                           END_MARKER);
+        let render_options = RenderOptions {
+            vandalism_span_style: "background: yellow".to_string(),
+            ..RenderOptions::default()
+        };
+        assert_eq!(
+            "<p>a <span style=\"background: yellow\" class=\"vandalism-added vandalism-1\" data-revid=\"1\" data-revids=\"1\">b</span> c</p>",
+            process_merge_markers(html.clone(), &render_options));
+
+        let render_options =
+            RenderOptions { vandalism_span_style: "".to_string(), ..RenderOptions::default() };
+        assert_eq!(
+            "<p>a <span class=\"vandalism-added vandalism-1\" data-revid=\"1\" data-revids=\"1\">b</span> c</p>",
+            process_merge_markers(html, &render_options));
+    }
+
+    #[test]
+    fn test_format_restoration_footer() {
+        let html = format!(
+            "<p>{}1{}first <b>restored</b>{}1{} text {}2{}second{}2{} and {}1{}more{}1{}</p>",
+            START_MARKER, START_MARKER, END_MARKER, END_MARKER, START_MARKER, START_MARKER,
+            END_MARKER, END_MARKER, START_MARKER, START_MARKER, END_MARKER, END_MARKER);
+        let mut reverting_users = HashMap::new();
+        reverting_users.insert(1, "Alice".to_string());
+        reverting_users.insert(2, "<Bob>".to_string());
+        assert_eq!(
+            "<div id=\"restoration-footer\"><h2>Restored edits</h2><ul><li><a href=\"/w/index.php?diff=prev&amp;oldid=1\">Revision 1</a> (reverted by Alice, 18 bytes): <q>first restored</q></li><li><a href=\"/w/index.php?diff=prev&amp;oldid=2\">Revision 2</a> (reverted by &lt;Bob&gt;, 6 bytes): <q>second</q></li></ul></div>",
+            format_restoration_footer(&html, &reverting_users));
+    }
+
+    #[test]
+    fn test_finish_article_body_merge_timestamp() {
+        let html = format!("<p>a {}1{}b{}1{} c</p>", START_MARKER, START_MARKER, END_MARKER,
+                           END_MARKER);
+        let merged_at = time::at_utc(Timespec::new(1500000000, 0));
+        let article_body = finish_article_body(html.clone(), &HashMap::new(),
+                                               &RenderOptions::default(), &merged_at);
+        assert!(!article_body.contains("merge-timestamp"));
+
+        let render_options = RenderOptions { merge_timestamp: true, ..RenderOptions::default() };
+        let article_body = finish_article_body(html, &HashMap::new(), &render_options, &merged_at);
+        assert!(article_body.ends_with(concat!(
+            "<p id=\"merge-timestamp\">Merged at ",
+            "<time datetime=\"2017-07-14T02:40:00Z\">2017-07-14T02:40:00Z</time></p>")));
+    }
+
+    #[test]
+    fn test_format_restoration_footer_nothing_restored() {
+        assert_eq!("", format_restoration_footer("<p>text</p>", &HashMap::new()));
+    }
+
+    #[test]
+    fn test_insert_vandalism_toggle() {
+        let html = "<html><body><p>text</p><script>mw.loader.load();</script></body></html>";
+        let toggled_html = insert_vandalism_toggle(html);
+        assert!(toggled_html.starts_with(
+            "<html><body><p>text</p><script>mw.loader.load();</script><style>"));
+        assert!(toggled_html.ends_with("})();</script></body></html>"));
+        assert_eq!(1, toggled_html.matches("<button id=\"wmw-vandalism-toggle\"").count());
+        // It's only inserted once.
+        assert_eq!(toggled_html, insert_vandalism_toggle(&toggled_html));
+
+        assert_eq!(format!("<p>text{}", VANDALISM_TOGGLE), insert_vandalism_toggle("<p>text"));
+    }
+
+    #[test]
+    fn test_rewrite_article_links() {
+        let html = concat!(
+            r#"<a href="https://en.wikipedia.org/wiki/William_Howard_Taft">Taft</a> "#,
+            r#"<a href="http://en.wikipedia.org/wiki/Ohio#History">Ohio</a> "#,
+            r#"<a href="/wiki/Cincinnati">Cincinnati</a> "#,
+            r#"<a href="https://en.wikipedia.org/w/index.php?action=edit">edit</a> "#,
+            r#"<link rel="stylesheet" href="https://en.wikipedia.org/w/load.php?modules=site"> "#,
+            r#"<link rel="canonical" href="https://en.wikipedia.org/wiki/Taft"> "#,
+            r#"<img src="https://en.wikipedia.org/wiki/Special:CentralAutoLogin/start"> "#,
+            r#"<a href="//en.wikipedia.org/wiki/Ohio">Ohio</a> "#,
+            r#"<a href="https://en.wikipedia.org.example.com/wiki/Ohio">Ohio</a> "#,
+            r#"<a href="https://example.com/wiki/Ohio">Ohio</a>"#);
+        let expected = concat!(
+            r#"<a href="/wiki/William_Howard_Taft">Taft</a> "#,
+            r#"<a href="/wiki/Ohio#History">Ohio</a> "#,
+            r#"<a href="/wiki/Cincinnati">Cincinnati</a> "#,
+            r#"<a href="https://en.wikipedia.org/w/index.php?action=edit">edit</a> "#,
+            r#"<link rel="stylesheet" href="https://en.wikipedia.org/w/load.php?modules=site"> "#,
+            r#"<link rel="canonical" href="https://en.wikipedia.org/wiki/Taft"> "#,
+            r#"<img src="https://en.wikipedia.org/wiki/Special:CentralAutoLogin/start"> "#,
+            r#"<a href="//en.wikipedia.org/wiki/Ohio">Ohio</a> "#,
+            r#"<a href="https://en.wikipedia.org.example.com/wiki/Ohio">Ohio</a> "#,
+            r#"<a href="https://example.com/wiki/Ohio">Ohio</a>"#);
+        assert_eq!(expected, rewrite_article_links(html, "en.wikipedia.org"));
+    }
+
+    /// Serves `html` to every request, slowly. Returns a Wiki on the server, and a Receiver for
+    /// each request.
+    fn serve_page(html: &str) -> (Wiki, Receiver<ReceivedRequest>) {
+        let (port, receiver) = serve_slowly(http_response("Connection: close\r\n", html),
+                                            Duration::from_millis(200));
+        let wiki = Wiki::new("http".to_string(), "127.0.0.1".to_string(), port, Client::new(),
+                             "test-agent/1.0".to_string(), None, WikiOptions::default());
+        (wiki, receiver)
+    }
+
+    #[test]
+    fn test_concurrent_skeleton_fetches_are_shared() {
+        let (wiki, receiver) =
+            serve_page("<html><body><div id=\"mw-content-text\">old</div></body></html>");
+        let skeleton_fetches = SkeletonFetches::new(true, WorkerPool::new("test-worker", 2));
+
+        let pages: Vec<Page> = (0..2).map(|_| {
+            Page::new("Cincinnati", 1, wiki.clone(), Skin::Desktop, RenderOptions::default(),
+                      &skeleton_fetches)
+        }).collect();
+        for page in pages {
+            let html = page.replace_body_and_remove_merge_markers("body".to_string(),
+                                                                  &HashMap::new(),
+                                                                  &time::now_utc());
+            assert!(html.unwrap().contains("<div id=\"mw-content-text\">body</div>"));
+        }
+        receiver.recv().unwrap();
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_panicked_skeleton_fetch_is_finished() {
+        let skeleton_fetches = SkeletonFetches::new(true, WorkerPool::new("test-worker", 1));
+        let (sender, receiver) = channel();
+        let (joined_sender, joined_receiver) = channel();
+        skeleton_fetches.in_progress.lock().unwrap().insert(
+            "key".to_string(), ("placeholder".to_string(), vec![joined_sender]));
+        let finished_fetch = FinishedFetch {
+            skeleton_fetches: skeleton_fetches.clone(),
+            key: "key".to_string(),
+            sender: sender,
+            page_skeleton: None,
+        };
+        skeleton_fetches.workers.execute(move|| {
+            let _finished_fetch = finished_fetch;
+            panic!("Fetch failed");
+        });
+        assert!(receiver.recv().unwrap().is_err());
+        assert!(joined_receiver.recv().unwrap().is_err());
+        assert!(skeleton_fetches.in_progress.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_page_without_content_node() {
+        // E.g. an error page.
+        let original_html = "<html><head></head><body><p>Wikimedia Error</p></body></html>";
+        let (wiki, _) = serve_page(original_html);
+        let page = Page::new("Cincinnati", 1, wiki, Skin::Desktop, RenderOptions::default(),
+                             &SkeletonFetches::new(false, WorkerPool::new("test-worker", 1)));
+        // The page is served as it was, without the article body.
+        assert_eq!(Ok(original_html.to_string()),
+                   page.replace_body_and_remove_merge_markers("body".to_string(), &HashMap::new(),
+                                                              &time::now_utc()));
+    }
 }
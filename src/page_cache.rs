@@ -0,0 +1,69 @@
+//! An in-memory cache of finished pages, so that popular articles don't have to be merged again on
+//! every request.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Maps page titles to finished pages. When full, the oldest page is evicted.
+pub struct PageCache<V: Clone> {
+    /// The most pages to hold, or 0 to disable the cache.
+    capacity: usize,
+    /// The cached pages, and their titles in the order they were inserted.
+    entries: Mutex<(HashMap<String, V>, VecDeque<String>)>,
+}
+
+impl<V: Clone> PageCache<V> {
+    pub fn new(capacity: usize) -> PageCache<V> {
+        PageCache {
+            capacity: capacity,
+            entries: Mutex::new((HashMap::new(), VecDeque::new())),
+        }
+    }
+
+    /// Returns whether the cache holds anything, i.e. its capacity isn't 0.
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn get(&self, title: &str) -> Option<V> {
+        self.entries.lock().unwrap().0.get(title).map(|page| page.clone())
+    }
+
+    pub fn insert(&self, title: &str, page: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let &mut (ref mut pages, ref mut titles) = &mut *entries;
+        if pages.insert(title.to_string(), page).is_none() {
+            titles.push_back(title.to_string());
+            if titles.len() > self.capacity {
+                let oldest_title = titles.pop_front().unwrap();
+                pages.remove(&oldest_title);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PageCache;
+
+    #[test]
+    fn test_page_cache_evicts_oldest() {
+        let cache = PageCache::new(2);
+        cache.insert("A", 1);
+        cache.insert("B", 2);
+        cache.insert("C", 3);
+        assert_eq!(None, cache.get("A"));
+        assert_eq!(Some(2), cache.get("B"));
+        assert_eq!(Some(3), cache.get("C"));
+    }
+
+    #[test]
+    fn test_page_cache_disabled() {
+        let cache = PageCache::new(0);
+        cache.insert("A", 1);
+        assert_eq!(None, cache.get("A"));
+    }
+}
@@ -0,0 +1,169 @@
+//! Per-wiki profiles: the settings that differ between the wikis the mirror can serve (mostly
+//! localized ones, like template names), bundled into one JSON file per wiki in
+//! --wiki_profiles_dir, so that pointing --wiki at another wiki picks up its settings.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use rustc_serialize::json;
+
+/// The settings in a wiki's profile. Settings it leaves out keep the values of their flags.
+#[derive(Clone, Debug, Default, PartialEq, RustcDecodable)]
+pub struct WikiProfile {
+    /// Whether the wiki uppercases the first letter of page titles (see --no_capital_links).
+    pub capital_links: Option<bool>,
+    /// The title of the wiki's main page, which requests without a title are redirected to (see
+    /// --empty_title_page).
+    pub main_page: Option<String>,
+    /// The names of the wiki's maintenance templates (see --maintenance_templates).
+    pub maintenance_templates: Option<Vec<String>>,
+    /// Text to show inside a restored deletion (see --removal_placeholder).
+    pub removal_placeholder: Option<String>,
+    /// The path of the page to serve at /about (see --about_page).
+    pub about_page: Option<String>,
+    /// The words in edit summaries that mark reverts of vandalism (see --vandalism_keywords).
+    pub vandalism_keywords: Option<Vec<String>>,
+}
+
+impl WikiProfile {
+    /// Reads the profile at `path`.
+    pub fn read(path: &str) -> Result<WikiProfile, String> {
+        let mut file = try_display!(File::open(path), "Failed to open wiki profile {}", path);
+        let mut contents = String::new();
+        try_display!(file.read_to_string(&mut contents), "Failed to read wiki profile {}", path);
+        Ok(try_display!(json::decode(&contents), "Failed to parse wiki profile {}", path))
+    }
+
+    /// Applies the profile's settings to the values of their flags. A flag that isn't at its
+    /// default value (or, for `capital_links` and `empty_title_page`, isn't None) was given on the
+    /// command line, which takes precedence over the profile.
+    pub fn apply(&self, capital_links: &mut Option<bool>, empty_title_page: &mut Option<String>,
+                 maintenance_templates: &mut String, removal_placeholder: &mut String,
+                 about_page: &mut String, vandalism_keywords: &mut String) {
+        if capital_links.is_none() {
+            *capital_links = self.capital_links;
+        }
+        if empty_title_page.is_none() {
+            *empty_title_page = self.main_page.clone();
+        }
+        if let (true, &Some(ref names)) =
+            (maintenance_templates.is_empty(), &self.maintenance_templates) {
+            *maintenance_templates = names.join(",");
+        }
+        if let (true, &Some(ref placeholder)) =
+            (removal_placeholder.is_empty(), &self.removal_placeholder) {
+            *removal_placeholder = placeholder.clone();
+        }
+        if let (true, &Some(ref path)) = (about_page.is_empty(), &self.about_page) {
+            *about_page = path.clone();
+        }
+        if let (true, &Some(ref keywords)) =
+            (vandalism_keywords.is_empty(), &self.vandalism_keywords) {
+            *vandalism_keywords = keywords.join(",");
+        }
+    }
+}
+
+/// Returns the path of the profile in `dir` for the wiki at `hostname`: "<hostname>.json" if
+/// there is one, or else that of its closest parent domain (e.g. "wikipedia.org.json" for
+/// "fr.wikipedia.org"), so settings shared by a family of wikis can live in one file. Returns None
+/// if there's no profile for it.
+pub fn find_wiki_profile(dir: &str, hostname: &str) -> Option<String> {
+    let labels = hostname.split('.').collect::<Vec<_>>();
+    (0..labels.len())
+        .map(|i| format!("{}/{}.json", dir, labels[i..].join(".")))
+        .find(|path| Path::new(path).is_file())
+}
+
+/// Reads the profile in `dir` for the wiki at `hostname` (see `find_wiki_profile()`), if there is
+/// one.
+pub fn load_wiki_profile(dir: &str, hostname: &str) -> Result<Option<WikiProfile>, String> {
+    match find_wiki_profile(dir, hostname) {
+        Some(path) => {
+            info!("Using wiki profile {}", path);
+            WikiProfile::read(&path).map(Some)
+        },
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WikiProfile, find_wiki_profile, load_wiki_profile};
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_load_wiki_profile() {
+        let temp_dir = TempDir::new("wiki-profiles").unwrap();
+        let dir = temp_dir.path();
+        let dir_path = dir.to_str().unwrap();
+        write!(File::create(dir.join("wikipedia.org.json")).unwrap(),
+               r#"{{"maintenance_templates": ["Cleanup"]}}"#).unwrap();
+        write!(File::create(dir.join("fr.wikipedia.org.json")).unwrap(),
+               r#"{{"capital_links": true, "main_page": "Wikipédia:Accueil_principal",
+                    "maintenance_templates": ["Ébauche", "À sourcer"],
+                    "removal_placeholder": "[supprimé]", "vandalism_keywords": ["vandalisme"]}}"#)
+            .unwrap();
+
+        assert_eq!(Some(format!("{}/fr.wikipedia.org.json", dir_path)),
+                   find_wiki_profile(dir_path, "fr.wikipedia.org"));
+        assert_eq!(Some(format!("{}/wikipedia.org.json", dir_path)),
+                   find_wiki_profile(dir_path, "de.wikipedia.org"));
+        assert_eq!(None, find_wiki_profile(dir_path, "example.com"));
+        assert_eq!(Ok(None), load_wiki_profile(dir_path, "example.com"));
+
+        let profile = load_wiki_profile(dir_path, "fr.wikipedia.org").unwrap().unwrap();
+        let mut capital_links = None;
+        let mut empty_title_page = None;
+        let mut maintenance_templates = "".to_string();
+        let mut removal_placeholder = "".to_string();
+        let mut about_page = "".to_string();
+        let mut vandalism_keywords = "".to_string();
+        profile.apply(&mut capital_links, &mut empty_title_page, &mut maintenance_templates,
+                      &mut removal_placeholder, &mut about_page, &mut vandalism_keywords);
+        assert_eq!(Some(true), capital_links);
+        assert_eq!(Some("Wikipédia:Accueil_principal".to_string()), empty_title_page);
+        assert_eq!("Ébauche,À sourcer", maintenance_templates);
+        assert_eq!("[supprimé]", removal_placeholder);
+        assert_eq!("", about_page);
+        assert_eq!("vandalisme", vandalism_keywords);
+
+        // Flags given on the command line take precedence.
+        let mut empty_title_page = Some("".to_string());
+        let mut removal_placeholder = "[removed]".to_string();
+        let mut vandalism_keywords = "vandal,revert".to_string();
+        profile.apply(&mut capital_links, &mut empty_title_page, &mut maintenance_templates,
+                      &mut removal_placeholder, &mut about_page, &mut vandalism_keywords);
+        assert_eq!(Some("".to_string()), empty_title_page);
+        assert_eq!("[removed]", removal_placeholder);
+        assert_eq!("vandal,revert", vandalism_keywords);
+    }
+
+    #[test]
+    fn test_wiki_profile_capital_links() {
+        let profile = WikiProfile { capital_links: Some(false), ..WikiProfile::default() };
+        let (mut maintenance_templates, mut removal_placeholder, mut about_page) =
+            ("".to_string(), "".to_string(), "".to_string());
+        let (mut vandalism_keywords, mut empty_title_page) = ("".to_string(), None);
+        let mut capital_links = None;
+        profile.apply(&mut capital_links, &mut empty_title_page, &mut maintenance_templates,
+                      &mut removal_placeholder, &mut about_page, &mut vandalism_keywords);
+        assert_eq!(Some(false), capital_links);
+
+        // A profile can turn capital links back on for a wiki, if the flag isn't given.
+        let profile = WikiProfile { capital_links: Some(true), ..WikiProfile::default() };
+        let mut capital_links = None;
+        profile.apply(&mut capital_links, &mut empty_title_page, &mut maintenance_templates,
+                      &mut removal_placeholder, &mut about_page, &mut vandalism_keywords);
+        assert_eq!(Some(true), capital_links);
+
+        // --no_capital_links takes precedence.
+        let mut capital_links = Some(false);
+        profile.apply(&mut capital_links, &mut empty_title_page, &mut maintenance_templates,
+                      &mut removal_placeholder, &mut about_page, &mut vandalism_keywords);
+        assert_eq!(Some(false), capital_links);
+    }
+}
@@ -0,0 +1,153 @@
+//! Telling which revisions of a page reverted vandalism. Edit summaries are the simplest signal,
+//! but automated reverts (e.g. by Huggle or ClueBot) word theirs in many ways, and not every
+//! summary that mentions vandalism belongs to a revert. MediaWiki also tags reverts made by
+//! rollback, by undoing an edit, or by hand, and a revert restores the content (and so the SHA1)
+//! of an earlier revision exactly.
+
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use wiki::Revision;
+
+/// How antivandalism revisions are told apart from other revisions (see --revert_detection).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RevertDetection {
+    /// By edit summaries containing any of the vandalism keywords.
+    Comment,
+    /// By revert tags, or by content matching that of an earlier revision.
+    Revert,
+    /// By either of the above.
+    Either,
+}
+
+impl FromStr for RevertDetection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RevertDetection, String> {
+        match s {
+            "comment" => Ok(RevertDetection::Comment),
+            "revert" => Ok(RevertDetection::Revert),
+            "either" => Ok(RevertDetection::Either),
+            _ => Err(format!("Unknown revert detection strategy \"{}\"", s)),
+        }
+    }
+}
+
+/// The tags MediaWiki gives reverts.
+const REVERT_TAGS: [&'static str; 3] = ["mw-manual-revert", "mw-rollback", "mw-undo"];
+
+/// Returns whether `revision` is tagged as a revert.
+fn has_revert_tag(revision: &Revision) -> bool {
+    revision.tags.iter().any(|tag| REVERT_TAGS.contains(&&tag[..]))
+}
+
+/// Returns the IDs of the revisions in `revisions` (a page's history, newest first) that restored
+/// the content of a revision before their parent, going by their SHA1s. Revisions with the same
+/// content as their parents (null edits) aren't reverts, and revisions whose SHA1s are hidden
+/// never match. Only the revisions in `revisions` are compared, so reverts of revisions older
+/// than all of them go unnoticed.
+fn get_sha1_revert_ids(revisions: &[Revision]) -> HashSet<u64> {
+    // The SHA1s of the revisions before the parent of the one being checked.
+    let mut older_sha1s = HashSet::new();
+    let mut revert_ids = HashSet::new();
+    for (index, revision) in revisions.iter().enumerate().rev() {
+        if let Some(&Some(ref sha1)) = revisions.get(index + 2).map(|older| &older.sha1) {
+            older_sha1s.insert(&sha1[..]);
+        }
+        if let Some(ref sha1) = revision.sha1 {
+            let parent_sha1 = revisions.get(index + 1).and_then(|parent| parent.sha1.as_ref());
+            if older_sha1s.contains(&sha1[..]) && parent_sha1 != Some(sha1) {
+                revert_ids.insert(revision.revid);
+            }
+        }
+    }
+    revert_ids
+}
+
+/// Returns the revisions in `revisions` (a page's history, newest first) that reverted vandalism,
+/// detected by `detection`. `is_antivandalism_comment` returns whether a revision's edit summary
+/// marks it as one.
+pub fn find_reverts<'a, F>(revisions: &'a [Revision], detection: RevertDetection,
+                           is_antivandalism_comment: F) -> Vec<&'a Revision>
+    where F: Fn(&Revision) -> bool {
+    let sha1_revert_ids = match detection {
+        RevertDetection::Comment => HashSet::new(),
+        RevertDetection::Revert | RevertDetection::Either => get_sha1_revert_ids(revisions),
+    };
+    let is_revert = |revision: &Revision| {
+        has_revert_tag(revision) || sha1_revert_ids.contains(&revision.revid)
+    };
+    revisions.iter().filter(|&revision| {
+        match detection {
+            RevertDetection::Comment => is_antivandalism_comment(revision),
+            RevertDetection::Revert => is_revert(revision),
+            RevertDetection::Either => is_antivandalism_comment(revision) || is_revert(revision),
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RevertDetection, find_reverts, get_sha1_revert_ids};
+    use wiki::Revision;
+
+    fn revision(revid: u64, comment: &str, tags: &[&str], sha1: Option<&str>) -> Revision {
+        Revision { revid: revid, parentid: revid - 1, comment: comment.to_string(),
+                   user: "User".to_string(),
+                   tags: tags.iter().map(|tag| tag.to_string()).collect(),
+                   sha1: sha1.map(|sha1| sha1.to_string()) }
+    }
+
+    /// A page's history, newest first.
+    fn history() -> Vec<Revision> {
+        vec![
+            // A null edit, with the same content as its parent.
+            revision(19, "Null edit", &[], Some("e")),
+            // A revert by rollback, of a revision whose SHA1 is hidden.
+            revision(18, "Reverted edits by Vandal", &["mw-rollback"], Some("e")),
+            revision(17, "", &[], None),
+            // An automated revert whose summary doesn't mention vandalism.
+            revision(16, "Reverting possible test edit", &[], Some("d")),
+            revision(15, "Blanked the section", &[], Some("c")),
+            revision(14, "Copyedit", &[], Some("d")),
+            // A summary that mentions vandalism, but not of a revert.
+            revision(13, "Added a section on vandalism", &[], Some("b")),
+            revision(12, "", &["mw-undo"], Some("a")),
+            revision(11, "Created page", &[], Some("x")),
+        ]
+    }
+
+    fn revids(revisions: Vec<&Revision>) -> Vec<u64> {
+        revisions.iter().map(|revision| revision.revid).collect()
+    }
+
+    #[test]
+    fn test_parse_revert_detection() {
+        assert_eq!(Ok(RevertDetection::Comment), "comment".parse());
+        assert_eq!(Ok(RevertDetection::Revert), "revert".parse());
+        assert_eq!(Ok(RevertDetection::Either), "either".parse());
+        assert!("tags".parse::<RevertDetection>().is_err());
+    }
+
+    #[test]
+    fn test_get_sha1_revert_ids() {
+        let mut revert_ids = get_sha1_revert_ids(&history()).into_iter().collect::<Vec<_>>();
+        revert_ids.sort();
+        assert_eq!(vec![16], revert_ids);
+    }
+
+    #[test]
+    fn test_find_reverts() {
+        let history = history();
+        let is_antivandalism_comment = |revision: &Revision| revision.comment.contains("vandal");
+        assert_eq!(vec![13],
+                   revids(find_reverts(&history, RevertDetection::Comment,
+                                       &is_antivandalism_comment)));
+        assert_eq!(vec![18, 16, 12],
+                   revids(find_reverts(&history, RevertDetection::Revert,
+                                       &is_antivandalism_comment)));
+        assert_eq!(vec![18, 16, 13, 12],
+                   revids(find_reverts(&history, RevertDetection::Either,
+                                       &is_antivandalism_comment)));
+    }
+}
@@ -0,0 +1,160 @@
+//! Turns a merged article into a review queue: instead of restoring the reverted content silently,
+//! it lists each piece of it, with the revision that removed it, as a suggested edit for patrollers
+//! and researchers to consider.
+
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use page::escape_html;
+use wikipedia_minus_wikipedians::END_MARKER;
+use wikipedia_minus_wikipedians::MergedArticle;
+use wikipedia_minus_wikipedians::REMOVAL_MARKER;
+use wikipedia_minus_wikipedians::START_MARKER;
+use wikipedia_minus_wikipedians::remove_current_text_markers;
+
+/// A piece of content that was removed by a revert, and that merged cleanly into the latest
+/// revision of the page.
+#[derive(Clone, Debug, PartialEq, RustcEncodable)]
+pub struct SuggestedEdit {
+    /// The title of the section the content would be restored to.
+    pub section_title: String,
+    /// The revision that removed the content.
+    pub revid: u64,
+    /// The user who made revision `revid`, if known.
+    pub user: Option<String>,
+    /// The removed wikitext.
+    pub wikitext: String,
+}
+
+/// Returns the content restored in `merged_article`, one SuggestedEdit per region of merge
+/// markers, in the order the regions end in each section. `reverting_users` maps the ID of each
+/// revision merged to the user who made it.
+pub fn get_suggested_edits(merged_article: &MergedArticle, reverting_users: &HashMap<u64, String>)
+                           -> Vec<SuggestedEdit> {
+    let marker_regex = Regex::new(&format!(
+        "{}([0-9]+){}?{}|{}([0-9]+){}?{}", START_MARKER, REMOVAL_MARKER, START_MARKER, END_MARKER,
+        REMOVAL_MARKER, END_MARKER)).unwrap();
+    let mut suggested_edits = Vec::new();
+    for section in &merged_article.sections {
+        // Maps from the revision ID of each open region to the offset where its content starts.
+        let mut region_starts: HashMap<u64, usize> = HashMap::new();
+        // The current text kept beside restored vandalism isn't a suggested edit.
+        let content = remove_current_text_markers(&section.content);
+        for captures in marker_regex.captures_iter(&content) {
+            let (match_start, match_end) = captures.pos(0).unwrap();
+            if let Some(revid) = captures.at(1) {
+                region_starts.insert(revid.parse().unwrap(), match_end);
+            } else if let Some(revid) = captures.at(2) {
+                let revid: u64 = revid.parse().unwrap();
+                if let Some(region_start) = region_starts.remove(&revid) {
+                    // Regions can nest and overlap, so other regions' markers may be inside.
+                    let wikitext =
+                        marker_regex.replace_all(&content[region_start..match_start], "");
+                    if !wikitext.trim().is_empty() {
+                        suggested_edits.push(SuggestedEdit {
+                            section_title: section.title.clone(),
+                            revid: revid,
+                            user: reverting_users.get(&revid).cloned(),
+                            wikitext: wikitext,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    suggested_edits
+}
+
+/// Formats `suggested_edits` to the page `title` as an HTML review queue, grouped by section.
+pub fn format_review_page(title: &str, suggested_edits: &[SuggestedEdit]) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Suggested edits to {}</title>\
+         </head><body><h1>Suggested edits to <a href=\"/wiki/{}\">{}</a></h1>",
+        escape_html(title), escape_html(title), escape_html(title));
+    if suggested_edits.is_empty() {
+        html.push_str("<p>No removed content merges cleanly into the current page.</p>");
+    }
+    let mut current_section: Option<&str> = None;
+    for suggested_edit in suggested_edits {
+        if current_section != Some(&suggested_edit.section_title[..]) {
+            if current_section.is_some() {
+                html.push_str("</ol>");
+            }
+            let heading = if suggested_edit.section_title.is_empty() {
+                "(Introduction)".to_string()
+            } else {
+                escape_html(&suggested_edit.section_title)
+            };
+            html.push_str(&format!("<h2>{}</h2><ol>", heading));
+            current_section = Some(&suggested_edit.section_title[..]);
+        }
+        html.push_str(&format!(
+            "<li>Removed in <a href=\"/w/index.php?diff=prev&amp;oldid={}\">revision {}</a> by \
+             {}; consider whether it should have been:<pre>{}</pre></li>",
+            suggested_edit.revid, suggested_edit.revid,
+            escape_html(suggested_edit.user.as_ref().map(|user| &user[..]).unwrap_or("unknown")),
+            escape_html(&suggested_edit.wikitext)));
+    }
+    if current_section.is_some() {
+        html.push_str("</ol>");
+    }
+    html.push_str("</body></html>");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_review_page, get_suggested_edits, SuggestedEdit};
+    use std::collections::HashMap;
+    use wikipedia_minus_wikipedians::{END_MARKER, START_MARKER};
+    use wikipedia_minus_wikipedians::{MergeStats, MergedArticle, MergedSection};
+
+    fn suggested_edit(section_title: &str, revid: u64, user: Option<&str>, wikitext: &str)
+                      -> SuggestedEdit {
+        SuggestedEdit {
+            section_title: section_title.to_string(),
+            revid: revid,
+            user: user.map(|user| user.to_string()),
+            wikitext: wikitext.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_get_suggested_edits() {
+        let merged_article = MergedArticle {
+            sections: vec![
+                MergedSection {
+                    title: "".to_string(),
+                    content: format!("Intro {}12{}lol{}12{} text. {}34{}Bad {}56{}word{}56{}{}34{}",
+                                     START_MARKER, START_MARKER, END_MARKER, END_MARKER,
+                                     START_MARKER, START_MARKER, START_MARKER, START_MARKER,
+                                     END_MARKER, END_MARKER, END_MARKER, END_MARKER),
+                    stats: MergeStats::default(),
+                },
+                MergedSection {
+                    title: "History".to_string(),
+                    content: "== History ==\nNothing restored.".to_string(),
+                    stats: MergeStats::default(),
+                },
+            ],
+        };
+        let mut reverting_users = HashMap::new();
+        reverting_users.insert(12, "Patroller".to_string());
+        reverting_users.insert(34, "Bot".to_string());
+        assert_eq!(vec![suggested_edit("", 12, Some("Patroller"), "lol"),
+                        suggested_edit("", 56, None, "word"),
+                        suggested_edit("", 34, Some("Bot"), "Bad word")],
+                   get_suggested_edits(&merged_article, &reverting_users));
+    }
+
+    #[test]
+    fn test_format_review_page() {
+        let html = format_review_page(
+            "Foo", &[suggested_edit("", 12, Some("Patroller"), "<b>lol</b>"),
+                     suggested_edit("History", 34, None, "Bad")]);
+        assert!(html.contains("<h2>(Introduction)</h2><ol><li>Removed in <a href=\"/w/index.php?diff=prev&amp;oldid=12\">revision 12</a> by Patroller; consider whether it should have been:<pre>&lt;b&gt;lol&lt;/b&gt;</pre></li></ol>"));
+        assert!(html.contains("<h2>History</h2><ol><li>Removed in <a href=\"/w/index.php?diff=prev&amp;oldid=34\">revision 34</a> by unknown;"));
+        assert!(format_review_page("Foo", &[]).contains("<p>No removed content"));
+    }
+}
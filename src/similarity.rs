@@ -0,0 +1,66 @@
+//! A cheap estimate of how similar two texts are, for skipping merges that are hopeless (e.g. where
+//! the "vandalized" revision is really a rewrite of the whole section) before spending the time
+//! limit on a longest-common-subsequence calculation that would mostly end in conflicts.
+
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher, SipHasher};
+use std::iter;
+
+/// The number of consecutive words in each shingle.
+const SHINGLE_SIZE: usize = 3;
+
+/// Returns the hash of a run of words.
+fn hash_shingle(shingle: &[&[u8]]) -> u64 {
+    let mut hasher = SipHasher::new();
+    shingle.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Returns the hash of each distinct run of SHINGLE_SIZE consecutive words in `words`. If there are
+/// fewer words than that, all of them together are the only shingle.
+fn get_shingles<'a, I>(words: I) -> HashSet<u64> where I: Iterator<Item=&'a [u8]> {
+    let words = words.collect::<Vec<_>>();
+    if words.is_empty() {
+        HashSet::new()
+    } else if words.len() < SHINGLE_SIZE {
+        iter::once(hash_shingle(&words)).collect()
+    } else {
+        words.windows(SHINGLE_SIZE).map(hash_shingle).collect()
+    }
+}
+
+/// Estimates the similarity of two texts, given iterators over their words, as the Jaccard index
+/// of their sets of shingles: 1.0 if they have all the same runs of words, and 0.0 if they have
+/// none in common. Takes linear time, unlike diffing them.
+pub fn estimate_similarity<'a, I>(words1: I, words2: I) -> f64
+    where I: Iterator<Item=&'a [u8]> {
+    let shingles1 = get_shingles(words1);
+    let shingles2 = get_shingles(words2);
+    let union = shingles1.union(&shingles2).count();
+    if union == 0 {
+        // Two empty texts are identical.
+        return 1.0;
+    }
+    shingles1.intersection(&shingles2).count() as f64 / union as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::estimate_similarity;
+    use merge::Words;
+
+    fn similarity(text1: &str, text2: &str) -> f64 {
+        estimate_similarity(Words::new(text1), Words::new(text2))
+    }
+
+    #[test]
+    fn test_estimate_similarity() {
+        assert_eq!(1.0, similarity("", ""));
+        assert_eq!(1.0, similarity("The quick brown fox.", "The quick brown fox."));
+        assert_eq!(1.0, similarity("Fox.", "Fox."));
+        assert_eq!(0.0, similarity("The quick brown fox.", "Lorem ipsum dolor sit amet."));
+        assert_eq!(0.0, similarity("The quick brown fox.", ""));
+        // Changing the last word leaves one of the two shingles: "The quick brown ".
+        assert_eq!(1.0 / 3.0, similarity("The quick brown fox.", "The quick brown dog."));
+    }
+}
@@ -0,0 +1,158 @@
+//! Snapshots of finished pages on disk, so that when the wiki can't be reached, the mirror can
+//! still serve the last version of each page it rendered, marked as stale.
+
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+
+use rustc_serialize::json;
+use time;
+
+/// A finished page, as it was when it was saved.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Snapshot {
+    /// The page's HTML.
+    pub page: String,
+    /// When the page was saved, in seconds since the epoch.
+    pub saved_at: i64,
+}
+
+impl Snapshot {
+    /// Returns the page, with a banner at the top of its body saying when it was saved.
+    pub fn to_stale_page(&self) -> String {
+        let saved_at = format!("{}", time::at_utc(time::Timespec::new(self.saved_at, 0)).rfc3339());
+        let banner = format!(
+            "<div id=\"stale-snapshot\">Wikipedia can't be reached right now, so this is a copy \
+             of the page from <time datetime=\"{}\">{}</time>.</div>",
+            saved_at, saved_at);
+        // The banner goes just inside the <body> tag, or at the very start if there isn't one.
+        let body_start = self.page.find("<body")
+            .and_then(|start| self.page[start..].find('>').map(|end| start + end + 1))
+            .unwrap_or(0);
+        format!("{}{}{}", &self.page[..body_start], banner, &self.page[body_start..])
+    }
+}
+
+/// A directory of snapshots, one for each requested page (see `get_snapshot_key()`), holding at
+/// most `max_snapshots` of them (or any number, if it's 0).
+pub struct SnapshotStore {
+    dir: String,
+    max_snapshots: usize,
+}
+
+impl SnapshotStore {
+    pub fn new(dir: String, max_snapshots: usize) -> SnapshotStore {
+        SnapshotStore { dir: dir, max_snapshots: max_snapshots }
+    }
+
+    /// Returns the path of the snapshot for `key`.
+    fn path(&self, key: &str) -> String {
+        format!("{}/{}.json", self.dir, key.replace("%", "%25").replace("/", "%2F"))
+    }
+
+    /// Saves `page` as the snapshot for `key`, replacing any earlier one. The snapshot is written
+    /// to a temporary file and then renamed, so that a concurrent `load()` never sees half of it.
+    pub fn save(&self, key: &str, page: &str) -> Result<(), String> {
+        let snapshot = Snapshot { page: page.to_string(), saved_at: time::get_time().sec };
+        let encoded = try_display!(json::encode(&snapshot), "Failed to encode snapshot");
+        let path = self.path(key);
+        let temp_path = format!("{}.{}.tmp", path, time::precise_time_ns());
+        {
+            let mut file = try_display!(File::create(&temp_path), "Failed to create snapshot {}",
+                                        temp_path);
+            try_display!(file.write_all(encoded.as_bytes()), "Failed to write snapshot {}",
+                         temp_path);
+        }
+        try_display!(fs::rename(&temp_path, &path), "Failed to save snapshot {}", path);
+        self.evict();
+        Ok(())
+    }
+
+    /// Deletes the least recently saved snapshots, until there are at most `max_snapshots`.
+    fn evict(&self) {
+        if self.max_snapshots == 0 {
+            return;
+        }
+        let entries = try_return!(fs::read_dir(&self.dir), (), "Failed to list snapshots in {}",
+                                  self.dir);
+        let mut snapshots = entries.filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().map_or(false, |extension| extension == "json"))
+            .filter_map(|entry| {
+                entry.metadata().and_then(|metadata| metadata.modified()).ok()
+                    .map(|modified| (modified, entry.path()))
+            })
+            .collect::<Vec<_>>();
+        if snapshots.len() <= self.max_snapshots {
+            return;
+        }
+        snapshots.sort();
+        let excess = snapshots.len() - self.max_snapshots;
+        for &(_, ref path) in &snapshots[..excess] {
+            if let Err(error) = fs::remove_file(path) {
+                warn!("Failed to delete snapshot {}: {}", path.display(), error);
+            }
+        }
+    }
+
+    /// Returns the snapshot for `key`, if one has been saved.
+    pub fn load(&self, key: &str) -> Option<Snapshot> {
+        let path = self.path(key);
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => return None,
+        };
+        let mut contents = String::new();
+        try_return!(file.read_to_string(&mut contents), None, "Failed to read snapshot {}", path);
+        Some(try_return!(json::decode(&contents), None, "Failed to parse snapshot {}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Snapshot, SnapshotStore};
+    use std::thread;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let dir = TempDir::new("snapshots").unwrap();
+        let store = SnapshotStore::new(dir.path().to_str().unwrap().to_string(), 0);
+        assert_eq!(None, store.load("AC/DC"));
+        store.save("AC/DC", "<html>Old</html>").unwrap();
+        store.save("AC/DC", "<html>New</html>").unwrap();
+        assert_eq!(Some("<html>New</html>".to_string()),
+                   store.load("AC/DC").map(|snapshot| snapshot.page));
+    }
+
+    #[test]
+    fn test_least_recently_saved_snapshots_are_evicted() {
+        let dir = TempDir::new("snapshots").unwrap();
+        let store = SnapshotStore::new(dir.path().to_str().unwrap().to_string(), 2);
+        for key in &["A", "B", "A", "C"] {
+            store.save(key, &format!("<html>{}</html>", key)).unwrap();
+            // So that each save has a later modification time than the last.
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(store.load("A").is_some());
+        assert!(store.load("B").is_none());
+        assert!(store.load("C").is_some());
+    }
+
+    #[test]
+    fn test_to_stale_page() {
+        // 2015-08-01 12:00 UTC.
+        let snapshot = Snapshot {
+            page: "<html><body class=\"page\"><p>Text</p></body></html>".to_string(),
+            saved_at: 1438430400,
+        };
+        assert_eq!("<html><body class=\"page\"><div id=\"stale-snapshot\">Wikipedia can't be \
+                    reached right now, so this is a copy of the page from <time \
+                    datetime=\"2015-08-01T12:00:00Z\">2015-08-01T12:00:00Z</time>.</div>\
+                    <p>Text</p></body></html>",
+                   snapshot.to_stale_page());
+        let snapshot = Snapshot { page: "<p>Text</p>".to_string(), ..snapshot };
+        assert!(snapshot.to_stale_page().ends_with("</time>.</div><p>Text</p>"));
+    }
+}
@@ -0,0 +1,176 @@
+//! Fake servers on loopback ports, for tests of code that talks to the wiki or to Redis.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use solicit::http::Header;
+use solicit::http::Response;
+use solicit::server::SimpleServer;
+
+/// The header lines (starting with the request line) and body of a request to a fake server.
+pub type ReceivedRequest = (Vec<String>, String);
+
+/// Formats an HTTP 200 response with the extra header lines `headers` and the body `body`.
+pub fn http_response(headers: &str, body: &str) -> String {
+    http_response_with_status("200 OK", headers, body)
+}
+
+/// Formats an HTTP response with the status `status` (e.g. "404 Not Found"), the extra header
+/// lines `headers`, and the body `body`.
+pub fn http_response_with_status(status: &str, headers: &str, body: &str) -> String {
+    format!("HTTP/1.1 {}\r\n{}Content-Length: {}\r\n\r\n{}", status, headers, body.len(), body)
+}
+
+/// Returns an API response listing revisions `revids` of a page, with `rvcontinue` to continue
+/// from if it's Some.
+pub fn revisions_response(revids: &[u64], comment: &str, rvcontinue: Option<&str>) -> String {
+    let revisions = revids.iter().map(|revid| format!(
+        r#"{{"revid": {}, "parentid": {}, "user": "User", "comment": "{}"}}"#,
+        revid, revid - 1, comment)).collect::<Vec<_>>().join(", ");
+    let continuation = match rvcontinue {
+        Some(rvcontinue) => format!(r#""continue": {{"rvcontinue": "{}"}}, "#, rvcontinue),
+        None => "".to_string(),
+    };
+    http_response("", &format!(
+        r#"{{{}"query": {{"pages": {{"1": {{"pageid": 1, "revisions": [{}]}}}}}}}}"#,
+        continuation, revisions))
+}
+
+/// Returns a response to a query for the content of revision `revid`.
+pub fn content_response(revid: u64, content: &str) -> String {
+    let revision =
+        format!(r#"{{"revid": {}, "parentid": {}, "*": "{}"}}"#, revid, revid - 1, content);
+    http_response("", &format!(
+        r#"{{"query": {{"pages": {{"1": {{"pageid": 1, "revisions": [{}]}}}}}}}}"#, revision))
+}
+
+/// Starts a server on a loopback port, which hands each of the first `connections` connections to
+/// it (or all of them, if None) to `handle`, one at a time. Returns its port.
+pub fn serve<F>(connections: Option<usize>, mut handle: F) -> u16
+    where F: FnMut(TcpStream) + Send + 'static {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move|| {
+        let mut remaining = connections;
+        while remaining != Some(0) {
+            let (stream, _) = listener.accept().unwrap();
+            handle(stream);
+            remaining = remaining.map(|remaining| remaining - 1);
+        }
+    });
+    port
+}
+
+/// Returns a loopback port that nothing is listening on.
+pub fn unused_port() -> u16 {
+    // The port is free again once the listener is dropped.
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Reads an HTTP request from `stream`.
+fn read_request(stream: &mut TcpStream) -> ReceivedRequest {
+    let mut reader = BufReader::new(stream);
+    let mut headers = Vec::new();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_right().to_string();
+        if line.is_empty() {
+            break;
+        }
+        if line.to_lowercase().starts_with("content-length:") {
+            content_length = line["content-length:".len()..].trim().parse().unwrap();
+        }
+        headers.push(line);
+    }
+    let mut body = String::new();
+    reader.take(content_length).read_to_string(&mut body).unwrap();
+    (headers, body)
+}
+
+/// Starts a fake wiki that answers one request with each of `responses` (which can be formatted
+/// with `http_response()`), in order. Returns its port, and a Receiver for each request.
+pub fn serve_responses<R>(responses: Vec<R>) -> (u16, Receiver<ReceivedRequest>)
+    where R: Into<Vec<u8>> + Send + 'static {
+    let (sender, receiver) = channel();
+    let connections = responses.len();
+    let mut responses = responses.into_iter();
+    let port = serve(Some(connections), move|mut stream| {
+        let request = read_request(&mut stream);
+        stream.write_all(&responses.next().unwrap().into()).unwrap();
+        // The test may not be waiting for the request.
+        let _ = sender.send(request);
+    });
+    (port, receiver)
+}
+
+/// Starts a fake wiki that answers every request with `response`, each after `delay` (so that
+/// concurrent requests overlap). Returns its port, and a Receiver for each request, sent as soon
+/// as it's read.
+pub fn serve_slowly(response: String, delay: Duration) -> (u16, Receiver<ReceivedRequest>) {
+    let (sender, receiver) = channel();
+    let port = serve(None, move|mut stream| {
+        let _ = sender.send(read_request(&mut stream));
+        thread::sleep(delay);
+        stream.write_all(response.as_bytes()).unwrap();
+    });
+    (port, receiver)
+}
+
+/// Starts a fake HTTP/2 server, over cleartext, which answers every request on the first
+/// connection to it with a 200, whose X-Path header is the request's path, and whose body is the
+/// request's body. Returns its port.
+pub fn serve_http2() -> u16 {
+    serve(Some(1), |stream| {
+        let mut server = SimpleServer::new(stream, |request| {
+            let path = request.headers.iter().find(|header| header.name() == b":path")
+                .map_or(vec![], |header| header.value().to_vec());
+            Response {
+                stream_id: request.stream_id,
+                headers: vec![Header::new(b":status".to_vec(), b"200".to_vec()),
+                              Header::new(b"x-path".to_vec(), path)],
+                body: request.body.to_vec(),
+            }
+        }).unwrap();
+        while server.handle_next().is_ok() {}
+    })
+}
+
+/// Starts a fake Redis server, which answers every command on the first connection to it with
+/// "+OK", except GET, which always misses. Returns its port, and a Receiver for the arguments of
+/// each command.
+pub fn serve_redis() -> (u16, Receiver<Vec<String>>) {
+    let (sender, receiver) = channel();
+    let port = serve(Some(1), move|stream| {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            let arg_count: usize = line.trim_right()[1..].parse().unwrap();
+            let mut args = Vec::new();
+            for _ in 0..arg_count {
+                let mut length_line = String::new();
+                reader.read_line(&mut length_line).unwrap();
+                let length: usize = length_line.trim_right()[1..].parse().unwrap();
+                let mut arg = Vec::new();
+                (&mut reader).take(length as u64 + 2).read_to_end(&mut arg).unwrap();
+                arg.truncate(length);
+                args.push(String::from_utf8(arg).unwrap());
+            }
+            if args[0] == "GET" {
+                writer.write_all(b"$-1\r\n").unwrap();
+            } else {
+                writer.write_all(b"+OK\r\n").unwrap();
+            }
+            let _ = sender.send(args);
+        }
+    });
+    (port, receiver)
+}
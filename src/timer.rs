@@ -1,23 +1,82 @@
 extern crate time;
 
+use logging;
+use metrics::metrics;
+
 /// A struct that uses RAII to log durations: when dropped, it logs the number of milliseconds it
-/// existed, prefixed by `name`.
+/// existed, prefixed by `name` (see `logging::log_timing()`), and records it in the histogram of
+/// the durations of `phase` (e.g. "parse_wikitext"), which, unlike `name`, mustn't vary by page.
+/// `stop()` does the same, and returns the duration.
 pub struct Timer {
+    phase: &'static str,
     name: String,
-    start_time_ns: u64
+    start_time_ns: u64,
+    /// Whether the duration has already been logged by `stop()`, so that dropping the timer
+    /// shouldn't log it again.
+    stopped: bool,
 }
 
 impl Timer {
-    pub fn new(name: String) -> Timer {
+    pub fn new(phase: &'static str, name: String) -> Timer {
         Timer {
+            phase: phase,
             name: name,
             start_time_ns: time::precise_time_ns(),
+            stopped: false,
         }
     }
+
+    /// Returns the number of milliseconds since the timer was created.
+    pub fn elapsed_ms(&self) -> u64 {
+        (time::precise_time_ns() - self.start_time_ns) / 1_000_000
+    }
+
+    /// Logs and records the timer's duration, as dropping it would, and returns it, in
+    /// milliseconds.
+    pub fn stop(mut self) -> u64 {
+        let duration_ms = self.elapsed_ms();
+        self.record(duration_ms);
+        self.stopped = true;
+        duration_ms
+    }
+
+    fn record(&self, duration_ms: u64) {
+        logging::log_timing(self.phase, &self.name, duration_ms);
+        metrics().observe_duration(self.phase, duration_ms);
+    }
 }
 
 impl Drop for Timer {
     fn drop(&mut self) {
-        info!("{}: {} ms", self.name, (time::precise_time_ns() - self.start_time_ns) / 1_000_000);
+        if !self.stopped {
+            let duration_ms = self.elapsed_ms();
+            self.record(duration_ms);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timer;
+    use metrics::metrics;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_elapsed_ms() {
+        let timer = Timer::new("test_elapsed_ms", "Test timer".to_string());
+        thread::sleep(Duration::from_millis(20));
+        assert!(timer.elapsed_ms() >= 20);
+    }
+
+    #[test]
+    fn test_stop() {
+        let timer = Timer::new("test_stop", "Test timer".to_string());
+        thread::sleep(Duration::from_millis(20));
+        assert!(timer.stop() >= 20);
+        // The duration is recorded once, when the timer is stopped, and not again when it's
+        // dropped.
+        assert!(metrics().render().contains(
+            "wmw_phase_duration_milliseconds_count{phase=\"test_stop\"} 1\n"));
     }
 }
@@ -1,26 +1,299 @@
 extern crate redis;
 
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
 use std::io::Read;
-use std::sync::Arc;
+use std::io::Write;
+use std::str;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
 
+use flate2::read::GzDecoder;
+use flate2::read::ZlibDecoder;
 use hyper::Client;
+use hyper::header::AcceptEncoding;
 use hyper::header::Connection;
-use redis::Commands;
+use hyper::header::Encoding;
+use hyper::header::Headers;
+use hyper::header::UserAgent;
+use hyper::header::qitem;
+use hyper::status::StatusClass;
 use redis::ConnectionInfo;
 use rustc_serialize::json::Json;
+use time;
 use url::percent_encoding;
 
 
 use ::json;
 use ::json::JsonPathElement::{Key, Only};
+use cache;
+use cache::{Cache, CacheBackend, MemoryCache, RedisCache};
+use error::AppError;
+use http2::Http2Client;
+use limiter::RateLimiter;
+use metrics::{Counter, metrics};
 use timer::Timer;
 
 #[derive(Clone)]
 pub struct Wiki {
+    /// The URL scheme to reach the wiki over, "http" or "https".
+    pub scheme: String,
     pub hostname: String,
     pub port: u16,
     client: Arc<Client>,
-    redis_connection_info: Option<ConnectionInfo>,
+    /// The client that sends requests over HTTP/2, if `options.http2` is set. Requests it fails to
+    /// send are sent with `client` instead.
+    http2_client: Option<Arc<Http2Client>>,
+    /// The User-Agent header sent with every request to the wiki. Wikimedia's API policy requires
+    /// it to identify the client and give a way to contact its operator.
+    user_agent: String,
+    /// The cache that API responses are kept in (see `options.cache_backends`), or None to not
+    /// cache them.
+    cache: Option<Arc<Box<Cache>>>,
+    /// Limits the rate of requests to the wiki. Shared by all clones of this Wiki.
+    rate_limiter: RateLimiter,
+    options: WikiOptions,
+    /// Maps page titles to their canonical titles, as found by `get_canonical_title()`, and the
+    /// times (in seconds since the epoch) they were found.
+    canonical_titles: Arc<Mutex<HashMap<String, (String, i64)>>>,
+}
+
+/// Settings that control how a Wiki talks to the wiki.
+#[derive(Clone)]
+pub struct WikiOptions {
+    /// The most revisions `get_revisions()` will fetch for a page, no matter how many are asked
+    /// for.
+    pub max_revisions_scanned: u64,
+    /// A file that canonical titles are persisted to, so they survive restarts. Each line is a
+    /// title, its canonical title, and the time (in seconds since the epoch) it was found,
+    /// separated by tabs. None to not persist them.
+    pub canonical_titles_file: Option<String>,
+    /// How long a canonical title is trusted before it's looked up again, since redirects change,
+    /// or 0 to trust it forever.
+    pub canonical_title_ttl_seconds: u64,
+    /// The maxlag parameter to send with API calls: the most seconds of database replication lag
+    /// at which the wiki should still serve them. None to not send it.
+    pub maxlag: Option<u64>,
+    /// The number of times to retry an API call that's rejected because of replication lag.
+    pub maxlag_retries: u64,
+    /// The most connections to Redis to have open at once, or 0 for no limit.
+    pub max_redis_connections: usize,
+    /// The caches API responses are kept in, in the order they're checked. Values found in a later
+    /// cache are copied into the earlier ones. Redis is skipped if no server is configured.
+    pub cache_backends: Vec<CacheBackend>,
+    /// The most API responses the memory cache holds, if it's in `cache_backends`.
+    pub memory_cache_capacity: usize,
+    /// The most queries `get_all_revisions()` makes at once. If this is more than 1, it fetches
+    /// older revisions in parallel windows (see `get_revisions_windowed()`), instead of only the
+    /// revisions that fit in a single query.
+    pub revision_fetch_parallelism: u64,
+    /// How many seconds values cached in Redis live before they expire, or None for them to never
+    /// expire.
+    pub cache_ttl_seconds: Option<u64>,
+    /// The number of times to retry an API call that fails with a connection error or a 5xx
+    /// status, backing off exponentially (see API_RETRY_BACKOFF_MS) between tries.
+    pub api_max_retries: u64,
+    /// The most requests to send to the wiki per second, across all threads, or 0 for no limit.
+    pub max_api_requests_per_second: u64,
+    /// The most redirects `get_canonical_title()` follows before giving up.
+    pub max_redirect_depth: u64,
+    /// Which kinds of API call have their responses cached. When more than one rule applies to a
+    /// call, the last one wins.
+    pub cache_rules: Vec<ApiCacheRule>,
+    /// The format of the JSON the API is asked to return.
+    pub format_version: FormatVersion,
+    /// Whether to send requests to the wiki over HTTP/2, multiplexed on one connection, instead of
+    /// opening an HTTP/1.1 connection for each.
+    pub http2: bool,
+    /// The file of CA certificates to verify the wiki's certificate against, for HTTP/2 over https.
+    pub http2_ca_file: String,
+}
+
+impl Default for WikiOptions {
+    fn default() -> WikiOptions {
+        WikiOptions {
+            max_revisions_scanned: 5000,
+            canonical_titles_file: None,
+            canonical_title_ttl_seconds: 7 * 24 * 60 * 60,
+            maxlag: None,
+            maxlag_retries: 3,
+            max_redis_connections: 8,
+            cache_backends: vec![CacheBackend::Redis],
+            memory_cache_capacity: 1000,
+            revision_fetch_parallelism: 1,
+            cache_ttl_seconds: None,
+            api_max_retries: 3,
+            max_api_requests_per_second: 0,
+            max_redirect_depth: 10,
+            cache_rules: default_cache_rules(),
+            format_version: FormatVersion::Legacy,
+            http2: false,
+            http2_ca_file: "/etc/ssl/certs/ca-certificates.crt".to_string(),
+        }
+    }
+}
+
+/// A version of the API's JSON format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FormatVersion {
+    /// The original format, where `query.pages` is an object keyed by page ID, and content is
+    /// under "*" keys.
+    Legacy,
+    /// formatversion=2, where `query.pages` is an array, and content is under "content" keys (or,
+    /// for parsed text, isn't wrapped in an object at all).
+    Version2,
+}
+
+impl FormatVersion {
+    /// Returns the key that revision content is under.
+    fn content_key(&self) -> &'static str {
+        match *self {
+            FormatVersion::Legacy => "*",
+            FormatVersion::Version2 => "content",
+        }
+    }
+}
+
+impl FromStr for FormatVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FormatVersion, String> {
+        match s {
+            "1" => Ok(FormatVersion::Legacy),
+            "2" => Ok(FormatVersion::Version2),
+            _ => Err(format!("Unknown API formatversion \"{}\"", s)),
+        }
+    }
+}
+
+/// A rule for whether the responses to one kind of API call are cached, and for how long.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiCacheRule {
+    /// The kind of API call the rule applies to (see `get_api_call_kind()`), e.g. "parse" or
+    /// "query+logevents".
+    pub action: String,
+    pub cacheable: bool,
+    /// How many seconds cached responses live before they expire, or None to use
+    /// `WikiOptions::cache_ttl_seconds`.
+    pub ttl_seconds: Option<u64>,
+}
+
+impl ApiCacheRule {
+    pub fn new(action: &str, cacheable: bool, ttl_seconds: Option<u64>) -> ApiCacheRule {
+        ApiCacheRule {
+            action: action.to_string(),
+            cacheable: cacheable,
+            ttl_seconds: ttl_seconds,
+        }
+    }
+}
+
+/// Returns the API calls cached by default: revision content, which never changes, and parsing
+/// wikitext, which only changes when templates do.
+pub fn default_cache_rules() -> Vec<ApiCacheRule> {
+    vec![ApiCacheRule::new("query+revisions+content", true, None),
+         ApiCacheRule::new("parse", true, None)]
+}
+
+/// Returns the shortest time any cached API response lives before it expires, or None if none of
+/// them expire.
+fn get_shortest_cache_ttl_seconds(options: &WikiOptions) -> Option<u64> {
+    options.cache_rules.iter()
+        .filter(|rule| rule.cacheable)
+        .filter_map(|rule| rule.ttl_seconds.or(options.cache_ttl_seconds))
+        .chain(options.cache_ttl_seconds)
+        .min()
+}
+
+/// Parses a comma-separated list of cache rules, each "ACTION" (cache with the default TTL),
+/// "ACTION:SECONDS" (cache for that many seconds), or "ACTION:off" (don't cache).
+pub fn parse_cache_rules(rules: &str) -> Result<Vec<ApiCacheRule>, String> {
+    let mut cache_rules = Vec::new();
+    for rule in rules.split(',').map(|rule| rule.trim()).filter(|rule| !rule.is_empty()) {
+        let mut components = rule.splitn(2, ':');
+        let action = components.next().unwrap();
+        if action.is_empty() {
+            return Err(format!("Cache rule \"{}\" has no action", rule));
+        }
+        cache_rules.push(match components.next() {
+            None => ApiCacheRule::new(action, true, None),
+            Some("off") => ApiCacheRule::new(action, false, None),
+            Some(ttl_seconds) => ApiCacheRule::new(
+                action, true,
+                Some(try_display!(ttl_seconds.parse::<u64>(), "Invalid TTL in cache rule \"{}\"",
+                                  rule))),
+        });
+    }
+    Ok(cache_rules)
+}
+
+/// Returns the kind of API call made with `parameters`, for matching against ApiCacheRules: the
+/// action, followed for queries by the query module ("query+revisions"), and for revision queries
+/// that fetch content, by "+content", since (unlike lists of revisions) revision content never
+/// changes.
+fn get_api_call_kind(parameters: &[(&str, &str)]) -> String {
+    let get_parameter = |name: &str| {
+        parameters.iter().find(|&&(parameter, _)| parameter == name).map(|&(_, value)| value)
+    };
+    let action = get_parameter("action").unwrap_or("");
+    if action != "query" {
+        return action.to_string();
+    }
+    let mut kind = action.to_string();
+    if let Some(module) = get_parameter("prop").or(get_parameter("list")) {
+        kind = format!("{}+{}", kind, module);
+        let fetches_content = get_parameter("rvprop")
+            .map_or(false, |rvprop| rvprop.split('|').any(|prop| prop == "content"));
+        if module == "revisions" && fetches_content {
+            kind.push_str("+content");
+        }
+    }
+    kind
+}
+
+/// The most revisions the API returns for a single query (without the apihighlimits right).
+const MAX_REVISIONS_PER_QUERY: u64 = 500;
+
+/// The most revision IDs the API accepts in a single query (without the apihighlimits right).
+const MAX_REVIDS_PER_QUERY: usize = 50;
+
+/// The sizes of the successive batches of revisions `get_revisions_until()` fetches. After the
+/// last, it keeps fetching batches of the last size.
+const REVISION_BATCH_SIZES: [u64; 3] = [50, 200, 500];
+
+/// How long to wait before retrying an API call rejected because of replication lag, if the wiki
+/// doesn't say.
+const DEFAULT_MAXLAG_RETRY_SECS: u64 = 5;
+
+/// How long to wait before the first retry of an API call that failed. Each later retry waits twice
+/// as long as the one before, up to MAX_API_RETRY_BACKOFF_DOUBLINGS times.
+const API_RETRY_BACKOFF_MS: u64 = 100;
+
+/// How many times the wait before retrying an API call is doubled at most, so that many retries
+/// neither overflow it nor wait for hours.
+const MAX_API_RETRY_BACKOFF_DOUBLINGS: u64 = 7;
+
+/// The path of the MediaWiki API endpoint on the wiki.
+const API_PATH: &'static str = "/w/api.php";
+
+/// The API call that pings the wiki, to check that it can be reached.
+const API_PING_QUERY: &'static str = "action=query&meta=siteinfo&format=json";
+
+/// How a call to the MediaWiki API is sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ApiMethod {
+    /// With the parameters in the query string, so proxies between here and the wiki can cache
+    /// the response.
+    Get,
+    /// With the parameters in the request body, which has no length limit.
+    Post,
 }
 
 #[derive(Clone)]
@@ -28,161 +301,672 @@ pub struct Revision {
     pub revid: u64,
     pub parentid: u64,
     pub comment: String,
+    /// The name (or IP address) of the user who made the revision.
+    pub user: String,
+    /// The revision's change tags (e.g. "mw-rollback").
+    pub tags: Vec<String>,
+    /// The SHA1 of the page's content as of the revision, or None if it's hidden.
+    pub sha1: Option<String>,
 }
 
 impl Wiki {
-    /// Constructs a Wiki object representing the wiki at `hostname` (e.g. "en.wikipedia.org").
-    pub fn new(hostname: String, port: u16, client: Client,
-               redis_connection_info: Option<ConnectionInfo>)
+    /// Constructs a Wiki object representing the wiki at `hostname` (e.g. "en.wikipedia.org"),
+    /// reached over `scheme` ("http" or "https"), identifying itself as `user_agent`.
+    pub fn new(scheme: String, hostname: String, port: u16, client: Client, user_agent: String,
+               redis_connection_info: Option<ConnectionInfo>, options: WikiOptions)
                -> Wiki {
+        let mut caches: Vec<Box<Cache>> = Vec::new();
+        for backend in &options.cache_backends {
+            match (*backend, &redis_connection_info) {
+                (CacheBackend::Memory, _) => {
+                    caches.push(Box::new(MemoryCache::new(options.memory_cache_capacity)));
+                },
+                (CacheBackend::Redis, &Some(ref connection_info)) => {
+                    caches.push(Box::new(RedisCache::new(connection_info.clone(),
+                                                         options.max_redis_connections)));
+                },
+                (CacheBackend::Redis, &None) => (),
+            }
+        }
+        let cache = cache::tier_caches(caches, get_shortest_cache_ttl_seconds(&options))
+            .map(Arc::new);
+        let http2_client = if options.http2 {
+            Some(Arc::new(Http2Client::new(&scheme, &hostname, port, &options.http2_ca_file)))
+        } else {
+            None
+        };
         Wiki {
+            scheme: scheme,
             hostname: hostname,
             port: port,
             client: Arc::new(client),
-            redis_connection_info: redis_connection_info,
+            http2_client: http2_client,
+            user_agent: user_agent,
+            cache: cache,
+            rate_limiter: RateLimiter::new(options.max_api_requests_per_second),
+            options: options,
+            canonical_titles: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    // TODO: implement a connection pool, or per-thread connections. I tried to do this several ways
-    // and failed (redis::Connection isn't Send or Sync, and I couldn't get thread-locals to work).
-    // Note: Panics if called when `self.redis_connection_info` is `None`.
-    fn get_redis_connection(&self) -> redis::Connection {
-        // The redis-rs docs "heavily encourage" the use of URLs instead of the
-        // ConnectionInfo struct, but redis::IntoConnectionInfo is only implemented for
-        // &str, so I can't construct a URL and pass it in without using String::as_str(),
-        // which is marked unstable.
-        let redis_client =
-            redis::Client::open((&self.redis_connection_info).clone().unwrap()).unwrap();
-        redis_client.get_connection().unwrap()
+    /// Returns a Wiki object for the same wiki, reached at `hostname` instead. It shares this one's
+    /// HTTP/1.1 client and canonical titles, but has its own HTTP/2 connection.
+    pub fn with_hostname(&self, hostname: String) -> Wiki {
+        let http2_client = self.http2_client.as_ref().map(|_| {
+            Arc::new(Http2Client::new(&self.scheme, &hostname, self.port,
+                                      &self.options.http2_ca_file))
+        });
+        Wiki { hostname: hostname, http2_client: http2_client, ..self.clone() }
     }
 
-    fn try_get_cached_value(&self, key: String) -> Option<String> {
-        if self.redis_connection_info.is_none() {
-            return None;
+    /// Loads the canonical titles persisted to `options.canonical_titles_file`, if there is one, so
+    /// that they don't need to be looked up again, and rewrites the file with only the ones that
+    /// haven't expired, so that it doesn't grow forever. Should be called at startup. Returns the
+    /// number of titles loaded.
+    pub fn load_canonical_titles(&self) -> Result<usize, String> {
+        let path = match self.options.canonical_titles_file {
+            Some(ref path) => path,
+            None => return Ok(0),
+        };
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                try_display!(file.read_to_string(&mut contents),
+                             "Failed to read canonical titles file {}", path);
+            },
+            // The file will be created when the first canonical title is found.
+            Err(_) => return Ok(0),
         }
-        // TODO: distinguish errors other than not-found, and log them (but still return None).
-        self.get_redis_connection().get(key).ok()
+        let now = time::get_time().sec;
+        let loaded_titles = parse_canonical_titles(&contents).into_iter()
+            .filter(|&(_, (_, found_at))| !self.is_canonical_title_expired(found_at, now))
+            .collect::<HashMap<_, _>>();
+        let count = loaded_titles.len();
+        let compacted = loaded_titles.iter()
+            .map(|(title, &(ref canonical_title, found_at))| {
+                format!("{}\t{}\t{}\n", title, canonical_title, found_at)
+            }).collect::<String>();
+        // Written to a temporary file first, so that a crash can't lose the whole file.
+        let temp_path = format!("{}.tmp", path);
+        let result = File::create(&temp_path)
+            .and_then(|mut file| file.write_all(compacted.as_bytes()))
+            .and_then(|_| fs::rename(&temp_path, path));
+        if let Err(err) = result {
+            warn!("Failed to rewrite canonical titles file {}: {}", path, err);
+        }
+        self.canonical_titles.lock().unwrap().extend(loaded_titles);
+        Ok(count)
     }
 
-    fn try_cache_value(&self, key: String, value: String) {
-        if self.redis_connection_info.is_some() {
-            // TODO: log errors here
-            let _: redis::RedisResult<String> = self.get_redis_connection().set(key, value);
+    /// Returns whether a canonical title found at `found_at` has expired by `now` (both in seconds
+    /// since the epoch). See `options.canonical_title_ttl_seconds`.
+    fn is_canonical_title_expired(&self, found_at: i64, now: i64) -> bool {
+        self.options.canonical_title_ttl_seconds != 0 &&
+            now - found_at >= self.options.canonical_title_ttl_seconds as i64
+    }
+
+    /// Returns the known canonical title of `title`, if it hasn't expired.
+    fn get_known_canonical_title(&self, title: &str) -> Option<String> {
+        match self.canonical_titles.lock().unwrap().get(title) {
+            Some(&(ref canonical_title, found_at))
+                if !self.is_canonical_title_expired(found_at, time::get_time().sec) => {
+                Some(canonical_title.clone())
+            },
+            _ => None,
         }
     }
 
+    /// Remembers that the canonical title of `title` is `canonical_title`, and persists that to
+    /// `options.canonical_titles_file`, if there is one.
+    fn remember_canonical_title(&self, title: &str, canonical_title: &str) {
+        let now = time::get_time().sec;
+        self.canonical_titles.lock().unwrap()
+            .insert(title.to_string(), (canonical_title.to_string(), now));
+        if let Some(ref path) = self.options.canonical_titles_file {
+            let result = OpenOptions::new().append(true).create(true).open(path)
+                .and_then(|mut file| writeln!(file, "{}\t{}\t{}", title, canonical_title, now));
+            if let Err(err) = result {
+                warn!("Failed to persist canonical title of \"{}\" to {}: {}", title, path, err);
+            }
+        }
+    }
+
+    /// Returns the URL of the wiki's root, e.g. "https://en.wikipedia.org". The port is only
+    /// included if it isn't the default port for the scheme.
+    fn base_url(&self) -> String {
+        match (&self.scheme[..], self.port) {
+            ("http", 80) | ("https", 443) => format!("{}://{}", self.scheme, self.hostname),
+            _ => format!("{}://{}:{}", self.scheme, self.hostname, self.port),
+        }
+    }
+
+    /// Returns the URL of the wiki's MediaWiki API endpoint.
+    fn api_url(&self) -> String {
+        format!("{}{}", self.base_url(), API_PATH)
+    }
+
+    /// Returns the URL of the wiki's MediaWiki API endpoint, with the form-encoded parameters
+    /// `query` as its query string.
+    fn api_query_url(&self, query: &str) -> String {
+        format!("{}{}", self.base_url(), get_api_query_path(query))
+    }
+
+    /// Returns the URL of the rendered page `title`.
+    fn page_url(&self, title: &str) -> String {
+        format!("{}{}", self.base_url(), get_page_path(title))
+    }
+
+    fn try_get_cached_value(&self, key: String) -> Option<String> {
+        self.cache.as_ref().and_then(|cache| cache.get(&key))
+    }
+
+    /// Caches `value` under `key`, expiring after `ttl_seconds`, or never if it's None.
+    fn try_cache_value_with_ttl(&self, key: String, value: String, ttl_seconds: Option<u64>) {
+        if let Some(ref cache) = self.cache {
+            cache.set(&key, &value, ttl_seconds);
+        }
+    }
+
+    /// Returns the rule in `options.cache_rules` for API calls made with `parameters`, if any.
+    fn get_cache_rule(&self, parameters: &[(&str, &str)]) -> Option<&ApiCacheRule> {
+        let kind = get_api_call_kind(parameters);
+        self.options.cache_rules.iter().rev().find(|rule| rule.action == kind)
+    }
+
     /// Calls the MediaWiki API with the given parameters and format=json. Returns the raw JSON.
-    fn call_mediawiki_api(&self, parameters: Vec<(&str, &str)>, cacheable: bool)
-                          -> Result<String, String> {
-        let query =
+    /// Calls that are cached (see `options.cache_rules`) are idempotent reads, and are sent as
+    /// GETs; the rest are POSTed.
+    fn call_mediawiki_api(&self, parameters: Vec<(&str, &str)>) -> Result<String, String> {
+        let cacheable = self.get_cache_rule(&parameters).map_or(false, |rule| rule.cacheable);
+        let method = if cacheable { ApiMethod::Get } else { ApiMethod::Post };
+        self.call_mediawiki_api_with_method(parameters, method)
+    }
+
+    /// Calls the MediaWiki API with the given parameters and format=json, sending them with
+    /// `method`. Returns the raw JSON. The response is cached according to `options.cache_rules`.
+    ///
+    /// If `options.maxlag` is set, the wiki rejects the call while its databases are lagged, in
+    /// which case it's retried (up to `options.maxlag_retries` times) after the wait the wiki asks
+    /// for. A call that fails outright is retried up to `options.api_max_retries` times.
+    fn call_mediawiki_api_with_method(&self, parameters: Vec<(&str, &str)>, method: ApiMethod)
+                                      -> Result<String, String> {
+        let (cacheable, ttl_seconds) = match self.get_cache_rule(&parameters) {
+            Some(rule) => (rule.cacheable, rule.ttl_seconds.or(self.options.cache_ttl_seconds)),
+            None => (false, None),
+        };
+        let mut query =
             parameters.into_iter().map(|p| format!("{}={}", p.0, p.1))
             .collect::<Vec<_>>().join("&") + "&format=json";
+        if self.options.format_version == FormatVersion::Version2 {
+            query.push_str("&formatversion=2");
+        }
 
         if cacheable {
             match self.try_get_cached_value(query.clone()) {
-                Some(result) => return Ok(result),
-                _ => (),
+                Some(result) => {
+                    metrics().increment(Counter::ApiCacheHits);
+                    return Ok(result);
+                },
+                _ => metrics().increment(Counter::ApiCacheMisses),
             }
         }
 
-        let mut response = try_display!(
-            self.client.post(&format!("https://{}/w/api.php", self.hostname))
-                .body(&query).header(Connection::close()).send(), "Error calling Wikimedia API");
-        let mut body = String::new();
-        match response.read_to_string(&mut body) {
-            Ok(..) => {
-                // TODO: make this asynchronous
-                if cacheable {
-                    self.try_cache_value(query.clone(), body.clone())
-                }
-                Ok(body)
+        // maxlag is left out of the cache key, since it doesn't change the result.
+        let request_query = match self.options.maxlag {
+            Some(maxlag) => format!("{}&maxlag={}", query, maxlag),
+            None => query.clone(),
+        };
+        let mut retries = 0;
+        let mut failures = 0;
+        loop {
+            let response = match self.send_to_api(&request_query, method) {
+                Ok(response) => response,
+                Err(msg) => {
+                    if failures >= self.options.api_max_retries {
+                        return Err(msg);
+                    }
+                    let backoff_ms =
+                        API_RETRY_BACKOFF_MS << cmp::min(failures, MAX_API_RETRY_BACKOFF_DOUBLINGS);
+                    warn!("{}; retrying API call in {} ms", msg, backoff_ms);
+                    thread::sleep(Duration::from_millis(backoff_ms));
+                    failures += 1;
+                    continue;
+                },
+            };
+            let retry_after = response.retry_after;
+            // A body that can't be decoded isn't retried, since the wiki would most likely send the
+            // same one again.
+            let body = try!(response.decode_body());
+            match get_maxlag_retry_secs(&body, retry_after) {
+                Some(retry_secs) if retries < self.options.maxlag_retries => {
+                    warn!("Wiki is lagged; retrying API call in {} seconds", retry_secs);
+                    thread::sleep(Duration::from_secs(retry_secs));
+                    retries += 1;
+                },
+                Some(_) => {
+                    return Err(format!("Wiki is still lagged after {} retries of API call",
+                                       retries));
+                },
+                None => {
+                    // TODO: make this asynchronous
+                    if cacheable {
+                        self.try_cache_value_with_ttl(query.clone(), body.clone(), ttl_seconds)
+                    }
+                    return Ok(body);
+                },
+            }
+        }
+    }
+
+    /// Sends the form-encoded parameters `request_query` to the API once, with `method`, and
+    /// returns the response, undecoded. Fails on a connection error or a 5xx status, which are
+    /// worth retrying; a 4xx status is returned like any other response.
+    fn send_to_api(&self, request_query: &str, method: ApiMethod) -> Result<ApiResponse, String> {
+        self.rate_limiter.acquire();
+        if let Some(http2_client) = self.get_available_http2_client() {
+            if let Some(result) = self.send_to_api_over_http2(http2_client, request_query, method) {
+                return result;
+            }
+        }
+        let result = match method {
+            ApiMethod::Get => {
+                let url = self.api_query_url(request_query);
+                self.client.get(&url).header(Connection::close())
+                    .header(UserAgent(self.user_agent.clone())).header(accept_compressed()).send()
+            },
+            ApiMethod::Post => {
+                self.client.post(&self.api_url())
+                    .body(request_query).header(Connection::close())
+                    .header(UserAgent(self.user_agent.clone())).header(accept_compressed()).send()
+            },
+        };
+        let mut response = try_display!(result, "Error calling Wikimedia API");
+        if response.status.class() == StatusClass::ServerError {
+            return Err(format!("Wikimedia API returned {}", response.status));
+        }
+        let mut raw_body = Vec::new();
+        try_display!(response.read_to_end(&mut raw_body), "Error reading Wikimedia API response");
+        Ok(ApiResponse {
+            raw_body: raw_body,
+            content_encoding: get_raw_header(&response.headers, "Content-Encoding")
+                .map(|value| value.to_string()),
+            retry_after: get_raw_header(&response.headers, "Retry-After")
+                .and_then(|value| value.trim().parse().ok()),
+        })
+    }
+
+    /// Returns the client to send requests over HTTP/2 with, or None if they should go over
+    /// HTTP/1.1, because HTTP/2 isn't enabled, or was unavailable from the wiki when last tried.
+    fn get_available_http2_client(&self) -> Option<&Http2Client> {
+        match self.http2_client {
+            Some(ref http2_client) if http2_client.is_available() => Some(&**http2_client),
+            _ => None,
+        }
+    }
+
+    /// Does the work of `send_to_api()` over HTTP/2. Returns None, after logging why, if the call
+    /// couldn't be sent that way, in which case it should be sent over HTTP/1.1 instead.
+    fn send_to_api_over_http2(&self, http2_client: &Http2Client, request_query: &str,
+                              method: ApiMethod) -> Option<Result<ApiResponse, String>> {
+        let mut headers = vec![("User-Agent", &self.user_agent[..]),
+                               ("Accept-Encoding", "gzip, deflate")];
+        let result = match method {
+            ApiMethod::Get => http2_client.get(&get_api_query_path(request_query), &headers),
+            ApiMethod::Post => {
+                headers.push(("Content-Type", "application/x-www-form-urlencoded"));
+                http2_client.post(API_PATH, &headers, request_query)
+            },
+        };
+        let response = match result {
+            Ok(response) => response,
+            Err(msg) => {
+                warn!("{}; falling back to HTTP/1.1", msg);
+                return None;
             },
-            Err(error) =>
-                Err(format!("Error converting Wikimedia API response to UTF-8: {}", error)),
+        };
+        if response.status >= 500 {
+            return Some(Err(format!("Wikimedia API returned {}", response.status)));
         }
+        Some(Ok(ApiResponse {
+            raw_body: response.body.clone(),
+            content_encoding: response.header("Content-Encoding").map(|value| value.to_string()),
+            retry_after: response.header("Retry-After").and_then(|value| value.trim().parse().ok()),
+        }))
     }
 
-    /// Returns the last `limit` revisions for the page `title`.
+    /// Returns the number of revisions to actually fetch when `limit` are asked for.
+    fn revisions_to_scan(&self, limit: u64) -> u64 {
+        if limit > self.options.max_revisions_scanned {
+            warn!("Asked for {} revisions, but only scanning {}", limit,
+                  self.options.max_revisions_scanned);
+            self.options.max_revisions_scanned
+        } else {
+            limit
+        }
+    }
+
+    /// Returns the last `limit` revisions for the page `title`, or the last
+    /// `max_revisions_scanned` if that's fewer. The API returns at most MAX_REVISIONS_PER_QUERY
+    /// revisions per query, so this follows its continuation tokens until it has them all.
     pub fn get_revisions(&self, title: &str, limit: u64) -> Result<Vec<Revision>, String> {
-        let _timer = Timer::new(format!("Got {} revisions of \"{}\"", limit, &title));
-        let json_str = try!(self.call_mediawiki_api(
-            vec![("action", "query"), ("prop", "revisions"), ("titles", title),
-                 ("rvprop", "comment|ids"), ("rvlimit", &limit.to_string())], false));
-        let json = try_display!(
-            Json::from_str(&json_str),
-            "Error parsing API response for {} revisions of \"{}\"", limit, title);
-        let revisions_json = try!(
-            json::get_json_array(&json, &[Key("query"), Key("pages"), Only, Key("revisions")]));
-
-        let mut revisions = Vec::with_capacity(revisions_json.len());
-        for revision_json in revisions_json {
-            revisions.push(
-                Revision {
-                    revid: try!(json::get_json_number(revision_json, &[Key("revid")])),
-                    parentid: try!(json::get_json_number(revision_json, &[Key("parentid")])),
-                    comment: try!(json::get_json_string(revision_json, &[Key("comment")])).to_string()
-                });
+        let limit = self.revisions_to_scan(limit);
+        let _timer =
+            Timer::new("get_revisions", format!("Got {} revisions of \"{}\"", limit, &title));
+        let mut revisions: Vec<Revision> = Vec::new();
+        let mut rvcontinue: Option<String> = None;
+        while (revisions.len() as u64) < limit {
+            let rvlimit =
+                cmp::min(limit - revisions.len() as u64, MAX_REVISIONS_PER_QUERY).to_string();
+            let (query_revisions, next_rvcontinue) = {
+                let mut parameters = vec![("rvlimit", &rvlimit[..])];
+                if let Some(ref rvcontinue) = rvcontinue {
+                    parameters.push(("rvcontinue", &rvcontinue[..]));
+                }
+                try!(self.query_revisions_continued(title, parameters))
+            };
+            revisions.extend(query_revisions);
+            match next_rvcontinue {
+                Some(next_rvcontinue) => rvcontinue = Some(next_rvcontinue),
+                None => break,
+            }
+        }
+        revisions.truncate(limit as usize);
+        Ok(revisions)
+    }
+
+    /// Returns the revisions of the page `title` to look for reverted vandalism in: as many as a
+    /// single query returns, or, if `options.revision_fetch_parallelism` is more than 1, the last
+    /// `options.max_revisions_scanned`.
+    pub fn get_all_revisions(&self, title: &str) -> Result<Vec<Revision>, String> {
+        if self.options.revision_fetch_parallelism > 1 {
+            self.get_revisions_windowed(title, self.options.max_revisions_scanned,
+                                        self.options.revision_fetch_parallelism)
+        } else {
+            self.get_revisions(title, MAX_REVISIONS_PER_QUERY)
+        }
+    }
+
+    /// Returns (approximately) the last `limit` revisions of the page `title`, or the last
+    /// `max_revisions_scanned` if that's fewer, making up to `parallelism` queries at once.
+    ///
+    /// Following the API's continuation tokens is inherently sequential, so instead, this fetches
+    /// the newest revisions, splits the range of revision IDs between the page's first revision and
+    /// the oldest revision fetched into windows, and fetches the newest revisions in each window in
+    /// parallel. A window that holds more revisions than a single query returns loses its oldest
+    /// ones, so the result can have gaps; that's the price of the speedup.
+    pub fn get_revisions_windowed(&self, title: &str, limit: u64, parallelism: u64)
+                                  -> Result<Vec<Revision>, String> {
+        let limit = self.revisions_to_scan(limit);
+        let _timer = Timer::new("get_revisions_in_parallel",
+                                format!("Got {} revisions of \"{}\" in parallel", limit, &title));
+        let newest_revisions = try!(self.query_revisions(
+            title, vec![("rvlimit", &cmp::min(limit, MAX_REVISIONS_PER_QUERY).to_string())]));
+        if newest_revisions.len() as u64 >= limit ||
+            (newest_revisions.len() as u64) < MAX_REVISIONS_PER_QUERY {
+            // Either that's all that was asked for, or it's the page's whole history.
+            return Ok(newest_revisions);
+        }
+        let first_revision = try!(try!(self.query_revisions(
+            title, vec![("rvlimit", "1"), ("rvdir", "newer")])).pop().ok_or(
+            format!("No revisions found for page \"{}\"", title)));
+        let oldest_fetched_id = newest_revisions.last().unwrap().revid;
+        if first_revision.revid >= oldest_fetched_id {
+            return Ok(newest_revisions);
+        }
+
+        let remaining = limit - newest_revisions.len() as u64;
+        let window_count = cmp::min(
+            parallelism, (remaining + MAX_REVISIONS_PER_QUERY - 1) / MAX_REVISIONS_PER_QUERY);
+        let receivers = get_revision_windows(first_revision.revid, oldest_fetched_id - 1,
+                                             window_count).into_iter()
+            .map(|(start_id, end_id)| {
+                let (sender, receiver) = channel();
+                let wiki = self.clone();
+                let title = title.to_string();
+                thread::Builder::new()
+                    .name(format!("fetch-revisions-{}-{}-{}", title, start_id, end_id))
+                    .spawn(move|| {
+                        sender.send(wiki.query_revisions(
+                            &title, vec![("rvlimit", &MAX_REVISIONS_PER_QUERY.to_string()),
+                                         ("rvstartid", &start_id.to_string()),
+                                         ("rvendid", &end_id.to_string())])).unwrap();
+                    }).unwrap();
+                receiver
+            }).collect::<Vec<_>>();
+
+        let mut windows = vec![newest_revisions];
+        for receiver in receivers {
+            windows.push(try!(try_display!(receiver.recv(), "Failed to get data from thread")));
+        }
+        Ok(reconcile_revision_windows(windows, limit))
+    }
+
+    /// Returns the revisions of the page `title`, newest first, fetched in growing batches (see
+    /// `REVISION_BATCH_SIZES`) until `is_enough` returns true for all the revisions fetched so far,
+    /// the page's history runs out, or `max_revisions_scanned` revisions have been fetched. This
+    /// avoids downloading hundreds of revisions when the ones that matter are recent.
+    pub fn get_revisions_until<F>(&self, title: &str, is_enough: F)
+                                  -> Result<Vec<Revision>, String>
+        where F: Fn(&[Revision]) -> bool {
+        let _timer = Timer::new("get_revisions_in_batches",
+                                format!("Got revisions of \"{}\" in batches", &title));
+        let mut revisions: Vec<Revision> = Vec::new();
+        let mut rvcontinue: Option<String> = None;
+        let mut batch = 0;
+        while (revisions.len() as u64) < self.options.max_revisions_scanned {
+            let batch_size = cmp::min(
+                REVISION_BATCH_SIZES[cmp::min(batch, REVISION_BATCH_SIZES.len() - 1)],
+                self.options.max_revisions_scanned - revisions.len() as u64).to_string();
+            let (batch_revisions, next_rvcontinue) = {
+                let mut parameters = vec![("rvlimit", &batch_size[..])];
+                if let Some(ref rvcontinue) = rvcontinue {
+                    parameters.push(("rvcontinue", &rvcontinue[..]));
+                }
+                try!(self.query_revisions_continued(title, parameters))
+            };
+            revisions.extend(batch_revisions);
+            if is_enough(&revisions) {
+                break;
+            }
+            match next_rvcontinue {
+                Some(next_rvcontinue) => rvcontinue = Some(next_rvcontinue),
+                None => break,
+            }
+            batch += 1;
         }
         Ok(revisions)
     }
 
-    /// Returns the latest revision ID for the page `title`.
-    pub fn get_latest_revision(&self, title: &str) -> Result<Revision, String> {
-        let _timer = Timer::new(format!("Got latest revision of \"{}\"", &title));
-        let mut revisions = try!(self.get_revisions(title, 1));
-        revisions.pop().ok_or(format!("No revisions found for page \"{}\"", title))
+    /// Queries the revisions of the page `title`, newest first, with the rv* parameters
+    /// `parameters` (which should include at least rvlimit).
+    fn query_revisions(&self, title: &str, parameters: Vec<(&str, &str)>)
+                       -> Result<Vec<Revision>, String> {
+        Ok(try!(self.query_revisions_continued(title, parameters)).0)
+    }
+
+    /// Like `query_revisions()`, but also returns the rvcontinue value to pass to fetch the
+    /// revisions after these, or None if there aren't any.
+    fn query_revisions_continued(&self, title: &str, parameters: Vec<(&str, &str)>)
+                                 -> Result<(Vec<Revision>, Option<String>), String> {
+        let mut all_parameters = vec![("action", "query"), ("prop", "revisions"), ("titles", title),
+                                      ("rvprop", "comment|ids|sha1|tags|user")];
+        all_parameters.extend(parameters);
+        let json_str = try!(self.call_mediawiki_api(all_parameters));
+        parse_revisions(&json_str, title)
+    }
+
+    /// Checks that the wiki's API, and the Redis server API responses are cached in (if there is
+    /// one), can be reached, for health checks. Unlike other API calls, the API ping is neither
+    /// retried nor cached.
+    pub fn check_health(&self) -> Result<(), String> {
+        let body = try!(try!(self.send_to_api(API_PING_QUERY, ApiMethod::Get)).decode_body());
+        let json = try_display!(Json::from_str(&body), "Invalid response to API ping");
+        if json.find("query").is_none() {
+            return Err(format!("Unexpected response to API ping: {}", body));
+        }
+        match self.cache {
+            Some(ref cache) => cache.ping(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns whether the wiki's API answers a single (unretried, uncached) ping, to tell an
+    /// outage apart from calls that failed while the wiki was up.
+    pub fn is_reachable(&self) -> bool {
+        self.send_to_api(API_PING_QUERY, ApiMethod::Get).is_ok()
+    }
+
+    /// Returns the latest revision ID for the page `title`, or `AppError::PageNotFound` if it has
+    /// none (because it doesn't exist).
+    pub fn get_latest_revision(&self, title: &str) -> Result<Revision, AppError> {
+        let _timer =
+            Timer::new("get_latest_revision", format!("Got latest revision of \"{}\"", &title));
+        let mut revisions = try!(self.get_revisions(title, 1).map_err(AppError::Upstream));
+        revisions.pop().ok_or(AppError::PageNotFound)
     }
 
     /// Returns the contents of the page `title` as of (i.e., immediately after) revision `id`.
     pub fn get_revision_content(&self, title: &str, id: u64) -> Result<String, String> {
-        let _timer = Timer::new(format!("Got content of revision {} of \"{}\"", &id, &title));
+        let _timer = Timer::new("get_revision_content",
+                                format!("Got content of revision {} of \"{}\"", &id, &title));
         let json_str = try!(self.call_mediawiki_api(
-            vec![("action", "query"), ("prop", "revisions"), ("titles", title), ("rvprop", "content"),
-                 ("rvlimit", "1"), ("rvstartid", &id.to_string())], true));
-        let json = try_display!(
-            Json::from_str(&json_str),
-            "Error parsing API response for content of \"{}\" revision {}", title, id);
-        Ok(try!(json::get_json_string(
-            &json,
-            &[Key("query"), Key("pages"), Only, Key("revisions"), Only, Key("*")])).to_string())
+            vec![("action", "query"), ("prop", "revisions"), ("titles", title),
+                 ("rvprop", "content|ids"), ("rvlimit", "1"), ("rvstartid", &id.to_string())]));
+        parse_revision_content(&json_str, title, id, self.options.format_version)
     }
 
-    /// Follows all redirects to find the canonical name of the page at `title`.
-    pub fn get_canonical_title(&self, title: &str) -> Result<String, String> {
-        let _timer = Timer::new(format!("Got canonical title of \"{}\"", &title));
-        let latest_revision_id = try!(self.get_latest_revision(title)).revid;
-        let page_contents = try!(self.get_revision_content(title, latest_revision_id));
+    /// Returns the contents of the revisions `ids` of the page `title`, keyed by revision ID. The
+    /// revisions are fetched MAX_REVIDS_PER_QUERY to a query, with the queries made in parallel.
+    pub fn get_revision_contents(&self, title: &str, ids: &[u64])
+                                 -> Result<HashMap<u64, String>, String> {
+        let _timer = Timer::new("get_revision_contents",
+                                format!("Got content of {} revisions of \"{}\" in batches",
+                                        ids.len(), &title));
+        let receivers = ids.chunks(MAX_REVIDS_PER_QUERY).map(|batch| {
+            let (sender, receiver) = channel();
+            let wiki = self.clone();
+            let title = title.to_string();
+            let revids = batch.iter().map(|id| id.to_string()).collect::<Vec<_>>().join("|");
+            // Not run on the handler's worker pool: the merge jobs occupying it wait on these.
+            thread::Builder::new().name(format!("fetch-contents-{}-{}", title, batch[0]))
+                .spawn(move|| {
+                    sender.send(
+                        wiki.call_mediawiki_api(
+                            vec![("action", "query"), ("prop", "revisions"), ("revids", &revids),
+                                 ("rvprop", "content|ids")])
+                            .and_then(|json_str| parse_revision_contents(
+                                &json_str, &title, wiki.options.format_version)))
+                        .unwrap();
+                }).unwrap();
+            receiver
+        }).collect::<Vec<_>>();
 
-        let regex = regex!(r"#REDIRECT \[\[([^]]+)\]\].*");
-        match regex.captures(&page_contents) {
-            Some(captures) => self.get_canonical_title(captures.at(1).unwrap()),
-            None => Ok(title.to_string()),
+        let mut contents = HashMap::with_capacity(ids.len());
+        for receiver in receivers {
+            contents.extend(
+                try!(try!(try_display!(receiver.recv(), "Failed to get data from thread"))));
+        }
+        for id in ids {
+            if !contents.contains_key(id) {
+                return Err(format!("No content returned for revision {} of \"{}\"", id, title));
+            }
+        }
+        Ok(contents)
+    }
+
+    /// Follows all redirects to find the canonical name of the page at `title`. Returns an error if
+    /// the redirects loop, or if there are more than `options.max_redirect_depth` of them, and
+    /// `AppError::PageNotFound` if the page (or one it redirects to) doesn't exist.
+    pub fn get_canonical_title(&self, title: &str) -> Result<String, AppError> {
+        let _timer =
+            Timer::new("get_canonical_title", format!("Got canonical title of \"{}\"", &title));
+        let title = split_title_fragment(title).0;
+        // The titles passed through on the way to the canonical title, including the canonical
+        // title itself unless it was already known.
+        let mut visited_titles = HashSet::new();
+        let mut current_title = title.to_string();
+        loop {
+            if let Some(canonical_title) = self.get_known_canonical_title(&current_title) {
+                current_title = canonical_title;
+                break;
+            }
+            if !visited_titles.insert(current_title.clone()) {
+                return Err(AppError::Upstream(format!(
+                    "Redirects from \"{}\" loop back to \"{}\"", title, current_title)));
+            }
+            if visited_titles.len() as u64 > self.options.max_redirect_depth + 1 {
+                return Err(AppError::Upstream(format!(
+                    "Gave up on \"{}\" after following {} redirects", title,
+                    self.options.max_redirect_depth)));
+            }
+            let latest_revision_id = try!(self.get_latest_revision(&current_title)).revid;
+            let page_contents =
+                try!(self.get_revision_content(&current_title, latest_revision_id)
+                         .map_err(AppError::Upstream));
+            match parse_redirect_target(&page_contents) {
+                Some(target) => current_title = target,
+                None => break,
+            }
+        }
+        for visited_title in &visited_titles {
+            self.remember_canonical_title(visited_title, &current_title);
         }
+        Ok(current_title)
+    }
+
+    /// Returns the title of the draft the page `title` was promoted from ("Draft:`title`"), if the
+    /// move log shows the draft being moved to `title`. Titles are percent-encoded.
+    pub fn get_draft_origin_title(&self, title: &str) -> Result<Option<String>, String> {
+        let _timer =
+            Timer::new("get_draft_origin_title", format!("Got moves to \"{}\"", &title));
+        let draft_title = match get_draft_title(title) {
+            Some(draft_title) => draft_title,
+            None => return Ok(None),
+        };
+        let json_str = try!(self.call_mediawiki_api(
+            vec![("action", "query"), ("list", "logevents"), ("letype", "move"),
+                 ("leprop", "title|details"), ("letitle", &draft_title), ("lelimit", "50")]));
+        let normalized_title = normalize_title(title, false);
+        let was_moved = try!(parse_move_targets(&json_str, &draft_title)).iter()
+            .any(|target| normalize_title(target, false) == normalized_title);
+        Ok(if was_moved { Some(draft_title) } else { None })
     }
 
     /// Parses the wikitext in `wikitext` as though it were the contents of the page `title`,
     /// returning the rendered HTML.
     pub fn parse_wikitext(&self, title: &str, wikitext: &str) -> Result<String, String> {
-        let _timer = Timer::new(format!("Parsed wikitext for \"{}\"", &title));
+        let _timer =
+            Timer::new("parse_wikitext", format!("Parsed wikitext for \"{}\"", &title));
         let encoded_wikitext =
             percent_encoding::percent_encode(
                 wikitext.as_bytes(), percent_encoding::FORM_URLENCODED_ENCODE_SET);
-        let response = try!(self.call_mediawiki_api(
+        // The wikitext can be far too long to fit in a URL, so it's always POSTed.
+        let response = try!(self.call_mediawiki_api_with_method(
             vec![("action", "parse"), ("prop", "text"), ("disablepp", ""),
-                 ("contentmodel", "wikitext"), ("title", title), ("text", &encoded_wikitext)], true));
-        let json = try_display!(
-            Json::from_str(&response),
-            "Error parsing API response for parsing merged wikitext of \"{}\"", title);
-        Ok(try!(json::get_json_string(&json, &[Key("parse"), Key("text"), Key("*")])).to_string())
+                 ("contentmodel", "wikitext"), ("title", title), ("text", &encoded_wikitext)],
+            ApiMethod::Post));
+        parse_parsed_text(&response, title, self.options.format_version)
     }
 
     /// Gets the current, fully-rendered (**HTML**) contents of the page `title`.
     pub fn get_current_page_content(&self, title: &str) -> Result<String, String> {
-        let _timer = Timer::new(format!("Got current HTML contents of \"{}\"", &title));
-        let url = format!("https://{}/wiki/{}", self.hostname, title);
+        let _timer = Timer::new("get_current_page_content",
+                                format!("Got current HTML contents of \"{}\"", &title));
+        let url = self.page_url(title);
+        self.rate_limiter.acquire();
+        if let Some(http2_client) = self.get_available_http2_client() {
+            match http2_client.get(&get_page_path(title), &[("User-Agent", &self.user_agent[..])]) {
+                Ok(response) => {
+                    return Ok(try_display!(String::from_utf8(response.body),
+                                           "Error converting page {} to UTF-8", url));
+                },
+                Err(msg) => warn!("{}; falling back to HTTP/1.1", msg),
+            }
+        }
         let mut response =
             try_display!(
-                self.client.get(&url).header(Connection::close()).send(),
+                self.client.get(&url).header(Connection::close())
+                    .header(UserAgent(self.user_agent.clone())).send(),
                 "Error fetching URL {}", url);
         let mut body = String::new();
         match response.read_to_string(&mut body) {
@@ -192,6 +976,423 @@ impl Wiki {
     }
 }
 
+/// Splits the revision IDs from `first_id` to `last_id` (inclusive) into `count` windows of about
+/// the same size, and returns the (newest ID, oldest ID) of each window, newest window first.
+fn get_revision_windows(first_id: u64, last_id: u64, count: u64) -> Vec<(u64, u64)> {
+    let total = last_id - first_id + 1;
+    let count = cmp::max(1, cmp::min(count, total));
+    (0..count).map(|i| {
+        let newest_id = last_id - total * i / count;
+        let oldest_id = last_id + 1 - total * (i + 1) / count;
+        (newest_id, oldest_id)
+    }).collect()
+}
+
+/// Combines the revisions fetched in several windows (which may overlap) into the newest `limit`
+/// distinct revisions, newest first.
+fn reconcile_revision_windows(windows: Vec<Vec<Revision>>, limit: u64) -> Vec<Revision> {
+    let mut all_revisions = windows.into_iter().flat_map(|window| window.into_iter())
+        .collect::<Vec<_>>();
+    all_revisions.sort_by(|a, b| b.revid.cmp(&a.revid));
+    let mut revisions: Vec<Revision> = Vec::with_capacity(all_revisions.len());
+    for revision in all_revisions {
+        if revisions.len() as u64 == limit {
+            break;
+        }
+        if revisions.last().map_or(true, |last| last.revid != revision.revid) {
+            revisions.push(revision);
+        }
+    }
+    revisions
+}
+
+/// If the API response `body` is an error because the wiki's databases are too lagged, returns how
+/// many seconds to wait before retrying: `retry_after` (from the Retry-After header) if it's
+/// present, or DEFAULT_MAXLAG_RETRY_SECS. Otherwise, returns None.
+fn get_maxlag_retry_secs(body: &str, retry_after: Option<u64>) -> Option<u64> {
+    // Most responses can be ruled out without parsing them.
+    if !body.contains("maxlag") {
+        return None;
+    }
+    match Json::from_str(body) {
+        Ok(json) => match json::get_json_string(&json, &[Key("error"), Key("code")]) {
+            Ok("maxlag") => Some(retry_after.unwrap_or(DEFAULT_MAXLAG_RETRY_SECS)),
+            _ => None,
+        },
+        Err(..) => None,
+    }
+}
+
+/// Parses the API response `json_str` for revisions of the page `title`, returning the revisions,
+/// and the rvcontinue value to pass to fetch the revisions after these, if there are any.
+fn parse_revisions(json_str: &str, title: &str) -> Result<(Vec<Revision>, Option<String>), String> {
+    let json = try_display!(
+        Json::from_str(json_str),
+        "Error parsing API response for revisions of \"{}\"", title);
+    // In both formats, `query.pages` holds just the one page, whether it's an object (keyed by
+    // page ID) or an array.
+    let revisions_json = match json::get_json_array(
+        &json, &[Key("query"), Key("pages"), Only, Key("revisions")]) {
+        Ok(revisions_json) => revisions_json,
+        Err(msg) => {
+            // A page that exists, but has no revisions in the requested range, has no
+            // "revisions" key at all.
+            if json::get_json_number(&json, &[Key("query"), Key("pages"), Only, Key("pageid")])
+                .is_ok() {
+                return Ok((Vec::new(), None));
+            }
+            // A page that doesn't exist is flagged as missing (with true in formatversion=2, and
+            // an empty string before it). It has no revisions either, which
+            // `get_latest_revision()` reports as `AppError::PageNotFound`.
+            if let Ok(Some(_)) = json::get_optional_json_value(
+                &json, &[Key("query"), Key("pages"), Only, Key("missing")]) {
+                return Ok((Vec::new(), None));
+            }
+            return Err(msg.to_string());
+        },
+    };
+
+    let mut revisions = Vec::with_capacity(revisions_json.len());
+    for revision_json in revisions_json {
+        // Revisions whose comments were suppressed have no "comment" key.
+        let comment =
+            match try!(json::get_optional_json_value(revision_json, &[Key("comment")])) {
+                Some(_) => try!(json::get_json_string(revision_json, &[Key("comment")])),
+                None => "",
+            };
+        // Wikis too old to have change tags have no "tags" key, and revisions whose content was
+        // suppressed have no "sha1" key.
+        let tags = match try!(json::get_optional_json_value(revision_json, &[Key("tags")])) {
+            Some(_) => &try!(json::get_json_array(revision_json, &[Key("tags")]))[..],
+            None => &[][..],
+        };
+        let tags = try!(tags.iter().map(|tag| {
+            tag.as_string().map(|tag| tag.to_string())
+                .ok_or(format!("Invalid tag in revision of \"{}\": {}", title, tag))
+        }).collect::<Result<Vec<_>, _>>());
+        let sha1 = match try!(json::get_optional_json_value(revision_json, &[Key("sha1")])) {
+            Some(_) => {
+                Some(try!(json::get_json_string(revision_json, &[Key("sha1")])).to_string())
+            },
+            None => None,
+        };
+        revisions.push(
+            Revision {
+                revid: try!(json::get_json_number(revision_json, &[Key("revid")])),
+                parentid: try!(json::get_json_number(revision_json, &[Key("parentid")])),
+                comment: comment.to_string(),
+                user: try!(json::get_json_string(revision_json, &[Key("user")])).to_string(),
+                tags: tags,
+                sha1: sha1,
+            });
+    }
+    // Depending on the wiki's version, continuation is under "continue" or (the old format)
+    // "query-continue".
+    let rvcontinue = json::get_json_string(&json, &[Key("continue"), Key("rvcontinue")])
+        .or(json::get_json_string(
+            &json, &[Key("query-continue"), Key("revisions"), Key("rvcontinue")]))
+        .ok().map(|rvcontinue| rvcontinue.to_string());
+    Ok((revisions, rvcontinue))
+}
+
+/// Parses the API response `json_str`, in `format_version`, for the content of revision `id` of
+/// the page `title`. With rvstartid, the API returns the latest revision at or before `id`, which
+/// is a different revision if `id` was deleted, so this returns an error unless the response is
+/// for revision `id` itself.
+fn parse_revision_content(json_str: &str, title: &str, id: u64, format_version: FormatVersion)
+                          -> Result<String, String> {
+    let json = try_display!(
+        Json::from_str(json_str),
+        "Error parsing API response for content of \"{}\" revision {}", title, id);
+    let returned_id = try!(json::get_json_number(
+        &json, &[Key("query"), Key("pages"), Only, Key("revisions"), Only, Key("revid")]));
+    if returned_id != id {
+        return Err(format!("Asked for content of \"{}\" revision {}, but got revision {}", title,
+                           id, returned_id));
+    }
+    Ok(try!(json::get_json_string(
+        &json, &[Key("query"), Key("pages"), Only, Key("revisions"), Only,
+                 Key(format_version.content_key())])).to_string())
+}
+
+/// Parses the API response `json_str`, in `format_version`, for the content of several revisions
+/// of the page `title`, returning (revision ID, content) pairs.
+fn parse_revision_contents(json_str: &str, title: &str, format_version: FormatVersion)
+                           -> Result<Vec<(u64, String)>, String> {
+    let json = try_display!(
+        Json::from_str(json_str),
+        "Error parsing API response for content of revisions of \"{}\"", title);
+    let revisions_json = try!(json::get_json_array(
+        &json, &[Key("query"), Key("pages"), Only, Key("revisions")]));
+    let mut contents = Vec::with_capacity(revisions_json.len());
+    for revision_json in revisions_json {
+        contents.push((try!(json::get_json_number(revision_json, &[Key("revid")])),
+                       try!(json::get_json_string(
+                           revision_json, &[Key(format_version.content_key())])).to_string()));
+    }
+    Ok(contents)
+}
+
+/// Parses the API response `json_str`, in `format_version`, for parsing wikitext as the page
+/// `title`, returning the rendered HTML.
+fn parse_parsed_text(json_str: &str, title: &str, format_version: FormatVersion)
+                     -> Result<String, String> {
+    let json = try_display!(
+        Json::from_str(json_str),
+        "Error parsing API response for parsing merged wikitext of \"{}\"", title);
+    let text = match format_version {
+        FormatVersion::Legacy => try!(json::get_json_string(&json, &[Key("parse"), Key("text"),
+                                                                    Key("*")])),
+        FormatVersion::Version2 => try!(json::get_json_string(&json, &[Key("parse"), Key("text")])),
+    };
+    Ok(text.to_string())
+}
+
+/// If `hostname` is the mobile version of a wiki's hostname (e.g. "en.m.wikipedia.org", or
+/// "m.mediawiki.org"), returns the desktop version ("en.wikipedia.org", "mediawiki.org").
+/// Otherwise, returns None.
+pub fn get_desktop_hostname(hostname: &str) -> Option<String> {
+    let mut labels = hostname.split('.').collect::<Vec<_>>();
+    match labels.iter().take(2).position(|label| *label == "m") {
+        // The "m" label has to be followed by at least a domain and a top-level domain.
+        Some(index) if labels.len() - index >= 3 => {
+            labels.remove(index);
+            Some(labels.join("."))
+        },
+        _ => None,
+    }
+}
+
+/// Returns the mobile version of the wiki hostname `hostname`: "en.m.wikipedia.org" for
+/// "en.wikipedia.org", and "m.mediawiki.org" for "mediawiki.org". Mobile hostnames are returned
+/// unchanged.
+pub fn get_mobile_hostname(hostname: &str) -> String {
+    if get_desktop_hostname(hostname).is_some() {
+        return hostname.to_string();
+    }
+    let mut labels = hostname.split('.').collect::<Vec<_>>();
+    let index = if labels.len() >= 3 { 1 } else { 0 };
+    labels.insert(index, "m");
+    labels.join(".")
+}
+
+/// Parses the contents of a canonical titles file (see `WikiOptions::canonical_titles_file`) into a
+/// map from title to canonical title and the time it was found. Later lines for a title replace
+/// earlier ones. Malformed lines are skipped, and lines without a time (which older versions wrote)
+/// are treated as found at the epoch, so that they expire.
+fn parse_canonical_titles(contents: &str) -> HashMap<String, (String, i64)> {
+    let mut canonical_titles = HashMap::new();
+    for line in contents.lines() {
+        let fields = line.split('\t').collect::<Vec<_>>();
+        let found_at = match fields.len() {
+            2 => Some(0),
+            3 => fields[2].parse().ok(),
+            _ => None,
+        };
+        match found_at {
+            Some(found_at) if !fields[0].is_empty() && !fields[1].is_empty() => {
+                canonical_titles.insert(fields[0].to_string(), (fields[1].to_string(), found_at));
+            },
+            _ => warn!("Skipping malformed line in canonical titles file: \"{}\"", line),
+        }
+    }
+    canonical_titles
+}
+
+/// What to do with the fragment of a requested title (the "History" in "Foo#History").
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FragmentPolicy {
+    /// Redirect to the page without the fragment in its title, with the fragment moved into the
+    /// URL's fragment, so the browser scrolls to it.
+    Redirect,
+    /// Serve the page, dropping the fragment.
+    Strip,
+}
+
+impl FromStr for FragmentPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FragmentPolicy, String> {
+        match s {
+            "redirect" => Ok(FragmentPolicy::Redirect),
+            "strip" => Ok(FragmentPolicy::Strip),
+            _ => Err(format!("Unknown fragment policy \"{}\"", s)),
+        }
+    }
+}
+
+/// Splits the percent-encoded page title `title` at the start of its fragment (a "#", which is
+/// usually encoded as "%23" if it's made it to the server), returning the title and the fragment
+/// (without the "#"), if there is one. "#" can't appear in a page title, so anything after it is
+/// never part of the title.
+pub fn split_title_fragment(title: &str) -> (&str, Option<&str>) {
+    match regex!("#|%23").find(title) {
+        Some((start, end)) => (&title[..start], Some(&title[end..])),
+        None => (title, None),
+    }
+}
+
+/// Normalizes a percent-encoded page title from a request URL the way MediaWiki does, so that e.g.
+/// "united_states", "United_States/", and "United%20States" all become "United_States": spaces become
+/// underscores, runs of underscores are collapsed, leading and trailing underscores and slashes are
+/// removed, and (if `capital_links` is set, as it is on Wikipedia) the first letter is uppercased.
+/// The result is percent-encoded.
+pub fn normalize_title(title: &str, capital_links: bool) -> String {
+    let decoded_title = percent_encoding::lossy_utf8_percent_decode(title.as_bytes());
+    let words = decoded_title.split(|c| c == ' ' || c == '_').filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    let normalized_title = words.join("_");
+    let normalized_title = normalized_title.trim_right_matches('/').trim_right_matches('_');
+    let normalized_title = if capital_links {
+        let mut chars = normalized_title.chars();
+        match chars.next() {
+            Some(first_char) => first_char.to_uppercase().chain(chars).collect::<String>(),
+            None => String::new(),
+        }
+    } else {
+        normalized_title.to_string()
+    };
+    percent_encoding::percent_encode(
+        normalized_title.as_bytes(), percent_encoding::FORM_URLENCODED_ENCODE_SET)
+}
+
+/// Returns the path of the wiki's MediaWiki API endpoint, with the form-encoded parameters `query`
+/// as its query string. Parameters that were already percent-encoded (like titles) are left as they
+/// are; anything else that isn't allowed in a query string is encoded.
+fn get_api_query_path(query: &str) -> String {
+    format!("{}?{}", API_PATH,
+            percent_encoding::percent_encode(query.as_bytes(), percent_encoding::QUERY_ENCODE_SET))
+}
+
+/// Returns the path of the rendered page `title`.
+fn get_page_path(title: &str) -> String {
+    format!("/wiki/{}", title)
+}
+
+/// Returns the header that asks for a response compressed in any way `decode_response_body()` can
+/// decode.
+fn accept_compressed() -> AcceptEncoding {
+    AcceptEncoding(vec![qitem(Encoding::Gzip), qitem(Encoding::Deflate)])
+}
+
+/// Returns the value of the header `name` in `headers`, if it's present and is valid UTF-8.
+fn get_raw_header<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+    headers.get_raw(name)
+        .and_then(|values| values.first())
+        .and_then(|value| str::from_utf8(value).ok())
+}
+
+/// A response from the API, as `send_to_api()` returns it.
+struct ApiResponse {
+    /// The body, still compressed if it was sent that way.
+    raw_body: Vec<u8>,
+    /// The value of the Content-Encoding header, if any.
+    content_encoding: Option<String>,
+    /// The number of seconds in the Retry-After header, if any.
+    retry_after: Option<u64>,
+}
+
+impl ApiResponse {
+    /// Decompresses the body, and converts it to UTF-8.
+    fn decode_body(self) -> Result<String, String> {
+        decode_response_body(self.raw_body,
+                             self.content_encoding.as_ref().map(|encoding| &encoding[..]))
+    }
+}
+
+/// Decompresses the response body `raw_body`, according to its Content-Encoding header
+/// `content_encoding` (if any), and converts it to UTF-8.
+fn decode_response_body(raw_body: Vec<u8>, content_encoding: Option<&str>)
+                        -> Result<String, String> {
+    let mut body = String::new();
+    match content_encoding.map(|encoding| encoding.trim().to_lowercase()) {
+        Some(ref encoding) if encoding == "gzip" => {
+            let mut decoder = try_display!(GzDecoder::new(&raw_body[..]),
+                                           "Error reading gzipped Wikimedia API response");
+            try_display!(decoder.read_to_string(&mut body),
+                         "Error decompressing gzipped Wikimedia API response");
+        },
+        // HTTP's "deflate" is zlib-wrapped, not raw deflate.
+        Some(ref encoding) if encoding == "deflate" => {
+            try_display!(ZlibDecoder::new(&raw_body[..]).read_to_string(&mut body),
+                         "Error decompressing deflated Wikimedia API response");
+        },
+        Some(ref encoding) if encoding != "identity" => {
+            return Err(format!("Unsupported Content-Encoding \"{}\" in Wikimedia API response",
+                               encoding));
+        },
+        _ => {
+            body = try_display!(String::from_utf8(raw_body),
+                                "Error converting Wikimedia API response to UTF-8");
+        },
+    }
+    Ok(body)
+}
+
+/// If the wikitext `page_contents` is a redirect, returns the title of the page it redirects to.
+/// Like MediaWiki, this accepts any capitalization of "#REDIRECT", optional whitespace and colon
+/// around it, and piped links. Section anchors in the target are dropped, since the canonical title
+/// is the title of the whole page.
+fn parse_redirect_target(page_contents: &str) -> Option<String> {
+    let regex = regex!(r"^\s*(?i:#REDIRECT)\s*:?\s*\[\[([^\]|#]+)(#[^\]|]*)?(\|[^\]]*)?\]\]");
+    regex.captures(page_contents).map(|captures| captures.at(1).unwrap().trim().to_string())
+}
+
+/// Returns the title of the talk page associated with the percent-encoded page title `title` (e.g.
+/// "Talk:Foo" for "Foo", "User_talk:Foo" for "User:Foo"), percent-encoded. Returns None if `title`
+/// is already a talk page, or is in a namespace without talk pages.
+pub fn get_talk_page_title(title: &str) -> Option<String> {
+    const NAMESPACES: [&'static str; 9] = ["User", "Wikipedia", "File", "MediaWiki", "Template",
+                                           "Help", "Category", "Portal", "Draft"];
+    let decoded_title = percent_encoding::lossy_utf8_percent_decode(title.as_bytes());
+    let talk_page_title = match decoded_title.find(':') {
+        Some(index) => {
+            let namespace = &decoded_title[..index];
+            if namespace == "Talk" || namespace.ends_with("_talk") || namespace == "Special" ||
+                namespace == "Media" {
+                return None;
+            } else if NAMESPACES.contains(&namespace) {
+                format!("{}_talk{}", namespace, &decoded_title[index..])
+            } else {
+                // A colon in an article title, like "Star_Wars:_Episode_IV".
+                format!("Talk:{}", decoded_title)
+            }
+        },
+        None => format!("Talk:{}", decoded_title),
+    };
+    Some(percent_encoding::percent_encode(
+        talk_page_title.as_bytes(), percent_encoding::FORM_URLENCODED_ENCODE_SET))
+}
+
+/// Returns the title of the page in draft space corresponding to the percent-encoded page title
+/// `title` (e.g. "Draft:Foo" for "Foo"), percent-encoded. Returns None if `title` is already a
+/// draft.
+fn get_draft_title(title: &str) -> Option<String> {
+    let decoded_title = percent_encoding::lossy_utf8_percent_decode(title.as_bytes());
+    if decoded_title.starts_with("Draft:") {
+        return None;
+    }
+    Some(percent_encoding::percent_encode(format!("Draft:{}", decoded_title).as_bytes(),
+                                          percent_encoding::FORM_URLENCODED_ENCODE_SET))
+}
+
+/// Parses a move log query (list=logevents&letype=move) for the page `title`, returning the title
+/// the page was moved to by each move.
+fn parse_move_targets(json_str: &str, title: &str) -> Result<Vec<String>, String> {
+    let json = try_display!(Json::from_str(json_str),
+                            "Error parsing API response for moves of \"{}\"", title);
+    let log_events = try!(json::get_json_array(&json, &[Key("query"), Key("logevents")]));
+    let mut targets = Vec::with_capacity(log_events.len());
+    for log_event in log_events {
+        // Older versions of MediaWiki put the target under "move" instead of "params".
+        let target = try!(
+            json::get_json_string(log_event, &[Key("params"), Key("target_title")])
+                .or(json::get_json_string(log_event, &[Key("move"), Key("new_title")])));
+        targets.push(target.to_string());
+    }
+    Ok(targets)
+}
+
 /// Parses out the sections of a Wikipedia page. Returns a vector of (section title, section
 /// content). Section title is "" for the content before the first heading. Section content
 /// includes the heading.
@@ -213,9 +1414,131 @@ pub fn parse_sections(wikitext: &str) -> Vec<(String, String)> {
         (section_title.to_owned(), section_heading.to_owned() + section_contents)).collect()
 }
 
+/// Returns `wikitext` with every template whose name starts with one of `names` removed, including
+/// any templates nested inside it. For example, the name "Infobox" matches both "{{Infobox|...}}"
+/// and "{{infobox person|...}}". Unterminated templates are left in place.
+pub fn remove_templates(wikitext: &str, names: &[&str]) -> String {
+    remove_matching_templates(wikitext, |template| {
+        names.iter().any(|name| template.starts_with(&name.to_lowercase()))
+    })
+}
+
+/// Returns `wikitext` with every template named exactly one of `names` (ignoring case, and treating
+/// underscores as spaces) removed. Unlike `remove_templates()`, the name "Cn" matches "{{cn}}" and
+/// "{{Cn|date=May 2015}}", but not "{{cnbc}}".
+pub fn remove_named_templates(wikitext: &str, names: &[String]) -> String {
+    let names =
+        names.iter().map(|name| name.replace("_", " ").to_lowercase()).collect::<Vec<_>>();
+    remove_matching_templates(wikitext, |template| {
+        let name = template.split(|c: char| c == '|' || c == '}').next().unwrap();
+        names.contains(&name.trim().replace("_", " "))
+    })
+}
+
+/// Returns `wikitext` with every template for which `is_match` returns true removed, including any
+/// templates nested inside it. `is_match` is given the lowercased text after the template's "{{",
+/// starting at its name. Unterminated templates are left in place.
+fn remove_matching_templates<F>(wikitext: &str, is_match: F) -> String
+    where F: Fn(&str) -> bool {
+    let mut result = String::new();
+    let mut rest = wikitext;
+    while let Some(start) = rest.find("{{") {
+        let template = rest[start + 2..].trim_left().to_lowercase();
+        let template_length =
+            if is_match(&template) {
+                find_template_length(&rest[start..])
+            } else {
+                None
+            };
+        match template_length {
+            Some(length) => {
+                result.push_str(&rest[..start]);
+                rest = &rest[start + length..];
+            },
+            None => {
+                result.push_str(&rest[..start + 2]);
+                rest = &rest[start + 2..];
+            },
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Given `wikitext` starting with "{{", returns the length of the template it starts (up to and
+/// including the matching "}}"), or None if the template is never closed.
+fn find_template_length(wikitext: &str) -> Option<usize> {
+    let bytes = wikitext.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    while i + 1 < bytes.len() {
+        if &bytes[i..i + 2] == b"{{" {
+            depth += 1;
+            i += 2;
+        } else if &bytes[i..i + 2] == b"}}" {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return Some(i);
+            }
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
 #[cfg(test)]
 mod tests {
-    use super::parse_sections;
+    use super::{ApiCacheRule, FormatVersion, decode_response_body, default_cache_rules,
+                get_api_call_kind,
+                get_desktop_hostname, get_draft_title, get_maxlag_retry_secs, get_mobile_hostname,
+                get_revision_windows, get_talk_page_title, normalize_title, parse_cache_rules,
+                parse_canonical_titles, parse_move_targets, parse_parsed_text,
+                parse_redirect_target, parse_revision_content, parse_revision_contents,
+                parse_revisions, parse_sections, reconcile_revision_windows,
+                remove_named_templates, remove_templates, split_title_fragment, Revision, Wiki,
+                WikiOptions};
+    use error::AppError;
+    use flate2::Compression;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use hyper::Client;
+    use redis::{ConnectionAddr, ConnectionInfo};
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use tempfile::NamedTempFile;
+    use test_server::{content_response, http_response, http_response_with_status,
+                      revisions_response, serve_redis, serve_responses};
+    use time;
+
+    fn test_wiki(scheme: &str, hostname: &str, port: u16, options: WikiOptions) -> Wiki {
+        Wiki::new(scheme.to_string(), hostname.to_string(), port, Client::new(),
+                  "test-agent/1.0".to_string(), None, options)
+    }
+
+    #[test]
+    fn test_urls_use_configured_scheme() {
+        let wiki = test_wiki("http", "localhost", 80, WikiOptions::default());
+        assert_eq!("http://localhost/w/api.php", wiki.api_url());
+        assert_eq!("http://localhost/wiki/Test", wiki.page_url("Test"));
+    }
+
+    #[test]
+    fn test_urls_include_non_default_port() {
+        let wiki = test_wiki("http", "localhost", 8080, WikiOptions::default());
+        assert_eq!("http://localhost:8080/w/api.php", wiki.api_url());
+        let wiki = test_wiki("https", "en.wikipedia.org", 443, WikiOptions::default());
+        assert_eq!("https://en.wikipedia.org/wiki/Test", wiki.page_url("Test"));
+    }
+
+    #[test]
+    fn test_revisions_to_scan_is_capped() {
+        let wiki = test_wiki("https", "en.wikipedia.org", 443,
+                             WikiOptions { max_revisions_scanned: 1000, ..Default::default() });
+        assert_eq!(500, wiki.revisions_to_scan(500));
+        assert_eq!(1000, wiki.revisions_to_scan(1000));
+        assert_eq!(1000, wiki.revisions_to_scan(100000));
+    }
 
     #[test]
     fn test_parse_sections() {
@@ -279,4 +1602,710 @@ mod tests {
                  "==test section==\ntest contents\n===subsection===\nqwer".to_owned())],
             sections);
     }
+
+    #[test]
+    fn test_normalize_title_variants() {
+        for title in &["United_States", "united_states", "United_States/", "United%20States",
+                       "United States", "_United__States_", "united%20_States/"] {
+            assert_eq!("United_States", normalize_title(title, true));
+        }
+    }
+
+    #[test]
+    fn test_split_title_fragment() {
+        assert_eq!(("Foo", None), split_title_fragment("Foo"));
+        assert_eq!(("Foo", Some("History")), split_title_fragment("Foo%23History"));
+        assert_eq!(("Foo", Some("History")), split_title_fragment("Foo#History"));
+        assert_eq!(("Foo", Some("")), split_title_fragment("Foo%23"));
+        assert_eq!(("Foo", Some("A%23B")), split_title_fragment("Foo%23A%23B"));
+    }
+
+    #[test]
+    fn test_normalize_title_without_capital_links() {
+        assert_eq!("united_States", normalize_title("united%20States/", false));
+    }
+
+    #[test]
+    fn test_normalize_title_reencodes() {
+        assert_eq!("At%26t", normalize_title("at%26t", true));
+        assert_eq!("%C3%89mile_Zola", normalize_title("%C3%A9mile%20Zola", true));
+    }
+
+    #[test]
+    fn test_remove_templates() {
+        let wikitext = "{{Infobox person\n| name = {{nowrap|A B}}\n| born = {{{born|}}}\n}}Text {{cite|x}}{{navbox|y}}";
+        assert_eq!("Text {{cite|x}}", remove_templates(wikitext, &["Infobox", "Navbox"]));
+    }
+
+    #[test]
+    fn test_remove_named_templates() {
+        let names = vec!["Citation needed".to_string(), "Cn".to_string()];
+        let wikitext = "A.{{cn}} B.{{Citation_needed|date=May 2015}} C.{{cnbc|x}}{{Cite|url=x}}";
+        assert_eq!("A. B. C.{{cnbc|x}}{{Cite|url=x}}", remove_named_templates(wikitext, &names));
+    }
+
+    #[test]
+    fn test_remove_templates_unterminated() {
+        let wikitext = "Text {{Infobox|name = {{nowrap|A B}}";
+        assert_eq!(wikitext, remove_templates(wikitext, &["Infobox"]));
+    }
+
+    #[test]
+    fn test_parse_canonical_titles() {
+        let canonical_titles = parse_canonical_titles(
+            "USA\tUnited_States\t100\nmalformed\n\nUK\tUnited_Kingdom\tyesterday\n\
+             UK\tUnited_Kingdom\t200\nGB\tUnited_Kingdom\n");
+        assert_eq!(3, canonical_titles.len());
+        assert_eq!(Some(&("United_States".to_string(), 100)), canonical_titles.get("USA"));
+        assert_eq!(Some(&("United_Kingdom".to_string(), 200)), canonical_titles.get("UK"));
+        // Lines without a time are from older versions.
+        assert_eq!(Some(&("United_Kingdom".to_string(), 0)), canonical_titles.get("GB"));
+    }
+
+    #[test]
+    fn test_persisted_canonical_titles_are_loaded() {
+        let mut file = NamedTempFile::new().unwrap();
+        let now = time::get_time().sec;
+        writeln!(file, "USA\tUnited_States\t{}", now - 60).unwrap();
+        writeln!(file, "UK\tUnited_Kingdom\t{}", now - 7200).unwrap();
+        writeln!(file, "USA\tUnited_States\t{}", now).unwrap();
+        let options = WikiOptions {
+            canonical_titles_file: Some(file.path().to_str().unwrap().to_string()),
+            canonical_title_ttl_seconds: 3600,
+            ..WikiOptions::default()
+        };
+        // The hostname is unreachable, so the canonical title must come from the file.
+        let wiki = test_wiki("http", "wiki.invalid", 80, options);
+        assert_eq!(Ok(1), wiki.load_canonical_titles());
+        assert_eq!(Ok("United_States".to_string()), wiki.get_canonical_title("USA"));
+        // The expired title has to be looked up again.
+        assert!(wiki.get_canonical_title("UK").is_err());
+
+        // The file is rewritten with only the unexpired titles, once each.
+        let mut contents = String::new();
+        File::open(file.path()).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(format!("USA\tUnited_States\t{}\n", now), contents);
+    }
+
+    #[test]
+    fn test_get_talk_page_title() {
+        assert_eq!(Some("Talk%3AFoo".to_string()), get_talk_page_title("Foo"));
+        assert_eq!(Some("User_talk%3AFoo".to_string()), get_talk_page_title("User%3AFoo"));
+        assert_eq!(Some("Talk%3AStar_Wars%3A_Episode_IV".to_string()),
+                   get_talk_page_title("Star_Wars%3A_Episode_IV"));
+        assert_eq!(None, get_talk_page_title("Talk%3AFoo"));
+        assert_eq!(None, get_talk_page_title("User_talk%3AFoo"));
+        assert_eq!(None, get_talk_page_title("Special%3ARandom"));
+    }
+
+    #[test]
+    fn test_get_draft_title() {
+        assert_eq!(Some("Draft%3AFoo_bar".to_string()), get_draft_title("Foo_bar"));
+        assert_eq!(None, get_draft_title("Draft%3AFoo"));
+    }
+
+    #[test]
+    fn test_parse_move_targets() {
+        let json = r#"{"query": {"logevents": [
+            {"title": "Draft:Foo", "params": {"target_ns": 0, "target_title": "Foo"}},
+            {"title": "Draft:Foo", "move": {"new_ns": 2, "new_title": "User:Bar/Foo"}}]}}"#;
+        assert_eq!(Ok(vec!["Foo".to_string(), "User:Bar/Foo".to_string()]),
+                   parse_move_targets(json, "Draft%3AFoo"));
+    }
+
+    #[test]
+    fn test_parse_redirect_target() {
+        let target = Some("United States".to_string());
+        assert_eq!(target, parse_redirect_target("#REDIRECT [[United States]]"));
+        assert_eq!(target, parse_redirect_target("#redirect [[United States]]"));
+        assert_eq!(target, parse_redirect_target("#Redirect[[United States]]"));
+        assert_eq!(target, parse_redirect_target("  #REDIRECT: [[ United States ]]\n"));
+        assert_eq!(target, parse_redirect_target("#REDIRECT [[United States|USA]]"));
+        assert_eq!(target, parse_redirect_target("#REDIRECT [[United States#History]]"));
+        assert_eq!(target, parse_redirect_target(
+            "#REDIRECT [[United States]]\n\n{{R from abbreviation}}"));
+    }
+
+    #[test]
+    fn test_parse_redirect_target_not_a_redirect() {
+        assert_eq!(None, parse_redirect_target("The United States is a country."));
+        assert_eq!(None, parse_redirect_target("See [[United States]].\n#REDIRECT [[Foo]]"));
+        assert_eq!(None, parse_redirect_target("#REDIRECT [[#History]]"));
+    }
+
+    #[test]
+    fn test_parse_revision_content() {
+        let json_str =
+            r#"{"query": {"pages": {"123": {"revisions": [{"revid": 456, "*": "Text"}]}}}}"#;
+        assert_eq!(Ok("Text".to_string()),
+                   parse_revision_content(json_str, "Page", 456, FormatVersion::Legacy));
+    }
+
+    #[test]
+    fn test_parse_revision_content_version_2() {
+        let json_str = r#"{"query": {"pages": [{"pageid": 123, "revisions": [
+            {"revid": 456, "contentformat": "text/x-wiki", "content": "Text"}]}]}}"#;
+        assert_eq!(Ok("Text".to_string()),
+                   parse_revision_content(json_str, "Page", 456, FormatVersion::Version2));
+        assert!(parse_revision_content(json_str, "Page", 456, FormatVersion::Legacy).is_err());
+    }
+
+    #[test]
+    fn test_parse_revision_content_with_different_revid() {
+        let json_str =
+            r#"{"query": {"pages": {"123": {"revisions": [{"revid": 455, "*": "Text"}]}}}}"#;
+        assert!(parse_revision_content(json_str, "Page", 456, FormatVersion::Legacy).is_err());
+    }
+
+    #[test]
+    fn test_parse_revision_contents() {
+        let json = r#"{"query": {"pages": {"1": {"pageid": 1, "title": "Foo", "revisions": [
+            {"revid": 12, "parentid": 11, "*": "New text"},
+            {"revid": 11, "parentid": 10, "*": "Old text"}]}}}}"#;
+        assert_eq!(Ok(vec![(12, "New text".to_string()), (11, "Old text".to_string())]),
+                   parse_revision_contents(json, "Foo", FormatVersion::Legacy));
+    }
+
+    #[test]
+    fn test_parse_revision_contents_version_2() {
+        let json = r#"{"query": {"pages": [{"pageid": 1, "title": "Foo", "revisions": [
+            {"revid": 12, "parentid": 11, "content": "New text"},
+            {"revid": 11, "parentid": 10, "content": "Old text"}]}]}}"#;
+        assert_eq!(Ok(vec![(12, "New text".to_string()), (11, "Old text".to_string())]),
+                   parse_revision_contents(json, "Foo", FormatVersion::Version2));
+    }
+
+    #[test]
+    fn test_parse_revisions_both_formats() {
+        let legacy = r#"{"continue": {"rvcontinue": "20150801000000|10"}, "query": {"pages": {
+            "1": {"pageid": 1, "revisions": [
+                {"revid": 12, "parentid": 11, "user": "User", "comment": "Edit"}]}}}}"#;
+        let version_2 = r#"{"continue": {"rvcontinue": "20150801000000|10"}, "query": {"pages": [
+            {"pageid": 1, "revisions": [
+                {"revid": 12, "parentid": 11, "user": "User", "comment": "Edit"}]}]}}"#;
+        for json in &[legacy, version_2] {
+            let (revisions, rvcontinue) = parse_revisions(json, "Foo").unwrap();
+            assert_eq!(1, revisions.len());
+            assert_eq!((12, 11), (revisions[0].revid, revisions[0].parentid));
+            assert_eq!(("User", "Edit"), (&revisions[0].user[..], &revisions[0].comment[..]));
+            assert_eq!(Some("20150801000000|10".to_string()), rvcontinue);
+        }
+        let no_revisions = r#"{"query": {"pages": [{"pageid": 1}]}}"#;
+        assert!(parse_revisions(no_revisions, "Foo").unwrap().0.is_empty());
+        let missing = r#"{"query": {"pages": [{"ns": 0, "title": "Foo", "missing": true}]}}"#;
+        assert!(parse_revisions(missing, "Foo").unwrap().0.is_empty());
+        let legacy_missing =
+            r#"{"query": {"pages": {"-1": {"ns": 0, "title": "Foo", "missing": ""}}}}"#;
+        assert!(parse_revisions(legacy_missing, "Foo").unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn test_parse_revisions_with_suppressed_comment() {
+        let json = r#"{"query": {"pages": {"1": {"pageid": 1, "revisions": [
+            {"revid": 12, "parentid": 11, "user": "User", "commenthidden": ""},
+            {"revid": 11, "parentid": 10, "user": "User", "comment": "Edit"}]}}}}"#;
+        let (revisions, _) = parse_revisions(json, "Foo").unwrap();
+        assert_eq!(vec!["", "Edit"],
+                   revisions.iter().map(|revision| &revision.comment[..]).collect::<Vec<_>>());
+        let wrong_type = r#"{"query": {"pages": {"1": {"pageid": 1, "revisions": [
+            {"revid": 12, "parentid": 11, "user": "User", "comment": 4}]}}}}"#;
+        assert!(parse_revisions(wrong_type, "Foo").is_err());
+    }
+
+    #[test]
+    fn test_parse_revisions_with_tags_and_sha1() {
+        let json = r#"{"query": {"pages": {"1": {"pageid": 1, "revisions": [
+            {"revid": 13, "parentid": 12, "user": "User", "comment": "Undid revision 12",
+             "tags": ["mw-undo"], "sha1": "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12"},
+            {"revid": 12, "parentid": 11, "user": "User", "comment": "Edit", "sha1hidden": ""},
+            {"revid": 11, "parentid": 10, "user": "User", "comment": "Edit"}]}}}}"#;
+        let (revisions, _) = parse_revisions(json, "Foo").unwrap();
+        assert_eq!(vec!["mw-undo".to_string()], revisions[0].tags);
+        assert_eq!(Some("2fd4e1c67a2d28fced849ee1bb76e7391b93eb12".to_string()),
+                   revisions[0].sha1);
+        assert!(revisions[1].tags.is_empty());
+        assert_eq!((None, None), (revisions[1].sha1.clone(), revisions[2].sha1.clone()));
+        let wrong_type = r#"{"query": {"pages": {"1": {"pageid": 1, "revisions": [
+            {"revid": 12, "parentid": 11, "user": "User", "comment": "Edit", "tags": [4]}]}}}}"#;
+        assert!(parse_revisions(wrong_type, "Foo").is_err());
+    }
+
+    #[test]
+    fn test_parse_parsed_text_both_formats() {
+        assert_eq!(Ok("<p>Text</p>".to_string()),
+                   parse_parsed_text(r#"{"parse": {"title": "Foo", "text": {"*": "<p>Text</p>"}}}"#,
+                                     "Foo", FormatVersion::Legacy));
+        assert_eq!(Ok("<p>Text</p>".to_string()),
+                   parse_parsed_text(r#"{"parse": {"title": "Foo", "text": "<p>Text</p>"}}"#,
+                                     "Foo", FormatVersion::Version2));
+    }
+
+    #[test]
+    fn test_api_calls_request_format_version() {
+        let (port, receiver) =
+            serve_responses(vec![http_response("", "{}"), http_response("", "{}")]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        assert!(wiki.call_mediawiki_api(vec![("action", "query")]).is_ok());
+        assert_eq!("action=query&format=json", receiver.recv().unwrap().1);
+        let wiki = test_wiki("http", "127.0.0.1", port,
+                             WikiOptions { format_version: FormatVersion::Version2,
+                                           ..WikiOptions::default() });
+        assert!(wiki.call_mediawiki_api(vec![("action", "query")]).is_ok());
+        assert_eq!("action=query&format=json&formatversion=2", receiver.recv().unwrap().1);
+    }
+
+    #[test]
+    fn test_get_desktop_hostname() {
+        assert_eq!(Some("en.wikipedia.org".to_string()),
+                   get_desktop_hostname("en.m.wikipedia.org"));
+        assert_eq!(Some("mediawiki.org".to_string()), get_desktop_hostname("m.mediawiki.org"));
+        assert_eq!(None, get_desktop_hostname("en.wikipedia.org"));
+        assert_eq!(None, get_desktop_hostname("m.org"));
+        assert_eq!(None, get_desktop_hostname("localhost"));
+    }
+
+    #[test]
+    fn test_get_mobile_hostname() {
+        assert_eq!("en.m.wikipedia.org", get_mobile_hostname("en.wikipedia.org"));
+        assert_eq!("m.mediawiki.org", get_mobile_hostname("mediawiki.org"));
+        assert_eq!("en.m.wikipedia.org", get_mobile_hostname("en.m.wikipedia.org"));
+    }
+
+    fn redis_wiki(redis_port: u16, options: WikiOptions) -> Wiki {
+        Wiki::new("http".to_string(), "localhost".to_string(), 80, Client::new(),
+                  "test-agent/1.0".to_string(),
+                  Some(ConnectionInfo {
+                      addr: Box::new(ConnectionAddr::Tcp("127.0.0.1".to_string(), redis_port)),
+                      db: 0,
+                      passwd: None,
+                  }),
+                  options)
+    }
+
+    #[test]
+    fn test_cached_values_expire_after_ttl() {
+        let (port, receiver) = serve_redis();
+        let wiki = redis_wiki(port, WikiOptions::default());
+        wiki.try_cache_value_with_ttl("key".to_string(), "value".to_string(), Some(5));
+        assert_eq!(vec!["SETEX", "key", "5", "value"], receiver.recv().unwrap());
+    }
+
+    #[test]
+    fn test_cached_values_without_ttl_never_expire() {
+        let (port, receiver) = serve_redis();
+        let wiki = redis_wiki(port, WikiOptions::default());
+        wiki.try_cache_value_with_ttl("key".to_string(), "value".to_string(), None);
+        assert_eq!(vec!["SET", "key", "value"], receiver.recv().unwrap());
+    }
+
+    #[test]
+    fn test_get_api_call_kind() {
+        assert_eq!("parse", get_api_call_kind(&[("action", "parse"), ("prop", "text")]));
+        assert_eq!("query+logevents",
+                   get_api_call_kind(&[("action", "query"), ("list", "logevents")]));
+        assert_eq!("query+revisions",
+                   get_api_call_kind(&[("action", "query"), ("prop", "revisions"),
+                                       ("rvprop", "comment|ids|sha1|tags|user")]));
+        assert_eq!("query+revisions+content",
+                   get_api_call_kind(&[("action", "query"), ("prop", "revisions"),
+                                       ("rvprop", "content|ids")]));
+        assert_eq!("query", get_api_call_kind(&[("action", "query")]));
+    }
+
+    #[test]
+    fn test_parse_cache_rules() {
+        assert_eq!(Ok(vec![ApiCacheRule::new("query+logevents", true, Some(30)),
+                           ApiCacheRule::new("parse", false, None),
+                           ApiCacheRule::new("query+siteinfo", true, None)]),
+                   parse_cache_rules("query+logevents:30, parse:off,query+siteinfo"));
+        assert_eq!(Ok(vec![]), parse_cache_rules(""));
+        assert!(parse_cache_rules("parse:soon").is_err());
+        assert!(parse_cache_rules(":30").is_err());
+    }
+
+    #[test]
+    fn test_api_calls_are_cached_by_rule() {
+        let (redis_port, redis_receiver) = serve_redis();
+        let (wiki_port, _) = serve_responses(vec![http_response("", "{}"),
+                                                  http_response("", "{}")]);
+        let mut cache_rules = default_cache_rules();
+        cache_rules.push(ApiCacheRule::new("query+logevents", true, Some(30)));
+        let wiki = Wiki::new(
+            "http".to_string(), "127.0.0.1".to_string(), wiki_port, Client::new(),
+            "test-agent/1.0".to_string(),
+            Some(ConnectionInfo {
+                addr: Box::new(ConnectionAddr::Tcp("127.0.0.1".to_string(), redis_port)),
+                db: 0,
+                passwd: None,
+            }),
+            WikiOptions { cache_rules: cache_rules, cache_ttl_seconds: Some(600),
+                          ..WikiOptions::default() });
+
+        assert_eq!(Ok("{}".to_string()),
+                   wiki.call_mediawiki_api(vec![("action", "query"), ("list", "logevents")]));
+        let key = "action=query&list=logevents&format=json".to_string();
+        assert_eq!(vec!["GET".to_string(), key.clone()], redis_receiver.recv().unwrap());
+        assert_eq!(vec!["SETEX".to_string(), key, "30".to_string(), "{}".to_string()],
+                   redis_receiver.recv().unwrap());
+
+        assert_eq!(Ok("{}".to_string()),
+                   wiki.call_mediawiki_api(vec![("action", "query"), ("list", "allpages")]));
+        assert!(redis_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_requests_send_user_agent() {
+        let (port, receiver) = serve_responses(vec![http_response("", "page")]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        assert_eq!(Ok("page".to_string()), wiki.get_current_page_content("Foo"));
+        let (headers, _) = receiver.recv().unwrap();
+        assert!(headers.contains(&"User-Agent: test-agent/1.0".to_string()));
+    }
+
+    #[test]
+    fn test_get_maxlag_retry_secs() {
+        let lagged = r#"{"error": {"code": "maxlag", "info": "Waiting for db1: 7 seconds"}}"#;
+        assert_eq!(Some(2), get_maxlag_retry_secs(lagged, Some(2)));
+        assert_eq!(Some(5), get_maxlag_retry_secs(lagged, None));
+        assert_eq!(None, get_maxlag_retry_secs(r#"{"batchcomplete": ""}"#, None));
+        assert_eq!(None, get_maxlag_retry_secs(r#"{"error": {"code": "badtitle"}}"#, None));
+    }
+
+    #[test]
+    fn test_call_mediawiki_api_with_maxlag() {
+        let (port, receiver) = serve_responses(vec![http_response("", "{}")]);
+        let wiki = test_wiki("http", "127.0.0.1", port,
+                             WikiOptions { maxlag: Some(5), ..WikiOptions::default() });
+        assert_eq!(Ok("{}".to_string()),
+                   wiki.call_mediawiki_api(vec![("action", "query")]));
+        let (_, body) = receiver.recv().unwrap();
+        assert_eq!("action=query&format=json&maxlag=5", body);
+    }
+
+    #[test]
+    fn test_call_mediawiki_api_retries_when_lagged() {
+        let lagged = r#"{"error": {"code": "maxlag", "info": "Waiting for db1: 7 seconds"}}"#;
+        let (port, _receiver) = serve_responses(
+            vec![http_response("Retry-After: 0\r\n", lagged), http_response("", "{}")]);
+        let wiki = test_wiki("http", "127.0.0.1", port,
+                             WikiOptions { maxlag: Some(5), ..WikiOptions::default() });
+        assert_eq!(Ok("{}".to_string()),
+                   wiki.call_mediawiki_api(vec![("action", "query")]));
+    }
+
+    #[test]
+    fn test_call_mediawiki_api_gives_up_when_lagged() {
+        let lagged = r#"{"error": {"code": "maxlag", "info": "Waiting for db1: 7 seconds"}}"#;
+        let (port, _receiver) = serve_responses(
+            vec![http_response("Retry-After: 0\r\n", lagged),
+                 http_response("Retry-After: 0\r\n", lagged)]);
+        let wiki = test_wiki("http", "127.0.0.1", port,
+                             WikiOptions { maxlag: Some(5), maxlag_retries: 1,
+                                           ..WikiOptions::default() });
+        assert!(wiki.call_mediawiki_api(vec![("action", "query")]).is_err());
+    }
+
+    #[test]
+    fn test_get_revision_windows() {
+        assert_eq!(vec![(100, 76), (75, 51), (50, 26), (25, 1)], get_revision_windows(1, 100, 4));
+        assert_eq!(vec![(10, 8), (7, 5), (4, 1)], get_revision_windows(1, 10, 3));
+        assert_eq!(vec![(2, 2), (1, 1)], get_revision_windows(1, 2, 5));
+    }
+
+    #[test]
+    fn test_windowed_revisions_reconstruct_full_history() {
+        fn revision(revid: u64) -> Revision {
+            Revision { revid: revid, parentid: revid - 1, comment: "".to_string(),
+                       user: "User".to_string(), tags: Vec::new(), sha1: None }
+        }
+        // The page's revisions, newest first, among other pages' revisions.
+        let history = (1..2000).rev().filter(|revid| revid % 3 == 0).map(revision)
+            .collect::<Vec<_>>();
+        let query = |newest_id: u64, oldest_id: u64, limit: usize| {
+            history.iter().filter(|revision| revision.revid <= newest_id &&
+                                  revision.revid >= oldest_id)
+                .take(limit).cloned().collect::<Vec<_>>()
+        };
+
+        let newest_revisions = query(1999, 0, 100);
+        let oldest_fetched_id = newest_revisions.last().unwrap().revid;
+        let mut windows = vec![newest_revisions.clone(), newest_revisions];
+        for (newest_id, oldest_id) in get_revision_windows(3, oldest_fetched_id - 1, 4) {
+            windows.push(query(newest_id, oldest_id, 500));
+        }
+        assert_eq!(history.iter().map(|revision| revision.revid).collect::<Vec<_>>(),
+                   reconcile_revision_windows(windows, 5000).into_iter()
+                   .map(|revision| revision.revid).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_get_revisions_until_stops_early() {
+        // Only two batches are served, so fetching a third would fail.
+        let (port, receiver) = serve_responses(vec![
+            revisions_response(&[10, 9], "Edit", Some("20150801000000|8")),
+            revisions_response(&[8, 7], "Reverted vandalism", Some("20150801000000|6"))]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        let revisions = wiki.get_revisions_until("Foo", |revisions| {
+            revisions.iter().filter(|revision| revision.comment.contains("vandal")).count() >= 2
+        }).unwrap();
+        assert_eq!(vec![10, 9, 8, 7],
+                   revisions.iter().map(|revision| revision.revid).collect::<Vec<_>>());
+        let (_, first_body) = receiver.recv().unwrap();
+        assert!(first_body.contains("rvlimit=50&"));
+        assert!(!first_body.contains("rvcontinue"));
+        let (_, second_body) = receiver.recv().unwrap();
+        assert!(second_body.contains("rvlimit=200&rvcontinue=20150801000000|8&"));
+    }
+
+    #[test]
+    fn test_get_revisions_until_stops_at_end_of_history() {
+        let (port, _receiver) =
+            serve_responses(vec![revisions_response(&[2, 1], "Edit", None)]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        assert_eq!(2, wiki.get_revisions_until("Foo", |_| false).unwrap().len());
+    }
+
+    #[test]
+    fn test_get_revisions_follows_continuation() {
+        let (port, receiver) = serve_responses(vec![
+            revisions_response(&[10, 9], "Edit", Some("20150801000000|8")),
+            revisions_response(&[8, 7], "Edit", None)]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        let revisions = wiki.get_revisions("Foo", 1000).unwrap();
+        assert_eq!(vec![10, 9, 8, 7],
+                   revisions.iter().map(|revision| revision.revid).collect::<Vec<_>>());
+        let (_, first_body) = receiver.recv().unwrap();
+        assert!(first_body.contains("rvlimit=500&"));
+        let (_, second_body) = receiver.recv().unwrap();
+        assert!(second_body.contains("rvlimit=498&rvcontinue=20150801000000|8&"));
+    }
+
+    #[test]
+    fn test_get_revisions_stops_at_limit() {
+        // Only one response is served, so following the continuation would fail.
+        let (port, _receiver) = serve_responses(
+            vec![revisions_response(&[10, 9], "Edit", Some("20150801000000|8"))]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        assert_eq!(2, wiki.get_revisions("Foo", 2).unwrap().len());
+    }
+
+    #[test]
+    fn test_get_draft_origin_title() {
+        let moved = r#"{"query": {"logevents": [
+            {"title": "Draft:Foo bar", "params": {"target_ns": 0, "target_title": "Foo bar"}}]}}"#;
+        let (port, receiver) = serve_responses(vec![http_response("", moved)]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        assert_eq!(Ok(Some("Draft%3AFoo_bar".to_string())),
+                   wiki.get_draft_origin_title("Foo_bar"));
+        let (_, body) = receiver.recv().unwrap();
+        assert!(body.contains("letype=move&leprop=title|details&letitle=Draft%3AFoo_bar&"));
+
+        let not_moved = r#"{"query": {"logevents": []}}"#;
+        let (port, _receiver) = serve_responses(vec![http_response("", not_moved)]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        assert_eq!(Ok(None), wiki.get_draft_origin_title("Foo_bar"));
+    }
+
+    #[test]
+    fn test_get_revision_contents_batches_queries() {
+        fn contents_response(revids: &[u64]) -> String {
+            let revisions = revids.iter().map(|revid| format!(
+                r#"{{"revid": {}, "parentid": {}, "*": "Text {}"}}"#, revid, revid - 1, revid))
+                .collect::<Vec<_>>().join(", ");
+            http_response("", &format!(
+                r#"{{"query": {{"pages": {{"1": {{"pageid": 1, "revisions": [{}]}}}}}}}}"#,
+                revisions))
+        }
+        let ids = (1..61).collect::<Vec<u64>>();
+        // The batches are fetched in parallel, so either could be requested first; serve every
+        // revision in both responses.
+        let (port, receiver) =
+            serve_responses(vec![contents_response(&ids), contents_response(&ids)]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        let contents = wiki.get_revision_contents("Foo", &ids).unwrap();
+        assert_eq!(60, contents.len());
+        assert_eq!("Text 42", contents[&42]);
+        // The queries are cacheable, so they're sent as GETs, with the parameters in the URL.
+        let mut request_lines =
+            vec![receiver.recv().unwrap().0[0].clone(), receiver.recv().unwrap().0[0].clone()];
+        request_lines.sort();
+        assert!(request_lines[0].contains("revids=1"));
+        assert!(request_lines[0].contains("50&format=json"));
+        assert!(request_lines[1].contains("revids=51"));
+    }
+
+    #[test]
+    fn test_call_mediawiki_api_retries_server_errors() {
+        let (port, _receiver) = serve_responses(
+            vec![http_response_with_status("503 Service Unavailable", "", ""),
+                 http_response("", "{}")]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        assert_eq!(Ok("{}".to_string()),
+                   wiki.call_mediawiki_api(vec![("action", "query")]));
+    }
+
+    #[test]
+    fn test_call_mediawiki_api_gives_up_after_retries() {
+        let (port, _receiver) = serve_responses(
+            vec![http_response_with_status("503 Service Unavailable", "", ""),
+                 http_response_with_status("502 Bad Gateway", "", "")]);
+        let wiki = test_wiki("http", "127.0.0.1", port,
+                             WikiOptions { api_max_retries: 1, ..WikiOptions::default() });
+        assert!(wiki.call_mediawiki_api(vec![("action", "query")]).is_err());
+    }
+
+    #[test]
+    fn test_call_mediawiki_api_does_not_retry_client_errors() {
+        // Only one response is served, so a retry would fail.
+        let (port, _receiver) =
+            serve_responses(vec![http_response_with_status("404 Not Found", "", "{}")]);
+        let wiki = test_wiki("http", "127.0.0.1", port,
+                             WikiOptions { api_max_retries: 3, ..WikiOptions::default() });
+        assert_eq!(Ok("{}".to_string()),
+                   wiki.call_mediawiki_api(vec![("action", "query")]));
+    }
+
+    #[test]
+    fn test_call_mediawiki_api_does_not_retry_undecodable_responses() {
+        // 0xFF is never valid in UTF-8.
+        let invalid_response = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n{}\xFF".to_vec();
+        let (port, _receiver) =
+            serve_responses(vec![invalid_response, http_response("", "{}").into_bytes()]);
+        let wiki = test_wiki("http", "127.0.0.1", port,
+                             WikiOptions { api_max_retries: 3, ..WikiOptions::default() });
+        assert!(wiki.call_mediawiki_api(vec![("action", "query")]).is_err());
+    }
+
+    #[test]
+    fn test_api_requests_are_rate_limited() {
+        let (port, _receiver) = serve_responses((0..20).map(|_| http_response("", "{}")).collect());
+        let wiki = test_wiki("http", "127.0.0.1", port,
+                             WikiOptions { max_api_requests_per_second: 10,
+                                           ..WikiOptions::default() });
+        let start_ns = time::precise_time_ns();
+        for _ in 0..20 {
+            assert!(wiki.call_mediawiki_api(vec![("action", "query")]).is_ok());
+        }
+        // The first 10 are sent at once, and the other 10 at 10 per second.
+        assert!((time::precise_time_ns() - start_ns) / 1_000_000 >= 900);
+    }
+
+    #[test]
+    fn test_get_canonical_title_follows_redirects() {
+        let (port, _) = serve_responses(vec![
+            revisions_response(&[10], "Edit", None), content_response(10, "#REDIRECT [[B]]"),
+            revisions_response(&[20], "Edit", None), content_response(20, "Text")]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        assert_eq!(Ok("B".to_string()), wiki.get_canonical_title("A"));
+        // Both titles are remembered, so the fake wiki (which has stopped serving) isn't asked
+        // again.
+        assert_eq!(Ok("B".to_string()), wiki.get_canonical_title("A"));
+        assert_eq!(Ok("B".to_string()), wiki.get_canonical_title("B"));
+    }
+
+    #[test]
+    fn test_get_canonical_title_ignores_fragment() {
+        let (port, receiver) = serve_responses(vec![
+            revisions_response(&[10], "Edit", None), content_response(10, "Text")]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        assert_eq!(Ok("Foo".to_string()), wiki.get_canonical_title("Foo%23History"));
+        for _ in 0..2 {
+            assert!(!receiver.recv().unwrap().0[0].contains("History"));
+        }
+    }
+
+    #[test]
+    fn test_get_canonical_title_detects_redirect_loops() {
+        let (port, _) = serve_responses(vec![
+            revisions_response(&[10], "Edit", None), content_response(10, "#REDIRECT [[B]]"),
+            revisions_response(&[20], "Edit", None), content_response(20, "#REDIRECT [[A]]")]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        assert_eq!(Err(AppError::Upstream(
+                       "Redirects from \"A\" loop back to \"A\"".to_string())),
+                   wiki.get_canonical_title("A"));
+    }
+
+    #[test]
+    fn test_get_canonical_title_of_missing_page() {
+        let (port, _) = serve_responses(vec![http_response(
+            "", r#"{"query": {"pages": {"-1": {"ns": 0, "title": "Foo", "missing": ""}}}}"#)]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        assert_eq!(Err(AppError::PageNotFound), wiki.get_canonical_title("Foo"));
+    }
+
+    #[test]
+    fn test_get_canonical_title_limits_redirect_depth() {
+        let (port, _) = serve_responses(vec![
+            revisions_response(&[10], "Edit", None), content_response(10, "#REDIRECT [[B]]"),
+            revisions_response(&[20], "Edit", None), content_response(20, "#REDIRECT [[C]]")]);
+        let wiki = test_wiki("http", "127.0.0.1", port,
+                             WikiOptions { max_redirect_depth: 1, ..WikiOptions::default() });
+        assert_eq!(Err(AppError::Upstream(
+                       "Gave up on \"A\" after following 1 redirects".to_string())),
+                   wiki.get_canonical_title("A"));
+    }
+
+    #[test]
+    fn test_api_method_matches_cacheable() {
+        let (port, receiver) = serve_responses(vec![
+            content_response(10, "Text"), http_response("", "{}"),
+            http_response("", r#"{"parse": {"text": {"*": "<p>Text</p>"}}}"#)]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+
+        assert_eq!(Ok("Text".to_string()), wiki.get_revision_content("Foo Bar", 10));
+        let (headers, body) = receiver.recv().unwrap();
+        assert!(headers[0].starts_with(
+            "GET /w/api.php?action=query&prop=revisions&titles=Foo%20Bar&"));
+        assert_eq!("", body);
+
+        assert_eq!(Ok("{}".to_string()),
+                   wiki.call_mediawiki_api(vec![("action", "query")]));
+        let (headers, body) = receiver.recv().unwrap();
+        assert!(headers[0].starts_with("POST /w/api.php "));
+        assert_eq!("action=query&format=json", body);
+
+        // Parsing is cacheable, but the wikitext could be too long for a URL.
+        assert_eq!(Ok("<p>Text</p>".to_string()), wiki.parse_wikitext("Foo", "Text"));
+        let (headers, body) = receiver.recv().unwrap();
+        assert!(headers[0].starts_with("POST /w/api.php "));
+        assert!(body.contains("&text=Text&"));
+    }
+
+    #[test]
+    fn test_decode_response_body() {
+        let json = r#"{"batchcomplete": ""}"#;
+        let mut gzip_encoder = GzEncoder::new(Vec::new(), Compression::Default);
+        gzip_encoder.write_all(json.as_bytes()).unwrap();
+        let gzipped = gzip_encoder.finish().unwrap();
+        assert_eq!(Ok(json.to_string()), decode_response_body(gzipped.clone(), Some("gzip")));
+        assert_eq!(Ok(json.to_string()), decode_response_body(gzipped, Some(" GZIP ")));
+
+        let mut zlib_encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+        zlib_encoder.write_all(json.as_bytes()).unwrap();
+        let deflated = zlib_encoder.finish().unwrap();
+        assert_eq!(Ok(json.to_string()), decode_response_body(deflated, Some("deflate")));
+
+        assert_eq!(Ok(json.to_string()), decode_response_body(json.as_bytes().to_vec(), None));
+        assert_eq!(Ok(json.to_string()),
+                   decode_response_body(json.as_bytes().to_vec(), Some("identity")));
+        assert!(decode_response_body(json.as_bytes().to_vec(), Some("gzip")).is_err());
+        assert!(decode_response_body(json.as_bytes().to_vec(), Some("br")).is_err());
+    }
+
+    #[test]
+    fn test_api_calls_accept_gzip() {
+        let json = r#"{"batchcomplete": ""}"#;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(json.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let mut response =
+            format!("HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+                    gzipped.len()).into_bytes();
+        response.extend(gzipped);
+        let (port, receiver) = serve_responses(vec![response]);
+        let wiki = test_wiki("http", "127.0.0.1", port, WikiOptions::default());
+        assert_eq!(Ok(json.to_string()), wiki.call_mediawiki_api(vec![("action", "query")]));
+        let (headers, _) = receiver.recv().unwrap();
+        assert!(headers.iter().any(|header| header.starts_with("Accept-Encoding: gzip")));
+    }
 }
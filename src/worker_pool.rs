@@ -0,0 +1,139 @@
+//! A fixed number of worker threads that run jobs from a shared queue, so that the threads a
+//! request needs (one per section it merges, plus one to fetch its page skeleton) don't grow with
+//! the size of the article.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+/// A job for a worker. Box<FnOnce()> can't be called, so jobs are boxed as this instead.
+trait Job: Send {
+    fn run(self: Box<Self>);
+}
+
+impl<F: FnOnce() + Send> Job for F {
+    fn run(self: Box<Self>) {
+        (*self)()
+    }
+}
+
+/// The state shared between a pool and its workers.
+struct Workers {
+    /// The queue the workers take jobs from.
+    jobs: Mutex<Receiver<Box<Job + 'static>>>,
+    /// The number of workers running a job.
+    busy: AtomicUsize,
+    /// The most workers that have been running jobs at once.
+    peak_busy: AtomicUsize,
+}
+
+/// A pool of worker threads. Jobs run in the order they're submitted, as workers become free.
+/// Clones share the same workers.
+#[derive(Clone)]
+pub struct WorkerPool {
+    jobs: Arc<Mutex<Sender<Box<Job + 'static>>>>,
+    workers: Arc<Workers>,
+}
+
+impl WorkerPool {
+    /// Starts a pool of `size` workers, named "<name>-<index>".
+    pub fn new(name: &str, size: usize) -> WorkerPool {
+        assert!(size > 0, "A worker pool needs at least one worker");
+        let (sender, receiver) = channel::<Box<Job + 'static>>();
+        let workers = Arc::new(Workers {
+            jobs: Mutex::new(receiver),
+            busy: AtomicUsize::new(0),
+            peak_busy: AtomicUsize::new(0),
+        });
+        for index in 0..size {
+            spawn_worker(format!("{}-{}", name, index), workers.clone());
+        }
+        WorkerPool { jobs: Arc::new(Mutex::new(sender)), workers: workers }
+    }
+
+    /// Queues `job` to run on the next free worker.
+    pub fn execute<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        // The workers only stop once every Sender is gone, so this can't fail.
+        self.jobs.lock().unwrap().send(Box::new(job)).unwrap();
+    }
+
+    /// Returns the most workers that have been running jobs at once.
+    pub fn peak_busy_workers(&self) -> usize {
+        self.workers.peak_busy.load(Ordering::SeqCst)
+    }
+}
+
+/// Starts a worker thread named `name`, which runs jobs from `workers` until the pool is dropped.
+fn spawn_worker(name: String, workers: Arc<Workers>) {
+    let thread_name = name.clone();
+    thread::Builder::new().name(thread_name).spawn(move|| {
+        // Replaces this worker if a job panics, so the pool doesn't shrink.
+        let _sentinel = Sentinel { name: name, workers: workers.clone() };
+        loop {
+            let job = match workers.jobs.lock().unwrap().recv() {
+                Ok(job) => job,
+                // The pool is gone.
+                Err(_) => return,
+            };
+            let busy = workers.busy.fetch_add(1, Ordering::SeqCst) + 1;
+            // Raises the peak to `busy`, unless another worker has raised it past that.
+            let mut peak = workers.peak_busy.load(Ordering::SeqCst);
+            while busy > peak {
+                peak = workers.peak_busy.compare_and_swap(peak, busy, Ordering::SeqCst);
+            }
+            job.run();
+            workers.busy.fetch_sub(1, Ordering::SeqCst);
+        }
+    }).unwrap();
+}
+
+/// Watches a worker thread, and starts a replacement if it panics.
+struct Sentinel {
+    name: String,
+    workers: Arc<Workers>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            warn!("Worker {} panicked; replacing it", self.name);
+            self.workers.busy.fetch_sub(1, Ordering::SeqCst);
+            spawn_worker(self.name.clone(), self.workers.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WorkerPool;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_worker_pool_runs_every_job() {
+        let pool = WorkerPool::new("test", 3);
+        let (sender, receiver) = channel();
+        for i in 0..20 {
+            let sender = sender.clone();
+            pool.execute(move|| {
+                thread::sleep(Duration::from_millis(5));
+                sender.send(i).unwrap();
+            });
+        }
+        let mut results = (0..20).map(|_| receiver.recv().unwrap()).collect::<Vec<_>>();
+        results.sort();
+        assert_eq!((0..20).collect::<Vec<_>>(), results);
+        assert!(pool.peak_busy_workers() <= 3);
+    }
+
+    #[test]
+    fn test_worker_pool_survives_panics() {
+        let pool = WorkerPool::new("test", 1);
+        pool.execute(|| panic!("Job failed"));
+        let (sender, receiver) = channel();
+        pool.execute(move|| sender.send(()).unwrap());
+        assert_eq!(Ok(()), receiver.recv());
+    }
+}